@@ -0,0 +1,67 @@
+; backdrop.nes: fills the screen with palette color $21 (light blue) and idles.
+;
+; Written for the sprocketnes documentation examples and dedicated to the
+; public domain. NROM-128 with 8 KB of blank CHR ROM, so every background
+; pixel is the backdrop color.
+;
+; Build with ca65/ld65 (NROM-128 layout, PRG at $C000), or use the checked-in
+; backdrop.nes.
+
+PPUCTRL   = $2000
+PPUMASK   = $2001
+PPUSTATUS = $2002
+PPUSCROLL = $2005
+PPUADDR   = $2006
+PPUDATA   = $2007
+
+.segment "HEADER"
+    .byte "NES", $1a
+    .byte 1                 ; 16 KB PRG ROM
+    .byte 1                 ; 8 KB CHR ROM
+    .byte 0, 0              ; mapper 0, horizontal mirroring
+    .res 8, 0
+
+.segment "CODE"
+reset:
+    sei
+    cld
+    ldx #$ff
+    txs
+    lda #$00
+    sta PPUCTRL             ; no NMI
+    sta PPUMASK             ; rendering off
+
+    ; Wait two vblanks for the PPU to warm up.
+@vblank1:
+    bit PPUSTATUS
+    bpl @vblank1
+@vblank2:
+    bit PPUSTATUS
+    bpl @vblank2
+
+    ; Backdrop color.
+    lda #$3f
+    sta PPUADDR
+    lda #$00
+    sta PPUADDR
+    lda #$21
+    sta PPUDATA
+
+    lda #$00
+    sta PPUSCROLL
+    sta PPUSCROLL
+    lda #$0a                ; show the background, including the leftmost 8 pixels
+    sta PPUMASK
+
+forever:
+    jmp forever
+
+nmi:
+irq:
+    rti
+
+.segment "VECTORS"
+    .word nmi, reset, irq
+
+.segment "CHARS"
+    .res 8192, 0
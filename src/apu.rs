@@ -7,23 +7,30 @@
 #![cfg_attr(not(feature = "audio"), allow(dead_code))]
 #![cfg_attr(not(feature = "audio"), allow(unused_variables))]
 
+#[cfg(feature = "audio")]
+use audio;
 use audio::OutputBuffer;
 use mem::Mem;
+use region::Region;
 
 #[cfg(feature = "audio")]
 use speex::Resampler;
 use util::{Save, Xorshift};
 
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::ops::{Deref, DerefMut};
-
-const CYCLES_PER_EVEN_TICK: u64 = 7438;
-const CYCLES_PER_ODD_TICK: u64 = 7439;
+use std::rc::Rc;
+#[cfg(feature = "audio")]
+use std::time::Instant;
 
 const NES_SAMPLE_RATE: u32 = 1789920; // Actual is 1789800, but this is divisible by 240.
-const OUTPUT_SAMPLE_RATE: u32 = 44100;
+pub const OUTPUT_SAMPLE_RATE: u32 = 44100;
 const TICK_FREQUENCY: u32 = 240;
 const NES_SAMPLES_PER_TICK: u32 = NES_SAMPLE_RATE / TICK_FREQUENCY;
+/// Expansion audio is rendered at a 33rd of the APU's sample rate, which divides a tick evenly.
+const EXPANSION_DOWNSAMPLING: u32 = 33;
 
 const PULSE_WAVEFORMS: [u8; 4] = [0b01000000, 0b01100000, 0b01111000, 0b10011111];
 
@@ -37,11 +44,6 @@ const TRIANGLE_WAVEFORM: [u8; 32] = [
     13, 14, 15,
 ];
 
-// TODO: PAL
-const NOISE_PERIODS: [u16; 16] = [
-    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
-];
-
 //
 // Channel lengths
 //
@@ -64,6 +66,7 @@ impl DisableBit for DisableBit7 {
 }
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuLength {
     disable: bool,
     id: u8,
@@ -109,6 +112,7 @@ impl ApuLength {
 
 /// Volume envelope
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuEnvelope {
     enabled: bool,
     volume: u8,
@@ -187,6 +191,7 @@ impl ApuEnvelope {
 
 /// Audio frequencies, shared by the pulses and the triangle
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuTimer {
     /// The raw timer value as written to the register.
     value: u16,
@@ -227,6 +232,7 @@ impl ApuTimer {
 
 /// APUPULSE: [0x4000, 0x4008)
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuPulse {
     envelope: ApuEnvelope,
     sweep: ApuPulseSweep,
@@ -260,6 +266,7 @@ save_struct!(ApuPulse {
 
 /// APU pulse sweep
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuPulseSweep(u8);
 
 impl Deref for ApuPulseSweep {
@@ -296,6 +303,7 @@ impl ApuPulseSweep {
 
 /// APUTRIANGLE: [0x4008, 0x400c)
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuTriangle {
     timer: ApuTimer,
     length: ApuLength,
@@ -354,6 +362,7 @@ impl ApuTriangle {
 
 /// APUNOISE: [0x400c, 0x4010)
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuNoise {
     envelope: ApuEnvelope,
     /// The number of ticks per possible waveform change.
@@ -381,8 +390,89 @@ impl ApuNoise {
     }
 }
 
+/// APUDMC: [0x4010, 0x4014)
+///
+/// FIXME: The sample reader fetches bytes through the DMA arbiter (see `mem::Dma`), but the output
+/// unit is not emulated yet. Only levels written to $4011 are heard.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct ApuDmc {
+    /// $4010: IL--RRRR
+    flags: u8,
+    /// $4011: the 7-bit direct load value
+    output_level: u8,
+    /// $4012: AAAAAAAA
+    sample_address: u8,
+    /// $4013: LLLLLLLL
+    sample_length: u8,
+    /// The sample reader: the address of the next byte, how many are left, and the CPU cycle at
+    /// which the next one is needed (0 for right away).
+    current_address: u16,
+    bytes_remaining: u16,
+    next_fetch: u64,
+}
+
+save_struct!(ApuDmc {
+    flags,
+    output_level,
+    sample_address,
+    sample_length,
+    current_address,
+    bytes_remaining,
+    next_fetch
+});
+
+impl ApuDmc {
+    fn new() -> ApuDmc {
+        ApuDmc {
+            flags: 0,
+            output_level: 0,
+            sample_address: 0,
+            sample_length: 0,
+            current_address: 0,
+            bytes_remaining: 0,
+            next_fetch: 0,
+        }
+    }
+
+    fn storeb(&mut self, addr: u16, val: u8) {
+        match addr & 0x3 {
+            0 => self.flags = val,
+            1 => self.output_level = val & 0x7f,
+            2 => self.sample_address = val,
+            3 => self.sample_length = val,
+            _ => panic!("can't happen"),
+        }
+    }
+
+    fn irq_enabled(self) -> bool {
+        (self.flags & 0x80) != 0
+    }
+    fn loops(self) -> bool {
+        (self.flags & 0x40) != 0
+    }
+    fn rate_index(self) -> u8 {
+        self.flags & 0xf
+    }
+    /// The sample address in CPU space: %11AAAAAA.AA000000
+    fn address(self) -> u16 {
+        0xc000 | ((self.sample_address as u16) << 6)
+    }
+    /// The sample length in bytes: %LLLL.LLLL0001
+    fn length(self) -> u16 {
+        ((self.sample_length as u16) << 4) | 1
+    }
+
+    /// Starts reading the sample from the beginning.
+    fn restart(&mut self) {
+        self.current_address = self.address();
+        self.bytes_remaining = self.length();
+    }
+}
+
 /// APUSTATUS: 0x4015
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct ApuStatus(u8);
 
 impl Deref for ApuStatus {
@@ -411,31 +501,365 @@ impl ApuStatus {
     fn noise_enabled(self) -> bool {
         self.0 & 0x08 != 0
     }
+
+    fn dmc_enabled(self) -> bool {
+        self.0 & 0x10 != 0
+    }
 }
 
 /// Audio registers
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Regs {
     pulses: [ApuPulse; 2],
     triangle: ApuTriangle,
     noise: ApuNoise,
+    dmc: ApuDmc,
     status: ApuStatus,
 }
 
 impl Save for Regs {
-    fn save(&mut self, fd: &mut File) {
-        self.pulses[0].save(fd);
-        self.pulses[1].save(fd);
-        self.triangle.save(fd);
-        self.noise.save(fd);
-        self.status.save(fd);
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.pulses[0].save(fd)?;
+        self.pulses[1].save(fd)?;
+        self.triangle.save(fd)?;
+        self.noise.save(fd)?;
+        self.dmc.save(fd)?;
+        self.status.save(fd)?;
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.pulses[0].load(fd)?;
+        self.pulses[1].load(fd)?;
+        self.triangle.load(fd)?;
+        self.noise.load(fd)?;
+        self.dmc.load(fd)?;
+        self.status.load(fd)?;
+        Ok(())
+    }
+}
+
+//
+// Register snapshots for visualizers
+//
+
+/// The state of a pulse channel as seen by a visualizer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PulseSnapshot {
+    pub enabled: bool,
+    /// The raw 11-bit timer period.
+    pub period: u16,
+    /// The current envelope (or constant) volume, 0-15.
+    pub volume: u8,
+    /// The duty cycle index, 0-3.
+    pub duty: u8,
+    pub length_remaining: u8,
+}
+
+/// The state of the triangle channel as seen by a visualizer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TriangleSnapshot {
+    pub enabled: bool,
+    pub period: u16,
+    pub linear_counter: u8,
+    pub length_remaining: u8,
+}
+
+/// The state of the noise channel as seen by a visualizer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NoiseSnapshot {
+    pub enabled: bool,
+    /// The timer period in CPU cycles, from the NTSC period table.
+    pub period: u16,
+    pub volume: u8,
+    pub length_remaining: u8,
+}
+
+/// The state of the delta modulation channel as seen by a visualizer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DmcSnapshot {
+    pub enabled: bool,
+    pub irq_enabled: bool,
+    pub loops: bool,
+    pub rate_index: u8,
+    pub output_level: u8,
+    /// The sample start address in CPU space.
+    pub address: u16,
+    /// The sample length in bytes.
+    pub length: u16,
+}
+
+/// A read-only copy of the APU registers. Frontends take one of these per frame (see
+/// `Apu::snapshot()`) instead of reaching into the APU's private state.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApuSnapshot {
+    pub pulses: [PulseSnapshot; 2],
+    pub triangle: TriangleSnapshot,
+    pub noise: NoiseSnapshot,
+    pub dmc: DmcSnapshot,
+}
+
+//
+// Fast-forward
+//
+
+/// What to do with the sound while running faster than real time.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FastForwardAudio {
+    /// Play silence.
+    Mute,
+    /// Play one batch of samples in every `speed` at normal pitch and drop the rest.
+    Drop,
+    /// Play all the samples sped up, which raises the pitch.
+    Pitch,
+}
+
+impl FastForwardAudio {
+    pub fn from_name(name: &str) -> Option<FastForwardAudio> {
+        match name {
+            "mute" => Some(FastForwardAudio::Mute),
+            "drop" => Some(FastForwardAudio::Drop),
+            "pitch" => Some(FastForwardAudio::Pitch),
+            _ => None,
+        }
+    }
+}
+
+/// Fast-forward state. The emulator is paced by waiting for the audio device, so running at
+/// `speed` means waiting once every `speed` batches of output, and those batches have to be
+/// turned into one.
+pub struct FastForward {
+    /// How many times faster than real time to run; 1 is normal speed.
+    pub speed: u32,
+    pub audio: FastForwardAudio,
+    /// Output produced since the device last caught up, for `FastForwardAudio::Pitch`.
+    pending: Vec<i16>,
+    batches: u32,
+}
+
+impl Default for FastForward {
+    fn default() -> FastForward {
+        FastForward::new()
+    }
+}
+
+impl FastForward {
+    pub fn new() -> FastForward {
+        FastForward {
+            speed: 1,
+            audio: FastForwardAudio::Mute,
+            pending: Vec::new(),
+            batches: 0,
+        }
+    }
+
+    /// Takes a batch of output samples. Returns what to play before emulating further, or `None`
+    /// if the emulator should carry on without waiting.
+    ///
+    /// ```
+    /// use nes::apu::{FastForward, FastForwardAudio};
+    ///
+    /// let mut fast_forward = FastForward::new();
+    /// fast_forward.speed = 2;
+    /// fast_forward.audio = FastForwardAudio::Pitch;
+    /// assert_eq!(fast_forward.push(&[10, 20]), None);
+    /// assert_eq!(fast_forward.push(&[30, 40]), Some(vec![15, 35]));
+    ///
+    /// fast_forward.audio = FastForwardAudio::Drop;
+    /// assert_eq!(fast_forward.push(&[10, 20]), None);
+    /// assert_eq!(fast_forward.push(&[30, 40]), Some(vec![30, 40]));
+    ///
+    /// fast_forward.audio = FastForwardAudio::Mute;
+    /// assert_eq!(fast_forward.push(&[10, 20]), None);
+    /// assert_eq!(fast_forward.push(&[30, 40]), Some(vec![0, 0]));
+    /// ```
+    pub fn push(&mut self, batch: &[i16]) -> Option<Vec<i16>> {
+        if self.speed <= 1 {
+            self.pending.clear();
+            self.batches = 0;
+            return Some(batch.to_vec());
+        }
+
+        self.batches += 1;
+        if self.audio == FastForwardAudio::Pitch {
+            self.pending.extend_from_slice(batch);
+        }
+        if self.batches < self.speed {
+            return None;
+        }
+        self.batches = 0;
+
+        Some(match self.audio {
+            FastForwardAudio::Mute => vec![0; batch.len()],
+            FastForwardAudio::Drop => batch.to_vec(),
+            FastForwardAudio::Pitch => {
+                // Average each run of `speed` samples into one.
+                let samples = self
+                    .pending
+                    .chunks(self.speed as usize)
+                    .map(|run| {
+                        (run.iter().map(|&sample| sample as i32).sum::<i32>() / run.len() as i32)
+                            as i16
+                    })
+                    .collect();
+                self.pending.clear();
+                samples
+            }
+        })
+    }
+}
+
+//
+// Output sample accounting
+//
+
+/// Converts emulated CPU cycles into a count of output samples using exact integer arithmetic,
+/// so the number of samples produced depends only on how many cycles ran and never drifts from
+/// the video.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SampleClock {
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cpu_clock: u64,
+    /// The CPU cycle up to which samples have been accounted for.
+    cycle: u64,
+}
+
+save_struct!(SampleClock { cycle });
+
+impl SampleClock {
+    pub fn new(region: Region) -> SampleClock {
+        SampleClock {
+            cpu_clock: region.cpu_clock() as u64,
+            cycle: 0,
+        }
+    }
+
+    fn samples_at(&self, cycle: u64) -> u64 {
+        (cycle as u128 * OUTPUT_SAMPLE_RATE as u128 / self.cpu_clock as u128) as u64
+    }
+
+    /// Returns how many output samples the CPU cycles from `start` to `end` are worth.
+    pub fn count(&self, start: u64, end: u64) -> usize {
+        self.samples_at(end).saturating_sub(self.samples_at(start)) as usize
+    }
+
+    /// Returns how many output samples the cycles since the last call are worth, and moves the
+    /// clock to `cycle`.
+    pub fn advance(&mut self, cycle: u64) -> usize {
+        let count = self
+            .samples_at(cycle)
+            .saturating_sub(self.samples_at(self.cycle));
+        self.cycle = cycle;
+        count as usize
+    }
+
+    /// Takes the output samples the cycles since the last call are worth from the front of
+    /// `pending`, and moves the clock to `cycle`. The APU plays a quarter frame at a time, so
+    /// `pending` can run short; then the last sample is repeated, and the late ones come out next
+    /// time.
+    pub fn take(&mut self, cycle: u64, pending: &mut Vec<i16>) -> Vec<i16> {
+        let count = self.advance(cycle);
+        let mut samples: Vec<i16> = pending.drain(..count.min(pending.len())).collect();
+        let last = samples.last().cloned().unwrap_or(0);
+        samples.resize(count, last);
+        samples
+    }
+
+    /// Pads `samples` by repeating the last one, or trims them, to the number of output samples
+    /// the cycles since the last call are worth, and moves the clock to `cycle`. A resampler can
+    /// put out a few more or fewer from one batch to the next; this keeps the sound in step with
+    /// the video.
+    ///
+    /// ```
+    /// use nes::apu::SampleClock;
+    /// use nes::region::Region;
+    ///
+    /// let mut clock = SampleClock::new(Region::Ntsc);
+    /// let mut samples = vec![1, 2, 3];
+    /// clock.fit(Region::Ntsc.cpu_clock() as u64, &mut samples);
+    /// assert_eq!(samples.len(), 44100);
+    /// assert_eq!(samples[44099], 3);
+    /// ```
+    pub fn fit(&mut self, cycle: u64, samples: &mut Vec<i16>) {
+        let count = self.advance(cycle);
+        let last = samples.last().cloned().unwrap_or(0);
+        samples.resize(count, last);
+    }
+}
+
+//
+// Direct DAC writes
+//
+
+/// More writes than a tick could hold even if every cycle wrote $4011.
+const MAX_DAC_WRITES: usize = 16384;
+
+/// Levels written to $4011 and not yet played, each with the cycle it was written on. Games
+/// stream PCM this way, so each write is played at its cycle rather than once per tick.
+///
+/// ```
+/// use nes::apu::DacWrites;
+///
+/// let mut dac = DacWrites::new();
+/// dac.push(100, 0x10);
+/// dac.push(150, 0x20);
+/// let mut out = [0; 4];
+/// dac.play(0, 200, &mut out);
+/// assert_eq!(out, [0, 0, 0x1000, 0x2000]);
+/// ```
+pub struct DacWrites {
+    writes: VecDeque<(u64, u8)>,
+    /// The level being played.
+    level: u8,
+}
+
+impl Default for DacWrites {
+    fn default() -> DacWrites {
+        DacWrites::new()
     }
-    fn load(&mut self, fd: &mut File) {
-        self.pulses[0].load(fd);
-        self.pulses[1].load(fd);
-        self.triangle.load(fd);
-        self.noise.load(fd);
-        self.status.load(fd);
+}
+
+impl DacWrites {
+    pub fn new() -> DacWrites {
+        DacWrites {
+            writes: VecDeque::new(),
+            level: 0,
+        }
+    }
+
+    /// Queues a write of `level` on CPU cycle `cycle`, which is no earlier than the last. Past
+    /// `MAX_DAC_WRITES`, the oldest is dropped, so the queue stays small when nothing plays it.
+    pub fn push(&mut self, cycle: u64, level: u8) {
+        if self.writes.len() == MAX_DAC_WRITES {
+            self.writes.pop_front();
+        }
+        self.writes.push_back((cycle, level));
+    }
+
+    /// Fills `out` with samples spread evenly over the cycles from `start` to `end`, each at the
+    /// level last written by its cycle.
+    pub fn play(&mut self, start: u64, end: u64, out: &mut [i16]) {
+        if self.writes.is_empty() {
+            for dest in out.iter_mut() {
+                *dest = (self.level as i16) << 8;
+            }
+            return;
+        }
+        let len = out.len() as u64;
+        for (i, dest) in out.iter_mut().enumerate() {
+            let cycle = start + (end - start) * i as u64 / len;
+            while let Some(&(write_cycle, level)) = self.writes.front() {
+                if write_cycle > cycle {
+                    break;
+                }
+                self.level = level;
+                self.writes.pop_front();
+            }
+            // A quarter of a triangle step per level, near the mixer's third, so that full scale
+            // fits in a sample.
+            *dest = (self.level as i16) << 8;
+        }
     }
 }
 
@@ -446,42 +870,107 @@ impl Save for Regs {
 const SAMPLE_COUNT: usize = 178992;
 
 struct SampleBuffer {
-    samples: [i16; SAMPLE_COUNT],
+    samples: Vec<i16>,
+}
+
+impl SampleBuffer {
+    fn new() -> SampleBuffer {
+        SampleBuffer {
+            samples: vec![0; SAMPLE_COUNT],
+        }
+    }
+}
+
+/// Box-filters the APU's samples down to the output rate, for `Apu::take_samples`.
+#[derive(Default)]
+struct Downsampler {
+    /// How far into the output sample being summed the input is, in units where an input sample
+    /// is the output rate and an output sample the input rate.
+    phase: u64,
+    sum: i64,
+    count: i64,
+}
+
+/// Sound from a chip outside the APU, such as an expansion port module, mixed with its channels.
+/// Its state is saved with the rest of the machine.
+pub trait ExpansionAudio: Save {
+    /// Renders the next `out.len()` samples at `sample_rate` Hz.
+    fn render(&mut self, out: &mut [i16], sample_rate: u32);
 }
 
 /// APU state
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default = "Apu::detached"))]
 pub struct Apu {
     regs: Regs,
 
-    #[cfg(feature = "audio")]
-    sample_buffers: Box<[SampleBuffer; 5]>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sample_buffers: Box<[SampleBuffer; 6]>,
 
     sample_buffer_offset: usize,
+    /// How much of the sample buffers `take_samples` has returned.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    taken: usize,
+    #[cfg_attr(feature = "serde", serde(skip))]
     output_buffer: Option<*mut OutputBuffer>,
 
     #[cfg(feature = "audio")]
+    #[cfg_attr(feature = "serde", serde(skip))]
     resampler: Resampler,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    downsampler: Downsampler,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    region: Region,
+    /// The output volume in percent.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub volume: u8,
 
     pub cy: u64,
     pub ticks: u64,
+    /// The CPU cycle the instruction being executed started at, set before each CPU step, which
+    /// times writes to $4011.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cycle: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    dac: DacWrites,
+    sample_clock: SampleClock,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub fast_forward: FastForward,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub expansion: Option<Rc<RefCell<dyn ExpansionAudio>>>,
 }
 
-save_struct!(Apu { regs, cy, ticks });
+save_struct!(Apu {
+    regs,
+    cy,
+    ticks,
+    sample_clock
+});
 
 impl Mem for Apu {
     fn loadb(&mut self, addr: u16) -> u8 {
         match addr {
-            0x4015 => *self.regs.status,
+            0x4015 => {
+                // Bit 4 reads whether the DMC sample is still playing.
+                let active = (self.regs.dmc.bytes_remaining > 0) as u8;
+                (*self.regs.status & !0x10) | active << 4
+            }
             _ => 0,
         }
     }
     fn storeb(&mut self, addr: u16, val: u8) {
-        #[cfg(feature = "audio")]
         match addr {
             0x4000..=0x4003 => self.update_pulse(addr, val, 0),
             0x4004..=0x4007 => self.update_pulse(addr, val, 1),
             0x4008..=0x400b => self.regs.triangle.storeb(addr, val),
             0x400c..=0x400f => self.update_noise(addr, val),
+            0x4010..=0x4013 => {
+                self.regs.dmc.storeb(addr, val);
+                if addr == 0x4011 {
+                    self.dac.push(self.cycle, val & 0x7f);
+                }
+            }
             0x4015 => self.update_status(val),
             _ => {} // TODO
         }
@@ -489,48 +978,114 @@ impl Mem for Apu {
 }
 
 impl Apu {
-    pub fn new(output_buffer: Option<*mut OutputBuffer>) -> Apu {
+    /// An NTSC APU without audio output, for deserializing.
+    #[cfg(feature = "serde")]
+    pub fn detached() -> Apu {
+        Apu::new(None, Region::Ntsc)
+    }
+
+    pub fn new(output_buffer: Option<*mut OutputBuffer>, region: Region) -> Apu {
         Apu {
             regs: Regs {
                 pulses: [ApuPulse::new(), ApuPulse::new()],
                 triangle: ApuTriangle::new(),
                 noise: ApuNoise::new(),
+                dmc: ApuDmc::new(),
                 status: ApuStatus(0),
             },
 
-            #[cfg(feature = "audio")]
             sample_buffers: Box::new([
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
+                SampleBuffer::new(),
+                SampleBuffer::new(),
+                SampleBuffer::new(),
+                SampleBuffer::new(),
+                SampleBuffer::new(),
+                SampleBuffer::new(),
             ]),
 
             sample_buffer_offset: 0,
+            taken: 0,
             output_buffer: output_buffer,
 
             #[cfg(feature = "audio")]
             resampler: Resampler::new(1, NES_SAMPLE_RATE, OUTPUT_SAMPLE_RATE, 0).unwrap(),
+            downsampler: Downsampler::default(),
+
+            region,
+            volume: 100,
 
             cy: 0,
             ticks: 0,
+            cycle: 0,
+            dac: DacWrites::new(),
+            sample_clock: SampleClock::new(region),
+            fast_forward: FastForward::new(),
+            expansion: None,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Plays `speed` times as fast as real time, resampling to keep the pitch. The emulation
+    /// follows, as it waits for the audio device to play each batch of samples. The speed is only
+    /// turned into whole clock rates, which a single rounded multiply or divide gets the same on
+    /// every platform; emulation itself uses no floating point.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.sample_clock.cpu_clock = (self.region.cpu_clock() as f64 * speed).round() as u64;
+        #[cfg(feature = "audio")]
+        {
+            let out_rate = (OUTPUT_SAMPLE_RATE as f64 / speed).round() as u32;
+            self.resampler.set_rate(NES_SAMPLE_RATE, out_rate);
+        }
+    }
+
+    /// The frame rate at the speed given to `set_speed`, not counting fast-forward.
+    pub fn frame_rate(&self) -> f64 {
+        let cpu_clock = self.region.cpu_clock() as f64;
+        self.region.frame_rate() * self.sample_clock.cpu_clock as f64 / cpu_clock
+    }
+
+    /// Returns a copy of the current register state, for visualizers.
+    pub fn snapshot(&self) -> ApuSnapshot {
+        let regs = &self.regs;
+        let pulse = |index: usize| {
+            let pulse = &regs.pulses[index];
+            PulseSnapshot {
+                enabled: regs.status.pulse_enabled(index as u8),
+                period: pulse.timer.value,
+                volume: pulse.envelope.volume,
+                duty: pulse.duty,
+                length_remaining: pulse.envelope.length.remaining,
+            }
+        };
+        ApuSnapshot {
+            pulses: [pulse(0), pulse(1)],
+            triangle: TriangleSnapshot {
+                enabled: regs.status.triangle_enabled(),
+                period: regs.triangle.timer.value,
+                linear_counter: regs.triangle.linear_counter,
+                length_remaining: regs.triangle.length.remaining,
+            },
+            noise: NoiseSnapshot {
+                enabled: regs.status.noise_enabled(),
+                period: regs.noise.timer,
+                volume: regs.noise.envelope.volume,
+                length_remaining: regs.noise.envelope.length.remaining,
+            },
+            dmc: DmcSnapshot {
+                enabled: regs.status.dmc_enabled(),
+                irq_enabled: regs.dmc.irq_enabled(),
+                loops: regs.dmc.loops(),
+                rate_index: regs.dmc.rate_index(),
+                output_level: regs.dmc.output_level,
+                address: regs.dmc.address(),
+                length: regs.dmc.length(),
+            },
         }
     }
-}
 
-#[cfg(feature = "audio")]
-impl Apu {
     fn update_status(&mut self, val: u8) {
         self.regs.status = ApuStatus(val);
 
@@ -545,6 +1100,39 @@ impl Apu {
         if !self.regs.status.noise_enabled() {
             self.regs.noise.envelope.length.remaining = 0;
         }
+        if !self.regs.status.dmc_enabled() {
+            self.regs.dmc.bytes_remaining = 0;
+        } else if self.regs.dmc.bytes_remaining == 0 {
+            self.regs.dmc.restart();
+            self.regs.dmc.next_fetch = 0;
+        }
+    }
+
+    /// Returns the address of the next DMC sample byte if the reader needs it by CPU cycle
+    /// `cycle`. The caller fetches it with DMA.
+    pub fn dmc_fetch(&mut self, cycle: u64) -> Option<u16> {
+        let interval = self.region.dmc_periods()[self.regs.dmc.rate_index() as usize] as u64 * 8;
+        let dmc = &mut self.regs.dmc;
+        if dmc.bytes_remaining == 0 || dmc.next_fetch > cycle {
+            return None;
+        }
+
+        let addr = dmc.current_address;
+        // The address wraps around from $FFFF to $8000.
+        dmc.current_address = addr.checked_add(1).unwrap_or(0x8000);
+        dmc.bytes_remaining -= 1;
+        if dmc.bytes_remaining == 0 && dmc.loops() {
+            dmc.restart();
+        }
+        // Keep to the rate, unless the reader has fallen more than a byte behind, e.g. because it
+        // was just started.
+        let due = if cycle - dmc.next_fetch < interval {
+            dmc.next_fetch
+        } else {
+            cycle
+        };
+        dmc.next_fetch = due + interval;
+        Some(addr)
     }
 
     // FIXME: Refactor into a method on ApuPulse itself.
@@ -570,34 +1158,43 @@ impl Apu {
 
         if (addr & 3) == 2 {
             // TODO: Mode bit.
-            self.regs.noise.timer = NOISE_PERIODS[val as usize & 0xf];
+            self.regs.noise.timer = self.region.noise_periods()[val as usize & 0xf];
         }
     }
+}
 
+impl Apu {
     //
     // Playback
     //
 
+    // FIXME: Every tick produces NES_SAMPLES_PER_TICK samples regardless of region, so PAL audio
+    // plays slightly off pitch.
     pub fn step(&mut self, run_to_cycle: u64) {
+        let (cycles_per_even_tick, cycles_per_odd_tick) = self.region.apu_tick_cycles();
         loop {
             let mut next_tick_cycle = self.cy;
             if self.ticks % 2 == 0 {
-                next_tick_cycle += CYCLES_PER_EVEN_TICK;
+                next_tick_cycle += cycles_per_even_tick;
             } else {
-                next_tick_cycle += CYCLES_PER_ODD_TICK;
+                next_tick_cycle += cycles_per_odd_tick;
             }
 
-            if next_tick_cycle > run_to_cycle {
+            // With the buffers full, wait for `play_channels` to empty them and catch up then.
+            if next_tick_cycle > run_to_cycle
+                || self.sample_buffer_offset == self.sample_buffers[0].samples.len()
+            {
                 break;
             }
 
-            self.tick();
+            self.tick(next_tick_cycle);
 
             self.cy = next_tick_cycle;
         }
     }
 
-    fn tick(&mut self) {
+    /// Runs the quarter frame from `self.cy` to `end`.
+    fn tick(&mut self, end: u64) {
         // 120 Hz operations: length counter and sweep.
         if self.ticks % 2 == 0 {
             // TODO: Remember that triangle wave has a different length disable bit.
@@ -639,6 +1236,8 @@ impl Apu {
         self.play_pulse(1, 1);
         self.play_triangle(2);
         self.play_noise(3);
+        self.play_expansion(4);
+        self.play_dac(5, end);
         self.sample_buffer_offset += NES_SAMPLES_PER_TICK as usize;
 
         // TODO: 60 Hz IRQ.
@@ -768,56 +1367,172 @@ impl Apu {
         }
     }
 
-    // Resamples and flushes channel buffers to the audio output device if necessary.
+    fn play_expansion(&mut self, channel: usize) {
+        let buffer = Apu::get_or_zero_sample_buffer(
+            &mut self.sample_buffers[channel].samples,
+            self.sample_buffer_offset,
+            self.expansion.is_some(),
+        );
+        if let (Some(buffer), Some(expansion)) = (buffer, self.expansion.as_ref()) {
+            let mut rendered = [0; (NES_SAMPLES_PER_TICK / EXPANSION_DOWNSAMPLING) as usize];
+            expansion
+                .borrow_mut()
+                .render(&mut rendered, NES_SAMPLE_RATE / EXPANSION_DOWNSAMPLING);
+            let held = buffer.chunks_mut(EXPANSION_DOWNSAMPLING as usize);
+            for (dest, &sample) in held.zip(rendered.iter()) {
+                for dest in dest {
+                    *dest = sample;
+                }
+            }
+        }
+    }
+
+    /// Plays the DMC output level, changing it at the sample each $4011 write's cycle falls on.
+    fn play_dac(&mut self, channel: usize, end: u64) {
+        let offset = self.sample_buffer_offset;
+        let buffer = &mut self.sample_buffers[channel].samples;
+        let buffer = &mut buffer[offset..offset + NES_SAMPLES_PER_TICK as usize];
+        self.dac.play(self.cy, end, buffer);
+    }
+
+    /// Mixes the channels' samples at `index` at the output volume.
+    #[cfg(feature = "audio")]
+    fn mix(&self, index: usize) -> i16 {
+        // FIXME: This should not be a linear mix, for accuracy.
+        let mut val = 0;
+        for buffer in self.sample_buffers.iter() {
+            val += buffer.samples[index] as i32;
+        }
+        (val * self.volume as i32 / 100).clamp(-32768, 32767) as i16
+    }
+
+    /// Mixes the samples played since the last call, downsamples them to `OUTPUT_SAMPLE_RATE` and
+    /// appends them to `out`, for recording the sound or running without an audio device. Call it
+    /// before `play_channels`, which empties the buffers once they are full.
+    ///
+    /// ```
+    /// use nes::apu::Apu;
+    /// use nes::mem::Mem;
+    /// use nes::region::Region;
+    ///
+    /// // Play an A on the first pulse at full volume for a second.
+    /// let mut apu = Apu::new(None, Region::Ntsc);
+    /// apu.storeb(0x4015, 0x01);
+    /// apu.storeb(0x4000, 0xbf);
+    /// apu.storeb(0x4002, 0xfd);
+    /// apu.storeb(0x4003, 0x08);
+    /// let mut samples = Vec::new();
+    /// for frame in 1..61 {
+    ///     apu.step(frame * 29830);
+    ///     apu.take_samples(&mut samples);
+    ///     apu.play_channels();
+    /// }
+    /// assert!(samples.len() > 44100 - 200 && samples.len() <= 44100);
+    /// assert!(samples.iter().any(|&sample| sample != 0));
+    /// ```
+    pub fn take_samples(&mut self, out: &mut Vec<i16>) {
+        // Each pair of ticks plays twice NES_SAMPLES_PER_TICK samples over its cycles, so one
+        // input sample is `output` parts of the `input` an output sample takes.
+        let (cycles_per_even_tick, cycles_per_odd_tick) = self.region.apu_tick_cycles();
+        let input = NES_SAMPLES_PER_TICK as u64 * 2 * self.sample_clock.cpu_clock;
+        let output = OUTPUT_SAMPLE_RATE as u64 * (cycles_per_even_tick + cycles_per_odd_tick);
+        let mut mixed = vec![0; self.sample_buffer_offset - self.taken];
+        for buffer in self.sample_buffers.iter() {
+            for (dest, &sample) in mixed.iter_mut().zip(buffer.samples[self.taken..].iter()) {
+                *dest += sample as i32;
+            }
+        }
+        let downsampler = &mut self.downsampler;
+        for val in mixed {
+            let sample = (val * self.volume as i32 / 100).clamp(-32768, 32767);
+            downsampler.sum += sample as i64;
+            downsampler.count += 1;
+            downsampler.phase += output;
+            if downsampler.phase >= input {
+                downsampler.phase -= input;
+                out.push((downsampler.sum / downsampler.count) as i16);
+                downsampler.sum = 0;
+                downsampler.count = 0;
+            }
+        }
+        self.taken = self.sample_buffer_offset;
+    }
+
+    /// Empties the channel buffers once they are full, after resampling and flushing them to the
+    /// audio output device if there is one.
     pub fn play_channels(&mut self) {
         let sample_buffer_length = self.sample_buffers[0].samples.len();
         if self.sample_buffer_offset < sample_buffer_length {
             return;
         }
         self.sample_buffer_offset = 0;
+        self.taken = 0;
 
-        // First, mix all sample buffers into the first one.
-        //
-        // FIXME: This should not be a linear mix, for accuracy.
-        for i in 0..self.sample_buffers[0].samples.len() {
-            let mut val = 0;
-            for j in 0..5 {
-                val += self.sample_buffers[j].samples[i] as i32;
-            }
+        #[cfg(feature = "audio")]
+        self.flush();
+    }
 
-            if val > 32767 {
-                val = 32767;
-            } else if val < -32768 {
-                val = -32768;
-            }
+    #[cfg(feature = "audio")]
+    fn flush(&mut self) {
+        let output_buffer = match self.output_buffer {
+            Some(output_buffer) => output_buffer,
+            None => return,
+        };
 
-            self.sample_buffers[0].samples[i] = val as i16;
+        // First, mix all sample buffers into the first one.
+        for i in 0..self.sample_buffers[0].samples.len() {
+            self.sample_buffers[0].samples[i] = self.mix(i);
         }
 
-        if self.output_buffer.is_none() {
+        // Resample, then pad or trim to the number of samples the emulated time is worth, so the
+        // output never drifts from the video whatever the resampler's latency does.
+        let buffer_size = unsafe { (*output_buffer).samples.len() };
+        let mut resampled = vec![0; buffer_size / 2];
+        let (_, written) =
+            self.resampler
+                .process(0, &self.sample_buffers[0].samples, &mut resampled);
+        resampled.truncate(written as usize);
+        self.sample_clock.fit(self.cy, &mut resampled);
+        let mut output = match self.fast_forward.push(&resampled) {
+            Some(output) => output,
+            None => return,
+        };
+        output.truncate(buffer_size / 2);
+        let output: Vec<u8> = output
+            .iter()
+            .flat_map(|&sample| vec![sample as u8, (sample as u16 >> 8) as u8])
+            .collect();
+
+        // Without a device, nothing plays the samples, so drop them rather than wait.
+        if !audio::is_playing() {
             return;
         }
-        let output_buffer = self.output_buffer.unwrap();
 
-        // Wait for the audio callback to catch up if necessary.
+        // Wait for the audio callback to catch up if necessary. A device unplugged while waiting
+        // never calls back, so give up after a while and let the main loop notice.
+        let deadline = Instant::now() + audio::CALLBACK_TIMEOUT;
         loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
             unsafe {
                 let lock = audio::AUDIO_MUTEX.lock().unwrap();
-                let _lock = audio::AUDIO_CONDVAR.wait(lock).unwrap();
+                let _lock = audio::AUDIO_CONDVAR
+                    .wait_timeout(lock, deadline - now)
+                    .unwrap();
                 if (*output_buffer).play_offset == (*output_buffer).samples.len() {
                     break;
                 }
             }
         }
+
+        // The callback plays from `play_offset` to the end, so put the samples at the end.
         let _lock = audio::lock();
         unsafe {
-            // Resample and output the audio.
-            let _ = self.resampler.process(
-                0,
-                &mut self.sample_buffers[0].samples,
-                &mut (*output_buffer).samples,
-            );
-            (*output_buffer).play_offset = 0;
+            let start = buffer_size - output.len();
+            (&mut (*output_buffer).samples)[start..].copy_from_slice(&output);
+            (*output_buffer).play_offset = start;
         }
     }
 }
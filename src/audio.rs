@@ -1,4 +1,8 @@
 //! SDL audio interface. Used by the APU to actually play audio.
+//!
+//! The output device can go away while playing, when a USB DAC is unplugged for example. SDL then
+//! stops it, and `AudioOutput::reopen` opens whichever device is now the default, keeping the
+//! output buffer the APU writes to.
 
 //
 // Author: Patrick Walton
@@ -7,12 +11,17 @@
 // TODO: This module is very unsafe. Adding a reader-writer audio lock to SDL would help make it
 // safe.
 
-use sdl2::audio::{AudioCallback, AudioDevice, AudioDeviceLockGuard, AudioSpecDesired};
+use metrics::METRICS;
+
+use sdl2::audio::{
+    AudioCallback, AudioDevice, AudioDeviceLockGuard, AudioSpecDesired, AudioStatus,
+};
 use sdl2::Sdl;
 use std::cmp;
 use std::mem;
 use std::slice::from_raw_parts_mut;
 use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
 //
 // The audio callback
@@ -20,6 +29,10 @@ use std::sync::{Condvar, Mutex};
 
 const SAMPLE_COUNT: usize = 4410 * 2;
 
+/// How long the APU waits for the callback to play its samples before giving up on the device.
+/// The callback runs every 100 ms, so this only runs out when the device is gone.
+pub const CALLBACK_TIMEOUT: Duration = Duration::from_millis(300);
+
 static mut G_AUDIO_DEVICE: Option<*mut AudioDevice<NesAudioCallback>> = None;
 
 static mut G_OUTPUT_BUFFER: Option<*mut OutputBuffer> = None;
@@ -47,6 +60,9 @@ impl AudioCallback for NesAudioCallback {
             let play_offset = output_buffer.play_offset;
             let output_buffer_len = output_buffer.samples.len();
 
+            if play_offset + samples.len() > output_buffer_len {
+                METRICS.audio_underruns.add(1);
+            }
             for i in 0..samples.len() {
                 if i + play_offset >= output_buffer_len {
                     break;
@@ -61,47 +77,83 @@ impl AudioCallback for NesAudioCallback {
     }
 }
 
-/// Audio initialization. If successful, returns a pointer to an allocated `OutputBuffer` that can
-/// be filled with raw audio data.
-pub fn open(sdl: &Sdl) -> Option<*mut OutputBuffer> {
-    let output_buffer = Box::new(OutputBuffer {
-        samples: [0; SAMPLE_COUNT],
-        play_offset: 0,
-    });
-    let output_buffer_ptr: *mut OutputBuffer = unsafe { mem::transmute(&*output_buffer) };
+/// The output device and the buffer it plays from. Dropping it closes the device and frees the
+/// buffer, so it must outlive the APUs writing to the buffer.
+pub struct AudioOutput {
+    buffer: *mut OutputBuffer,
+}
+
+impl AudioOutput {
+    /// The buffer to fill with raw audio data.
+    pub fn buffer(&self) -> *mut OutputBuffer {
+        self.buffer
+    }
+
+    /// Closes the output device and opens the default one, keeping the output buffer.
+    pub fn reopen(&self, sdl: &Sdl) -> Result<(), String> {
+        close_device();
+        open_device(sdl)
+    }
+}
+
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        // The callback reads the buffer until the device is closed.
+        close_device();
+        unsafe {
+            G_OUTPUT_BUFFER = None;
+            drop(Box::from_raw(self.buffer));
+        }
+    }
+}
 
+/// Audio initialization. Returns `None`, after printing why, if no device could be opened.
+pub fn open(sdl: &Sdl) -> Option<AudioOutput> {
+    let output = AudioOutput {
+        buffer: Box::into_raw(Box::new(OutputBuffer {
+            samples: [0; SAMPLE_COUNT],
+            play_offset: 0,
+        })),
+    };
     unsafe {
-        G_OUTPUT_BUFFER = Some(output_buffer_ptr);
-        mem::forget(output_buffer);
+        G_OUTPUT_BUFFER = Some(output.buffer);
+    }
+
+    match open_device(sdl) {
+        Ok(()) => Some(output),
+        Err(e) => {
+            println!("Error initializing audio: {}", e);
+            None
+        }
     }
+}
 
+fn open_device(sdl: &Sdl) -> Result<(), String> {
     let spec = AudioSpecDesired {
         freq: Some(44100),
         channels: Some(1),
         samples: Some(4410),
     };
 
-    let audio_subsystem = sdl.audio().unwrap();
+    let audio_subsystem = sdl.audio()?;
+    let device = audio_subsystem.open_playback(None, &spec, |_| NesAudioCallback)?;
+    device.resume();
     unsafe {
-        match audio_subsystem.open_playback(None, &spec, |_| NesAudioCallback) {
-            Ok(device) => {
-                device.resume();
-                G_AUDIO_DEVICE = Some(mem::transmute(Box::new(device)));
-                return Some(output_buffer_ptr);
-            }
-            Err(e) => {
-                println!("Error initializing AudioDevice: {}", e);
-                return None;
-            }
-        }
+        G_AUDIO_DEVICE = Some(Box::into_raw(Box::new(device)));
     }
+    Ok(())
+}
+
+/// Whether a device is open and playing. Once its device is unplugged, it stops.
+pub fn is_playing() -> bool {
+    unsafe { G_AUDIO_DEVICE.is_some_and(|dev| (*dev).status() == AudioStatus::Playing) }
 }
 
 //
 // Audio tear-down
 //
 
-pub fn close() {
+fn close_device() {
     unsafe {
         match G_AUDIO_DEVICE {
             None => {}
@@ -0,0 +1,212 @@
+//! The barcode reader of the Bandai Datach Joint ROM System, which games read cards through.
+//!
+//! A swiped barcode is played back to the game as the bars and spaces of its EAN-13 or EAN-8
+//! symbol, one module every `CYCLES_PER_MODULE` CPU cycles, on bit 3 of $6000-$7FFF.
+
+use util::{self, Save};
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// How long the reader holds each bar or space, in CPU cycles.
+const CYCLES_PER_MODULE: u64 = 1000;
+/// How many modules of blank card the reader sees before and after the symbol.
+const QUIET_ZONE: usize = 32;
+
+/// The bit that reads back a space.
+const SPACE: u8 = 0x08;
+const BAR: u8 = 0;
+
+/// For the first digit of an EAN-13 barcode, which of the next six digits are encoded with odd
+/// parity (true) rather than even.
+const ODD_PARITY: [[bool; 6]; 10] = [
+    [true, true, true, true, true, true],
+    [true, true, false, true, false, false],
+    [true, true, false, false, true, false],
+    [true, true, false, false, false, true],
+    [true, false, true, true, false, false],
+    [true, false, false, true, true, false],
+    [true, false, false, false, true, true],
+    [true, false, true, false, true, false],
+    [true, false, true, false, false, true],
+    [true, false, false, true, false, true],
+];
+
+/// The right-hand (R) code of each digit, bars as 1s, most significant module first. The
+/// left-hand odd (L) code is its complement and the even (G) code its reversal.
+const RIGHT_CODES: [u8; 10] = [
+    0b1110010, 0b1100110, 0b1101100, 0b1000010, 0b1011100, 0b1001110, 0b1010000, 0b1000100,
+    0b1001000, 0b1110100,
+];
+
+/// An EAN-13 or EAN-8 barcode.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Barcode {
+    digits: Vec<u8>,
+}
+
+impl Barcode {
+    /// Parses a barcode of 13 or 8 digits. The last digit is the check digit; a wrong one is
+    /// replaced with the right one, as cards are often transcribed without it.
+    pub fn parse(text: &str) -> Result<Barcode, String> {
+        let mut digits = Vec::new();
+        for c in text.chars() {
+            match c.to_digit(10) {
+                Some(digit) => digits.push(digit as u8),
+                None => return Err(format!("barcode `{}` isn't all digits", text)),
+            }
+        }
+        if digits.len() != 13 && digits.len() != 8 {
+            return Err(format!("barcode `{}` isn't 13 or 8 digits long", text));
+        }
+        let check = Barcode::check_digit(&digits[..digits.len() - 1]);
+        *digits.last_mut().unwrap() = check;
+        Ok(Barcode { digits })
+    }
+
+    /// Parses a comma-separated list of barcodes.
+    pub fn parse_list(text: &str) -> Result<Vec<Barcode>, String> {
+        text.split(',')
+            .map(|code| code.trim())
+            .filter(|code| !code.is_empty())
+            .map(Barcode::parse)
+            .collect()
+    }
+
+    /// Reads barcodes from a file, one per line. Blank lines and lines starting with `#` are
+    /// skipped.
+    pub fn load_file(path: &Path) -> io::Result<Vec<Barcode>> {
+        let text = fs::read_to_string(path)?;
+        text.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                Barcode::parse(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            })
+            .collect()
+    }
+
+    /// The EAN check digit: weights alternate 3 and 1 from the rightmost data digit.
+    fn check_digit(data: &[u8]) -> u8 {
+        let sum: u32 = data
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, &digit)| digit as u32 * if i % 2 == 0 { 3 } else { 1 })
+            .sum();
+        ((10 - sum % 10) % 10) as u8
+    }
+
+    /// The modules of the symbol with its quiet zones, as the reader outputs them.
+    fn modules(&self) -> Vec<u8> {
+        let mut bars = vec![true, false, true];
+        let count = self.digits.len();
+        if count == 13 {
+            // The first digit is encoded in the parities of the next six.
+            let parity = ODD_PARITY[self.digits[0] as usize];
+            for (i, &digit) in self.digits[1..7].iter().enumerate() {
+                let code = RIGHT_CODES[digit as usize];
+                let code = if parity[i] {
+                    !code & 0x7f
+                } else {
+                    code.reverse_bits() >> 1
+                };
+                push_code(&mut bars, code);
+            }
+        } else {
+            for &digit in &self.digits[..4] {
+                push_code(&mut bars, !RIGHT_CODES[digit as usize] & 0x7f);
+            }
+        }
+        bars.extend_from_slice(&[false, true, false, true, false]);
+        for &digit in &self.digits[count - count / 2..] {
+            push_code(&mut bars, RIGHT_CODES[digit as usize]);
+        }
+        bars.extend_from_slice(&[true, false, true]);
+
+        let mut modules = vec![SPACE; QUIET_ZONE];
+        modules.extend(bars.iter().map(|&bar| if bar { BAR } else { SPACE }));
+        modules.extend_from_slice(&[SPACE; QUIET_ZONE]);
+        modules
+    }
+}
+
+fn push_code(bars: &mut Vec<bool>, code: u8) {
+    for bit in (0..7).rev() {
+        bars.push((code >> bit) & 1 != 0);
+    }
+}
+
+impl fmt::Display for Barcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for digit in &self.digits {
+            write!(f, "{}", digit)?;
+        }
+        Ok(())
+    }
+}
+
+/// The reader on the Datach unit.
+pub struct BarcodeReader {
+    /// The card being swiped, if any.
+    modules: Vec<u8>,
+    /// CPU cycles since it was swiped.
+    cycles: u64,
+}
+
+impl Default for BarcodeReader {
+    fn default() -> BarcodeReader {
+        BarcodeReader::new()
+    }
+}
+
+impl Save for BarcodeReader {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        let mut len = self.modules.len() as u16;
+        len.save(fd)?;
+        (&mut self.modules[..]).save(fd)?;
+        self.cycles.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        let mut len = 0u16;
+        len.load(fd)?;
+        let mut modules = vec![0; len as usize];
+        util::read_to_buf(&mut modules, fd)?;
+        self.modules = modules;
+        self.cycles.load(fd)
+    }
+}
+
+impl BarcodeReader {
+    pub fn new() -> BarcodeReader {
+        BarcodeReader {
+            modules: Vec::new(),
+            cycles: 0,
+        }
+    }
+
+    /// Starts swiping a card, replacing any swipe in progress.
+    pub fn swipe(&mut self, barcode: &Barcode) {
+        self.modules = barcode.modules();
+        self.cycles = 0;
+    }
+
+    pub fn cpu_cycles(&mut self, cycles: u64) {
+        if !self.modules.is_empty() {
+            self.cycles += cycles;
+            if self.cycles / CYCLES_PER_MODULE >= self.modules.len() as u64 {
+                self.modules.clear();
+            }
+        }
+    }
+
+    /// The value of bit 3 of $6000-$7FFF: set over a space, clear over a bar or with no card.
+    pub fn output(&self) -> u8 {
+        match self.modules.get((self.cycles / CYCLES_PER_MODULE) as usize) {
+            Some(&module) => module,
+            None => 0,
+        }
+    }
+}
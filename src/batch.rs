@@ -0,0 +1,335 @@
+//! Deterministic batch runs for regression testing: a manifest lists ROMs, the input to play and
+//! the screen hashes expected at given frames, and the entries are run headlessly in parallel.
+//!
+//! A manifest has one entry per line; blank lines and lines starting with `#` are skipped. An
+//! entry is a ROM path and a frame count, followed by any of:
+//!
+//! * `movie=<file>` - the input to play, a `.fm2` movie or a `.lmv` latch movie.
+//! * `hash=<frame>:<crc32>` - the screen hash expected after the given frame, as printed by
+//!   `sprocketnes compare`. May be repeated.
+//!
+//! Relative paths are relative to the manifest, for example:
+//!
+//! ```text
+//! roms/smb.nes 600 movie=movies/smb.fm2 hash=300:1a2b3c4d hash=600:deadbeef
+//! ```
+
+use compat;
+use config::Config;
+use emulator::Emulator;
+use movie::{LatchMovie, Movie};
+use rom::Rom;
+use util;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+pub struct BatchEntry {
+    pub rom: PathBuf,
+    pub frames: usize,
+    pub movie: Option<PathBuf>,
+    /// The expected screen hash after each listed frame.
+    pub hashes: Vec<(usize, u32)>,
+}
+
+pub enum BatchStatus {
+    /// Every listed hash matched.
+    Passed,
+    /// At least one hash didn't match.
+    Failed,
+    /// The ROM or movie couldn't be loaded, or the emulator panicked.
+    Error(String),
+}
+
+pub struct HashCheck {
+    pub frame: usize,
+    pub expected: u32,
+    /// The hash seen, or `None` if the run stopped before the frame.
+    pub actual: Option<u32>,
+}
+
+impl HashCheck {
+    pub fn matches(&self) -> bool {
+        self.actual == Some(self.expected)
+    }
+}
+
+pub struct BatchResult {
+    pub rom: PathBuf,
+    pub movie: Option<PathBuf>,
+    pub status: BatchStatus,
+    pub frames_run: usize,
+    pub checks: Vec<HashCheck>,
+    /// The screen hash after the last frame run.
+    pub final_hash: Option<u32>,
+    pub millis: u64,
+    /// How many times each opcode ran, with the `stats` feature.
+    pub opcode_counts: Vec<u64>,
+}
+
+/// Reads the manifest at `path`.
+pub fn load_manifest(path: &Path) -> io::Result<Vec<BatchEntry>> {
+    let text = fs::read_to_string(path)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    parse_manifest(&text, base)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Parses a manifest, resolving relative paths against `base`.
+pub fn parse_manifest(text: &str, base: &Path) -> Result<Vec<BatchEntry>, String> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed = |what: &str| format!("line {}: {}", index + 1, what);
+
+        let mut fields = line.split_whitespace();
+        let rom = base.join(fields.next().unwrap());
+        let frames = match fields.next().and_then(|frames| frames.parse().ok()) {
+            Some(frames) => frames,
+            None => return Err(malformed("expected a frame count after the ROM")),
+        };
+        let mut entry = BatchEntry {
+            rom,
+            frames,
+            movie: None,
+            hashes: Vec::new(),
+        };
+        for field in fields {
+            if let Some(movie) = field.strip_prefix("movie=") {
+                entry.movie = Some(base.join(movie));
+            } else if let Some(hash) = field.strip_prefix("hash=") {
+                let mut parts = hash.splitn(2, ':');
+                let frame = parts.next().and_then(|frame| frame.parse().ok());
+                let hash = parts
+                    .next()
+                    .and_then(|hash| u32::from_str_radix(hash, 16).ok());
+                match (frame, hash) {
+                    (Some(frame), Some(hash)) if frame >= 1 && frame <= entry.frames => {
+                        entry.hashes.push((frame, hash))
+                    }
+                    _ => return Err(malformed(&format!("bad hash {}", field))),
+                }
+            } else {
+                return Err(malformed(&format!("unknown field {}", field)));
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Runs one entry, checking the screen after each listed frame.
+pub fn run_entry(entry: &BatchEntry) -> BatchResult {
+    let start = Instant::now();
+    let mut result = BatchResult {
+        rom: entry.rom.clone(),
+        movie: entry.movie.clone(),
+        status: BatchStatus::Passed,
+        frames_run: 0,
+        checks: entry
+            .hashes
+            .iter()
+            .map(|&(frame, expected)| HashCheck {
+                frame,
+                expected,
+                actual: None,
+            })
+            .collect(),
+        final_hash: None,
+        millis: 0,
+        opcode_counts: Vec::new(),
+    };
+
+    let run = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), String> {
+        let rom = File::open(&entry.rom)
+            .map_err(From::from)
+            .and_then(|mut file| Rom::load(&mut file))
+            .map_err(|err| format!("loading {}: {}", entry.rom.display(), err))?;
+        let mut emulator = Emulator::new(rom, &Config::new())
+            .map_err(|err| format!("loading {}: {}", entry.rom.display(), err))?;
+
+        // `.fm2` movies set the buttons a frame at a time; latch movies play back on their own.
+        let mut movie = None;
+        if let Some(ref path) = entry.movie {
+            let loaded = if path.extension().is_some_and(|extension| extension == "lmv") {
+                LatchMovie::load(path).map(|latches| {
+                    emulator.cpu.mem.input.latch_movie = Some(latches);
+                })
+            } else {
+                Movie::load(path).map(|frames| movie = Some(frames))
+            };
+            loaded.map_err(|err| format!("loading {}: {}", path.display(), err))?;
+        }
+
+        for index in 0..entry.frames {
+            if let Some(ref movie) = movie {
+                emulator.set_buttons(movie.frames.get(index).cloned().unwrap_or(0));
+            }
+            emulator.step_frame();
+            result.frames_run += 1;
+            let frame = index + 1;
+            if result.checks.iter().any(|check| check.frame == frame) {
+                let hash = emulator.screen_hash();
+                for check in result
+                    .checks
+                    .iter_mut()
+                    .filter(|check| check.frame == frame)
+                {
+                    check.actual = Some(hash);
+                }
+            }
+        }
+        result.final_hash = Some(emulator.screen_hash());
+        result.opcode_counts = emulator.cpu.opcode_counts.to_vec();
+        Ok(())
+    }));
+
+    result.status = match run {
+        Ok(Ok(())) if result.checks.iter().all(HashCheck::matches) => BatchStatus::Passed,
+        Ok(Ok(())) => BatchStatus::Failed,
+        Ok(Err(message)) => BatchStatus::Error(message),
+        Err(payload) => BatchStatus::Error(format!(
+            "crashed at frame {}: {}",
+            result.frames_run + 1,
+            compat::panic_message(&payload)
+        )),
+    };
+    let elapsed = start.elapsed();
+    result.millis = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+    result
+}
+
+/// Runs `entries` on `jobs` threads, calling `progress` as each one finishes. Results are in
+/// manifest order.
+pub fn run<F>(entries: Vec<BatchEntry>, jobs: usize, mut progress: F) -> Vec<BatchResult>
+where
+    F: FnMut(&BatchResult),
+{
+    let count = entries.len();
+    let queue = Arc::new(Mutex::new(entries.into_iter().enumerate()));
+    let (sender, receiver) = mpsc::channel();
+
+    // Keep the default panic hook from printing a backtrace for every broken ROM.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let workers: Vec<_> = (0..jobs.max(1).min(count.max(1)))
+        .map(|_| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((index, entry)) => {
+                        if sender.send((index, run_entry(&entry))).is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut results: Vec<Option<BatchResult>> = (0..count).map(|_| None).collect();
+    for (index, result) in receiver {
+        progress(&result);
+        results[index] = Some(result);
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+    panic::set_hook(default_hook);
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Whether every entry passed.
+pub fn all_passed(results: &[BatchResult]) -> bool {
+    results
+        .iter()
+        .all(|result| matches!(result.status, BatchStatus::Passed))
+}
+
+/// Writes the results as a JSON document.
+pub fn write_json<W: Write>(results: &[BatchResult], writer: &mut W) -> io::Result<()> {
+    let count = |wanted: &str| {
+        results
+            .iter()
+            .filter(|result| status_name(&result.status) == wanted)
+            .count()
+    };
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"passed\": {},", count("passed"))?;
+    writeln!(writer, "  \"failed\": {},", count("failed"))?;
+    writeln!(writer, "  \"errors\": {},", count("error"))?;
+    writeln!(writer, "  \"results\": [")?;
+    for (i, result) in results.iter().enumerate() {
+        let checks: Vec<String> = result
+            .checks
+            .iter()
+            .map(|check| {
+                format!(
+                    "{{\"frame\": {}, \"expected\": \"{:08x}\", \"actual\": {}, \"matches\": {}}}",
+                    check.frame,
+                    check.expected,
+                    json_hash(check.actual),
+                    check.matches()
+                )
+            })
+            .collect();
+        let error = match result.status {
+            BatchStatus::Error(ref message) => util::json_string(message),
+            _ => "null".to_string(),
+        };
+        let movie = match result.movie {
+            Some(ref movie) => util::json_string(&movie.display().to_string()),
+            None => "null".to_string(),
+        };
+        writeln!(writer, "    {{")?;
+        writeln!(
+            writer,
+            "      \"rom\": {},",
+            util::json_string(&result.rom.display().to_string())
+        )?;
+        writeln!(writer, "      \"movie\": {},", movie)?;
+        writeln!(
+            writer,
+            "      \"status\": \"{}\",",
+            status_name(&result.status)
+        )?;
+        writeln!(writer, "      \"error\": {},", error)?;
+        writeln!(writer, "      \"frames_run\": {},", result.frames_run)?;
+        writeln!(
+            writer,
+            "      \"final_hash\": {},",
+            json_hash(result.final_hash)
+        )?;
+        writeln!(writer, "      \"checks\": [{}],", checks.join(", "))?;
+        writeln!(writer, "      \"millis\": {}", result.millis)?;
+        let separator = if i + 1 == results.len() { "" } else { "," };
+        writeln!(writer, "    }}{}", separator)?;
+    }
+    writeln!(writer, "  ]")?;
+    writeln!(writer, "}}")
+}
+
+fn status_name(status: &BatchStatus) -> &'static str {
+    match *status {
+        BatchStatus::Passed => "passed",
+        BatchStatus::Failed => "failed",
+        BatchStatus::Error(_) => "error",
+    }
+}
+
+fn json_hash(hash: Option<u32>) -> String {
+    hash.map_or("null".to_string(), |hash| format!("\"{:08x}\"", hash))
+}
@@ -0,0 +1,212 @@
+//! Persistence of battery-backed cartridge RAM and other non-volatile cartridge memory.
+//!
+//! The RAM is kept in a `.sav` file next to the ROM, and other regions a mapper declares, such as
+//! an EEPROM, in `<rom>.<name>.sav`. Before the file is overwritten, the previous
+//! contents are kept as a timestamped backup (`<rom>.sav.<unix-time>.bak`), so a save corrupted by
+//! the game or by a bad exit can be rolled back.
+
+use mapper::SaveRegion;
+use util;
+
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct BatteryFile {
+    path: PathBuf,
+    /// How many backups to keep.
+    backup_count: usize,
+}
+
+impl BatteryFile {
+    pub fn new(rom_path: &Path, backup_count: usize) -> BatteryFile {
+        BatteryFile {
+            path: rom_path.with_extension("sav"),
+            backup_count,
+        }
+    }
+
+    /// The file for the non-volatile region called `name`; see `SaveRegion`.
+    pub fn for_region(rom_path: &Path, name: &str, backup_count: usize) -> BatteryFile {
+        if name.is_empty() {
+            return BatteryFile::new(rom_path, backup_count);
+        }
+        BatteryFile {
+            path: rom_path.with_extension(format!("{}.sav", name)),
+            backup_count,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fills `ram` from the save file. A missing file leaves `ram` untouched.
+    pub fn load(&self, ram: &mut [u8]) -> io::Result<()> {
+        match File::open(&self.path) {
+            Ok(mut file) => util::read_to_buf(ram, &mut file),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes `ram` to the save file, first backing up the old file if its contents differ.
+    pub fn save(&self, ram: &[u8]) -> io::Result<()> {
+        match fs::read(&self.path) {
+            Ok(ref old) if &old[..] == ram => return Ok(()),
+            Ok(_) => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0);
+                fs::copy(&self.path, self.backup_path(timestamp))?;
+                for old_backup in self.backups()?.iter().skip(self.backup_count) {
+                    fs::remove_file(old_backup)?;
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        self.write(ram)
+    }
+
+    /// Writes `ram` to the save file without making a backup.
+    pub fn write(&self, ram: &[u8]) -> io::Result<()> {
+        util::write_atomic(&self.path, ram)
+    }
+
+    /// Returns the existing backups, newest first.
+    pub fn backups(&self) -> io::Result<Vec<PathBuf>> {
+        let file_name = self.path.file_name().unwrap().to_string_lossy();
+        let prefix = format!("{}.", file_name);
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(&prefix) || !name.ends_with(".bak") {
+                continue;
+            }
+            if let Ok(timestamp) = name[prefix.len()..name.len() - 4].parse::<u64>() {
+                backups.push((timestamp, self.backup_path(timestamp)));
+            }
+        }
+        backups.sort_by_key(|&(timestamp, _)| Reverse(timestamp));
+        Ok(backups.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Replaces the save file with a backup, where 1 is the newest backup. The current save file
+    /// is backed up first, so a restore can be undone.
+    pub fn restore(&self, index: usize) -> io::Result<PathBuf> {
+        let backups = self.backups()?;
+        let backup = match index.checked_sub(1).and_then(|index| backups.get(index)) {
+            Some(backup) => backup.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("there is no backup {}", index),
+                ))
+            }
+        };
+        let contents = fs::read(&backup)?;
+        self.save(&contents)?;
+        Ok(backup)
+    }
+
+    fn backup_path(&self, timestamp: u64) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap().to_os_string();
+        file_name.push(format!(".{}.bak", timestamp));
+        self.path.with_file_name(file_name)
+    }
+}
+
+/// The save files of all of a cartridge's non-volatile regions. Each is written only when its
+/// contents changed since they were loaded or last written, so they can be flushed often, and
+/// backed up only by the first write of a session.
+pub struct SaveFiles {
+    rom_path: PathBuf,
+    backup_count: usize,
+    files: Vec<SaveFile>,
+}
+
+struct SaveFile {
+    name: &'static str,
+    battery: BatteryFile,
+    /// The CRC-32 of the contents on disk, or `None` if the file hasn't been read or written.
+    crc: Option<u32>,
+    backed_up: bool,
+}
+
+impl SaveFiles {
+    pub fn new(rom_path: &Path, backup_count: usize) -> SaveFiles {
+        SaveFiles {
+            rom_path: rom_path.to_path_buf(),
+            backup_count,
+            files: Vec::new(),
+        }
+    }
+
+    fn file(&mut self, name: &'static str) -> &mut SaveFile {
+        match self.files.iter().position(|file| file.name == name) {
+            Some(index) => &mut self.files[index],
+            None => {
+                self.files.push(SaveFile {
+                    name,
+                    battery: BatteryFile::for_region(&self.rom_path, name, self.backup_count),
+                    crc: None,
+                    backed_up: false,
+                });
+                self.files.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Fills each region from its save file. Missing files leave their regions untouched. Stops
+    /// at the first error, which names the file.
+    pub fn load(&mut self, regions: &mut [SaveRegion]) -> io::Result<()> {
+        for region in regions {
+            let file = self.file(region.name);
+            file.battery
+                .load(region.data)
+                .map_err(|err| file.error(err))?;
+            file.crc = Some(util::crc32(region.data));
+        }
+        Ok(())
+    }
+
+    /// Writes each region that changed to its save file. Stops at the first error, which names
+    /// the file.
+    pub fn flush(&mut self, regions: &[SaveRegion]) -> io::Result<()> {
+        for region in regions {
+            let file = self.file(region.name);
+            let crc = util::crc32(region.data);
+            if file.crc == Some(crc) {
+                continue;
+            }
+            let result = if file.backed_up {
+                file.battery.write(region.data)
+            } else {
+                file.battery.save(region.data)
+            };
+            result.map_err(|err| file.error(err))?;
+            file.crc = Some(crc);
+            file.backed_up = true;
+        }
+        Ok(())
+    }
+}
+
+impl SaveFile {
+    fn error(&self, err: io::Error) -> io::Error {
+        io::Error::new(
+            err.kind(),
+            format!("{}: {}", self.battery.path().display(), err),
+        )
+    }
+}
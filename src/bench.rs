@@ -0,0 +1,248 @@
+//! Quick benchmarks of single subsystems, for measuring a change to one module without the noise
+//! of the whole machine. Each runs its subsystem on its own with synthetic input: the CPU on flat
+//! memory holding a small loop, the PPU drawing random tiles and sprites, and the APU playing all
+//! of its tone channels.
+
+use cpu::Cpu;
+use mem::Mem;
+use ppu::{ChrBus, Oam, Ppu, Vram};
+use region::Region;
+use util::Xorshift;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The program the CPU benchmark runs at $8000, a loop over a page of loads, arithmetic, stores,
+/// branches and a subroutine call.
+const CPU_PROGRAM: [u8; 24] = [
+    0xa2, 0x00, // LDX #$00
+    0xbd, 0x00, 0x02, // LDA $0200,X
+    0x69, 0x37, // ADC #$37
+    0x9d, 0x00, 0x03, // STA $0300,X
+    0x2a, // ROL A
+    0x45, 0x10, // EOR $10
+    0x85, 0x10, // STA $10
+    0xe8, // INX
+    0xd0, 0xf0, // BNE $8002
+    0x20, 0x18, 0x80, // JSR $8018
+    0x4c, 0x00, 0x80, // JMP $8000
+];
+/// The subroutine the program calls, right after it.
+const CPU_SUBROUTINE: [u8; 3] = [
+    0xe6, 0x11, // INC $11
+    0x60, // RTS
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    Cpu,
+    Ppu,
+    Apu,
+}
+
+impl Subsystem {
+    /// The subsystem a command-line flag such as `--bench-cpu` benchmarks.
+    pub fn from_flag(flag: &str) -> Option<Subsystem> {
+        match flag {
+            "--bench-cpu" => Some(Subsystem::Cpu),
+            "--bench-ppu" => Some(Subsystem::Ppu),
+            "--bench-apu" => Some(Subsystem::Apu),
+            _ => None,
+        }
+    }
+
+    /// What an iteration is: an instruction for the CPU, a frame for the others.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Subsystem::Cpu => "instructions",
+            Subsystem::Ppu | Subsystem::Apu => "frames",
+        }
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Subsystem::Cpu => "CPU",
+            Subsystem::Ppu => "PPU",
+            Subsystem::Apu => "APU",
+        })
+    }
+}
+
+pub struct BenchResult {
+    pub subsystem: Subsystem,
+    pub iterations: u64,
+    pub elapsed: Duration,
+    /// How long the iterations take on an NTSC NES, in seconds.
+    pub emulated: f64,
+}
+
+impl BenchResult {
+    pub fn per_second(&self) -> f64 {
+        self.iterations as f64 / seconds(self.elapsed)
+    }
+
+    /// How many times faster than a real NES the subsystem ran.
+    pub fn speed(&self) -> f64 {
+        self.emulated / seconds(self.elapsed)
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {} in {:.3} s, {:.0} {}/s, {:.1}x real time",
+            self.subsystem,
+            self.iterations,
+            self.subsystem.unit(),
+            seconds(self.elapsed),
+            self.per_second(),
+            self.subsystem.unit(),
+            self.speed()
+        )
+    }
+}
+
+/// Runs `iterations` of the subsystem's benchmark.
+pub fn run(subsystem: Subsystem, iterations: u64) -> BenchResult {
+    let start = Instant::now();
+    let emulated = match subsystem {
+        Subsystem::Cpu => bench_cpu(iterations),
+        Subsystem::Ppu => bench_ppu(iterations),
+        Subsystem::Apu => bench_apu(iterations),
+    };
+    BenchResult {
+        subsystem,
+        iterations,
+        elapsed: start.elapsed(),
+        emulated,
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+/// 64KB of RAM, without the NES's mirrors and devices.
+struct FlatMem(Box<[u8]>);
+
+impl Mem for FlatMem {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+    fn storeb(&mut self, addr: u16, val: u8) {
+        self.0[addr as usize] = val
+    }
+}
+
+/// Runs the CPU for `instructions` and returns how long they take on a NES.
+fn bench_cpu(instructions: u64) -> f64 {
+    let mut mem = FlatMem(vec![0; 0x10000].into_boxed_slice());
+    let subroutine = 0x8000 + CPU_PROGRAM.len();
+    mem.0[0x8000..subroutine].copy_from_slice(&CPU_PROGRAM);
+    mem.0[subroutine..subroutine + CPU_SUBROUTINE.len()].copy_from_slice(&CPU_SUBROUTINE);
+    let mut rng = Xorshift::new();
+    for byte in &mut mem.0[0x200..0x300] {
+        *byte = rng.next() as u8;
+    }
+    mem.storew(0xfffc, 0x8000);
+
+    let mut cpu = Cpu::new(mem);
+    cpu.reset();
+    for _ in 0..instructions {
+        cpu.step();
+    }
+    cpu.cy as f64 / Region::Ntsc.cpu_clock() as f64
+}
+
+/// 8KB of CHR-RAM.
+struct ChrRam(Box<[u8]>);
+
+impl ChrBus for ChrRam {
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.0[addr as usize & 0x1fff]
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.0[addr as usize & 0x1fff] = val
+    }
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(addr as usize & 0x1fff)
+    }
+}
+
+/// Has the PPU draw `frames` of random tiles under 64 random 8x16 sprites, and returns how long
+/// they take on a NES.
+fn bench_ppu(frames: u64) -> f64 {
+    let mut rng = Xorshift::new();
+    let chr: Vec<u8> = (0..0x2000).map(|_| rng.next() as u8).collect();
+    let mut ppu = Ppu::new(
+        Vram::new(Box::new(ChrRam(chr.into_boxed_slice()))),
+        Oam::new(),
+        Region::Ntsc,
+    );
+    // Nametables and attributes, then the palette, through PPUADDR and PPUDATA.
+    ppu.storeb(0x2006, 0x20);
+    ppu.storeb(0x2006, 0x00);
+    for _ in 0..0x800 {
+        ppu.storeb(0x2007, rng.next() as u8);
+    }
+    ppu.storeb(0x2006, 0x3f);
+    ppu.storeb(0x2006, 0x00);
+    for _ in 0..0x20 {
+        ppu.storeb(0x2007, rng.next() as u8 & 0x3f);
+    }
+    ppu.storeb(0x2003, 0);
+    for _ in 0..0x100 {
+        ppu.storeb(0x2004, rng.next() as u8);
+    }
+    ppu.storeb(0x2006, 0x00);
+    ppu.storeb(0x2006, 0x00);
+    ppu.storeb(0x2000, 0x20); // 8x16 sprites
+    ppu.storeb(0x2001, 0x1e); // Background and sprites, including the left column
+
+    let cycles_per_scanline = Region::Ntsc.cycles_per_scanline();
+    let mut cycle = 0;
+    let mut drawn = 0;
+    while drawn < frames {
+        cycle += cycles_per_scanline;
+        if ppu.step(cycle).new_frame {
+            drawn += 1;
+        }
+    }
+    frames as f64 / Region::Ntsc.frame_rate()
+}
+
+/// Has the APU play `frames` with both pulses, the triangle and the noise changing pitch every
+/// frame, and returns how long they take on a NES.
+fn bench_apu(frames: u64) -> f64 {
+    use apu::Apu;
+
+    let mut apu = Apu::new(None, Region::Ntsc);
+    apu.storeb(0x4015, 0x0f);
+    apu.storeb(0x4000, 0xbf);
+    apu.storeb(0x4004, 0x7f);
+    apu.storeb(0x4005, 0x99); // Sweep
+    apu.storeb(0x4008, 0xff);
+    apu.storeb(0x400c, 0x3f);
+
+    let cycles_per_frame = Region::Ntsc.cpu_clock() as f64 / Region::Ntsc.frame_rate();
+    let mut rng = Xorshift::new();
+    let mut samples = Vec::new();
+    for frame in 0..frames {
+        // Writing the period's high bits reloads the length counters, so the channels keep
+        // playing.
+        for &(low, high) in &[(0x4002, 0x4003), (0x4006, 0x4007), (0x400a, 0x400b)] {
+            apu.storeb(low, rng.next() as u8);
+            apu.storeb(high, 0x08 | rng.next() as u8 & 0x03);
+        }
+        apu.storeb(0x400e, rng.next() as u8 & 0x8f);
+        apu.storeb(0x400f, 0x08);
+        apu.step(((frame + 1) as f64 * cycles_per_frame) as u64);
+        apu.take_samples(&mut samples);
+        apu.play_channels();
+        samples.clear();
+    }
+    frames as f64 / Region::Ntsc.frame_rate()
+}
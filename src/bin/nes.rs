@@ -4,39 +4,153 @@
 
 extern crate nes;
 
-use nes::gfx::Scale;
+use nes::batch::{self, BatchStatus};
+use nes::battery::{BatteryFile, SaveFiles};
+use nes::bench::{self, Subsystem};
+use nes::capture::{self, Condition};
+use nes::compat::{self, CompatStatus};
+use nes::config::{self, Config};
+use nes::coverage::OpcodeHistogram;
+use nes::dump::{AudioDump, FrameDump, FrameFormat};
+use nes::emulator::Emulator;
+use nes::error::Error;
+use nes::mapper;
+use nes::movie::{LatchMovie, Movie};
+use nes::pipe;
+use nes::replay::ReplayBuffer;
 use nes::rom::Rom;
+use nes::savestate;
+use nes::scan::{Database, Scan};
+use nes::session::{self, Session};
+use nes::util;
+use nes::StopHandle;
 
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io;
 use std::path::Path;
+use std::process;
 
 struct Options {
     rom_path: String,
-    scale: Scale,
+    /// Configuration options given on the command line, as `(key, value)` pairs.
+    overrides: Vec<(&'static str, String)>,
+    list_sram_backups: bool,
+    /// The battery save backup to restore before starting, where 1 is the newest.
+    restore_sram: Option<usize>,
+    /// A session bundle to pick up.
+    import_session: Option<String>,
+    replay_session: bool,
+    /// The configuration file to read instead of `sprocketnes.cfg`.
+    config_path: Option<String>,
 }
 
 fn usage() {
     println!("usage: sprocketnes [options] <path-to-rom>");
+    println!(
+        "       sprocketnes scan <dir> [--report <file>] [--playlist <file>] [--database <file>]"
+    );
+    println!("       sprocketnes compat <dir> [--frames <n>] [--report <file>]");
+    println!("       sprocketnes compare <rom> <movie> --frame <n> [--golden <hash>]");
+    println!("       sprocketnes ab <rom> [--a <profile>] [--b <profile>] [--frames <n>]");
+    println!("                      [--movie <file>]");
+    println!("       sprocketnes batch <manifest> [--jobs <n>] [--report <file>]");
+    println!("                         [--opcode-report <file>]");
+    println!("       sprocketnes state-upgrade <old.sav> <new.sav> [--rom <rom>]");
+    println!("       sprocketnes dump <rom> [--frames <n>] [--movie <file>] [--dump-frames <dir>]");
+    println!("                        [--frame-format rgb|png] [--dump-audio <file>]");
+    println!("                        [--dump-memory <start>-<end>] [--run-until <condition>]");
+    println!("                        [--who-wrote <address>] [--vram-report <file>]");
+    println!("                        [--opcode-report <file>] [--keep-last <seconds>]");
+    println!("       sprocketnes rom-info <rom>");
+    println!("       sprocketnes pipe <rom>");
+    println!("       sprocketnes config export|import <file> [--config <file>]");
+    println!("       sprocketnes --bench-cpu|--bench-ppu|--bench-apu <n>");
     println!("options:");
     println!("    -1 scale by 1x (default)");
     println!("    -2 scale by 2x");
     println!("    -3 scale by 3x");
     println!("    -4 scale by 4x");
+    println!("    --trace <file> log memory accesses to a CSV file");
+    println!("    --trace-filter <filter> only log accesses matching the filter:");
+    println!("        all (default), ppu, 4016, or an address range such as 6000-7fff");
+    println!("    --vgm <file> log APU register writes to a VGM file");
+    println!("    --record-latches <file> record the input at every controller latch");
+    println!("    --play-latches <file> play back a recording made with --record-latches");
+    println!("    --metrics <address> serve Prometheus metrics at http://<address>/metrics");
+    println!("    --barcodes <file> barcodes for the Datach reader, one per line");
+    println!("    --export-layers <n> write the layers of the nth frame to PNG images");
+    println!("    --run-until <condition> run at full speed until the condition holds, then pause");
+    println!("        frame=<n>, pc=<address>, or anything the capture_on setting takes");
+    println!("    --sram-backups list the backups of the battery save and exit");
+    println!("    --restore-sram <n> restore the nth newest battery save backup");
+    println!("    --import-session <file> pick up a session exported with F10");
+    println!("    --replay-session replay the imported session's input from power on");
+    println!("    --config <file> read settings from the file instead of sprocketnes.cfg");
+    println!("    --nrom-fallback run a ROM whose mapper isn't emulated as NROM");
 }
 
 fn parse_args() -> Option<Options> {
     let mut options = Options {
         rom_path: String::new(),
-        scale: Scale::Scale1x,
+        overrides: Vec::new(),
+        list_sram_backups: false,
+        restore_sram: None,
+        import_session: None,
+        replay_session: false,
+        config_path: None,
     };
 
-    for arg in env::args().skip(1) {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
         match &*arg {
-            "-1" => options.scale = Scale::Scale1x,
-            "-2" => options.scale = Scale::Scale2x,
-            "-3" => options.scale = Scale::Scale3x,
-            "-4" => options.scale = Scale::Scale4x,
+            "-1" | "-2" | "-3" | "-4" => options.overrides.push(("scale", arg[1..].to_string())),
+            "--trace" | "--trace-filter" | "--vgm" | "--record-latches" | "--play-latches"
+            | "--metrics" | "--barcodes" | "--export-layers" | "--run-until" => {
+                let key = match &*arg {
+                    "--trace" => "trace",
+                    "--trace-filter" => "trace_filter",
+                    "--record-latches" => "record_latches",
+                    "--play-latches" => "play_latches",
+                    "--metrics" => "metrics",
+                    "--barcodes" => "barcode_file",
+                    "--export-layers" => "export_layers",
+                    "--run-until" => "run_until",
+                    _ => "vgm",
+                };
+                match args.next() {
+                    Some(value) => options.overrides.push((key, value)),
+                    None => {
+                        usage();
+                        return None;
+                    }
+                }
+            }
+            "--sram-backups" => options.list_sram_backups = true,
+            "--replay-session" => options.replay_session = true,
+            "--nrom-fallback" => options.overrides.push(("nrom_fallback", "on".to_string())),
+            "--config" => match args.next() {
+                Some(path) => options.config_path = Some(path),
+                None => {
+                    usage();
+                    return None;
+                }
+            },
+            "--import-session" => match args.next() {
+                Some(path) => options.import_session = Some(path),
+                None => {
+                    usage();
+                    return None;
+                }
+            },
+            "--restore-sram" => match args.next().and_then(|index| index.parse().ok()) {
+                Some(index) => options.restore_sram = Some(index),
+                None => {
+                    usage();
+                    return None;
+                }
+            },
             _ if arg.starts_with('-') => {
                 usage();
                 return None;
@@ -53,14 +167,918 @@ fn parse_args() -> Option<Options> {
     Some(options)
 }
 
+/// Surveys the ROMs in a directory and reports which ones use supported mappers.
+fn scan(mut args: env::Args) {
+    let mut dir = None;
+    let mut report_path = "scan-report.csv".to_string();
+    let mut playlist_path = None;
+    let mut database_path = None;
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--report" | "--playlist" | "--database" => match args.next() {
+                Some(path) if arg == "--report" => report_path = path,
+                Some(path) if arg == "--playlist" => playlist_path = Some(path),
+                Some(path) => database_path = Some(path),
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => dir = Some(arg),
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return usage(),
+    };
+
+    let database = match database_path {
+        Some(ref path) => match Database::load_file(Path::new(path)) {
+            Ok(database) => database,
+            Err(err) => return println!("Error loading database {}: {}", path, err),
+        },
+        None => Database::new(),
+    };
+
+    let scan = match Scan::run(Path::new(&dir), &database) {
+        Ok(scan) => scan,
+        Err(err) => return println!("Error scanning {}: {}", dir, err),
+    };
+    if let Err(err) = File::create(&report_path).and_then(|mut file| scan.write_report(&mut file)) {
+        return println!("Error writing {}: {}", report_path, err);
+    }
+    if let Some(ref playlist_path) = playlist_path {
+        let written =
+            File::create(playlist_path).and_then(|mut file| scan.write_playlist(&mut file));
+        if let Err(err) = written {
+            return println!("Error writing {}: {}", playlist_path, err);
+        }
+    }
+
+    let total = scan.roms.len();
+    let supported = scan.supported_count();
+    println!(
+        "{} ROMs, {} supported ({:.0}%), {} failed to load",
+        total,
+        supported,
+        100.0 * supported as f64 / total.max(1) as f64,
+        scan.errors.len()
+    );
+    if database_path.is_some() {
+        println!("{} found in the database", scan.identified_count());
+    }
+    let mut unsupported = BTreeMap::new();
+    for info in scan.roms.iter().filter(|info| !info.supported) {
+        *unsupported.entry(info.mapper).or_insert(0) += 1;
+    }
+    for (mapper, count) in unsupported {
+        println!("  mapper {}: {} ROMs", mapper, count);
+    }
+    println!("Report written to {}", report_path);
+}
+
+/// Boots every ROM in a directory without a window and reports which ones run.
+fn compat(mut args: env::Args) {
+    let mut dir = None;
+    let mut frames = 600;
+    let mut report_path = "compat-report.csv".to_string();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--frames" => match args.next().and_then(|frames| frames.parse().ok()) {
+                Some(count) => frames = count,
+                None => return usage(),
+            },
+            "--report" => match args.next() {
+                Some(path) => report_path = path,
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => dir = Some(arg),
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return usage(),
+    };
+
+    let results = compat::test_dir(Path::new(&dir), frames, |result| {
+        let status = match result.status {
+            CompatStatus::Ran if result.nonblank_frames == 0 => "blank".to_string(),
+            CompatStatus::Ran => "ok".to_string(),
+            CompatStatus::UnsupportedMapper(mapper) => format!("unsupported mapper {}", mapper),
+            CompatStatus::LoadError(ref err) => format!("load error: {}", err),
+            CompatStatus::Crashed(ref message) => {
+                format!("crashed at frame {}: {}", result.frames_run, message)
+            }
+            CompatStatus::Jammed(pc) => {
+                format!("jammed at frame {} at ${:04X}", result.frames_run, pc)
+            }
+        };
+        let note = if result.experimental {
+            " (experimental mapper)"
+        } else {
+            ""
+        };
+        println!("{}: {}{}", result.path.display(), status, note);
+    });
+    let results = match results {
+        Ok(results) => results,
+        Err(err) => return println!("Error scanning {}: {}", dir, err),
+    };
+    let written =
+        File::create(&report_path).and_then(|mut file| compat::write_report(&results, &mut file));
+    if let Err(err) = written {
+        return println!("Error writing {}: {}", report_path, err);
+    }
+    println!("Report written to {}", report_path);
+}
+
+/// Exit status for `git bisect run` when the commit can't be tested.
+const BISECT_SKIP: i32 = 125;
+
+/// Replays a movie and checks the screen after frame N against a known-good hash. Exits with 0 on
+/// a match, 1 on a mismatch and 125 if the ROM or movie can't be run, so it can drive
+/// `git bisect run`. Without `--golden`, prints the hash and exits with 0.
+fn compare(mut args: env::Args) {
+    let mut paths = Vec::new();
+    let mut frame = None;
+    let mut golden = None;
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--frame" => frame = args.next().and_then(|frame| frame.parse().ok()),
+            "--golden" => {
+                golden = args
+                    .next()
+                    .and_then(|hash| u32::from_str_radix(&hash, 16).ok())
+            }
+            _ if arg.starts_with('-') => return usage(),
+            _ => paths.push(arg),
+        }
+    }
+    let (rom_path, movie_path, frame) = match (paths.len(), frame) {
+        (2, Some(frame)) => (&paths[0], &paths[1], frame),
+        _ => return usage(),
+    };
+
+    let rom = match File::open(rom_path)
+        .map_err(From::from)
+        .and_then(|mut file| Rom::load(&mut file))
+    {
+        Ok(rom) => rom,
+        Err(err) => {
+            println!("Error loading {}: {}", rom_path, err);
+            process::exit(BISECT_SKIP);
+        }
+    };
+    let mut emulator = match Emulator::new(rom, &Config::new()) {
+        Ok(emulator) => emulator,
+        Err(err) => {
+            println!("Error loading {}: {}", rom_path, err);
+            process::exit(BISECT_SKIP);
+        }
+    };
+    let movie_path = Path::new(movie_path);
+    let loaded = if movie_path
+        .extension()
+        .map_or(false, |extension| extension == "lmv")
+    {
+        LatchMovie::load(movie_path).map(|movie| movie.play(&mut emulator, frame))
+    } else {
+        Movie::load(movie_path).map(|movie| movie.play(&mut emulator, frame))
+    };
+    if let Err(err) = loaded {
+        println!("Error loading {}: {}", movie_path.display(), err);
+        process::exit(BISECT_SKIP);
+    }
+    let hash = emulator.screen_hash();
+    match golden {
+        None => println!("frame {}: {:08x}", frame, hash),
+        Some(golden) if golden == hash => println!("frame {}: {:08x} matches", frame, hash),
+        Some(golden) => {
+            println!("frame {}: {:08x}, expected {:08x}", frame, hash, golden);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs a ROM under two accuracy profiles side by side and reports the first frame where their
+/// screens differ, to find out which accuracy settings a game cares about. Exits with 1 if they
+/// diverge.
+fn ab(mut args: env::Args) {
+    let mut rom_path = None;
+    let mut profiles = ["fast".to_string(), "accurate".to_string()];
+    let mut frames = 600;
+    let mut movie_path = None;
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--a" | "--b" => match args.next() {
+                Some(profile) => profiles[(arg == "--b") as usize] = profile,
+                None => return usage(),
+            },
+            "--frames" => match args.next().and_then(|frames| frames.parse().ok()) {
+                Some(count) => frames = count,
+                None => return usage(),
+            },
+            "--movie" => match args.next() {
+                Some(path) => movie_path = Some(path),
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = match rom_path {
+        Some(rom_path) => rom_path,
+        None => return usage(),
+    };
+
+    let mut runs = Vec::new();
+    for (name, profile) in ["a", "b"].iter().zip(&profiles) {
+        let mut config = Config::new();
+        if let Err(err) = apply_profile(&mut config, profile) {
+            return println!("Bad profile `{}`: {}", profile, err);
+        }
+        let rom = match File::open(&rom_path)
+            .map_err(From::from)
+            .and_then(|mut file| Rom::load(&mut file))
+        {
+            Ok(rom) => rom,
+            Err(err) => return println!("Error loading {}: {}", rom_path, err),
+        };
+        let mut emulator = match Emulator::new(rom, &config) {
+            Ok(emulator) => emulator,
+            Err(err) => return println!("Error loading {}: {}", rom_path, err),
+        };
+        let mut movie = None;
+        if let Some(ref path) = movie_path {
+            match load_movie(&mut emulator, Path::new(path)) {
+                Ok(loaded) => movie = loaded,
+                Err(err) => return println!("Error loading {}: {}", path, err),
+            }
+        }
+        // A random alignment is picked once, so say which one this run got.
+        println!(
+            "{}: {}, alignment {}",
+            name, profile, emulator.cpu.mem.ppu.alignment
+        );
+        runs.push((emulator, movie));
+    }
+
+    for index in 0..frames {
+        let mut hashes = [0; 2];
+        for (hash, &mut (ref mut emulator, ref movie)) in hashes.iter_mut().zip(&mut runs) {
+            if let Some(ref movie) = *movie {
+                emulator.set_buttons(movie.frames.get(index).cloned().unwrap_or(0));
+            }
+            emulator.step_frame();
+            *hash = emulator.screen_hash();
+        }
+        if hashes[0] != hashes[1] {
+            println!(
+                "Diverged at frame {}: {:08x} under a, {:08x} under b",
+                index + 1,
+                hashes[0],
+                hashes[1]
+            );
+            process::exit(1);
+        }
+    }
+    println!("Identical for {} frames", frames);
+}
+
+/// Applies an accuracy profile for `ab`: a preset of the `accuracy` setting, settings such as
+/// `interrupt_latency=immediate`, or both, separated by commas.
+fn apply_profile(config: &mut Config, profile: &str) -> Result<(), String> {
+    for item in profile.split(',').map(str::trim) {
+        let mut parts = item.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => config.set(key.trim(), value.trim())?,
+            _ => config.set("accuracy", item)?,
+        }
+    }
+    Ok(())
+}
+
+/// Runs the entries of a batch manifest in parallel and writes the results as JSON. Exits with 1
+/// if any entry failed or couldn't be run.
+fn batch(mut args: env::Args) {
+    let mut manifest_path = None;
+    let mut jobs = 4;
+    let mut report_path = "batch-results.json".to_string();
+    let mut opcode_report_path = None;
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--jobs" => match args.next().and_then(|jobs| jobs.parse().ok()) {
+                Some(count) if count > 0 => jobs = count,
+                _ => return usage(),
+            },
+            "--report" => match args.next() {
+                Some(path) => report_path = path,
+                None => return usage(),
+            },
+            "--opcode-report" => match args.next() {
+                Some(path) => opcode_report_path = Some(path),
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => manifest_path = Some(arg),
+        }
+    }
+    let manifest_path = match manifest_path {
+        Some(manifest_path) => manifest_path,
+        None => return usage(),
+    };
+
+    let entries = match batch::load_manifest(Path::new(&manifest_path)) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("Error loading {}: {}", manifest_path, err);
+            process::exit(1);
+        }
+    };
+    let results = batch::run(entries, jobs, |result| {
+        let status = match result.status {
+            BatchStatus::Passed => "passed".to_string(),
+            BatchStatus::Failed => {
+                let failed: Vec<String> = result
+                    .checks
+                    .iter()
+                    .filter(|check| !check.matches())
+                    .map(|check| check.frame.to_string())
+                    .collect();
+                format!("failed at frame {}", failed.join(", "))
+            }
+            BatchStatus::Error(ref message) => format!("error: {}", message),
+        };
+        println!("{}: {}", result.rom.display(), status);
+    });
+    let written =
+        File::create(&report_path).and_then(|mut file| batch::write_json(&results, &mut file));
+    if let Err(err) = written {
+        println!("Error writing {}: {}", report_path, err);
+        process::exit(1);
+    }
+    println!("Results written to {}", report_path);
+    if let Some(ref path) = opcode_report_path {
+        let mut histogram = OpcodeHistogram::new();
+        for result in &results {
+            histogram.add(&result.opcode_counts);
+        }
+        write_opcode_report(path, &histogram);
+    }
+    if !batch::all_passed(&results) {
+        process::exit(1);
+    }
+}
+
+/// Converts a savestate from an older version of the format to the current one. The ROM supplies
+/// the battery-backed PRG-RAM that states from before version 4 left out.
+fn state_upgrade(mut args: env::Args) {
+    let mut paths = Vec::new();
+    let mut rom_path = None;
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--rom" => match args.next() {
+                Some(path) => rom_path = Some(path),
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        return usage();
+    }
+
+    let old = match fs::read(&paths[0]) {
+        Ok(old) => old,
+        Err(err) => return println!("Error loading {}: {}", paths[0], err),
+    };
+    let version = match savestate::version(&old) {
+        Ok(version) => version,
+        Err(err) => return println!("Error loading {}: {}", paths[0], err),
+    };
+    let prg_ram = match rom_path {
+        Some(ref rom_path) => match saved_prg_ram(Path::new(rom_path)) {
+            Ok(prg_ram) => prg_ram,
+            Err(err) => return println!("Error loading {}: {}", rom_path, err),
+        },
+        None => {
+            if version < 4 {
+                println!(
+                    "Note: for games with battery-backed saves, give the ROM with --rom so that \
+                     the state gets the PRG-RAM"
+                );
+            }
+            None
+        }
+    };
+
+    let upgraded = match savestate::upgrade(&old, prg_ram.as_ref().map(|ram| &ram[..])) {
+        Ok(upgraded) => upgraded,
+        Err(err) => return println!("Error upgrading {}: {}", paths[0], err),
+    };
+    if let Err(err) = util::write_atomic(Path::new(&paths[1]), &upgraded) {
+        return println!("Error writing {}: {}", paths[1], err);
+    }
+    println!(
+        "Upgraded {} from version {} to {}",
+        paths[0],
+        version,
+        savestate::VERSION
+    );
+}
+
+/// The PRG-RAM that savestates of the ROM include, as its battery save file holds it, or `None`
+/// if they include none.
+fn saved_prg_ram(rom_path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    let rom = Rom::load(&mut File::open(rom_path)?)?;
+    let mut mapper = mapper::create_mapper(Box::new(rom))?;
+    let capabilities = mapper.capabilities();
+    if !capabilities.prg_ram || !capabilities.non_volatile {
+        return Ok(None);
+    }
+    SaveFiles::new(rom_path, 0).load(&mut mapper.save_regions())?;
+    Ok(mapper.save_data().map(|data| data.to_vec()))
+}
+
+/// Runs a ROM without a window, writing every frame and the audio to disk for external tools.
+fn dump(mut args: env::Args) {
+    let mut rom_path = None;
+    let mut frames = 600;
+    let mut movie_path = None;
+    let mut frames_dir = None;
+    let mut format = FrameFormat::Rgb;
+    let mut audio_path = None;
+    let mut memory_range = None;
+    let mut run_until = None;
+    let mut who_wrote = Vec::new();
+    let mut vram_report_path = None;
+    let mut opcode_report_path = None;
+    let mut keep_last = None;
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--frames" => match args.next().and_then(|frames| frames.parse().ok()) {
+                Some(count) => frames = count,
+                None => return usage(),
+            },
+            "--keep-last" => match args.next().and_then(|seconds| seconds.parse().ok()) {
+                Some(seconds) if seconds > 0.0 => keep_last = Some(seconds),
+                _ => return usage(),
+            },
+            "--frame-format" => match args.next().and_then(|name| FrameFormat::from_name(&name)) {
+                Some(frame_format) => format = frame_format,
+                None => return usage(),
+            },
+            "--dump-memory" => match args.next().and_then(|range| parse_range(&range)) {
+                Some(range) => memory_range = Some(range),
+                None => return usage(),
+            },
+            "--who-wrote" => match args.next().and_then(|addr| parse_addr(&addr)) {
+                Some(addr) => who_wrote.push(addr),
+                None => return usage(),
+            },
+            "--run-until" => match args.next().map(|condition| Condition::parse(&condition)) {
+                Some(Ok(condition)) => run_until = Some(condition),
+                Some(Err(err)) => return println!("Bad --run-until condition: {}", err),
+                None => return usage(),
+            },
+            "--movie" | "--dump-frames" | "--dump-audio" | "--vram-report" | "--opcode-report" => {
+                let path = match args.next() {
+                    Some(path) => path,
+                    None => return usage(),
+                };
+                match &*arg {
+                    "--movie" => movie_path = Some(path),
+                    "--dump-frames" => frames_dir = Some(path),
+                    "--vram-report" => vram_report_path = Some(path),
+                    "--opcode-report" => opcode_report_path = Some(path),
+                    _ => audio_path = Some(path),
+                }
+            }
+            _ if arg.starts_with('-') => return usage(),
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = match rom_path {
+        Some(rom_path) => rom_path,
+        None => return usage(),
+    };
+
+    let rom = match File::open(&rom_path)
+        .map_err(From::from)
+        .and_then(|mut file| Rom::load(&mut file))
+    {
+        Ok(rom) => rom,
+        Err(err) => return println!("Error loading {}: {}", rom_path, err),
+    };
+    let mut config = Config::new();
+    config.write_provenance = !who_wrote.is_empty() || vram_report_path.is_some();
+    let mut emulator = match Emulator::new(rom, &config) {
+        Ok(emulator) => emulator,
+        Err(err) => return println!("Error loading {}: {}", rom_path, err),
+    };
+
+    let mut movie = None;
+    if let Some(ref path) = movie_path {
+        match load_movie(&mut emulator, Path::new(path)) {
+            Ok(loaded) => movie = loaded,
+            Err(err) => return println!("Error loading {}: {}", path, err),
+        }
+    }
+    let frame_dump = match frames_dir {
+        Some(ref dir) => match FrameDump::new(Path::new(dir), format) {
+            Ok(frame_dump) => Some(frame_dump),
+            Err(err) => return println!("Error creating {}: {}", dir, err),
+        },
+        None => None,
+    };
+    let mut audio_dump = match audio_path {
+        Some(ref path) => match AudioDump::create(Path::new(path)) {
+            Ok(audio_dump) => Some(audio_dump),
+            Err(err) => return println!("Error creating {}: {}", path, err),
+        },
+        None => None,
+    };
+
+    // With --keep-last, frames and audio are held back, and only the last of them written.
+    let region = emulator.cpu.mem.ppu.region();
+    let mut replay = keep_last.map(|seconds| ReplayBuffer::for_seconds(seconds, region));
+    let mut ran = frames;
+    for index in 0..frames {
+        if let Some(ref movie) = movie {
+            emulator.set_buttons(movie.frames.get(index).cloned().unwrap_or(0));
+        }
+        let stopped = match run_until {
+            Some(ref condition) => emulator.step_frame_until(condition),
+            None => {
+                emulator.step_frame();
+                false
+            }
+        };
+        let audio = emulator.take_audio();
+        if let Some(ref mut replay) = replay {
+            if !stopped {
+                replay.push(index, emulator.screen(), &audio);
+            }
+        } else {
+            // A frame cut short by the condition is only partly drawn, so it isn't dumped.
+            match frame_dump {
+                Some(ref frame_dump) if !stopped => {
+                    if let Err(err) = frame_dump.write(index, &emulator.frame()) {
+                        return println!("Error writing frame {}: {}", index, err);
+                    }
+                }
+                _ => {}
+            }
+            if let Some(ref mut audio_dump) = audio_dump {
+                if let Err(err) = audio_dump.write(&audio) {
+                    return println!("Error writing audio: {}", err);
+                }
+            }
+        }
+        if stopped {
+            println!("{}", capture::describe_stop(&mut emulator.cpu));
+            ran = index;
+            break;
+        }
+    }
+    if let Some(ref mut replay) = replay {
+        if let Err(err) = replay.drain_into(frame_dump.as_ref(), audio_dump.as_mut()) {
+            return println!("Error writing the last frames: {}", err);
+        }
+    }
+    if let Some(audio_dump) = audio_dump {
+        if let Err(err) = audio_dump.finish() {
+            return println!("Error writing audio: {}", err);
+        }
+    }
+    println!("Ran {} frames", ran);
+    if let Some((start, end)) = memory_range {
+        let stdout = io::stdout();
+        if let Err(err) = emulator.hex_dump(&mut stdout.lock(), start, end) {
+            println!("Error dumping memory: {}", err);
+        }
+    }
+    for &addr in &who_wrote {
+        match emulator.last_writer(addr) {
+            Some(writer) => println!("${:04X} was last written by {}", addr, writer),
+            None => println!("${:04X} was never written", addr),
+        }
+    }
+    if let Some(ref path) = vram_report_path {
+        let written = File::create(path).and_then(|mut file| emulator.write_vram_report(&mut file));
+        if let Err(err) = written {
+            println!("Error writing {}: {}", path, err);
+        }
+    }
+    if let Some(ref path) = opcode_report_path {
+        let mut histogram = OpcodeHistogram::new();
+        histogram.add(&emulator.cpu.opcode_counts);
+        write_opcode_report(path, &histogram);
+    }
+}
+
+/// Prints what the header and the data after CHR-ROM say about a ROM.
+fn rom_info(mut args: env::Args) {
+    let rom_path = match (args.next(), args.next()) {
+        (Some(rom_path), None) => rom_path,
+        _ => return usage(),
+    };
+    let rom = match File::open(&rom_path)
+        .map_err(From::from)
+        .and_then(|mut file| Rom::load(&mut file))
+    {
+        Ok(rom) => rom,
+        Err(err) => return println!("Error loading {}: {}", rom_path, err),
+    };
+    let header = &rom.header;
+    let format = if header.is_nes2() { "NES 2.0" } else { "iNES" };
+    println!("Format: {}", format);
+    println!("{}", header);
+    println!("Region: {}", header.region());
+    println!("Battery: {}", header.battery());
+    if let Some(submapper) = header.submapper() {
+        println!("Submapper: {}", submapper);
+    }
+    match mapper::unsupported(header) {
+        Some(unsupported) => println!("Support: {}", unsupported),
+        None if mapper::ignores_submapper(header) => {
+            println!("Support: emulated as the base board, not telling submappers apart")
+        }
+        None if mapper::is_experimental(header.mapper()) => println!("Support: experimental"),
+        None => println!("Support: emulated"),
+    }
+    if header.misc_roms() > 0 {
+        println!(
+            "Misc ROMs: {} ({} bytes)",
+            header.misc_roms(),
+            rom.misc.len()
+        );
+    }
+    if let Some(ref title) = rom.metadata.title {
+        println!("Title: {}", title);
+    }
+    if let Some(ref author) = rom.metadata.author {
+        println!("Author: {}", author);
+    }
+    for warning in &rom.warnings {
+        println!("Warning: {}", warning);
+    }
+}
+
+/// Runs a ROM without a window on input read from stdin, answering each line with a screen hash on
+/// stdout; see `nes::pipe`.
+fn pipe_input(mut args: env::Args) {
+    let rom_path = match (args.next(), args.next()) {
+        (Some(rom_path), None) => rom_path,
+        _ => return usage(),
+    };
+    let emulator = File::open(&rom_path)
+        .map_err(From::from)
+        .and_then(|mut file| Rom::load(&mut file))
+        .and_then(|rom| Emulator::new(rom, &Config::new()));
+    let mut emulator = match emulator {
+        Ok(emulator) => emulator,
+        Err(err) => {
+            println!("error: loading {}: {}", rom_path, err);
+            process::exit(1);
+        }
+    };
+    // Errors go to stdout too, as the line the program reading it waits for.
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(err) = pipe::run(&mut emulator, stdin.lock(), &mut stdout.lock()) {
+        println!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn write_opcode_report(path: &str, histogram: &OpcodeHistogram) {
+    // Nothing is written if opcodes weren't counted.
+    let mut report = Vec::new();
+    match histogram
+        .write_report(&mut report)
+        .and_then(|()| fs::write(path, report))
+    {
+        Ok(()) => println!("Opcode report written to {}", path),
+        Err(err) => println!("Error writing {}: {}", path, err),
+    }
+}
+
+/// Loads a movie into `emulator`. Latch movies (`.lmv`) play back on their own; `.fm2` movies set
+/// the buttons a frame at a time, so they are returned for the caller to do so.
+fn load_movie(emulator: &mut Emulator, path: &Path) -> io::Result<Option<Movie>> {
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "lmv")
+    {
+        emulator.cpu.mem.input.latch_movie = Some(LatchMovie::load(path)?);
+        Ok(None)
+    } else {
+        Movie::load(path).map(Some)
+    }
+}
+
+fn parse_addr(addr: &str) -> Option<u16> {
+    u16::from_str_radix(addr, 16).ok()
+}
+
+/// Parses a range of hex addresses such as `6000-7fff`.
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+    let mut bounds = range.splitn(2, '-');
+    let start = parse_addr(bounds.next()?)?;
+    let end = parse_addr(bounds.next()?)?;
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Runs one subsystem on its own with synthetic input for `n` instructions or frames, and
+/// reports how fast it went.
+fn bench_subsystem(flag: &str, mut args: env::Args) {
+    let subsystem = match Subsystem::from_flag(flag) {
+        Some(subsystem) => subsystem,
+        None => return usage(),
+    };
+    let iterations = match args.next().and_then(|iterations| iterations.parse().ok()) {
+        Some(iterations) if args.next().is_none() => iterations,
+        _ => return usage(),
+    };
+    println!("{}", bench::run(subsystem, iterations));
+}
+
+/// Exports the configuration file to another file, or imports one in its place after checking it,
+/// for switching between setups.
+fn config_file(mut args: env::Args) {
+    let mut config_path = config::CONFIG_PATH.to_string();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--config" => match args.next() {
+                Some(path) => config_path = path,
+                None => return usage(),
+            },
+            _ if arg.starts_with('-') => return usage(),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        return usage();
+    }
+    let path = &positional[1];
+
+    match &*positional[0] {
+        "export" => {
+            if let Err(err) = fs::copy(&config_path, path) {
+                return println!("Error exporting {}: {}", config_path, err);
+            }
+            println!("Exported {} to {}", config_path, path);
+        }
+        "import" => {
+            let problems = match Config::check(Path::new(path)) {
+                Ok(problems) => problems,
+                Err(err) => return println!("Error loading {}: {}", path, err),
+            };
+            if !problems.is_empty() {
+                for problem in problems {
+                    println!("{}", problem);
+                }
+                return println!("Not importing {}", path);
+            }
+            // Keep the configuration being replaced, in case it wasn't exported.
+            let backup = format!("{}.bak", config_path);
+            if Path::new(&config_path).exists() {
+                if let Err(err) = fs::copy(&config_path, &backup) {
+                    return println!("Error backing up {}: {}", config_path, err);
+                }
+            }
+            if let Err(err) = fs::copy(path, &config_path) {
+                return println!("Error importing {}: {}", path, err);
+            }
+            println!("Imported {} into {}", path, config_path);
+        }
+        _ => usage(),
+    }
+}
+
 fn main() {
+    let mut args = env::args();
+    args.next();
+    match args.next() {
+        Some(ref command) if command == "scan" => return scan(args),
+        Some(ref command) if command == "compat" => return compat(args),
+        Some(ref command) if command == "compare" => return compare(args),
+        Some(ref command) if command == "ab" => return ab(args),
+        Some(ref command) if command == "batch" => return batch(args),
+        Some(ref command) if command == "state-upgrade" => return state_upgrade(args),
+        Some(ref command) if command == "dump" => return dump(args),
+        Some(ref command) if command == "rom-info" => return rom_info(args),
+        Some(ref command) if command == "pipe" => return pipe_input(args),
+        Some(ref command) if command == "config" => return config_file(args),
+        Some(ref command) if command.starts_with("--bench-") => {
+            return bench_subsystem(command, args)
+        }
+        _ => {}
+    }
+
     let options = match parse_args() {
         Some(options) => options,
         None => return,
     };
 
-    let rom_path = &options.rom_path;
-    let rom = Rom::load(&mut File::open(&Path::new(rom_path)).unwrap()).unwrap();
+    let rom_path = Path::new(&options.rom_path);
+    let rom = match File::open(rom_path)
+        .map_err(From::from)
+        .and_then(|mut file| Rom::load(&mut file))
+    {
+        Ok(rom) => rom,
+        Err(err) => return println!("Error loading {}: {}", options.rom_path, err),
+    };
+
+    let rom_name = rom_path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let config_path = options
+        .config_path
+        .as_ref()
+        .map_or(config::CONFIG_PATH, |path| &path[..]);
+    let mut config = Config::load(Path::new(config_path), &rom_name);
+
+    let mut session = None;
+    if let Some(ref path) = options.import_session {
+        let mut imported = match Session::load(Path::new(path)) {
+            Ok(imported) => imported,
+            Err(err) => return println!("Error loading session: {}", err),
+        };
+        match session::rom_crc32(rom_path) {
+            Ok(crc32) if crc32 == imported.rom_crc32 => {}
+            Ok(crc32) => {
+                return println!(
+                    "Session was exported from a ROM with CRC {:08x}, not {:08x}",
+                    imported.rom_crc32, crc32
+                )
+            }
+            Err(err) => return println!("Error reading ROM: {}", err),
+        }
+        for &(ref key, ref value) in &imported.settings {
+            if let Err(err) = config.set(key, value) {
+                println!("Ignoring session setting: {}", err);
+            }
+        }
+        imported.replay = options.replay_session;
+        match savestate::version(&imported.state) {
+            Ok(version) if version < savestate::BOARD_STATE_VERSION && !imported.replay => {
+                println!(
+                    "The session's savestate doesn't hold the cartridge's state; replaying its \
+                     input from power on instead"
+                );
+                imported.replay = true;
+            }
+            Ok(version) if version < savestate::VERSION => {
+                match savestate::upgrade(&imported.state, None) {
+                    Ok(state) => imported.state = state,
+                    Err(err) => return println!("Error upgrading the session's state: {}", err),
+                }
+            }
+            _ => {}
+        }
+        session = Some(imported);
+    }
 
-    nes::start_emulator(rom, options.scale);
+    for &(key, ref value) in &options.overrides {
+        if let Err(err) = config.set_override(key, value) {
+            println!("{}", err);
+            return;
+        }
+    }
+
+    let battery = BatteryFile::new(rom_path, config.sram_backups);
+    if options.list_sram_backups {
+        match battery.backups() {
+            Ok(backups) => {
+                for (index, backup) in backups.iter().enumerate() {
+                    println!("{}: {}", index + 1, backup.display());
+                }
+            }
+            Err(err) => println!("Error listing backups: {}", err),
+        }
+        return;
+    }
+    if let Some(index) = options.restore_sram {
+        match battery.restore(index) {
+            Ok(backup) => println!("Restored {}", backup.display()),
+            Err(err) => {
+                println!("Error restoring backup: {}", err);
+                return;
+            }
+        }
+    }
+
+    if let Err(err) = nes::start_emulator(rom, rom_path, config, session, StopHandle::new()) {
+        println!("Error: {}", err);
+        if let Error::UnsupportedMapper(_) = err {
+            println!("Pass --nrom-fallback to try running it as NROM anyway");
+        }
+        process::exit(1);
+    }
 }
@@ -0,0 +1,121 @@
+//! Names of iNES mappers, emulated or not, for telling the user what board a ROM needs, and for
+//! mappers that aren't emulated, the emulated one whose board is nearest: the same chip in
+//! another wiring, or a later chip that works the same way. Games may partly run on it.
+//!
+//! The names follow the NesDev wiki.
+
+/// A mapper's name, and the nearest emulated mapper if it isn't emulated itself.
+struct Board {
+    number: u16,
+    name: &'static str,
+    nearest: Option<u16>,
+}
+
+const fn board(number: u16, name: &'static str, nearest: Option<u16>) -> Board {
+    Board {
+        number,
+        name,
+        nearest,
+    }
+}
+
+/// Sorted by number.
+static BOARDS: [Board; 75] = [
+    board(0, "NROM", None),
+    board(1, "SxROM/MMC1", None),
+    board(2, "UxROM", Some(30)),
+    board(3, "CNROM", None),
+    board(4, "TxROM/MMC3", None),
+    board(5, "ExROM/MMC5", None),
+    board(7, "AxROM", None),
+    board(9, "PxROM/MMC2", None),
+    board(10, "FxROM/MMC4", None),
+    board(11, "Color Dreams", None),
+    board(13, "CPROM", None),
+    board(16, "Bandai FCG", None),
+    board(18, "Jaleco SS88006", None),
+    board(19, "Namco 163", None),
+    board(21, "Konami VRC4a/VRC4c", None),
+    board(22, "Konami VRC2a", None),
+    board(23, "Konami VRC2b/VRC4e", None),
+    board(24, "Konami VRC6a", None),
+    board(25, "Konami VRC4b/VRC4d", None),
+    board(26, "Konami VRC6b", None),
+    board(30, "UNROM 512", None),
+    board(32, "Irem G-101", None),
+    board(33, "Taito TC0190", None),
+    board(34, "BNROM/NINA-001", None),
+    board(48, "Taito TC0690", None),
+    board(64, "Tengen RAMBO-1", None),
+    board(65, "Irem H3001", None),
+    board(66, "GxROM", None),
+    board(68, "Sunsoft-4", None),
+    board(69, "Sunsoft FME-7", None),
+    board(70, "Bandai 74161/7432", None),
+    board(71, "Camerica BF909x", None),
+    board(73, "Konami VRC3", None),
+    board(74, "Waixing MMC3 with CHR-RAM", Some(4)),
+    board(75, "Konami VRC1", None),
+    board(76, "Namcot 3446", None),
+    board(77, "Irem 74HC161/32", None),
+    board(78, "Irem/Jaleco 74HC161/32", None),
+    board(79, "AVE NINA-03/NINA-06", None),
+    board(80, "Taito X1-005", None),
+    board(85, "Konami VRC7", None),
+    board(86, "Jaleco JF-13", None),
+    board(87, "Jaleco JF-xx", None),
+    board(88, "Namcot 3433", None),
+    board(89, "Sunsoft-2 on Sunsoft-3", None),
+    board(90, "J.Y. Company ASIC", None),
+    board(93, "Sunsoft-2 on Sunsoft-3R", None),
+    board(94, "UN1ROM", Some(30)),
+    board(95, "Namcot 3425", None),
+    board(97, "Irem TAM-S1", None),
+    board(105, "NES-EVENT/MMC1", Some(1)),
+    board(111, "GTROM", None),
+    board(113, "NINA-03/NINA-06 multicart", None),
+    board(118, "TxSROM/MMC3", Some(4)),
+    board(119, "TQROM/MMC3", Some(4)),
+    board(140, "Jaleco JF-11/JF-14", None),
+    board(153, "Bandai FCG with SRAM", None),
+    board(154, "Namcot 3453", None),
+    board(155, "MMC1A", Some(1)),
+    board(157, "Bandai Datach", None),
+    board(158, "Tengen 800037/RAMBO-1", Some(64)),
+    board(159, "Bandai FCG with 24C01", None),
+    board(163, "Nanjing", None),
+    board(180, "UNROM with 74HC08", Some(30)),
+    board(184, "Sunsoft-1", None),
+    board(185, "CNROM with CHR disable", None),
+    board(191, "Waixing MMC3 with CHR-RAM", Some(4)),
+    board(192, "Waixing MMC3 with CHR-RAM", Some(4)),
+    board(194, "Waixing MMC3 with CHR-RAM", Some(4)),
+    board(206, "DxROM/Namco 108", None),
+    board(209, "J.Y. Company ASIC", None),
+    board(210, "Namco 175/340", None),
+    board(211, "J.Y. Company ASIC", None),
+    board(228, "Action 52", None),
+    board(245, "Waixing MMC3", Some(4)),
+];
+
+fn find(number: u16) -> Option<&'static Board> {
+    BOARDS
+        .binary_search_by_key(&number, |board| board.number)
+        .ok()
+        .map(|index| &BOARDS[index])
+}
+
+/// The name of the board of a mapper, if it is known.
+///
+/// ```
+/// assert_eq!(nes::boards::name(4), Some("TxROM/MMC3"));
+/// assert_eq!(nes::boards::nearest(119), Some(4));
+/// ```
+pub fn name(number: u16) -> Option<&'static str> {
+    find(number).map(|board| board.name)
+}
+
+/// The emulated mapper whose board is nearest to that of a mapper that isn't emulated, if any.
+pub fn nearest(number: u16) -> Option<u16> {
+    find(number).and_then(|board| board.nearest)
+}
@@ -0,0 +1,202 @@
+use clock::MachineClock;
+use cpu::Cpu;
+use disasm;
+use mem::{MemMap, Peek};
+use ppu::PpuLocation;
+
+/// How a memory predicate compares the byte at its address.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn holds(self, lhs: u8, rhs: u8) -> bool {
+        match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Ge => lhs >= rhs,
+        }
+    }
+}
+
+/// A single test of the machine state.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Predicate {
+    /// The CPU is about to execute the instruction at this address.
+    Pc(u16),
+    /// The byte at an address compares to a value.
+    Memory(u16, Comparison, u8),
+    /// The last instruction wrote to a location in PPU memory.
+    PpuWrite(PpuLocation),
+    /// At least this many frames have finished since power on.
+    Frame(u64),
+}
+
+impl Predicate {
+    fn parse(text: &str) -> Result<Predicate, String> {
+        let text = text.trim();
+        if let Some(pc) = text.strip_prefix("pc=") {
+            return parse_hex(pc).map(Predicate::Pc);
+        }
+        if let Some(frame) = text.strip_prefix("frame=") {
+            return match frame.trim().parse() {
+                Ok(frame) => Ok(Predicate::Frame(frame)),
+                Err(_) => Err(format!("`{}` is not a frame number", frame)),
+            };
+        }
+        if let Some(addr) = text.strip_prefix("vram=") {
+            let addr = parse_hex(addr)?;
+            if addr >= 0x3f00 {
+                return Err(format!("${:04X} is not below the palette at $3F00", addr));
+            }
+            return Ok(Predicate::PpuWrite(PpuLocation::Vram(addr)));
+        }
+        if let Some(index) = text.strip_prefix("palette=") {
+            let index = parse_hex(index)?;
+            if index > 0x1f {
+                return Err(format!("`{:X}` is not a palette entry", index));
+            }
+            return Ok(Predicate::PpuWrite(PpuLocation::Palette(index as u8)));
+        }
+        if let Some(index) = text.strip_prefix("oam=") {
+            let index = parse_hex(index)?;
+            if index > 0xff {
+                return Err(format!("`{:X}` is not an OAM byte", index));
+            }
+            return Ok(Predicate::PpuWrite(PpuLocation::Oam(index as u8)));
+        }
+
+        // Longer operators first, so that `<=` isn't read as `<`.
+        let operators = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ];
+        for &(operator, comparison) in &operators {
+            if let Some(index) = text.find(operator) {
+                let addr = parse_hex(&text[..index])?;
+                if (0x2000..0x6000).contains(&addr) {
+                    return Err(format!("${:04X} is a register, not memory", addr));
+                }
+                let val = parse_hex(&text[index + operator.len()..])?;
+                if val > 0xff {
+                    return Err(format!("`{:X}` does not fit in a byte", val));
+                }
+                return Ok(Predicate::Memory(addr, comparison, val as u8));
+            }
+        }
+        Err(format!(
+            "expected `pc=<addr>`, `frame=<n>`, `vram=<addr>`, `palette=<index>`, \
+             `oam=<index>` or `<addr><op><value>`, found `{}`",
+            text
+        ))
+    }
+
+    fn holds(self, cpu: &mut Cpu<MemMap>) -> bool {
+        match self {
+            Predicate::Pc(pc) => cpu.pc() == pc,
+            Predicate::Memory(addr, comparison, val) => comparison.holds(cpu.mem.peek(addr), val),
+            Predicate::PpuWrite(location) => cpu.mem.ppu.was_written(location),
+            Predicate::Frame(frame) => MachineClock::read(cpu).since_power_on.frames >= frame,
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let text = text.trim();
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("`{}` is not a hex number", text))
+}
+
+/// A set of predicates that must all hold at once, written as e.g. `pc=c2f0 && 0075==03`. Memory
+/// addresses and values are hexadecimal; the comparison operators are `==`, `!=`, `<`, `<=`, `>`
+/// and `>=`. `vram=<addr>`, `palette=<index>` and `oam=<index>` hold right after an instruction
+/// writes to that location in PPU memory. `frame=<n>`, in decimal, holds from the end of the nth
+/// frame since power on.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Condition {
+    predicates: Vec<Predicate>,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let predicates = text
+            .split("&&")
+            .map(Predicate::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Condition { predicates })
+    }
+
+    /// Evaluates the condition between two instructions.
+    pub fn is_met(&self, cpu: &mut Cpu<MemMap>) -> bool {
+        let met = self.holds(cpu);
+        cpu.mem.ppu.clear_writes();
+        met
+    }
+
+    /// Evaluates the condition like `is_met`, but leaves the PPU writes for other conditions. Call
+    /// `Ppu::clear_writes` once they have all been evaluated.
+    pub fn holds(&self, cpu: &mut Cpu<MemMap>) -> bool {
+        self.predicates.iter().all(|predicate| predicate.holds(cpu))
+    }
+
+    fn watches_ppu(&self) -> bool {
+        self.predicates
+            .iter()
+            .any(|predicate| matches!(*predicate, Predicate::PpuWrite(_)))
+    }
+}
+
+/// Prepares `cpu` for evaluating `conditions`, recording PPU writes only if one needs them. Call
+/// whenever the conditions change.
+pub fn arm<'a, I: IntoIterator<Item = &'a Condition>>(conditions: I, cpu: &mut Cpu<MemMap>) {
+    let watch = conditions
+        .into_iter()
+        .any(|condition| condition.watches_ppu());
+    cpu.mem.ppu.watch_writes(watch);
+}
+
+/// Describes where the machine stopped, for when a condition stops it: the frame, the cycle and
+/// the code around the program counter.
+pub fn describe_stop(cpu: &mut Cpu<MemMap>) -> String {
+    let pc = cpu.pc();
+    let clock = MachineClock::read(cpu).since_power_on;
+    let mut text = format!(
+        "Stopped at ${:04X} in frame {}, at cycle {}",
+        pc, clock.frames, clock.cpu_cycles
+    );
+    for instruction in disasm::window(&mut Peek(&mut cpu.mem), pc, 4, 4) {
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let marker = if instruction.addr == pc { ">" } else { " " };
+        let line = format!(
+            "{} ${:04X}  {:8}  {} {}",
+            marker,
+            instruction.addr,
+            bytes.join(" "),
+            instruction.mnemonic,
+            instruction.operand
+        );
+        text.push('\n');
+        text.push_str(line.trim_end());
+    }
+    text
+}
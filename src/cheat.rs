@@ -0,0 +1,98 @@
+//! Memory cheats.
+//!
+//! A frozen address keeps its value: every CPU write to it stores the frozen value instead, which
+//! is how the classic infinite-lives cheats behave. Pokes (`MemMap::poke`) are one-shot writes that
+//! the game is free to overwrite.
+
+/// Whether `addr` is RAM or cartridge RAM, the memory that can be poked and frozen.
+pub fn is_memory(addr: u16) -> bool {
+    addr < 0x2000 || (0x6000..0x8000).contains(&addr)
+}
+
+/// Folds the mirrors of internal RAM onto $0000-$07FF.
+fn canonical(addr: u16) -> u16 {
+    if addr < 0x2000 {
+        addr & 0x7ff
+    } else {
+        addr
+    }
+}
+
+pub struct Cheats {
+    frozen: Vec<(u16, u8)>,
+}
+
+impl Default for Cheats {
+    fn default() -> Cheats {
+        Cheats::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats { frozen: Vec::new() }
+    }
+
+    /// Parses a comma-separated list of `<addr>=<value>` freezes in hexadecimal, e.g.
+    /// `0075=09, 00a0=01`.
+    pub fn parse(text: &str) -> Result<Vec<(u16, u8)>, String> {
+        text.split(',')
+            .filter(|freeze| !freeze.trim().is_empty())
+            .map(|freeze| {
+                let mut parts = freeze.splitn(2, '=');
+                let addr = parts.next().unwrap().trim();
+                let val = match parts.next() {
+                    Some(val) => val.trim(),
+                    None => return Err(format!("expected `<addr>=<value>`, found `{}`", freeze)),
+                };
+                let addr = u16::from_str_radix(addr, 16)
+                    .map_err(|_| format!("`{}` is not a hex address", addr))?;
+                if !is_memory(addr) {
+                    return Err(format!("${:04X} is not RAM", addr));
+                }
+                let val = u8::from_str_radix(val, 16)
+                    .map_err(|_| format!("`{}` is not a hex byte", val))?;
+                Ok((addr, val))
+            })
+            .collect()
+    }
+
+    /// The frozen addresses and their values.
+    pub fn frozen(&self) -> &[(u16, u8)] {
+        &self.frozen
+    }
+
+    /// Freezes `addr` at `val`, replacing any earlier freeze of it. Addresses that aren't memory
+    /// are ignored. Use `MemMap::freeze` to also write the value right away.
+    pub fn freeze(&mut self, addr: u16, val: u8) {
+        if !is_memory(addr) {
+            return;
+        }
+        self.unfreeze(addr);
+        self.frozen.push((canonical(addr), val));
+    }
+
+    /// Returns false if `addr` wasn't frozen.
+    pub fn unfreeze(&mut self, addr: u16) -> bool {
+        let (addr, count) = (canonical(addr), self.frozen.len());
+        self.frozen.retain(|&(frozen, _)| frozen != addr);
+        self.frozen.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.frozen.clear();
+    }
+
+    /// The value that a CPU write of `val` to `addr` stores.
+    #[inline]
+    pub fn apply(&self, addr: u16, val: u8) -> u8 {
+        if self.frozen.is_empty() {
+            return val;
+        }
+        let addr = canonical(addr);
+        self.frozen
+            .iter()
+            .find(|&&(frozen, _)| frozen == addr)
+            .map_or(val, |&(_, frozen_val)| frozen_val)
+    }
+}
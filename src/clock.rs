@@ -0,0 +1,74 @@
+//! The machine's clock: how long it has run since power on and since the last reset, in CPU
+//! cycles, PPU dots and frames, for tracers, movies and tools that need to say when something
+//! happened.
+
+use cpu::Cpu;
+use mem::MemMap;
+
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ClockCount {
+    pub cpu_cycles: u64,
+    /// PPU dots, 3 per CPU cycle, or 3.2 on PAL.
+    pub ppu_dots: u64,
+    /// Frames the PPU finished.
+    pub frames: u64,
+}
+
+impl fmt::Display for ClockCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {}, cycle {}, dot {}",
+            self.frames, self.cpu_cycles, self.ppu_dots
+        )
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct MachineClock {
+    pub since_power_on: ClockCount,
+    /// The same as `since_power_on` until the machine is first reset. Loading a savestate doesn't
+    /// restore it.
+    pub since_reset: ClockCount,
+}
+
+impl MachineClock {
+    /// Reads the clock of the machine `cpu` runs, as of its current cycle.
+    ///
+    /// ```
+    /// use nes::clock::MachineClock;
+    /// use nes::config::Config;
+    /// use nes::emulator::Emulator;
+    ///
+    /// let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+    /// let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+    /// for _ in 0..3 {
+    ///     emulator.step_frame();
+    /// }
+    /// emulator.cpu.reset();
+    /// emulator.step_frame();
+    /// let clock = MachineClock::read(&emulator.cpu);
+    /// assert_eq!(clock.since_power_on.frames, 4);
+    /// assert_eq!(clock.since_reset.frames, 1);
+    /// assert_eq!(clock.since_power_on.ppu_dots, clock.since_power_on.cpu_cycles * 3);
+    /// ```
+    pub fn read(cpu: &Cpu<MemMap>) -> MachineClock {
+        let region = cpu.mem.ppu.region();
+        let count = |cpu_cycles: u64, frames: u64| ClockCount {
+            cpu_cycles,
+            ppu_dots: region.ppu_dots(cpu_cycles),
+            frames,
+        };
+        let frame = cpu.mem.ppu.frame();
+        MachineClock {
+            since_power_on: count(cpu.cy, frame),
+            // A savestate from before the reset can leave the mark in the future.
+            since_reset: count(
+                cpu.cy.saturating_sub(cpu.mem.reset_cycle),
+                frame.saturating_sub(cpu.mem.reset_frame),
+            ),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Batch compatibility testing: boots ROMs without a window and records how far they get.
+
+use config::Config;
+use emulator::Emulator;
+use mapper::{self, Capabilities};
+use rom::Rom;
+use scan;
+
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+pub enum CompatStatus {
+    /// Ran for all the requested frames.
+    Ran,
+    UnsupportedMapper(u16),
+    LoadError(String),
+    /// The emulator panicked, with the panic message.
+    Crashed(String),
+    /// The game ran a KIL opcode at the given address, halting the CPU.
+    Jammed(u16),
+}
+
+pub struct CompatResult {
+    pub path: PathBuf,
+    pub status: CompatStatus,
+    /// Whether the ROM's mapper is one of the experimental pirate mappers.
+    pub experimental: bool,
+    /// What the ROM's board has, if its mapper could be created.
+    pub capabilities: Capabilities,
+    pub frames_run: usize,
+    /// How many frames showed more than a single color.
+    pub nonblank_frames: usize,
+    pub first_nonblank_frame: Option<usize>,
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Boots the ROM at `path` and runs it for `frames` frames.
+pub fn test_rom(path: &Path, frames: usize) -> CompatResult {
+    let mut result = CompatResult {
+        path: path.to_path_buf(),
+        status: CompatStatus::Ran,
+        experimental: false,
+        capabilities: Capabilities::default(),
+        frames_run: 0,
+        nonblank_frames: 0,
+        first_nonblank_frame: None,
+    };
+
+    let rom = match File::open(path)
+        .map_err(From::from)
+        .and_then(|mut file| Rom::load(&mut file))
+    {
+        Ok(rom) => rom,
+        Err(err) => {
+            result.status = CompatStatus::LoadError(err.to_string());
+            return result;
+        }
+    };
+    if let Some(unsupported) = mapper::unsupported(&rom.header) {
+        result.status = CompatStatus::UnsupportedMapper(unsupported.number);
+        return result;
+    }
+    result.experimental = mapper::is_experimental(rom.header.mapper());
+
+    // Keep the default panic hook from printing a backtrace for every broken ROM.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut emulator = match Emulator::new(rom, &Config::new()) {
+            Ok(emulator) => emulator,
+            Err(err) => {
+                result.status = CompatStatus::LoadError(err.to_string());
+                return;
+            }
+        };
+        result.capabilities = emulator.cpu.mem.mapper.borrow().capabilities();
+        for frame in 0..frames {
+            emulator.step_frame();
+            result.frames_run += 1;
+            if emulator.cpu.jammed() {
+                result.status = CompatStatus::Jammed(emulator.cpu.pc());
+                break;
+            }
+            if !emulator.screen_is_blank() {
+                result.nonblank_frames += 1;
+                result.first_nonblank_frame = result.first_nonblank_frame.or(Some(frame));
+            }
+        }
+    }));
+    panic::set_hook(default_hook);
+
+    if let Err(payload) = run {
+        result.status = CompatStatus::Crashed(panic_message(&payload));
+    }
+    result
+}
+
+/// Tests every ROM under `dir`, calling `progress` after each one.
+pub fn test_dir<F>(dir: &Path, frames: usize, mut progress: F) -> io::Result<Vec<CompatResult>>
+where
+    F: FnMut(&CompatResult),
+{
+    let mut results = Vec::new();
+    for path in scan::find_roms(dir)? {
+        let result = test_rom(&path, frames);
+        progress(&result);
+        results.push(result);
+    }
+    Ok(results)
+}
+
+pub fn write_report<W: Write>(results: &[CompatResult], writer: &mut W) -> io::Result<()> {
+    writeln!(
+        writer,
+        "path,status,detail,mapper_support,capabilities,frames_run,nonblank_frames,\
+         first_nonblank_frame"
+    )?;
+    for result in results {
+        let (status, detail) = match result.status {
+            CompatStatus::Ran => ("ran", String::new()),
+            CompatStatus::UnsupportedMapper(mapper) => ("unsupported_mapper", mapper.to_string()),
+            CompatStatus::LoadError(ref err) => ("load_error", err.clone()),
+            CompatStatus::Crashed(ref message) => ("crashed", message.clone()),
+            CompatStatus::Jammed(pc) => ("jammed", format!("${:04X}", pc)),
+        };
+        let support = match result.status {
+            CompatStatus::UnsupportedMapper(_) | CompatStatus::LoadError(_) => "",
+            _ if result.experimental => "experimental",
+            _ => "stable",
+        };
+        let first_nonblank_frame = result
+            .first_nonblank_frame
+            .map_or(String::new(), |frame| frame.to_string());
+        writeln!(
+            writer,
+            "\"{}\",{},\"{}\",{},{},{},{},{}",
+            result.path.display(),
+            status,
+            detail.replace('"', "\"\""),
+            support,
+            result.capabilities.names().join(" "),
+            result.frames_run,
+            result.nonblank_frames,
+            first_nonblank_frame
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,836 @@
+//! User configuration.
+//!
+//! Settings are read from a plain text file of `key = value` lines. Lines in a `[section]` only
+//! apply when the section name matches the loaded ROM's file name (without extension), which lets
+//! individual games override the global settings. `#` starts a comment.
+//!
+//! Sections named `[profile:<name>]` are controller profiles instead: key bindings and turbo
+//! patterns that take effect when the `profile` setting selects them, typically in a game's
+//! section. Sections named `[gamepad:<guid>]` hold the joystick buttons of one model of gamepad,
+//! identified by its SDL joystick GUID, wherever it is plugged in.
+//!
+//! All runtime settings live in the `Config` struct, which the main loop checks once per frame;
+//! editing the file while the emulator runs re-applies it without a restart.
+
+use apu::FastForwardAudio;
+use barcode::Barcode;
+use capture::Condition;
+use cheat::Cheats;
+use filter::{self, ColorBlindness};
+use gfx::{Rotation, Scale, SmoothRefresh};
+use glasses::StereoMode;
+use input::{self, Bindings, FirePattern, PadBindings};
+use power::PowerSaving;
+use ppu::ALIGNMENTS;
+use region::Region;
+use trace::TraceFilter;
+use zapper::ZapperCalibration;
+
+use sdl2::keyboard::Keycode;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The default location of the configuration file.
+pub const CONFIG_PATH: &str = "sprocketnes.cfg";
+
+/// What the names of controller profile sections start with.
+const PROFILE_PREFIX: &str = "profile:";
+/// What the names of gamepad sections start with.
+const GAMEPAD_PREFIX: &str = "gamepad:";
+
+/// The accuracy presets, by name, with the settings each one stands for. The PPU pipeline, DMA
+/// and open bus have one model each, so they aren't part of the presets.
+const ACCURACY_PRESETS: [(&str, &[(&str, &str)]); 3] = [
+    (
+        "fast",
+        &[("interrupt_latency", "immediate"), ("alignment", "0")],
+    ),
+    (
+        "balanced",
+        &[("interrupt_latency", "accurate"), ("alignment", "0")],
+    ),
+    (
+        "accurate",
+        &[("interrupt_latency", "accurate"), ("alignment", "random")],
+    ),
+];
+
+/// When NMIs and IRQs are delivered to the CPU.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum InterruptLatency {
+    /// Interrupts are taken as soon as they are signaled, between two instructions. Fast, and good
+    /// enough for most games.
+    #[default]
+    Immediate,
+    /// Interrupts are polled at the start of an instruction and taken after it completes, and the
+    /// interrupt sequence costs 7 cycles, as on hardware.
+    Accurate,
+}
+
+pub struct Config {
+    pub interrupt_latency: InterruptLatency,
+    /// The console region to emulate. `None` uses the region from the ROM header. Changing this
+    /// only takes effect on the next start.
+    pub region: Option<Region>,
+    /// The phase of the CPU clock against the PPU clock, from 0 to 3. `None` picks one at random
+    /// at each power on. Changing this only takes effect on the next start.
+    pub alignment: Option<u8>,
+    pub scale: Scale,
+    pub rotation: Rotation,
+    /// Whether the arrow keys turn with the picture.
+    pub rotate_input: bool,
+    pub bindings: Bindings,
+    /// Auto-fire patterns, indexed by button bit.
+    pub fire_patterns: [Option<FirePattern>; 8],
+    /// A `.pal` file replacing the built-in palette.
+    pub palette: Option<PathBuf>,
+    /// The audio volume in percent.
+    pub volume: u8,
+    /// How many times faster than real time to run while fast-forwarding.
+    pub fast_forward_speed: u32,
+    pub fast_forward_audio: FastForwardAudio,
+    /// Whether to adjust the speed slightly to match the display's refresh rate.
+    pub smooth_refresh: SmoothRefresh,
+    /// Whether to sleep between frames and show fewer of them. Only read at startup.
+    pub power_saving: PowerSaving,
+    /// A CSV file to log memory accesses to. Only read at startup.
+    pub trace: Option<PathBuf>,
+    pub trace_filter: TraceFilter,
+    /// A VGM file to log APU writes to, written on exit. Only read at startup.
+    pub vgm: Option<PathBuf>,
+    /// A latch movie to record, written on exit. Only read at startup.
+    pub record_latches: Option<PathBuf>,
+    /// A latch movie to play back. Only read at startup.
+    pub play_latches: Option<PathBuf>,
+    /// The address to serve metrics on, such as `127.0.0.1:9100`. Only read at startup.
+    pub metrics: Option<String>,
+    /// The Discord application ID to show the game being played under on Discord. Only read at
+    /// startup.
+    pub discord: Option<String>,
+    /// When met, a savestate and screenshot are captured. Re-armed when the configuration is
+    /// reloaded.
+    pub capture_on: Option<Condition>,
+    /// When met, the machine is paused. Until then it runs as fast as it can.
+    pub run_until: Option<Condition>,
+    /// How many old versions of the battery save to keep.
+    pub sram_backups: usize,
+    /// Memory for rewind snapshots, in megabytes. 0 disables rewinding.
+    pub rewind_memory: usize,
+    /// How many seconds of frames to keep for instant replay. 0 keeps none.
+    pub replay_seconds: f64,
+    /// Addresses frozen at a value, as cheats.
+    pub freeze: Vec<(u16, u8)>,
+    /// Whether an EPSM is plugged into the expansion port. Only read at startup.
+    pub epsm: bool,
+    /// Whether to run ROMs whose mapper isn't emulated on `Nrom::fallback` instead of refusing
+    /// them. Only read at startup.
+    pub nrom_fallback: bool,
+    /// Whether to track which instruction last wrote each byte of RAM.
+    pub write_provenance: bool,
+    /// How to show the two views of games for the Famicom 3D System.
+    pub glasses: StereoMode,
+    /// Whether to average each frame with the previous one, to smooth out flicker.
+    pub frame_blend: bool,
+    /// The names of the frame filters to run, in order.
+    pub filters: Vec<&'static str>,
+    /// What the `color_blind` filter corrects for.
+    pub color_blind: ColorBlindness,
+    /// The frame, counting from 1 at power on, to write the layers of to PNG images.
+    pub export_layers: Option<u64>,
+    /// Barcodes to swipe through the Datach barcode reader, in turn.
+    pub barcodes: Vec<Barcode>,
+    /// A file of more barcodes, one per line.
+    pub barcode_file: Option<PathBuf>,
+    /// Whether a Zapper is plugged into the second controller port.
+    pub zapper: bool,
+    pub zapper_calibration: ZapperCalibration,
+    /// The length in pixels of each arm of the crosshair drawn where the Zapper aims; 0 hides it.
+    pub crosshair_size: usize,
+    /// The RGB color of the crosshair.
+    pub crosshair_color: [u8; 3],
+    /// The controller profile in effect.
+    pub profile: Option<String>,
+    /// Joystick bindings, by the GUID of the joysticks they are for.
+    pub gamepads: Vec<(String, PadBindings)>,
+
+    path: PathBuf,
+    rom_name: String,
+    modified: Option<SystemTime>,
+    /// Options set on the command line, which take precedence over the file.
+    overrides: Vec<(String, String)>,
+    settings: Vec<(String, String)>,
+    /// The controller profiles in the file, by name, with their settings.
+    profiles: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            interrupt_latency: InterruptLatency::Immediate,
+            region: None,
+            alignment: Some(0),
+            scale: Scale::Scale1x,
+            rotation: Rotation::Rotate0,
+            rotate_input: true,
+            bindings: Bindings::new(),
+            fire_patterns: [None; 8],
+            palette: None,
+            volume: 100,
+            fast_forward_speed: 4,
+            fast_forward_audio: FastForwardAudio::Mute,
+            smooth_refresh: SmoothRefresh::Off,
+            power_saving: PowerSaving::Off,
+            trace: None,
+            trace_filter: TraceFilter::All,
+            vgm: None,
+            record_latches: None,
+            play_latches: None,
+            metrics: None,
+            discord: None,
+            capture_on: None,
+            run_until: None,
+            sram_backups: 5,
+            rewind_memory: 16,
+            replay_seconds: 5.0,
+            freeze: Vec::new(),
+            epsm: false,
+            nrom_fallback: false,
+            write_provenance: false,
+            glasses: StereoMode::Off,
+            frame_blend: false,
+            filters: vec!["glasses", "blend"],
+            color_blind: ColorBlindness::Deuteranopia,
+            export_layers: None,
+            barcodes: Vec::new(),
+            barcode_file: None,
+            zapper: false,
+            zapper_calibration: ZapperCalibration::new(),
+            crosshair_size: 3,
+            crosshair_color: [0xff, 0x00, 0x00],
+            profile: None,
+            gamepads: Vec::new(),
+
+            path: PathBuf::new(),
+            rom_name: String::new(),
+            modified: None,
+            overrides: Vec::new(),
+            settings: Vec::new(),
+            profiles: Vec::new(),
+        }
+    }
+
+    /// Loads the configuration at `path`, applying the global settings followed by the overrides
+    /// in the section named `rom_name`. A missing file yields the default configuration.
+    pub fn load(path: &Path, rom_name: &str) -> Config {
+        let mut config = Config::new();
+        config.path = path.to_path_buf();
+        config.rom_name = rom_name.to_string();
+        for problem in config.read_file() {
+            println!("{}", problem);
+        }
+        config
+    }
+
+    /// Checks every section of the configuration file at `path`, not only one game's, and returns
+    /// the problems found, as `<path>:<line>: <problem>`.
+    pub fn check(path: &Path) -> io::Result<Vec<String>> {
+        let text = fs::read_to_string(path)?;
+        let mut sections = vec![String::new()];
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if is_section_header(line) {
+                let name = line[1..line.len() - 1].trim();
+                let known = sections.iter().any(|section| section == name);
+                let special = name.starts_with(PROFILE_PREFIX) || name.starts_with(GAMEPAD_PREFIX);
+                if !special && !known {
+                    sections.push(name.to_string());
+                }
+            }
+        }
+
+        // The global settings are read along with every section, so only report problems once.
+        let mut problems = Vec::new();
+        for name in sections {
+            let mut config = Config::new();
+            config.path = path.to_path_buf();
+            config.rom_name = name;
+            for problem in config.read_file() {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Sets an option that takes precedence over the configuration file, including across reloads.
+    pub fn set_override(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.set(key, value)?;
+        self.overrides.retain(|(existing, _)| existing != key);
+        self.overrides.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Sets an option and writes it to the section for the loaded ROM in the configuration file, so
+    /// that it's remembered for that game.
+    pub fn remember(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.set(key, value)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let section = self.rom_name.clone();
+        self.write_settings(&section, &[(key.to_string(), value.to_string())])
+    }
+
+    /// Sets the bindings of the joysticks with GUID `guid` and writes them to their section of the
+    /// configuration file, so that they're remembered for every game.
+    pub fn remember_gamepad(&mut self, guid: &str, bindings: &PadBindings) -> io::Result<()> {
+        self.gamepads
+            .retain(|(known, _)| !known.eq_ignore_ascii_case(guid));
+        self.gamepads.push((guid.to_string(), bindings.clone()));
+        let section = format!("{}{}", GAMEPAD_PREFIX, guid);
+        self.write_settings(&section, &bindings.settings())
+    }
+
+    /// Writes `settings` to the section named `section` of the configuration file, replacing the
+    /// lines there for the same keys.
+    fn write_settings(&mut self, section: &str, settings: &[(String, String)]) -> io::Result<()> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let mut lines: Vec<String> = text.lines().map(|line| line.to_string()).collect();
+
+        let header = format!("[{}]", section);
+        if !lines.iter().any(|line| line.trim() == header) {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(header.clone());
+        }
+        for (key, value) in settings {
+            let start = lines.iter().position(|line| line.trim() == header).unwrap();
+            let end = lines[start + 1..]
+                .iter()
+                .position(|line| is_section_header(line))
+                .map_or(lines.len(), |index| start + 1 + index);
+            let setting = format!("{} = {}", key, value);
+            let existing = (start + 1..end).find(|&index| line_key(&lines[index]) == key);
+            match existing {
+                Some(index) => lines[index] = setting,
+                None => {
+                    // After the last setting, ahead of the blank lines before the next section.
+                    let last = (start..end)
+                        .rev()
+                        .find(|&index| !lines[index].trim().is_empty())
+                        .unwrap_or(start);
+                    lines.insert(last + 1, setting);
+                }
+            }
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        fs::write(&self.path, text)?;
+        // The file now says what the configuration already is, so there's nothing to reload.
+        self.modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        Ok(())
+    }
+
+    /// Re-reads the configuration file from scratch.
+    pub fn reload(&mut self) {
+        let (path, rom_name) = (self.path.clone(), self.rom_name.clone());
+        let overrides = mem::take(&mut self.overrides);
+        *self = Config::load(&path, &rom_name);
+        for (key, value) in overrides {
+            let _ = self.set_override(&key, &value);
+        }
+    }
+
+    /// Re-reads the configuration file if it was modified since it was last read. Returns true if
+    /// the configuration changed.
+    pub fn reload_if_changed(&mut self) -> bool {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == self.modified {
+            return false;
+        }
+        self.reload();
+        true
+    }
+
+    /// Applies the global settings and the section for `rom_name`, and returns the problems found.
+    fn read_file(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let path = self.path.clone();
+        let rom_name = self.rom_name.clone();
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return problems,
+        };
+
+        let lines: Vec<String> = BufReader::new(file).lines().map_while(Result::ok).collect();
+
+        // Profiles are gathered first, so that a game can select one defined further down. Gamepad
+        // sections are gathered along with them.
+        let (mut in_profile, mut in_gamepad) = (false, false);
+        for (index, line) in lines.iter().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if is_section_header(line) {
+                let name = line[1..line.len() - 1].trim();
+                in_profile = name.starts_with(PROFILE_PREFIX);
+                in_gamepad = name.starts_with(GAMEPAD_PREFIX);
+                if in_profile {
+                    let name = name[PROFILE_PREFIX.len()..].trim().to_string();
+                    self.profiles.push((name, Vec::new()));
+                } else if in_gamepad {
+                    let guid = name[GAMEPAD_PREFIX.len()..].trim().to_string();
+                    self.gamepads.push((guid, PadBindings::new()));
+                }
+                continue;
+            }
+            if !(in_profile || in_gamepad) || line.is_empty() {
+                continue;
+            }
+
+            let result = match line.find('=') {
+                Some(pos) if in_gamepad => {
+                    let key = line[..pos].trim();
+                    let bindings = &mut self.gamepads.last_mut().unwrap().1;
+                    if let Some(button) = key.strip_prefix("button_") {
+                        bindings.set(button, line[pos + 1..].trim())
+                    } else {
+                        Err(format!(
+                            "gamepad sections only hold `button_` settings, not `{}`",
+                            key
+                        ))
+                    }
+                }
+                Some(pos) => {
+                    let key = line[..pos].trim();
+                    if key.starts_with("key_") || key.starts_with("turbo_") {
+                        let setting = (key.to_string(), line[pos + 1..].trim().to_string());
+                        self.profiles.last_mut().unwrap().1.push(setting);
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "profiles only hold `key_` and `turbo_` settings, not `{}`",
+                            key
+                        ))
+                    }
+                }
+                None => Err("expected `key = value`".to_string()),
+            };
+            if let Err(err) = result {
+                problems.push(format!("{}:{}: {}", path.display(), index + 1, err));
+            }
+        }
+
+        let mut section_applies = true;
+        for (index, line) in lines.iter().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if is_section_header(line) {
+                section_applies = line[1..line.len() - 1].trim() == rom_name;
+                continue;
+            }
+            if !section_applies {
+                continue;
+            }
+
+            let result = match line.find('=') {
+                Some(pos) => self.set(line[..pos].trim(), line[pos + 1..].trim()),
+                None => Err("expected `key = value`".to_string()),
+            };
+            if let Err(err) = result {
+                problems.push(format!("{}:{}: {}", path.display(), index + 1, err));
+            }
+        }
+        problems
+    }
+
+    /// Sets a single option from its textual representation.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.apply(key, value)?;
+        self.settings.retain(|(existing, _)| existing != key);
+        self.settings.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Every option set, from the file or otherwise, as `(key, value)` pairs in the order they
+    /// were last set.
+    pub fn settings(&self) -> &[(String, String)] {
+        &self.settings
+    }
+
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "interrupt_latency" => {
+                self.interrupt_latency = match value {
+                    "immediate" => InterruptLatency::Immediate,
+                    "accurate" => InterruptLatency::Accurate,
+                    _ => return Err(format!("unknown interrupt latency `{}`", value)),
+                }
+            }
+            "accuracy" => {
+                let settings = match ACCURACY_PRESETS.iter().find(|preset| preset.0 == value) {
+                    Some(preset) => preset.1,
+                    None => return Err(format!("unknown accuracy preset `{}`", value)),
+                };
+                for &(key, setting) in settings {
+                    self.set(key, setting)?;
+                }
+            }
+            "region" => {
+                self.region = match value {
+                    "auto" => None,
+                    _ => match Region::from_name(value) {
+                        Some(region) => Some(region),
+                        None => return Err(format!("unknown region `{}`", value)),
+                    },
+                }
+            }
+            "alignment" => {
+                self.alignment = match value {
+                    "random" => None,
+                    _ => match value.parse() {
+                        Ok(alignment) if alignment < ALIGNMENTS => Some(alignment),
+                        _ => {
+                            return Err(format!(
+                                "`alignment` is `random` or 0 to {}, not `{}`",
+                                ALIGNMENTS - 1,
+                                value
+                            ))
+                        }
+                    },
+                }
+            }
+            "scale" => {
+                self.scale = match value {
+                    "1" => Scale::Scale1x,
+                    "2" => Scale::Scale2x,
+                    "3" => Scale::Scale3x,
+                    "4" => Scale::Scale4x,
+                    _ => return Err(format!("unsupported scale `{}`", value)),
+                }
+            }
+            "rotation" => {
+                self.rotation = match Rotation::from_degrees(value) {
+                    Some(rotation) => rotation,
+                    None => return Err(format!("rotation `{}` is not 0, 90 or 270", value)),
+                }
+            }
+            "rotate_input" => {
+                self.rotate_input = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("`rotate_input` is `on` or `off`, not `{}`", value)),
+                }
+            }
+            "palette" => {
+                self.palette = if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "volume" => {
+                self.volume = match value.parse() {
+                    Ok(volume) if volume <= 100 => volume,
+                    _ => return Err(format!("volume `{}` is not a percentage", value)),
+                }
+            }
+            "fast_forward_speed" => {
+                self.fast_forward_speed = match value.parse() {
+                    Ok(speed) if (2..=16).contains(&speed) => speed,
+                    _ => return Err(format!("fast-forward speed `{}` is not 2 to 16", value)),
+                }
+            }
+            "fast_forward_audio" => {
+                self.fast_forward_audio = match FastForwardAudio::from_name(value) {
+                    Some(audio) => audio,
+                    None => return Err(format!("unknown fast-forward audio `{}`", value)),
+                }
+            }
+            "smooth_refresh" => {
+                self.smooth_refresh = match SmoothRefresh::from_name(value) {
+                    Some(smooth) => smooth,
+                    None => {
+                        return Err(format!(
+                            "`smooth_refresh` is `off`, `auto` or a rate in Hz, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            "power_saving" => {
+                self.power_saving = match PowerSaving::from_name(value) {
+                    Some(power_saving) => power_saving,
+                    None => {
+                        return Err(format!(
+                            "`power_saving` is `off`, `on` or `half`, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            "trace" => {
+                self.trace = if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "vgm" => {
+                self.vgm = if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "record_latches" => {
+                self.record_latches = if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "play_latches" => {
+                self.play_latches = if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "metrics" => {
+                self.metrics = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_string())
+                }
+            }
+            "discord" => {
+                self.discord = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_string())
+                }
+            }
+            "epsm" => {
+                self.epsm = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("`epsm` is `on` or `off`, not `{}`", value)),
+                }
+            }
+            "nrom_fallback" => {
+                self.nrom_fallback = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("`nrom_fallback` is `on` or `off`, not `{}`", value)),
+                }
+            }
+            "write_provenance" => {
+                self.write_provenance = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        return Err(format!(
+                            "`write_provenance` is `on` or `off`, not `{}`",
+                            value
+                        ))
+                    }
+                }
+            }
+            "glasses" => {
+                self.glasses = match StereoMode::from_name(value) {
+                    Some(mode) => mode,
+                    None => return Err(format!("unknown 3D glasses mode `{}`", value)),
+                }
+            }
+            "frame_blend" => {
+                self.frame_blend = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("`frame_blend` is `on` or `off`, not `{}`", value)),
+                }
+            }
+            "filters" => self.filters = filter::parse_chain(value)?,
+            "color_blind" => {
+                self.color_blind = match ColorBlindness::from_name(value) {
+                    Some(kind) => kind,
+                    None => return Err(format!("unknown color blindness `{}`", value)),
+                }
+            }
+            "export_layers" => {
+                self.export_layers = match value.parse() {
+                    _ if value.is_empty() => None,
+                    Ok(frame) if frame > 0 => Some(frame),
+                    _ => return Err(format!("`{}` is not a frame number", value)),
+                }
+            }
+            "barcodes" => self.barcodes = Barcode::parse_list(value)?,
+            "barcode_file" => {
+                self.barcode_file = if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "zapper" => {
+                self.zapper = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("`zapper` is `on` or `off`, not `{}`", value)),
+                }
+            }
+            "zapper_threshold" => {
+                self.zapper_calibration.threshold = match value.parse() {
+                    Ok(threshold) => threshold,
+                    Err(_) => return Err(format!("Zapper threshold `{}` is not 0 to 255", value)),
+                }
+            }
+            "zapper_frames" => {
+                self.zapper_calibration.frames = match value.parse() {
+                    Ok(frames) if (1..=8).contains(&frames) => frames,
+                    _ => return Err(format!("Zapper frames `{}` is not 1 to 8", value)),
+                }
+            }
+            "zapper_assist" => {
+                self.zapper_calibration.assist_radius = match value.parse() {
+                    Ok(radius) if radius <= 32 => radius,
+                    _ => return Err(format!("Zapper assist radius `{}` is not 0 to 32", value)),
+                }
+            }
+            "crosshair_size" => {
+                self.crosshair_size = match value.parse() {
+                    Ok(size) if size <= 16 => size,
+                    _ => return Err(format!("Crosshair size `{}` is not 0 to 16", value)),
+                }
+            }
+            "crosshair_color" => {
+                self.crosshair_color = match parse_color(value) {
+                    Some(color) => color,
+                    None => return Err(format!("`{}` is not a hex color such as `ff0000`", value)),
+                }
+            }
+            "trace_filter" => {
+                self.trace_filter = match TraceFilter::from_name(value) {
+                    Some(filter) => filter,
+                    None => return Err(format!("unknown trace filter `{}`", value)),
+                }
+            }
+            "sram_backups" => {
+                self.sram_backups = match value.parse() {
+                    Ok(count) => count,
+                    Err(_) => return Err(format!("`{}` is not a number of backups", value)),
+                }
+            }
+            "rewind_memory" => {
+                self.rewind_memory = match value.parse() {
+                    Ok(megabytes) => megabytes,
+                    Err(_) => return Err(format!("`{}` is not a size in megabytes", value)),
+                }
+            }
+            "replay_seconds" => {
+                self.replay_seconds = match value.parse() {
+                    Ok(seconds) if (0.0..=60.0).contains(&seconds) => seconds,
+                    _ => return Err(format!("Replay length `{}` is not 0 to 60 seconds", value)),
+                }
+            }
+            "capture_on" => {
+                self.capture_on = if value.is_empty() {
+                    None
+                } else {
+                    Some(Condition::parse(value)?)
+                }
+            }
+            "run_until" => {
+                self.run_until = if value.is_empty() {
+                    None
+                } else {
+                    Some(Condition::parse(value)?)
+                }
+            }
+            "freeze" => self.freeze = Cheats::parse(value)?,
+            "profile" => {
+                self.profile = None;
+                if !value.is_empty() {
+                    let settings = match self.profiles.iter().find(|profile| profile.0 == value) {
+                        Some(profile) => profile.1.clone(),
+                        None => return Err(format!("unknown profile `{}`", value)),
+                    };
+                    for (key, setting) in settings {
+                        self.set(&key, &setting)
+                            .map_err(|err| format!("profile `{}`: {}", value, err))?;
+                    }
+                    self.profile = Some(value.to_string());
+                }
+            }
+            _ if key.starts_with("turbo_") => {
+                let bit = match input::button_bit(&key[6..]) {
+                    Some(bit) => bit,
+                    None => return Err(format!("unknown button `{}`", &key[6..])),
+                };
+                self.fire_patterns[bit as usize] = if value.is_empty() {
+                    None
+                } else {
+                    Some(FirePattern::parse(value)?)
+                };
+            }
+            _ if key.starts_with("key_") => {
+                let keycode = match Keycode::from_name(value) {
+                    Some(keycode) => keycode,
+                    None => return Err(format!("unknown key `{}`", value)),
+                };
+                let button = match self.bindings.button_mut(&key[4..]) {
+                    Some(button) => button,
+                    None => return Err(format!("unknown button `{}`", &key[4..])),
+                };
+                *button = keycode;
+            }
+            _ => return Err(format!("unknown option `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Parses an RGB color in hex, such as `ff0000`. There's no `#`, which would start a comment.
+fn parse_color(value: &str) -> Option<[u8; 3]> {
+    if value.len() != 6 || !value.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?])
+}
+
+fn is_section_header(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with('[') && line.ends_with(']')
+}
+
+/// The key of a `key = value` line, or `""` for other lines.
+fn line_key(line: &str) -> &str {
+    let line = line.split('#').next().unwrap();
+    match line.find('=') {
+        Some(pos) => line[..pos].trim(),
+        None => "",
+    }
+}
@@ -0,0 +1,100 @@
+//! Opcode histograms: how often each opcode ran, for seeing which instructions a ROM uses, and
+//! which of the opcodes the CPU implements a run never reached, for picking test ROMs that cover
+//! the rest.
+//!
+//! Opcodes are only counted with the `stats` feature.
+
+use disasm::{self, Disassembler};
+use mem::Mem;
+
+use std::io::{self, Write};
+
+/// An opcode followed by zero operands, for naming it with the disassembler.
+struct Opcode([u8; 3]);
+
+impl Mem for Opcode {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        self.0.get(addr as usize).cloned().unwrap_or(0)
+    }
+    fn storeb(&mut self, _: u16, _: u8) {}
+}
+
+/// The instruction an opcode decodes to with zero operands, e.g. `LDA $0000,X`, or
+/// `disasm::ILLEGAL`.
+fn instruction(op: u8) -> String {
+    let mut bytes = Opcode([op, 0, 0]);
+    Disassembler {
+        pc: 0,
+        mem: &mut bytes,
+    }
+    .disassemble()
+}
+
+/// Opcode counts summed over one or more runs.
+pub struct OpcodeHistogram {
+    counts: [u64; 256],
+}
+
+impl Default for OpcodeHistogram {
+    fn default() -> OpcodeHistogram {
+        OpcodeHistogram::new()
+    }
+}
+
+impl OpcodeHistogram {
+    pub fn new() -> OpcodeHistogram {
+        OpcodeHistogram { counts: [0; 256] }
+    }
+
+    /// Adds the counts of a run, as `Cpu::opcode_counts` holds them.
+    pub fn add(&mut self, counts: &[u64]) {
+        for (total, &count) in self.counts.iter_mut().zip(counts) {
+            *total += count;
+        }
+    }
+
+    /// Writes the report: the opcodes run, most first, with their counts and share of all
+    /// instructions, then the implemented opcodes that never ran.
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if !cfg!(feature = "stats") {
+            return Err(io::Error::other(
+                "opcodes are only counted with the `stats` feature",
+            ));
+        }
+        let instructions: Vec<String> = (0..256).map(|op| instruction(op as u8)).collect();
+        let implemented = instructions
+            .iter()
+            .filter(|instruction| *instruction != disasm::ILLEGAL)
+            .count();
+        let mut ran: Vec<usize> = (0..256).filter(|&op| self.counts[op] > 0).collect();
+        ran.sort_by(|&a, &b| self.counts[b].cmp(&self.counts[a]).then(a.cmp(&b)));
+        let total: u64 = self.counts.iter().sum();
+
+        writeln!(
+            writer,
+            "; {} instructions, {} of {} implemented opcodes run ({:.1}%)",
+            total,
+            ran.len(),
+            implemented,
+            100.0 * ran.len() as f64 / implemented as f64
+        )?;
+        for &op in &ran {
+            writeln!(
+                writer,
+                "{:02X} {:<12} {:>12} {:>6.2}%",
+                op,
+                instructions[op],
+                self.counts[op],
+                100.0 * self.counts[op] as f64 / total as f64
+            )?;
+        }
+
+        writeln!(writer, "; Implemented opcodes never run")?;
+        for (op, instruction) in instructions.iter().enumerate() {
+            if self.counts[op] == 0 && instruction != disasm::ILLEGAL {
+                writeln!(writer, "{:02X} {}", op, instruction)?;
+            }
+        }
+        Ok(())
+    }
+}
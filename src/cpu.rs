@@ -2,10 +2,11 @@
 // Author: Patrick Walton
 //
 
+use config::InterruptLatency;
 use mem::Mem;
 use util::Save;
 
-use std::fs::File;
+use std::io::{self, Read, Write};
 use std::ops::Deref;
 
 #[cfg(cpuspew)]
@@ -41,7 +42,16 @@ static CYCLE_TABLE: [u8; 256] = [
     /*0xF0*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
 ];
 
+/// Whether `op` is one of the KIL (also called JAM) opcodes, which halt the CPU.
+pub fn is_kil(op: u8) -> bool {
+    matches!(
+        op,
+        0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2
+    )
+}
+
 /// CPU Registers
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Regs {
     a: u8,
     x: u8,
@@ -142,6 +152,14 @@ impl<M: Mem> AddressingMode<M> for MemoryAddressingMode {
 /// This is implemented as a macro so that both the disassembler and the emulator can use it.
 macro_rules! decode_op {
     ($op:expr, $this:ident) => {
+        decode_op!(
+            $op,
+            $this,
+            panic!("unimplemented or illegal instruction: {}", $op)
+        )
+    };
+    // Evaluates `$illegal` for opcodes that aren't implemented.
+    ($op:expr, $this:ident, $illegal:expr) => {
         // We try to keep this in the same order as the implementations above.
         // TODO: Use arm macros to fix some of this duplication.
         match $op {
@@ -684,7 +702,7 @@ macro_rules! decode_op {
             // No operation
             0xea => $this.nop(),
 
-            _ => panic!("unimplemented or illegal instruction: {}", $op),
+            _ => $illegal,
         }
     };
 }
@@ -695,45 +713,99 @@ macro_rules! decode_op {
 
 pub type Cycles = u64;
 
+/// The interrupt inputs of the CPU, as raised by the devices of a machine.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct InterruptLines {
+    pub nmi: bool,
+    pub irq: bool,
+}
+
+impl InterruptLines {
+    pub fn merge(self, other: InterruptLines) -> InterruptLines {
+        InterruptLines {
+            nmi: self.nmi || other.nmi,
+            irq: self.irq || other.irq,
+        }
+    }
+}
+
+/// Something that raises interrupts, such as a timer. A bus that implements this lets the CPU
+/// poll it after every instruction with `Cpu::step_polled`.
+///
+/// The NES doesn't go through this: its main loop steps the PPU and mapper itself and calls
+/// `Cpu::nmi` and `Cpu::irq`, since it also needs to know when a frame ends.
+pub trait InterruptSource {
+    /// Catches up to CPU cycle `cycle` and returns the lines raised. NMI is edge-triggered, so
+    /// return it once per interrupt; IRQ is level-triggered, so return it for as long as the line
+    /// is held.
+    fn poll(&mut self, cycle: Cycles) -> InterruptLines;
+}
+
 /// The main CPU structure definition.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Cpu<M: Mem> {
     pub cy: Cycles,
     regs: Regs,
     pub mem: M,
+    // Per-frame statistics, only collected with the `stats` feature.
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub conditional_jump: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub branches_taken: Vec<(u16, u16)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub branches_not_taken: Vec<(u16, u16)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub loads: Vec<(char, u16)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub stores: Vec<(char, u16)>,
+    /// How many times each opcode has run since power on, also only with the `stats` feature.
+    #[cfg_attr(feature = "serde", serde(skip, default = "new_opcode_counts"))]
+    pub opcode_counts: Box<[u64]>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub interrupt_latency: InterruptLatency,
+    /// Interrupts signaled but not yet taken. Only used with `InterruptLatency::Accurate`.
+    nmi_pending: bool,
+    irq_pending: bool,
+    /// How many interrupt handlers have been entered and not yet returned from.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    interrupt_depth: u32,
+    /// Set by the KIL opcodes, which lock up the CPU until reset.
+    jammed: bool,
 }
 
-/// The CPU implements Mem so that it can handle writes to the DMA register.
+/// The CPU implements Mem so that the addressing modes can access memory through it.
 impl<M: Mem> Mem for Cpu<M> {
     fn loadb(&mut self, addr: u16) -> u8 {
         self.mem.loadb(addr)
     }
 
     fn storeb(&mut self, addr: u16, val: u8) {
-        // Handle OAM_DMA.
-        if addr == 0x4014 {
-            self.dma(val)
-        } else {
-            self.mem.storeb(addr, val)
-        }
+        self.mem.storeb(addr, val)
     }
 }
 
 impl<M: Mem + Save> Save for Cpu<M> {
-    fn save(&mut self, fd: &mut File) {
-        self.cy.save(fd);
-        self.regs.save(fd);
-        self.mem.save(fd);
-    }
-
-    fn load(&mut self, fd: &mut File) {
-        self.cy.load(fd);
-        self.regs.load(fd);
-        self.mem.load(fd);
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.cy.save(fd)?;
+        self.regs.save(fd)?;
+        self.nmi_pending.save(fd)?;
+        self.irq_pending.save(fd)?;
+        let mut jammed = self.jammed as u8;
+        jammed.save(fd)?;
+        self.mem.save(fd)?;
+        Ok(())
+    }
+
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.cy.load(fd)?;
+        self.regs.load(fd)?;
+        self.nmi_pending.load(fd)?;
+        self.irq_pending.load(fd)?;
+        let mut jammed = 0u8;
+        jammed.load(fd)?;
+        self.jammed = jammed != 0;
+        self.mem.load(fd)?;
+        Ok(())
     }
 }
 
@@ -760,20 +832,6 @@ impl<M: Mem> Cpu<M> {
     #[cfg(not(cpuspew))]
     fn trace(&mut self) {}
 
-    // Performs DMA to the OAMDATA ($2004) register.
-    fn dma(&mut self, hi_addr: u8) {
-        let start = (hi_addr as u16) << 8;
-
-        for addr in start..start + 256 {
-            let val = self.loadb(addr);
-            self.storeb(0x2004, val);
-
-            // FIXME: The last address sometimes takes 1 cycle, sometimes 2 -- NESdev isn't very
-            // clear on this.
-            self.cy += 2;
-        }
-    }
-
     // Memory access helpers
     /// Loads the byte at the program counter and increments the program counter.
     fn loadb_bump_pc(&mut self) -> u8 {
@@ -896,7 +954,9 @@ impl<M: Mem> Cpu<M> {
     // Loads
     #[inline(always)]
     fn lda<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.loads.push(('a', am.addr()));
+        if cfg!(feature = "stats") {
+            self.loads.push(('a', am.addr()));
+        }
         self.lda_imm(am);
     }
     #[inline(always)]
@@ -906,7 +966,9 @@ impl<M: Mem> Cpu<M> {
     }
     #[inline(always)]
     fn ldx<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.loads.push(('x', am.addr()));
+        if cfg!(feature = "stats") {
+            self.loads.push(('x', am.addr()));
+        }
         self.ldx_imm(am);
     }
     #[inline(always)]
@@ -916,7 +978,9 @@ impl<M: Mem> Cpu<M> {
     }
     #[inline(always)]
     fn ldy<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.loads.push(('y', am.addr()));
+        if cfg!(feature = "stats") {
+            self.loads.push(('y', am.addr()));
+        }
         self.ldy_imm(am);
     }
     #[inline(always)]
@@ -928,19 +992,25 @@ impl<M: Mem> Cpu<M> {
     // Stores
     #[inline(always)]
     fn sta<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.stores.push(('a', am.addr()));
+        if cfg!(feature = "stats") {
+            self.stores.push(('a', am.addr()));
+        }
         let a = self.regs.a;
         am.store(self, a)
     }
     #[inline(always)]
     fn stx<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.stores.push(('x', am.addr()));
+        if cfg!(feature = "stats") {
+            self.stores.push(('x', am.addr()));
+        }
         let x = self.regs.x;
         am.store(self, x)
     }
     #[inline(always)]
     fn sty<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.stores.push(('y', am.addr()));
+        if cfg!(feature = "stats") {
+            self.stores.push(('y', am.addr()));
+        }
         let y = self.regs.y;
         am.store(self, y)
     }
@@ -1143,11 +1213,15 @@ impl<M: Mem> Cpu<M> {
     fn bra_base(&mut self, cond: bool) {
         let disp = self.loadb_bump_pc() as i8;
         let dest = (self.regs.pc as i32 + disp as i32) as u16;
+        if cfg!(feature = "stats") {
+            if cond {
+                self.branches_taken.push((self.regs.pc, dest));
+            } else {
+                self.branches_not_taken.push((self.regs.pc, dest));
+            }
+        }
         if cond {
-            self.branches_taken.push((self.regs.pc, dest));
             self.regs.pc = dest;
-        } else {
-            self.branches_not_taken.push((self.regs.pc, dest));
         }
     }
     fn bpl(&mut self) {
@@ -1215,10 +1289,15 @@ impl<M: Mem> Cpu<M> {
         self.set_flag(IRQ_FLAG, true);
         self.regs.pc = self.loadw(BRK_VECTOR);
     }
+    fn kil(&mut self) {
+        self.regs.pc = self.regs.pc.wrapping_sub(1);
+        self.jammed = true;
+    }
     fn rti(&mut self) {
         let flags = self.popb();
         self.set_flags(flags);
         self.regs.pc = self.popw(); // NB: no + 1
+        self.interrupt_depth = self.interrupt_depth.saturating_sub(1);
     }
 
     // Stack operations
@@ -1244,46 +1323,114 @@ impl<M: Mem> Cpu<M> {
 
     // The main fetch-and-decode routine
     pub fn step(&mut self) {
+        if self.jammed {
+            // The clock keeps running for the rest of the machine.
+            self.cy += 2;
+            return;
+        }
         self.trace();
 
+        // Interrupts are polled before the instruction executes, but only taken once it's done. An
+        // IRQ masked at this point (e.g. right before a CLI) is dropped, like in immediate mode.
+        let take_nmi = self.nmi_pending;
+        let take_irq = self.irq_pending && !self.get_flag(IRQ_FLAG);
+        self.nmi_pending = false;
+        self.irq_pending = false;
+
         let op = self.loadb_bump_pc();
-        match op {
-            0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xb0 | 0xd0 | 0xf0 => {
-                self.conditional_jump = true;
-            }
+        if cfg!(feature = "stats") {
+            self.opcode_counts[op as usize] += 1;
+            match op {
+                0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xb0 | 0xd0 | 0xf0 => {
+                    self.conditional_jump = true;
+                }
 
-            _ => {
-                self.conditional_jump = false
+                _ => self.conditional_jump = false,
             }
         }
-        decode_op!(op, self);
+        decode_op!(
+            op,
+            self,
+            if is_kil(op) {
+                self.kil()
+            } else {
+                panic!("unimplemented or illegal instruction: {}", op)
+            }
+        );
 
         self.cy += CYCLE_TABLE[op as usize] as Cycles;
+        self.cy += self.mem.run_dma(self.cy);
+
+        if take_nmi {
+            self.interrupt(NMI_VECTOR);
+            self.cy += 7;
+        } else if take_irq {
+            self.interrupt(BRK_VECTOR);
+            self.cy += 7;
+        }
+    }
+
+    fn interrupt(&mut self, vector: u16) {
+        let (pc, flags) = (self.regs.pc, self.regs.flags);
+        self.pushw(pc);
+        self.pushb(flags);
+        self.regs.pc = self.loadw(vector);
+        self.interrupt_depth += 1;
     }
 
     /// External interfaces
+    pub fn pc(&self) -> u16 {
+        self.regs.pc
+    }
+
+    /// Whether the CPU is running an NMI or IRQ handler rather than the main program.
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth > 0
+    }
+
+    /// Whether a KIL opcode has halted the CPU. Only a reset starts it again.
+    pub fn jammed(&self) -> bool {
+        self.jammed
+    }
+
+    /// Whether the interrupt disable flag is set, masking IRQs.
+    pub fn irqs_masked(&self) -> bool {
+        self.get_flag(IRQ_FLAG)
+    }
+
     pub fn reset(&mut self) {
+        let cy = self.cy;
+        self.mem.on_reset(cy);
         self.regs.pc = self.loadw(RESET_VECTOR);
+        self.interrupt_depth = 0;
+        self.jammed = false;
     }
 
     pub fn nmi(&mut self) {
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(NMI_VECTOR);
+        if self.jammed {
+            return;
+        }
+        match self.interrupt_latency {
+            InterruptLatency::Immediate => self.interrupt(NMI_VECTOR),
+            InterruptLatency::Accurate => self.nmi_pending = true,
+        }
     }
 
     pub fn irq(&mut self) {
-        if self.get_flag(IRQ_FLAG) {
+        if self.jammed {
             return;
         }
-
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(BRK_VECTOR);
+        match self.interrupt_latency {
+            InterruptLatency::Immediate => {
+                if !self.get_flag(IRQ_FLAG) {
+                    self.interrupt(BRK_VECTOR)
+                }
+            }
+            InterruptLatency::Accurate => self.irq_pending = true,
+        }
     }
 
+    /// Creates a CPU on the given bus. Call `reset` to start it at the reset vector.
     pub fn new(mem: M) -> Cpu<M> {
         Cpu {
             cy: 0,
@@ -1294,6 +1441,81 @@ impl<M: Mem> Cpu<M> {
             branches_not_taken: Vec::default(),
             loads: Vec::default(),
             stores: Vec::default(),
+            opcode_counts: new_opcode_counts(),
+            interrupt_latency: InterruptLatency::Immediate,
+            nmi_pending: false,
+            irq_pending: false,
+            interrupt_depth: 0,
+            jammed: false,
+        }
+    }
+}
+
+fn new_opcode_counts() -> Box<[u64]> {
+    vec![0; 256].into_boxed_slice()
+}
+
+impl<M: Mem + InterruptSource> Cpu<M> {
+    /// Runs one instruction, then polls the bus for interrupts and takes them, NMI first. Returns
+    /// the lines the bus raised.
+    ///
+    /// ```
+    /// use nes::cpu::{Cpu, Cycles, InterruptLines, InterruptSource};
+    /// use nes::mem::{Bus, DeviceBus, Mem, Ram};
+    ///
+    /// /// A timer that raises IRQ every 100 cycles.
+    /// struct Timer(Cycles);
+    ///
+    /// impl InterruptSource for Timer {
+    ///     fn poll(&mut self, cycle: Cycles) -> InterruptLines {
+    ///         let irq = cycle >= self.0;
+    ///         if irq {
+    ///             self.0 += 100;
+    ///         }
+    ///         InterruptLines { nmi: false, irq }
+    ///     }
+    /// }
+    ///
+    /// /// A ROM filling $8000-$FFFF: CLI and an endless loop at $8000, and an IRQ handler at
+    /// /// $9000 that counts interrupts at $0010.
+    /// struct Rom;
+    ///
+    /// impl Mem for Rom {
+    ///     fn loadb(&mut self, addr: u16) -> u8 {
+    ///         let program = [0x58, 0x4c, 0x01, 0x80]; // CLI; JMP $8001
+    ///         let handler = [0xe6, 0x10, 0x40]; // INC $10; RTI
+    ///         match addr {
+    ///             0x8000..=0x8003 => program[addr as usize - 0x8000],
+    ///             0x9000..=0x9002 => handler[addr as usize - 0x9000],
+    ///             0xfffc | 0xfffe => 0x00,
+    ///             0xfffd => 0x80,
+    ///             0xffff => 0x90,
+    ///             _ => 0xea,
+    ///         }
+    ///     }
+    ///     fn storeb(&mut self, _: u16, _: u8) {}
+    /// }
+    ///
+    /// let mut bus = DeviceBus::new();
+    /// bus.attach(0x0000, 0x1fff, Box::new(Ram { val: [0; 0x800] }));
+    /// bus.attach(0x8000, 0xffff, Box::new(Rom));
+    /// bus.attach_interrupts(Box::new(Timer(100)));
+    ///
+    /// let mut cpu = Cpu::new(bus);
+    /// cpu.reset();
+    /// while cpu.cy < 1000 {
+    ///     cpu.step_polled();
+    /// }
+    /// assert_eq!(cpu.mem.loadb(0x0010), 9);
+    /// ```
+    pub fn step_polled(&mut self) -> InterruptLines {
+        self.step();
+        let lines = self.mem.poll(self.cy);
+        if lines.nmi {
+            self.nmi();
+        } else if lines.irq {
+            self.irq();
         }
+        lines
     }
 }
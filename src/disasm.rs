@@ -2,6 +2,7 @@
 // Author: Patrick Walton
 //
 
+use cpu;
 use mem::Mem;
 
 pub struct Disassembler<'a, M: Mem + 'a> {
@@ -32,6 +33,12 @@ impl<'a, M: Mem> Disassembler<'a, M> {
         format!("${:04X}", self.loadw_bump_pc() as usize)
     }
 
+    /// Reads a branch displacement and formats the address it branches to.
+    fn branch_target(&mut self) -> String {
+        let disp = self.loadb_bump_pc() as i8;
+        format!("${:04X}", self.pc.wrapping_add(disp as u16) as usize)
+    }
+
     //
     // Mnemonics
     //
@@ -180,45 +187,42 @@ impl<'a, M: Mem> Disassembler<'a, M> {
     }
 
     // Branches
-    // FIXME: Should disassemble the displacement!
     fn bpl(&mut self) -> String {
-        "BPL xx".to_string()
+        format!("BPL {}", self.branch_target())
     }
     fn bmi(&mut self) -> String {
-        "BMI xx".to_string()
+        format!("BMI {}", self.branch_target())
     }
     fn bvc(&mut self) -> String {
-        "BVC xx".to_string()
+        format!("BVC {}", self.branch_target())
     }
     fn bvs(&mut self) -> String {
-        "BVS xx".to_string()
+        format!("BVS {}", self.branch_target())
     }
     fn bcc(&mut self) -> String {
-        "BCC xx".to_string()
+        format!("BCC {}", self.branch_target())
     }
     fn bcs(&mut self) -> String {
-        "BCS xx".to_string()
+        format!("BCS {}", self.branch_target())
     }
     fn bne(&mut self) -> String {
-        "BNE xx".to_string()
+        format!("BNE {}", self.branch_target())
     }
     fn beq(&mut self) -> String {
-        "BEQ xx".to_string()
+        format!("BEQ {}", self.branch_target())
     }
 
     // Jumps
-    // FIXME: Should disassemble the address!
     fn jmp(&mut self) -> String {
-        "JMP xx".to_string()
+        format!("JMP {}", self.disw_bump_pc())
     }
     fn jmpi(&mut self) -> String {
-        "JMP (xx)".to_string()
+        format!("JMP ({})", self.disw_bump_pc())
     }
 
     // Procedure calls
-    // FIXME: Should disassemble the address!
     fn jsr(&mut self) -> String {
-        "JSR xx".to_string()
+        format!("JSR {}", self.disw_bump_pc())
     }
     fn rts(&mut self) -> String {
         "RTS".to_string()
@@ -293,6 +297,90 @@ impl<'a, M: Mem> Disassembler<'a, M> {
     #[inline(never)]
     pub fn disassemble(&mut self) -> String {
         let op = self.loadb_bump_pc();
-        decode_op!(op, self)
+        decode_op!(
+            op,
+            self,
+            if cpu::is_kil(op) {
+                "KIL".to_string()
+            } else {
+                ILLEGAL.to_string()
+            }
+        )
+    }
+
+    /// Disassembles the instruction at `pc` into its parts.
+    pub fn instruction(&mut self) -> Instruction {
+        let addr = self.pc;
+        let text = self.disassemble();
+        let length = self.pc.wrapping_sub(addr);
+        let bytes = (0..length)
+            .map(|i| self.mem.loadb(addr.wrapping_add(i)))
+            .collect();
+        let mut parts = text.splitn(2, ' ');
+        Instruction {
+            addr,
+            bytes,
+            mnemonic: parts.next().unwrap_or("").to_string(),
+            operand: parts.next().unwrap_or("").to_string(),
+        }
+    }
+}
+
+/// The mnemonic of opcodes the CPU doesn't implement.
+pub const ILLEGAL: &str = "???";
+
+/// A disassembled instruction, for debugger frontends.
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// Upper case, or `ILLEGAL`.
+    pub mnemonic: String,
+    /// In assembler syntax, e.g. `$0300,X`. Empty for implied and accumulator addressing.
+    pub operand: String,
+}
+
+/// Disassembles up to `before` instructions leading up to `pc`, the instruction at `pc`, and
+/// `after` instructions following it, as the memory is currently mapped.
+///
+/// Code before `pc` can't be decoded unambiguously, so the earliest start address whose
+/// instructions run into `pc` without illegal opcodes is used, and fewer than `before`
+/// instructions are returned if there is none.
+pub fn window<M: Mem>(mem: &mut M, pc: u16, before: usize, after: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    for distance in (1..=(before * 3).min(0xffff) as u16).rev() {
+        let preceding = decode_run(mem, pc.wrapping_sub(distance), distance);
+        if let Some(preceding) = preceding {
+            let skip = preceding.len().saturating_sub(before);
+            instructions.extend(preceding.into_iter().skip(skip));
+            break;
+        }
+    }
+
+    let mut disassembler = Disassembler { pc, mem };
+    for _ in 0..after + 1 {
+        instructions.push(disassembler.instruction());
+    }
+    instructions
+}
+
+/// Decodes the instructions in the `length` bytes from `start`, or returns `None` if they don't
+/// end exactly at the end or contain an illegal opcode.
+fn decode_run<M: Mem>(mem: &mut M, start: u16, length: u16) -> Option<Vec<Instruction>> {
+    let end = start.wrapping_add(length);
+    let mut disassembler = Disassembler { pc: start, mem };
+    let mut instructions = Vec::new();
+    while disassembler.pc.wrapping_sub(start) < length {
+        let instruction = disassembler.instruction();
+        if instruction.mnemonic == ILLEGAL {
+            return None;
+        }
+        instructions.push(instruction);
+    }
+    if disassembler.pc == end {
+        Some(instructions)
+    } else {
+        None
     }
 }
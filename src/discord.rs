@@ -0,0 +1,129 @@
+//! Discord Rich Presence: shows the game being played, and for how long, on the player's Discord
+//! profile.
+//!
+//! The presence is published over Discord's local IPC socket, a named pipe on Windows and a Unix
+//! socket elsewhere, by a background thread that reconnects whenever Discord restarts. It is only
+//! published when built with the `discord` feature.
+
+use util;
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to wait before looking for Discord again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// Discord listens on the first free one of `discord-ipc-0` to `discord-ipc-9`.
+const PIPE_COUNT: usize = 10;
+
+// Frame opcodes.
+const OP_HANDSHAKE: u32 = 0;
+const OP_FRAME: u32 = 1;
+const OP_CLOSE: u32 = 2;
+const OP_PING: u32 = 3;
+const OP_PONG: u32 = 4;
+
+trait Pipe: Read + Write {}
+
+impl<T: Read + Write> Pipe for T {}
+
+/// Shows `game` as being played from now on, under the Discord application `client_id`, from a
+/// background thread.
+pub fn publish(client_id: &str, game: &str) -> io::Result<()> {
+    if !cfg!(feature = "discord") {
+        return Err(io::Error::other(
+            "Discord Rich Presence requires the `discord` feature",
+        ));
+    }
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let handshake = format!("{{\"v\":1,\"client_id\":{}}}", util::json_string(client_id));
+    let activity = format!(
+        "{{\"cmd\":\"SET_ACTIVITY\",\"nonce\":\"1\",\"args\":{{\"pid\":{},\"activity\":\
+         {{\"details\":{},\"timestamps\":{{\"start\":{}}}}}}}}}",
+        process::id(),
+        util::json_string(game),
+        start
+    );
+    thread::spawn(move || loop {
+        if let Some(mut pipe) = connect() {
+            // Discord drops the presence when the connection closes, so hold it until then.
+            let _ = run(&mut *pipe, &handshake, &activity);
+        }
+        thread::sleep(RETRY_INTERVAL);
+    });
+    Ok(())
+}
+
+fn run(pipe: &mut dyn Pipe, handshake: &str, activity: &str) -> io::Result<()> {
+    write_frame(pipe, OP_HANDSHAKE, handshake.as_bytes())?;
+    // Discord answers the handshake with a READY frame before taking commands.
+    read_frame(pipe)?;
+    write_frame(pipe, OP_FRAME, activity.as_bytes())?;
+    loop {
+        let (opcode, payload) = read_frame(pipe)?;
+        match opcode {
+            OP_PING => write_frame(pipe, OP_PONG, &payload)?,
+            OP_CLOSE => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// A frame is a little-endian opcode and payload length, followed by the JSON payload.
+fn write_frame(pipe: &mut dyn Pipe, opcode: u32, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.extend_from_slice(&opcode.to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    pipe.write_all(&frame)?;
+    pipe.flush()
+}
+
+fn read_frame(pipe: &mut dyn Pipe) -> io::Result<(u32, Vec<u8>)> {
+    let mut header = [0; 8];
+    pipe.read_exact(&mut header)?;
+    let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut payload = vec![0; length as usize];
+    pipe.read_exact(&mut payload)?;
+    Ok((opcode, payload))
+}
+
+/// Connects to the running Discord client, or returns `None` if there isn't one.
+fn connect() -> Option<Box<dyn Pipe>> {
+    (0..PIPE_COUNT).filter_map(open_pipe).next()
+}
+
+#[cfg(unix)]
+fn open_pipe(index: usize) -> Option<Box<dyn Pipe>> {
+    use std::env;
+    use std::os::unix::net::UnixStream;
+
+    let directory = ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+        .iter()
+        .filter_map(env::var_os)
+        .next()
+        .map_or(PathBuf::from("/tmp"), PathBuf::from);
+    let path = directory.join(format!("discord-ipc-{}", index));
+    UnixStream::connect(path)
+        .ok()
+        .map(|stream| Box::new(stream) as Box<dyn Pipe>)
+}
+
+#[cfg(not(unix))]
+fn open_pipe(index: usize) -> Option<Box<dyn Pipe>> {
+    use std::fs::OpenOptions;
+
+    let path = PathBuf::from(format!(r"\\?\pipe\discord-ipc-{}", index));
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .ok()
+        .map(|file| Box::new(file) as Box<dyn Pipe>)
+}
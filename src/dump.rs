@@ -0,0 +1,114 @@
+//! Writing every frame and the audio of a headless run to disk, for external encoders and other
+//! tools.
+//!
+//! Frames are written to a directory as one file per frame, named by frame index from 0:
+//! `000000.rgb` holds raw 24-bit RGB, top row first, and `000000.png` a PNG image. Audio is
+//! written as raw signed 16-bit little-endian mono PCM at `OUTPUT_SAMPLE_RATE`, with exactly the
+//! samples that belong to each frame, so the two stay in sync.
+
+use emulator::{AudioChunk, Frame, FrameInfo, VideoSink};
+use png;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FrameFormat {
+    Rgb,
+    Png,
+}
+
+impl FrameFormat {
+    pub fn from_name(name: &str) -> Option<FrameFormat> {
+        match name {
+            "rgb" => Some(FrameFormat::Rgb),
+            "png" => Some(FrameFormat::Png),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            FrameFormat::Rgb => "rgb",
+            FrameFormat::Png => "png",
+        }
+    }
+}
+
+pub struct FrameDump {
+    dir: PathBuf,
+    format: FrameFormat,
+    /// Whether writing a frame failed, as a sink, which stops the dump.
+    failed: bool,
+}
+
+impl FrameDump {
+    /// Dumps frames into `dir`, creating it if needed.
+    pub fn new(dir: &Path, format: FrameFormat) -> io::Result<FrameDump> {
+        fs::create_dir_all(dir)?;
+        Ok(FrameDump {
+            dir: dir.to_path_buf(),
+            format,
+            failed: false,
+        })
+    }
+
+    pub fn write(&self, index: usize, frame: &Frame) -> io::Result<()> {
+        let path = self
+            .dir
+            .join(format!("{:06}.{}", index, self.format.extension()));
+        match self.format {
+            FrameFormat::Rgb => fs::write(path, &frame.pixels),
+            FrameFormat::Png => {
+                let rgba: Vec<u8> = frame
+                    .pixels
+                    .chunks(3)
+                    .flat_map(|pixel| vec![pixel[0], pixel[1], pixel[2], 0xff])
+                    .collect();
+                png::write_rgba(&path, Frame::WIDTH, Frame::HEIGHT, &rgba)
+            }
+        }
+    }
+}
+
+/// As a sink, frames are named by their number since power on, from 0.
+impl VideoSink for FrameDump {
+    fn frame(&mut self, info: &FrameInfo) {
+        if self.failed {
+            return;
+        }
+        let index = info.number as usize - 1;
+        if let Err(err) = self.write(index, &Frame::from_screen(info.screen)) {
+            println!("Error writing frame {}: {}", index, err);
+            self.failed = true;
+        }
+    }
+}
+
+pub struct AudioDump {
+    writer: BufWriter<File>,
+}
+
+impl AudioDump {
+    pub fn create(path: &Path) -> io::Result<AudioDump> {
+        Ok(AudioDump {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn write(&mut self, audio: &AudioChunk) -> io::Result<()> {
+        self.write_samples(&audio.samples)
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,478 @@
+//! A single NES without a window or audio device, for batch tools and embedding.
+//!
+//! To embed the emulator, load a ROM image with `Emulator::from_bytes`, then call `step_frame`
+//! once per video frame. After each frame, `frame` returns the picture and `take_audio` the
+//! samples that belong to it, and `set_buttons` sets the controller for the next frame:
+//!
+//! ```
+//! use nes::config::Config;
+//! use nes::emulator::Emulator;
+//!
+//! let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+//! let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+//!
+//! for _ in 0..10 {
+//!     emulator.set_buttons(0);
+//!     emulator.step_frame();
+//!     let frame = emulator.frame();
+//!     let audio = emulator.take_audio();
+//!     // Hand `frame.pixels` to a texture and `audio.samples` to a sound queue here.
+//!     assert_eq!(frame.pixels.len(), 256 * 240 * 3);
+//!     assert_eq!(audio.sample_rate, 44100);
+//!     assert!(audio.samples.len() > 700);
+//! }
+//!
+//! // `backdrop.nes` fills the screen with palette color $21.
+//! assert_eq!(emulator.frame().pixel(128, 120), [60, 188, 252]);
+//! ```
+//!
+//! `roms/backdrop.nes` is a public-domain test ROM built from `roms/backdrop.s`.
+//!
+//! Frames can also be pushed as they finish to any number of `VideoSink`s: a callback, files with
+//! `dump::FrameDump`, or a window with `gfx::Gfx`. The machine runs the same with none attached.
+
+use apu::{Apu, SampleClock, OUTPUT_SAMPLE_RATE};
+use capture::{self, Condition};
+use clock::MachineClock;
+use config::Config;
+use cpu::Cpu;
+use disasm::{self, Instruction};
+use epsm;
+use error::Error;
+use input::Input;
+use mapper;
+use mem::{MemMap, Peek};
+use metrics::METRICS;
+use ppu::{self, Oam, Ppu, Vram, SCREEN_HEIGHT, SCREEN_WIDTH};
+use provenance::Writer;
+use rom::Rom;
+use util;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// The size of a screen laid out as `Emulator::screen`.
+const SCREEN_BYTES: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
+
+/// A copy of the screen. The layout is fixed: `HEIGHT` rows of `WIDTH` pixels, top to bottom,
+/// each pixel three bytes of red, green and blue.
+#[derive(Clone)]
+pub struct Frame {
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub const WIDTH: usize = SCREEN_WIDTH;
+    pub const HEIGHT: usize = SCREEN_HEIGHT;
+
+    /// Converts a screen laid out as `Emulator::screen`.
+    pub fn from_screen(screen: &[u8]) -> Frame {
+        Frame {
+            pixels: screen
+                .chunks(3)
+                .flat_map(|pixel| vec![pixel[2], pixel[1], pixel[0]])
+                .collect(),
+        }
+    }
+
+    /// The red, green and blue of the pixel at column `x` of row `y`.
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * Frame::WIDTH + x) * 3;
+        [
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+        ]
+    }
+}
+
+/// What a frame callback gets when the PPU finishes a frame.
+pub struct FrameInfo<'a> {
+    /// The number of frames finished since power on, counting this one.
+    pub number: u64,
+    /// The picture, laid out as `Emulator::screen`.
+    pub screen: &'a [u8],
+    /// How many audio samples the frame is worth, which `take_audio` will return for it.
+    pub audio_samples: usize,
+    /// The buttons held on the first controller, in the layout of `set_buttons`.
+    pub buttons: u8,
+}
+
+/// Takes finished frames from an emulator, see `Emulator::add_video_sink`.
+pub trait VideoSink {
+    fn frame(&mut self, info: &FrameInfo);
+}
+
+/// Frame callbacks are sinks.
+impl<F: FnMut(&FrameInfo)> VideoSink for F {
+    fn frame(&mut self, info: &FrameInfo) {
+        self(info)
+    }
+}
+
+/// Drops every frame, for a sink slot that needs filling.
+pub struct NullVideo;
+
+impl VideoSink for NullVideo {
+    fn frame(&mut self, _: &FrameInfo) {}
+}
+
+/// The last finished frame of an emulator, readable from any thread, from `Emulator::framebuffer`.
+/// The emulator copies each frame into a spare buffer and swaps it in when the frame finishes, so
+/// readers never see one partly drawn and only ever hold the emulator up for the swap.
+///
+/// ```
+/// use nes::config::Config;
+/// use nes::emulator::Emulator;
+/// use std::thread;
+///
+/// let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+/// let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+/// let framebuffer = emulator.framebuffer();
+/// for _ in 0..3 {
+///     emulator.step_frame();
+/// }
+/// let reader = thread::spawn(move || {
+///     let mut screen = vec![0; 256 * 240 * 3];
+///     (framebuffer.read(&mut screen), framebuffer.frame().pixel(128, 120))
+/// });
+/// assert_eq!(reader.join().unwrap(), (3, [60, 188, 252]));
+/// ```
+#[derive(Clone)]
+pub struct FrameBuffer {
+    front: Arc<Mutex<FrontBuffer>>,
+}
+
+struct FrontBuffer {
+    number: u64,
+    screen: Box<[u8]>,
+}
+
+impl FrameBuffer {
+    fn new(number: u64, screen: &[u8]) -> FrameBuffer {
+        FrameBuffer {
+            front: Arc::new(Mutex::new(FrontBuffer {
+                number,
+                screen: screen.to_vec().into_boxed_slice(),
+            })),
+        }
+    }
+
+    /// Copies the last finished frame, laid out as `Emulator::screen`, into `screen`, and returns
+    /// its number as `FrameInfo` counts them.
+    pub fn read(&self, screen: &mut [u8]) -> u64 {
+        let front = self.front.lock().unwrap();
+        screen.copy_from_slice(&front.screen);
+        front.number
+    }
+
+    /// The number of the last finished frame, to check for a new one without copying it.
+    pub fn number(&self) -> u64 {
+        self.front.lock().unwrap().number
+    }
+
+    /// The last finished frame, converted to RGB.
+    pub fn frame(&self) -> Frame {
+        Frame::from_screen(&self.front.lock().unwrap().screen)
+    }
+
+    /// Makes `back` the front buffer, handing back the old front to draw the next frame into.
+    fn swap(&self, number: u64, back: &mut Box<[u8]>) {
+        let mut front = self.front.lock().unwrap();
+        front.number = number;
+        std::mem::swap(&mut front.screen, back);
+    }
+}
+
+/// Mono 16-bit samples at `sample_rate` Hz.
+#[derive(Clone)]
+pub struct AudioChunk {
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
+pub struct Emulator {
+    pub cpu: Cpu<MemMap>,
+    audio_clock: SampleClock,
+    /// Samples the APU has played that `take_audio` hasn't returned yet.
+    audio: Vec<i16>,
+    video_sinks: Vec<Box<dyn VideoSink>>,
+    /// The CPU cycle the last frame ended on.
+    frame_end: u64,
+    /// The last finished frame, while the PPU draws the next one.
+    screen: Box<[u8]>,
+    /// The buffer shared with other threads, and the spare the next frame is copied into.
+    framebuffer: Option<(FrameBuffer, Box<[u8]>)>,
+}
+
+impl Emulator {
+    /// Powers on a NES with the given cartridge.
+    pub fn new(rom: Rom, config: &Config) -> Result<Emulator, Error> {
+        let region = config.region.unwrap_or(rom.header.region());
+        let (mapper, _) = mapper::create_mapper_or_fallback(Box::new(rom), config.nrom_fallback)?;
+        let mapper = Rc::new(RefCell::new(mapper));
+
+        let ppu = Ppu::new(Vram::new(Box::new(mapper.clone())), Oam::new(), region);
+        let apu = Apu::new(None, region);
+        let memmap = MemMap::new(ppu, Input::headless(), mapper, apu);
+        let mut cpu = Cpu::new(memmap);
+        cpu.interrupt_latency = config.interrupt_latency;
+        cpu.mem.track_writes(config.write_provenance);
+        cpu.mem.ppu.alignment = config.alignment.unwrap_or_else(ppu::random_alignment);
+        cpu.reset();
+        for &(addr, val) in &config.freeze {
+            cpu.mem.freeze(addr, val);
+        }
+        if config.epsm {
+            epsm::attach(&mut cpu.mem);
+        }
+
+        Ok(Emulator {
+            cpu,
+            audio_clock: SampleClock::new(region),
+            audio: Vec::new(),
+            video_sinks: Vec::new(),
+            frame_end: 0,
+            screen: vec![0; SCREEN_BYTES].into_boxed_slice(),
+            framebuffer: None,
+        })
+    }
+
+    /// Powers on a NES with the iNES image in `bytes`.
+    pub fn from_bytes(bytes: &[u8], config: &Config) -> Result<Emulator, Error> {
+        Emulator::new(Rom::load(&mut &bytes[..])?, config)
+    }
+
+    /// Runs until the PPU finishes a frame.
+    pub fn step_frame(&mut self) {
+        let mut instructions = 1;
+        while !self.step_instruction() {
+            instructions += 1;
+        }
+        METRICS.instructions.add(instructions);
+    }
+
+    /// Runs like `step_frame`, but stops between two instructions as soon as `condition` holds,
+    /// even partway through the frame. Returns true if it stopped there.
+    pub fn step_frame_until(&mut self, condition: &Condition) -> bool {
+        capture::arm(Some(condition), &mut self.cpu);
+        let mut instructions = 0;
+        let met = loop {
+            let new_frame = self.step_instruction();
+            instructions += 1;
+            if condition.is_met(&mut self.cpu) {
+                break true;
+            }
+            if new_frame {
+                break false;
+            }
+        };
+        METRICS.instructions.add(instructions);
+        met
+    }
+
+    /// Runs one instruction. Returns true if the PPU finished a frame during it.
+    fn step_instruction(&mut self) -> bool {
+        if self.cpu.mem.input.has_scheduled() {
+            self.cpu.mem.input.cycle = self.cpu.cy;
+        }
+        self.cpu.mem.apu.cycle = self.cpu.cy;
+        self.cpu.mem.ppu.cycle = self.cpu.cy;
+        let pc = self.cpu.pc();
+        if let Some(ref mut provenance) = self.cpu.mem.provenance {
+            provenance.pc = pc;
+        }
+        self.cpu.step();
+
+        let ppu_result = self.cpu.mem.ppu.step(self.cpu.cy);
+        if ppu_result.vblank_nmi {
+            self.cpu.nmi();
+        } else if ppu_result.scanline_irq {
+            self.cpu.irq();
+        }
+        self.cpu.mem.apu.step(self.cpu.cy);
+
+        if ppu_result.new_frame {
+            METRICS.frames.add(1);
+            self.end_frame();
+        }
+        ppu_result.new_frame
+    }
+
+    /// Calls `callback` at the end of every frame from now on, before `step_frame` returns. This
+    /// is the place for recorders, netplay, scripts and the like to hook in.
+    ///
+    /// ```
+    /// use nes::config::Config;
+    /// use nes::emulator::Emulator;
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    ///
+    /// let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+    /// let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+    /// let last = Rc::new(Cell::new(0));
+    /// let seen = last.clone();
+    /// emulator.on_frame(move |info| {
+    ///     assert_eq!(info.screen.len(), 256 * 240 * 3);
+    ///     assert!(info.audio_samples > 700);
+    ///     seen.set(info.number);
+    /// });
+    /// for _ in 0..3 {
+    ///     emulator.step_frame();
+    /// }
+    /// assert_eq!(last.get(), 3);
+    /// ```
+    pub fn on_frame<F: FnMut(&FrameInfo) + 'static>(&mut self, callback: F) {
+        self.add_video_sink(callback);
+    }
+
+    /// Hands every frame from now on to `sink`, like `on_frame`.
+    pub fn add_video_sink<S: VideoSink + 'static>(&mut self, sink: S) {
+        self.video_sinks.push(Box::new(sink));
+    }
+
+    /// A handle to the last finished frame that other threads can read, such as a render thread.
+    /// Each call shares the same buffer.
+    pub fn framebuffer(&mut self) -> FrameBuffer {
+        if self.framebuffer.is_none() {
+            let number = self.clock().since_power_on.frames;
+            let framebuffer = FrameBuffer::new(number, &self.screen);
+            self.framebuffer = Some((framebuffer, self.screen.clone()));
+        }
+        self.framebuffer.as_ref().unwrap().0.clone()
+    }
+
+    fn end_frame(&mut self) {
+        let start = self.frame_end;
+        self.frame_end = self.cpu.cy;
+        let number = self.clock().since_power_on.frames;
+        self.screen.copy_from_slice(&self.cpu.mem.ppu.screen[..]);
+        // Keep up to a second of sound for `take_audio`, and drop older samples if it isn't
+        // called.
+        self.cpu.mem.apu.take_samples(&mut self.audio);
+        self.cpu.mem.apu.play_channels();
+        let excess = self.audio.len().saturating_sub(OUTPUT_SAMPLE_RATE as usize);
+        self.audio.drain(..excess);
+        if let Some((ref framebuffer, ref mut back)) = self.framebuffer {
+            back.copy_from_slice(&self.screen);
+            framebuffer.swap(number, back);
+        }
+        if self.video_sinks.is_empty() {
+            return;
+        }
+        let info = FrameInfo {
+            number,
+            screen: &self.screen,
+            audio_samples: self.audio_clock.count(start, self.frame_end),
+            buttons: self.cpu.mem.input.gamepad_0.bits(),
+        };
+        for sink in &mut self.video_sinks {
+            sink.frame(&info);
+        }
+    }
+
+    /// Sets the buttons held on the first controller, A in bit 0 through Right in bit 7.
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.cpu.mem.input.gamepad_0.set_bits(buttons);
+    }
+
+    /// Sets the buttons on the first controller from the first time the game latches it on or
+    /// after CPU cycle `cycle`, for changing input partway through a frame. `cycle` counts from
+    /// power on; add to `cycle()` to schedule relative to now.
+    pub fn set_buttons_at(&mut self, cycle: u64, buttons: u8) {
+        self.cpu.mem.input.schedule(cycle, buttons);
+    }
+
+    /// The number of CPU cycles run since power on.
+    pub fn cycle(&self) -> u64 {
+        self.cpu.cy
+    }
+
+    /// The CPU cycles, PPU dots and frames run since power on and since the last reset.
+    pub fn clock(&self) -> MachineClock {
+        MachineClock::read(&self.cpu)
+    }
+
+    /// Writes a byte of RAM or cartridge RAM once. The game may overwrite it.
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        self.cpu.mem.poke(addr, val);
+    }
+
+    /// Holds a byte of RAM or cartridge RAM at `val` until `unfreeze`: writes to it store `val`
+    /// instead.
+    pub fn freeze(&mut self, addr: u16, val: u8) {
+        self.cpu.mem.freeze(addr, val);
+    }
+
+    /// Writes the bytes from `start` to `end`, inclusive, as a hex dump labeled with the memory
+    /// they are in, such as `RAM` or `PRG-ROM bank 5`. See `MemMap::hex_dump`.
+    pub fn hex_dump<W: Write>(&mut self, writer: &mut W, start: u16, end: u16) -> io::Result<()> {
+        self.cpu.mem.hex_dump(writer, start, end)
+    }
+
+    /// The instruction that last wrote `addr` in RAM or cartridge RAM, and the bank it ran from.
+    /// `None` if nothing has, or the `write_provenance` setting is off.
+    pub fn last_writer<'a>(&'a self, addr: u16) -> Option<Writer<'a>> {
+        self.cpu.mem.last_writer(addr)
+    }
+
+    /// The instruction that last wrote the PPU address `addr` through PPUDATA, like `last_writer`.
+    pub fn last_vram_writer<'a>(&'a self, addr: u16) -> Option<Writer<'a>> {
+        self.cpu.mem.ppu.vram_provenance()?.last_writer(addr)
+    }
+
+    /// Writes who wrote the pattern tables and nametables, and the tiles drawn most, as text. Fails
+    /// if the `write_provenance` setting is off.
+    pub fn write_vram_report<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self.cpu.mem.ppu.vram_provenance() {
+            Some(provenance) => provenance.write_report(writer),
+            None => Err(io::Error::other("write provenance isn't tracked")),
+        }
+    }
+
+    /// Returns false if `addr` wasn't frozen.
+    pub fn unfreeze(&mut self, addr: u16) -> bool {
+        self.cpu.mem.cheats.unfreeze(addr)
+    }
+
+    /// The last finished frame, as 256x240 BGR pixels. After `step_frame_until` stops partway
+    /// through a frame, this is still the one before: what the PPU has drawn so far is left out.
+    pub fn screen(&self) -> &[u8] {
+        &self.screen
+    }
+
+    /// The last finished frame, converted to RGB.
+    pub fn frame(&self) -> Frame {
+        Frame::from_screen(self.screen())
+    }
+
+    /// The audio produced since the last call. The number of samples follows the CPU clock
+    /// exactly, so it is the same on every run; see `SampleClock::take`.
+    pub fn take_audio(&mut self) -> AudioChunk {
+        self.cpu.mem.apu.take_samples(&mut self.audio);
+        AudioChunk {
+            sample_rate: OUTPUT_SAMPLE_RATE,
+            samples: self.audio_clock.take(self.cpu.cy, &mut self.audio),
+        }
+    }
+
+    /// The disassembly of `before` instructions leading up to the program counter, the next
+    /// instruction and `after` instructions following it, read through the current bank mapping.
+    pub fn disassembly(&mut self, before: usize, after: usize) -> Vec<Instruction> {
+        let pc = self.cpu.pc();
+        disasm::window(&mut Peek(&mut self.cpu.mem), pc, before, after)
+    }
+
+    /// CRC-32 of the screen, for comparing frames against known-good output.
+    pub fn screen_hash(&self) -> u32 {
+        util::crc32(self.screen())
+    }
+
+    /// Returns true if every pixel of the screen is the same color.
+    pub fn screen_is_blank(&self) -> bool {
+        let screen = self.screen();
+        screen.chunks(3).all(|pixel| pixel == &screen[0..3])
+    }
+}
@@ -0,0 +1,727 @@
+//! The Expansion Port Sound Module (EPSM), a homebrew add-on built around the Yamaha YM2608
+//! (OPNA). Games write it through $401C-$401F: an address and a data register for each of the
+//! chip's two register banks, at $401C/$401D and $401E/$401F.
+//!
+//! The SSG (three square wave channels with noise and an envelope) and the six FM channels are
+//! emulated; the rhythm and ADPCM units, the timers and detune are not.
+//!
+//! Like the rest of the core, it is all integer math: the operators work in the log domain with
+//! lookup tables, as the chip does, so the sound is the same bit for bit on every platform.
+
+use apu::ExpansionAudio;
+use mem::{Bus, Mem, MemMap};
+use util::Save;
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+/// The addresses the EPSM's registers are written at.
+pub const START: u16 = 0x401c;
+pub const END: u16 = 0x401f;
+
+/// The YM2608's master clock, in Hz.
+pub const CLOCK: u64 = 8_000_000;
+/// The FM unit produces a sample every 144 clocks.
+const FM_DIVIDER: u64 = 144;
+/// The SSG's tone, noise and envelope counters count at an eighth of its clock, which is a
+/// quarter of the master clock.
+const SSG_DIVIDER: u64 = 32;
+
+/// Operator outputs are fixed-point, with 8192 as 1.0.
+const FULL_SCALE_BITS: u32 = 13;
+/// Attenuations are in 64ths of a halving, about 0.094 dB. Envelopes keep 16 more bits of
+/// fraction.
+const ENVELOPE_FRACTION_BITS: u32 = 16;
+/// At this attenuation, about 96 dB, an operator falls silent.
+const MAX_ATTENUATION: u32 = 1024;
+/// A full-scale modulator shifts the phase of the operator it feeds by four cycles. Phases are
+/// fractions of a cycle in 32 bits.
+const MODULATION_SHIFT: u32 = 32 - FULL_SCALE_BITS + 2;
+/// How much the envelope moves per FM sample at the rates of each group of four, the lowest of
+/// which changes attenuation by 1 every 4096 samples. Each rate is a quarter of a doubling faster
+/// than the one before.
+const RATE_STEPS: [u32; 4] = [65536, 77936, 92682, 110218];
+
+/// -log2(sin(x)) in 256ths, over the first quarter of a sine wave. As on the chip, operators add
+/// their attenuation to this and turn the sum back into a level with `EXP`.
+const LOG_SIN: [u16; 256] = [
+    2137, 1731, 1543, 1419, 1326, 1252, 1190, 1137, 1091, 1050, 1013, 979, 949, 920, 894, 869, 846,
+    825, 804, 785, 767, 749, 732, 717, 701, 687, 672, 659, 646, 633, 621, 609, 598, 587, 576, 566,
+    556, 546, 536, 527, 518, 509, 501, 492, 484, 476, 468, 461, 453, 446, 439, 432, 425, 418, 411,
+    405, 399, 392, 386, 380, 375, 369, 363, 358, 352, 347, 341, 336, 331, 326, 321, 316, 311, 307,
+    302, 297, 293, 289, 284, 280, 276, 271, 267, 263, 259, 255, 251, 248, 244, 240, 236, 233, 229,
+    226, 222, 219, 215, 212, 209, 205, 202, 199, 196, 193, 190, 187, 184, 181, 178, 175, 172, 169,
+    167, 164, 161, 159, 156, 153, 151, 148, 146, 143, 141, 138, 136, 134, 131, 129, 127, 125, 122,
+    120, 118, 116, 114, 112, 110, 108, 106, 104, 102, 100, 98, 96, 94, 92, 91, 89, 87, 85, 83, 82,
+    80, 78, 77, 75, 74, 72, 70, 69, 67, 66, 64, 63, 62, 60, 59, 57, 56, 55, 53, 52, 51, 49, 48, 47,
+    46, 45, 43, 42, 41, 40, 39, 38, 37, 36, 35, 34, 33, 32, 31, 30, 29, 28, 27, 26, 25, 24, 23, 23,
+    22, 21, 20, 20, 19, 18, 17, 17, 16, 15, 15, 14, 13, 13, 12, 12, 11, 10, 10, 9, 9, 8, 8, 7, 7,
+    7, 6, 6, 5, 5, 5, 4, 4, 4, 3, 3, 3, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+/// 2^(-x/256), with 8192 as 1.0.
+const EXP: [u16; 256] = [
+    8192, 8170, 8148, 8126, 8104, 8082, 8060, 8038, 8016, 7995, 7973, 7952, 7930, 7909, 7887, 7866,
+    7845, 7823, 7802, 7781, 7760, 7739, 7718, 7697, 7677, 7656, 7635, 7614, 7594, 7573, 7553, 7532,
+    7512, 7492, 7472, 7451, 7431, 7411, 7391, 7371, 7351, 7331, 7311, 7292, 7272, 7252, 7233, 7213,
+    7194, 7174, 7155, 7135, 7116, 7097, 7078, 7059, 7039, 7020, 7001, 6983, 6964, 6945, 6926, 6907,
+    6889, 6870, 6851, 6833, 6814, 6796, 6778, 6759, 6741, 6723, 6705, 6686, 6668, 6650, 6632, 6614,
+    6597, 6579, 6561, 6543, 6526, 6508, 6490, 6473, 6455, 6438, 6420, 6403, 6386, 6368, 6351, 6334,
+    6317, 6300, 6283, 6266, 6249, 6232, 6215, 6198, 6182, 6165, 6148, 6132, 6115, 6098, 6082, 6065,
+    6049, 6033, 6016, 6000, 5984, 5968, 5952, 5936, 5919, 5903, 5887, 5872, 5856, 5840, 5824, 5808,
+    5793, 5777, 5761, 5746, 5730, 5715, 5699, 5684, 5668, 5653, 5638, 5623, 5607, 5592, 5577, 5562,
+    5547, 5532, 5517, 5502, 5487, 5472, 5458, 5443, 5428, 5413, 5399, 5384, 5370, 5355, 5341, 5326,
+    5312, 5297, 5283, 5269, 5255, 5240, 5226, 5212, 5198, 5184, 5170, 5156, 5142, 5128, 5114, 5100,
+    5087, 5073, 5059, 5045, 5032, 5018, 5005, 4991, 4978, 4964, 4951, 4937, 4924, 4911, 4897, 4884,
+    4871, 4858, 4845, 4832, 4819, 4805, 4792, 4780, 4767, 4754, 4741, 4728, 4715, 4703, 4690, 4677,
+    4664, 4652, 4639, 4627, 4614, 4602, 4589, 4577, 4565, 4552, 4540, 4528, 4515, 4503, 4491, 4479,
+    4467, 4455, 4443, 4431, 4419, 4407, 4395, 4383, 4371, 4359, 4347, 4336, 4324, 4312, 4301, 4289,
+    4277, 4266, 4254, 4243, 4231, 4220, 4208, 4197, 4186, 4174, 4163, 4152, 4141, 4129, 4118, 4107,
+];
+/// The level of each SSG volume step, 1.5 dB apart, scaled to mix with the FM channels.
+const SSG_LEVELS: [u16; 32] = [
+    0, 22, 27, 32, 38, 45, 53, 63, 75, 90, 106, 126, 150, 179, 212, 252, 300, 357, 424, 504, 598,
+    711, 845, 1005, 1194, 1419, 1687, 2005, 2383, 2832, 3366, 4000,
+];
+
+/// Plugs an EPSM into the expansion port: its registers onto the bus and its sound into the APU.
+pub fn attach(mem: &mut MemMap) {
+    let epsm = Rc::new(RefCell::new(Epsm::new()));
+    mem.attach(START, END, Box::new(Port(epsm.clone())));
+    mem.apu.expansion = Some(epsm);
+}
+
+/// The EPSM's side of the bus.
+struct Port(Rc<RefCell<Epsm>>);
+
+impl Mem for Port {
+    /// The EPSM can't be read.
+    fn loadb(&mut self, _: u16) -> u8 {
+        0
+    }
+    fn storeb(&mut self, addr: u16, val: u8) {
+        self.0.borrow_mut().write(addr, val)
+    }
+}
+
+pub struct Epsm {
+    /// The register selected in each bank.
+    address: [u8; 2],
+    /// The YM2608 starts out compatible with the three-channel YM2203; register $29 enables FM
+    /// channels 3-5.
+    six_channels: bool,
+    ssg: Ssg,
+    fm: [FmChannel; 6],
+    /// Master clocks owed to the next FM sample, times the output sample rate.
+    fm_clock: u64,
+}
+
+impl Default for Epsm {
+    fn default() -> Epsm {
+        Epsm::new()
+    }
+}
+
+impl Epsm {
+    pub fn new() -> Epsm {
+        Epsm {
+            address: [0; 2],
+            six_channels: false,
+            ssg: Ssg::new(),
+            fm: [FmChannel::new(); 6],
+            fm_clock: 0,
+        }
+    }
+
+    /// Handles a write to $401C-$401F.
+    pub fn write(&mut self, addr: u16, val: u8) {
+        let bank = ((addr >> 1) & 1) as usize;
+        if addr & 1 == 0 {
+            self.address[bank] = val;
+        } else {
+            let reg = self.address[bank];
+            self.write_register(bank, reg, val);
+        }
+    }
+
+    fn write_register(&mut self, bank: usize, reg: u8, val: u8) {
+        match (bank, reg) {
+            (0, 0x00..=0x0f) => self.ssg.write(reg, val),
+            (0, 0x28) => {
+                // Key on and off: the operators in the high nibble, the channel in the low bits.
+                let channel = match val & 7 {
+                    channel @ 0..=2 => channel,
+                    channel @ 4..=6 => channel - 1,
+                    _ => return,
+                };
+                let operators = &mut self.fm[channel as usize].operators;
+                for (i, operator) in operators.iter_mut().enumerate() {
+                    if val & (0x10 << i) != 0 {
+                        operator.key_on()
+                    } else {
+                        operator.key_off()
+                    }
+                }
+            }
+            (0, 0x29) => self.six_channels = val & 0x80 != 0,
+            (_, 0x30..=0xb6) if reg & 3 != 3 => {
+                self.fm[bank * 3 + (reg & 3) as usize].write(reg, val)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for Epsm {
+    fn render(&mut self, out: &mut [i16], sample_rate: u32) {
+        let channels = if self.six_channels { 6 } else { 3 };
+        let fm_period = FM_DIVIDER * sample_rate as u64;
+        for dest in out.iter_mut() {
+            self.fm_clock += CLOCK;
+            let fm_samples = (self.fm_clock / fm_period) as u32;
+            self.fm_clock %= fm_period;
+            let fm: i32 = self.fm[..channels]
+                .iter_mut()
+                .map(|channel| channel.sample(fm_samples, sample_rate))
+                .sum();
+            let ssg = self.ssg.sample(sample_rate);
+            // Roughly as loud as the APU's channels: 6000 for a full-scale FM channel.
+            let val = ((fm * 375) >> 9) + ssg;
+            *dest = val.clamp(-32768, 32767) as i16;
+        }
+    }
+}
+
+impl Save for Epsm {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        (&mut self.address[..]).save(fd)?;
+        self.six_channels.save(fd)?;
+        self.ssg.save(fd)?;
+        for channel in &mut self.fm {
+            channel.save(fd)?;
+        }
+        self.fm_clock.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        (&mut self.address[..]).load(fd)?;
+        self.six_channels.load(fd)?;
+        self.ssg.load(fd)?;
+        for channel in &mut self.fm {
+            channel.load(fd)?;
+        }
+        self.fm_clock.load(fd)
+    }
+}
+
+//
+// SSG
+//
+
+/// The AY-3-8910 compatible part of the chip.
+struct Ssg {
+    regs: [u8; 16],
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u16,
+    noise: u32,
+    envelope_counter: u32,
+    /// 0-31 through the current envelope cycle.
+    envelope_step: u8,
+    envelope_rising: bool,
+    envelope_holding: bool,
+    /// Master clocks owed to the next counter tick, times the output sample rate.
+    clock: u64,
+}
+
+impl Ssg {
+    fn new() -> Ssg {
+        Ssg {
+            regs: [0; 16],
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise: 1,
+            envelope_counter: 0,
+            envelope_step: 31,
+            envelope_rising: false,
+            envelope_holding: true,
+            clock: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        self.regs[reg as usize] = val;
+        if reg == 13 {
+            // Writing the envelope shape restarts the envelope.
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_rising = val & 0x04 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tick(&mut self) {
+        for channel in 0..3 {
+            let period = ((self.regs[channel * 2 + 1] as u16 & 0xf) << 8
+                | self.regs[channel * 2] as u16)
+                .max(1);
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= period {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        // The noise generator runs at half the rate of the tone generators.
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.regs[6] as u16 & 0x1f).max(1) * 2 {
+            self.noise_counter = 0;
+            let bit = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | (bit << 16);
+        }
+
+        self.envelope_counter += 1;
+        if self.envelope_counter >= ((self.regs[12] as u32) << 8 | self.regs[11] as u32).max(1) {
+            self.envelope_counter = 0;
+            self.step_envelope();
+        }
+    }
+
+    fn step_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        // The end of a cycle. The shape's bits are CONTINUE, ATTACK, ALTERNATE and HOLD.
+        let shape = self.regs[13];
+        if shape & 0x08 == 0 {
+            self.envelope_rising = false;
+            self.envelope_holding = true;
+        } else if shape & 0x01 != 0 {
+            if shape & 0x02 != 0 {
+                self.envelope_rising = !self.envelope_rising;
+            }
+            self.envelope_holding = true;
+        } else {
+            if shape & 0x02 != 0 {
+                self.envelope_rising = !self.envelope_rising;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_rising {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    /// The next sample, up to `SSG_LEVELS[31]` for each channel.
+    fn sample(&mut self, sample_rate: u32) -> i32 {
+        let period = SSG_DIVIDER * sample_rate as u64;
+        self.clock += CLOCK;
+        while self.clock >= period {
+            self.clock -= period;
+            self.tick();
+        }
+
+        let mixer = self.regs[7];
+        let noise = self.noise & 1 != 0;
+        let mut sum = 0;
+        for channel in 0..3 {
+            // A disabled tone or noise leaves the channel on, which games use to play samples by
+            // writing the volume.
+            let tone_on = self.tone_outputs[channel] || mixer & (0x01 << channel) != 0;
+            let noise_on = noise || mixer & (0x08 << channel) != 0;
+            if !tone_on || !noise_on {
+                continue;
+            }
+            let volume = self.regs[8 + channel];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else if volume & 0xf == 0 {
+                0
+            } else {
+                (volume & 0xf) * 2 + 1
+            };
+            sum += SSG_LEVELS[level as usize] as i32;
+        }
+        sum
+    }
+}
+
+impl Save for Ssg {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        (&mut self.regs[..]).save(fd)?;
+        for channel in 0..3 {
+            self.tone_counters[channel].save(fd)?;
+            self.tone_outputs[channel].save(fd)?;
+        }
+        self.noise_counter.save(fd)?;
+        self.noise.save(fd)?;
+        self.envelope_counter.save(fd)?;
+        self.envelope_step.save(fd)?;
+        self.envelope_rising.save(fd)?;
+        self.envelope_holding.save(fd)?;
+        self.clock.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        (&mut self.regs[..]).load(fd)?;
+        for channel in 0..3 {
+            self.tone_counters[channel].load(fd)?;
+            self.tone_outputs[channel].load(fd)?;
+        }
+        self.noise_counter.load(fd)?;
+        self.noise.load(fd)?;
+        self.envelope_counter.load(fd)?;
+        self.envelope_step.load(fd)?;
+        self.envelope_rising.load(fd)?;
+        self.envelope_holding.load(fd)?;
+        self.clock.load(fd)
+    }
+}
+
+//
+// FM
+//
+
+#[derive(Copy, Clone, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Copy, Clone)]
+struct Operator {
+    multiple: u8,
+    total_level: u8,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+
+    /// The position in the sine wave, as a fraction of a cycle.
+    phase: u32,
+    /// The envelope's attenuation, with `ENVELOPE_FRACTION_BITS` of fraction.
+    attenuation: u32,
+    stage: Stage,
+}
+
+impl Save for Stage {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        let mut val: u8 = match *self {
+            Stage::Attack => 0,
+            Stage::Decay => 1,
+            Stage::Sustain => 2,
+            Stage::Release => 3,
+        };
+        val.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        let mut val: u8 = 0;
+        val.load(fd)?;
+        *self = match val {
+            0 => Stage::Attack,
+            1 => Stage::Decay,
+            2 => Stage::Sustain,
+            _ => Stage::Release,
+        };
+        Ok(())
+    }
+}
+
+save_struct!(Operator {
+    multiple,
+    total_level,
+    attack_rate,
+    decay_rate,
+    sustain_rate,
+    sustain_level,
+    release_rate,
+    phase,
+    attenuation,
+    stage
+});
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            multiple: 0,
+            total_level: 0,
+            attack_rate: 0,
+            decay_rate: 0,
+            sustain_rate: 0,
+            sustain_level: 0,
+            release_rate: 0,
+            phase: 0,
+            attenuation: MAX_ATTENUATION << ENVELOPE_FRACTION_BITS,
+            stage: Stage::Release,
+        }
+    }
+
+    fn key_on(&mut self) {
+        if self.stage == Stage::Release {
+            self.phase = 0;
+            self.stage = Stage::Attack;
+        }
+    }
+
+    fn key_off(&mut self) {
+        self.stage = Stage::Release;
+    }
+
+    /// Advances the envelope by `fm_samples` of the chip's own samples.
+    fn step_envelope(&mut self, fm_samples: u32) {
+        let rate = match self.stage {
+            Stage::Attack => self.attack_rate * 2,
+            Stage::Decay => self.decay_rate * 2,
+            Stage::Sustain => self.sustain_rate * 2,
+            Stage::Release => self.release_rate * 4 + 2,
+        };
+        if rate == 0 {
+            return;
+        }
+        // Every four steps of the rate double the speed.
+        let step = if rate >= 48 {
+            RATE_STEPS[rate as usize & 3] << (rate / 4 - 12)
+        } else {
+            RATE_STEPS[rate as usize & 3] >> (12 - rate / 4)
+        };
+
+        for _ in 0..fm_samples {
+            match self.stage {
+                Stage::Attack => {
+                    // The attack curves: each sample takes off a share of what is left.
+                    if rate >= 62 {
+                        self.attenuation = 0;
+                    } else {
+                        let share = (self.attenuation as u64 * step as u64) >> 20;
+                        self.attenuation -= share.min(self.attenuation as u64) as u32;
+                    }
+                    if self.attenuation < 1 << ENVELOPE_FRACTION_BITS {
+                        self.attenuation = 0;
+                        self.stage = Stage::Decay;
+                    }
+                }
+                _ => {
+                    let max = MAX_ATTENUATION << ENVELOPE_FRACTION_BITS;
+                    self.attenuation = (self.attenuation + step).min(max);
+                    // 3 dB per step of the sustain level, with the last step 93 dB.
+                    let sustain_level = if self.sustain_level == 15 {
+                        31 * 32
+                    } else {
+                        self.sustain_level as u32 * 32
+                    };
+                    let reached = self.attenuation >= sustain_level << ENVELOPE_FRACTION_BITS;
+                    if self.stage == Stage::Decay && reached {
+                        self.stage = Stage::Sustain;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The next output, from -8192 to 8192, with the phase shifted by `modulation`. `frequency`
+    /// is the channel's phase step at a multiple of 1/2.
+    fn output(&mut self, modulation: u32, frequency: u64) -> i32 {
+        let multiple = if self.multiple == 0 {
+            1
+        } else {
+            self.multiple as u64 * 2
+        };
+        self.phase = self.phase.wrapping_add((frequency * multiple) as u32);
+
+        // 0.75 dB per step of the total level.
+        let attenuation =
+            (self.attenuation >> ENVELOPE_FRACTION_BITS) + self.total_level as u32 * 8;
+        if attenuation >= MAX_ATTENUATION {
+            return 0;
+        }
+        // The top 10 bits of the phase: the half of the wave, the quarter, and where in it.
+        let position = self.phase.wrapping_add(modulation) >> 22;
+        let index = if position & 0x100 != 0 {
+            0xff - (position & 0xff)
+        } else {
+            position & 0xff
+        };
+        let log = LOG_SIN[index as usize] as u32 + (attenuation << 2);
+        let level = (EXP[log as usize & 0xff] as u32 >> (log >> 8)) as i32;
+        if position & 0x200 != 0 {
+            -level
+        } else {
+            level
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct FmChannel {
+    /// In algorithm order, which differs from the order of the registers.
+    operators: [Operator; 4],
+    f_number: u16,
+    block: u8,
+    /// The block and high bits of the F-number written to $A4, which take effect with the next
+    /// write to $A0.
+    frequency_latch: u8,
+    feedback: u8,
+    algorithm: u8,
+    /// The left and right output enables.
+    pan: u8,
+    /// The first operator's last two outputs, for feedback.
+    feedback_outputs: [i32; 2],
+}
+
+impl FmChannel {
+    fn new() -> FmChannel {
+        FmChannel {
+            operators: [Operator::new(); 4],
+            f_number: 0,
+            block: 0,
+            frequency_latch: 0,
+            feedback: 0,
+            algorithm: 0,
+            pan: 3,
+            feedback_outputs: [0; 2],
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        // Registers $30-$9F have one address per operator: the first, third, second and fourth.
+        let operator = [0, 2, 1, 3][(reg >> 2) as usize & 3];
+        let operator = &mut self.operators[operator];
+        match reg & 0xf0 {
+            0x30 => operator.multiple = val & 0xf,
+            0x40 => operator.total_level = val & 0x7f,
+            0x50 => operator.attack_rate = val & 0x1f,
+            0x60 => operator.decay_rate = val & 0x1f,
+            0x70 => operator.sustain_rate = val & 0x1f,
+            0x80 => {
+                operator.sustain_level = val >> 4;
+                operator.release_rate = val & 0xf;
+            }
+            0xa0 if reg & 0xc == 0 => {
+                self.f_number = (self.frequency_latch as u16 & 7) << 8 | val as u16;
+                self.block = (self.frequency_latch >> 3) & 7;
+            }
+            0xa0 if reg & 0xc == 4 => self.frequency_latch = val,
+            0xb0 if reg & 0xc == 0 => {
+                self.feedback = (val >> 3) & 7;
+                self.algorithm = val & 7;
+            }
+            0xb0 if reg & 0xc == 4 => self.pan = val >> 6,
+            _ => {}
+        }
+    }
+
+    /// How far the phase moves per output sample at a multiple of 1/2. The frequency in Hz is
+    /// the F-number times 2^block times the FM sample rate over 2^21.
+    fn phase_step(&self, sample_rate: u32) -> u64 {
+        ((self.f_number as u64) << self.block) * CLOCK * (1 << 10)
+            / (FM_DIVIDER * sample_rate as u64)
+    }
+
+    /// The next sample, from -8192 to 8192 for each carrier, after `fm_samples` of the chip's own.
+    fn sample(&mut self, fm_samples: u32, sample_rate: u32) -> i32 {
+        let frequency = self.phase_step(sample_rate);
+        for operator in &mut self.operators {
+            operator.step_envelope(fm_samples);
+        }
+
+        // The first operator modulates itself by the average of its last two outputs, by up to
+        // two cycles at full scale.
+        let feedback = if self.feedback == 0 {
+            0
+        } else {
+            let sum = (self.feedback_outputs[0] + self.feedback_outputs[1]) as i64;
+            (sum << (12 + self.feedback)) as u32
+        };
+        let op = &mut self.operators;
+        let o1 = op[0].output(feedback, frequency);
+        self.feedback_outputs = [self.feedback_outputs[1], o1];
+
+        let mut run = |operator: usize, modulator: i32| {
+            let modulation = ((modulator as i64) << MODULATION_SHIFT) as u32;
+            op[operator].output(modulation, frequency)
+        };
+        let out = match self.algorithm {
+            0 => {
+                let o2 = run(1, o1);
+                let o3 = run(2, o2);
+                run(3, o3)
+            }
+            1 => {
+                let o2 = run(1, 0);
+                let o3 = run(2, o1 + o2);
+                run(3, o3)
+            }
+            2 => {
+                let o2 = run(1, 0);
+                let o3 = run(2, o2);
+                run(3, o1 + o3)
+            }
+            3 => {
+                let o2 = run(1, o1);
+                let o3 = run(2, 0);
+                run(3, o2 + o3)
+            }
+            4 => {
+                let o2 = run(1, o1);
+                let o3 = run(2, 0);
+                o2 + run(3, o3)
+            }
+            5 => run(1, o1) + run(2, o1) + run(3, o1),
+            6 => run(1, o1) + run(2, 0) + run(3, 0),
+            _ => o1 + run(1, 0) + run(2, 0) + run(3, 0),
+        };
+        if self.pan == 0 {
+            0
+        } else {
+            out
+        }
+    }
+}
+
+impl Save for FmChannel {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        for operator in &mut self.operators {
+            operator.save(fd)?;
+        }
+        self.f_number.save(fd)?;
+        self.block.save(fd)?;
+        self.frequency_latch.save(fd)?;
+        self.feedback.save(fd)?;
+        self.algorithm.save(fd)?;
+        self.pan.save(fd)?;
+        for output in &mut self.feedback_outputs {
+            let mut val = *output as u32;
+            val.save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        for operator in &mut self.operators {
+            operator.load(fd)?;
+        }
+        self.f_number.load(fd)?;
+        self.block.load(fd)?;
+        self.frequency_latch.load(fd)?;
+        self.feedback.load(fd)?;
+        self.algorithm.load(fd)?;
+        self.pan.load(fd)?;
+        for output in &mut self.feedback_outputs {
+            let mut val = 0u32;
+            val.load(fd)?;
+            *output = val as i32;
+        }
+        Ok(())
+    }
+}
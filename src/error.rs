@@ -0,0 +1,48 @@
+//! The error type of the public API, so that embedders can handle failures instead of aborting.
+
+use mapper;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read or written.
+    Io(io::Error),
+    /// The ROM image isn't in iNES format.
+    InvalidRom,
+    /// The ROM uses a mapper that isn't emulated.
+    UnsupportedMapper(mapper::UnsupportedMapper),
+    /// A savestate is corrupt or can't be loaded by this build.
+    InvalidSavestate(&'static str),
+    /// SDL couldn't set up the window, renderer or texture.
+    Sdl(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::InvalidRom => write!(f, "not an iNES ROM image"),
+            Error::UnsupportedMapper(ref mapper) => write!(f, "{}", mapper),
+            Error::InvalidSavestate(message) => write!(f, "{}", message),
+            Error::Sdl(ref message) => write!(f, "SDL error: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
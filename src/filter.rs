@@ -0,0 +1,269 @@
+//! Post-processing of finished frames on their way from the PPU to the window, as a chain of
+//! filters run in the order of the `filters` setting, each on the output of the one before.
+//!
+//! Rotation isn't a filter: it happens when the frame is drawn to the window, after the chain. The
+//! on-screen display is drawn over the window last, so the filters never touch it.
+
+use config::Config;
+use gfx::{FrameBlend, SCREEN_SIZE};
+use glasses::{Eye, Glasses, StereoMode};
+use ppu::SCREEN_WIDTH;
+
+/// The filters there are, by the name they go by in the `filters` setting.
+pub const FILTER_NAMES: [&str; 4] = ["glasses", "blend", "ntsc", "color_blind"];
+
+pub trait FrameFilter {
+    /// The filter's name in the `filters` setting.
+    fn name(&self) -> &'static str;
+    /// Takes the filter's own settings from `config`.
+    fn configure(&mut self, config: &Config);
+    /// Processes a finished frame, drawn for `eye` of the 3D glasses. The screen is BGR.
+    fn process(&mut self, screen: &mut [u8; SCREEN_SIZE], eye: Eye);
+}
+
+/// Creates the filter named `name`, or returns `None` if there is no such filter.
+pub fn create(name: &str) -> Option<Box<dyn FrameFilter>> {
+    match name {
+        "glasses" => Some(Box::new(Glasses::new(StereoMode::Off))),
+        "blend" => Some(Box::new(FrameBlend::new())),
+        "ntsc" => Some(Box::new(Ntsc::new())),
+        "color_blind" => Some(Box::new(ColorBlindFilter::new(
+            ColorBlindness::Deuteranopia,
+        ))),
+        _ => None,
+    }
+}
+
+/// Parses the `filters` setting: filter names separated by commas, in the order to run them.
+/// `none` or nothing runs no filters.
+pub fn parse_chain(value: &str) -> Result<Vec<&'static str>, String> {
+    let mut chain = Vec::new();
+    if value.is_empty() || value == "none" {
+        return Ok(chain);
+    }
+    for name in value.split(',').map(str::trim) {
+        let name = match FILTER_NAMES.iter().find(|&&known| known == name) {
+            Some(&name) => name,
+            None => return Err(format!("unknown filter `{}`", name)),
+        };
+        if chain.contains(&name) {
+            return Err(format!("filter `{}` is listed twice", name));
+        }
+        chain.push(name);
+    }
+    Ok(chain)
+}
+
+impl FrameFilter for Glasses {
+    fn name(&self) -> &'static str {
+        "glasses"
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.mode = config.glasses;
+    }
+
+    fn process(&mut self, screen: &mut [u8; SCREEN_SIZE], eye: Eye) {
+        Glasses::process(self, screen, eye)
+    }
+}
+
+impl FrameFilter for FrameBlend {
+    fn name(&self) -> &'static str {
+        "blend"
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.enabled = config.frame_blend;
+    }
+
+    fn process(&mut self, screen: &mut [u8; SCREEN_SIZE], _: Eye) {
+        FrameBlend::process(self, screen)
+    }
+}
+
+//
+// NTSC
+//
+
+/// How many pixels the I and Q color signals are spread over. Q has the least bandwidth.
+const NTSC_I_WIDTH: usize = 4;
+const NTSC_Q_WIDTH: usize = 6;
+
+/// An approximation of composite video: the color signal has far less bandwidth than the
+/// brightness, so colors bleed into the pixels to their right, and edges soften slightly.
+pub struct Ntsc {
+    /// The YIQ of the row being filtered.
+    row: Vec<[f32; 3]>,
+}
+
+impl Default for Ntsc {
+    fn default() -> Ntsc {
+        Ntsc::new()
+    }
+}
+
+impl Ntsc {
+    pub fn new() -> Ntsc {
+        Ntsc {
+            row: vec![[0.0; 3]; SCREEN_WIDTH],
+        }
+    }
+}
+
+impl FrameFilter for Ntsc {
+    fn name(&self) -> &'static str {
+        "ntsc"
+    }
+
+    fn configure(&mut self, _: &Config) {}
+
+    fn process(&mut self, screen: &mut [u8; SCREEN_SIZE], _: Eye) {
+        for line in screen.chunks_mut(SCREEN_WIDTH * 3) {
+            for (yiq, pixel) in self.row.iter_mut().zip(line.chunks(3)) {
+                let (b, g, r) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                *yiq = [
+                    0.299 * r + 0.587 * g + 0.114 * b,
+                    0.596 * r - 0.274 * g - 0.322 * b,
+                    0.211 * r - 0.523 * g + 0.312 * b,
+                ];
+            }
+            for (x, pixel) in line.chunks_mut(3).enumerate() {
+                let y = 0.25 * self.row[x.saturating_sub(1)][0] + 0.75 * self.row[x][0];
+                let i = trailing_average(&self.row, x, 1, NTSC_I_WIDTH);
+                let q = trailing_average(&self.row, x, 2, NTSC_Q_WIDTH);
+                pixel[0] = clamp(y - 1.106 * i + 1.703 * q);
+                pixel[1] = clamp(y - 0.272 * i - 0.647 * q);
+                pixel[2] = clamp(y + 0.956 * i + 0.621 * q);
+            }
+        }
+    }
+}
+
+/// The average of `channel` over the `width` pixels ending at `x`.
+fn trailing_average(row: &[[f32; 3]], x: usize, channel: usize, width: usize) -> f32 {
+    let start = (x + 1).saturating_sub(width);
+    let sum: f32 = row[start..x + 1].iter().map(|yiq| yiq[channel]).sum();
+    sum / (x + 1 - start) as f32
+}
+
+fn clamp(value: f32) -> u8 {
+    value.clamp(0.0, 255.0).round() as u8
+}
+
+//
+// Color blindness
+//
+
+/// The kind of color blindness to correct for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorBlindness {
+    /// No red cones.
+    Protanopia,
+    /// No green cones, the most common kind.
+    Deuteranopia,
+    /// No blue cones.
+    Tritanopia,
+}
+
+impl ColorBlindness {
+    pub fn from_name(name: &str) -> Option<ColorBlindness> {
+        match name {
+            "protanopia" => Some(ColorBlindness::Protanopia),
+            "deuteranopia" => Some(ColorBlindness::Deuteranopia),
+            "tritanopia" => Some(ColorBlindness::Tritanopia),
+            _ => None,
+        }
+    }
+
+    /// How the eye sees an LMS color: the missing cone's response made up from the other two.
+    fn simulation(self) -> [[f32; 3]; 3] {
+        match self {
+            ColorBlindness::Protanopia => {
+                [[0.0, 2.02344, -2.52581], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+            }
+            ColorBlindness::Deuteranopia => {
+                [[1.0, 0.0, 0.0], [0.494207, 0.0, 1.24827], [0.0, 0.0, 1.0]]
+            }
+            ColorBlindness::Tritanopia => {
+                [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [-0.395913, 0.801109, 0.0]]
+            }
+        }
+    }
+}
+
+const RGB_TO_LMS: [[f32; 3]; 3] = [
+    [17.8824, 43.5161, 4.11935],
+    [3.45565, 27.1554, 3.86714],
+    [0.0299566, 0.184309, 1.46709],
+];
+const LMS_TO_RGB: [[f32; 3]; 3] = [
+    [0.08094445, -0.13050441, 0.116721064],
+    [-0.010248533, 0.05401933, -0.11361471],
+    [-0.00036529693, -0.0041216146, 0.6935114],
+];
+/// Moves the color differences a color-blind eye loses into channels it still tells apart.
+const ERROR_SHIFT: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [0.7, 1.0, 0.0], [0.7, 0.0, 1.0]];
+
+/// Recolors the picture so that colors a color-blind player would confuse look different
+/// (daltonization).
+pub struct ColorBlindFilter {
+    /// The RGB transform doing the whole correction.
+    matrix: [[f32; 3]; 3],
+}
+
+impl ColorBlindFilter {
+    pub fn new(kind: ColorBlindness) -> ColorBlindFilter {
+        let mut filter = ColorBlindFilter {
+            matrix: [[0.0; 3]; 3],
+        };
+        filter.set_kind(kind);
+        filter
+    }
+
+    /// The correction is the color plus the shifted difference between it and what the eye sees.
+    pub fn set_kind(&mut self, kind: ColorBlindness) {
+        let seen = multiply(&LMS_TO_RGB, &multiply(&kind.simulation(), &RGB_TO_LMS));
+        let mut lost = [[0.0; 3]; 3];
+        for row in 0..3 {
+            for column in 0..3 {
+                let identity = if row == column { 1.0 } else { 0.0 };
+                lost[row][column] = identity - seen[row][column];
+            }
+        }
+        self.matrix = multiply(&ERROR_SHIFT, &lost);
+        for i in 0..3 {
+            self.matrix[i][i] += 1.0;
+        }
+    }
+}
+
+impl FrameFilter for ColorBlindFilter {
+    fn name(&self) -> &'static str {
+        "color_blind"
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.set_kind(config.color_blind);
+    }
+
+    fn process(&mut self, screen: &mut [u8; SCREEN_SIZE], _: Eye) {
+        let m = &self.matrix;
+        for pixel in screen.chunks_mut(3) {
+            let (b, g, r) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+            pixel[0] = clamp(m[2][0] * r + m[2][1] * g + m[2][2] * b);
+            pixel[1] = clamp(m[1][0] * r + m[1][1] * g + m[1][2] * b);
+            pixel[2] = clamp(m[0][0] * r + m[0][1] * g + m[0][2] * b);
+        }
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut product = [[0.0; 3]; 3];
+    for row in 0..3 {
+        for column in 0..3 {
+            product[row][column] = (0..3).map(|k| a[row][k] * b[k][column]).sum();
+        }
+    }
+    product
+}
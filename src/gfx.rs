@@ -2,7 +2,17 @@
 // Author: Patrick Walton
 //
 
-use sdl2::render::{Canvas, Texture, TextureAccess};
+use config::Config;
+use emulator::{FrameInfo, VideoSink};
+use error::Error;
+use filter::{self, FrameFilter};
+use glasses::Eye;
+use region::Region;
+
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, Texture, TextureAccess, TextureCreator};
+use sdl2::video::{Window, WindowContext};
 use sdl2::Sdl;
 
 /// Emulated screen width in pixels
@@ -10,16 +20,28 @@ const SCREEN_WIDTH: usize = 256;
 /// Emulated screen height in pixels
 const SCREEN_HEIGHT: usize = 240;
 /// Screen texture size in bytes
-const SCREEN_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
+pub const SCREEN_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
 
 const FONT_HEIGHT: usize = 10;
 const FONT_GLYPH_COUNT: usize = 95;
 const FONT_GLYPH_LENGTH: usize = FONT_GLYPH_COUNT * FONT_HEIGHT;
 
-const STATUS_LINE_PADDING: usize = 6;
-const STATUS_LINE_X: usize = STATUS_LINE_PADDING;
-const STATUS_LINE_Y: usize = SCREEN_HEIGHT - STATUS_LINE_PADDING - FONT_HEIGHT;
-const STATUS_LINE_PAUSE_DURATION: usize = 120; // in 1/60 of a second
+const OSD_PADDING: usize = 6;
+const OSD_LINE_SPACING: usize = 2;
+const OSD_MESSAGE_DURATION: usize = 120; // in 1/60 of a second
+const OSD_FADE_DURATION: usize = 30; // in 1/60 of a second
+const OSD_BAR_WIDTH: usize = 64;
+const OSD_BAR_HEIGHT: usize = 6;
+const OSD_BAR_SPACING: usize = 4;
+const OSD_GRAPH_HEIGHT: usize = 40;
+/// The size of a color swatch, including the gap after it.
+const OSD_SWATCH_SIZE: usize = 8;
+const OSD_SWATCHES_PER_ROW: usize = 16;
+/// Frame time shown at the top of the frame time graph, in seconds.
+const OSD_GRAPH_MAX_TIME: f64 = 2.0 / 60.0;
+
+/// How far `SmoothRefresh` may change the emulation speed: 0.5%.
+const MAX_SPEED_ADJUSTMENT: f64 = 0.005;
 
 //
 // PT Ronda Seven
@@ -227,134 +249,451 @@ const FONT_ADVANCES: [u8; FONT_GLYPH_COUNT] = [
 // Text output
 //
 
-enum GlyphColor {
-    White,
-    Black,
-}
+/// The width of a glyph's cell in the atlas. Glyphs are drawn from their cell's left edge.
+const ATLAS_CELL_WIDTH: usize = 8;
+const ATLAS_WIDTH: usize = FONT_GLYPH_COUNT * ATLAS_CELL_WIDTH;
 
-fn draw_glyph(
-    pixels: &mut [u8],
-    surface_width: usize,
-    x: isize,
-    y: isize,
-    color: GlyphColor,
-    glyph_index: usize,
-) {
-    let color_byte = match color {
-        GlyphColor::White => 0xff,
-        GlyphColor::Black => 0x00,
-    };
-    for y_index in 0..10 {
-        let row = FONT_GLYPHS[glyph_index * 10 + y_index as usize];
-        for x_index in 0..8 {
-            if ((row >> (7 - x_index) as usize) & 1) != 0 {
-                for channel in 0..3 {
-                    let mut index =
-                        (y + y_index) * (surface_width as isize) * 3 + (x + x_index) * 3;
-                    index += channel;
-
-                    if index >= 0 && index < pixels.len() as isize {
-                        pixels[index as usize] = color_byte;
+/// The RGBA pixels of the glyph atlas: every glyph of the font side by side in one row, white
+/// where the glyph is set and transparent elsewhere, so text can be colored and faded by the
+/// renderer.
+fn atlas_pixels() -> Vec<u8> {
+    let mut pixels = vec![0; ATLAS_WIDTH * FONT_HEIGHT * 4];
+    for glyph_index in 0..FONT_GLYPH_COUNT {
+        for y in 0..FONT_HEIGHT {
+            let row = FONT_GLYPHS[glyph_index * FONT_HEIGHT + y];
+            for x in 0..ATLAS_CELL_WIDTH {
+                if ((row >> (7 - x)) & 1) != 0 {
+                    let index = (y * ATLAS_WIDTH + glyph_index * ATLAS_CELL_WIDTH + x) * 4;
+                    for byte in &mut pixels[index..index + 4] {
+                        *byte = 0xff;
                     }
                 }
             }
         }
     }
+    pixels
+}
+
+/// Returns the width in pixels of `string` when drawn with `OsdCanvas::text`.
+pub fn text_width(string: &str) -> usize {
+    string
+        .bytes()
+        .filter_map(|byte| FONT_ADVANCES.get(byte.wrapping_sub(32) as usize))
+        .map(|&advance| advance as usize)
+        .sum()
 }
 
-pub fn draw_text(pixels: &mut [u8], surface_width: usize, mut x: isize, y: isize, string: &str) {
-    for i in 0..string.len() {
-        let glyph_index = (string.as_bytes()[i] - 32) as usize;
-        if glyph_index < FONT_ADVANCES.len() {
-            draw_glyph(
-                pixels,
-                surface_width,
-                x,
-                y + 1,
-                GlyphColor::Black,
-                glyph_index,
-            ); // Shadow
-            draw_glyph(pixels, surface_width, x, y, GlyphColor::White, glyph_index); // Main
-            x += FONT_ADVANCES[glyph_index] as isize;
+/// Draws OSD text and shapes straight to the window, over the emulated picture. Coordinates are
+/// in emulated screen pixels and scaled up to the window.
+pub struct OsdCanvas<'a> {
+    renderer: &'a mut Canvas<Window>,
+    atlas: &'a mut Texture<'static>,
+    scale: usize,
+}
+
+impl<'a> OsdCanvas<'a> {
+    /// Draws `string` in `color` (RGB) over a black shadow, with the given opacity.
+    pub fn text(&mut self, x: isize, y: isize, string: &str, color: [u8; 3], alpha: u8) {
+        self.glyphs(x, y + 1, string, [0, 0, 0], alpha); // Shadow
+        self.glyphs(x, y, string, color, alpha); // Main
+    }
+
+    fn glyphs(&mut self, mut x: isize, y: isize, string: &str, color: [u8; 3], alpha: u8) {
+        self.atlas.set_color_mod(color[0], color[1], color[2]);
+        self.atlas.set_alpha_mod(alpha);
+        for byte in string.bytes() {
+            let glyph_index = byte.wrapping_sub(32) as usize;
+            if let Some(&advance) = FONT_ADVANCES.get(glyph_index) {
+                let source = Rect::new(
+                    (glyph_index * ATLAS_CELL_WIDTH) as i32,
+                    0,
+                    ATLAS_CELL_WIDTH as u32,
+                    FONT_HEIGHT as u32,
+                );
+                let destination = self.rect(x, y, ATLAS_CELL_WIDTH, FONT_HEIGHT);
+                let _ = self.renderer.copy(self.atlas, source, destination);
+                x += advance as isize;
+            }
+        }
+    }
+
+    /// Fills a rectangle with a gray level, blended with the given opacity.
+    pub fn fill_rect(
+        &mut self,
+        x: isize,
+        y: isize,
+        width: usize,
+        height: usize,
+        color: u8,
+        alpha: u8,
+    ) {
+        self.fill_rgb_rect(x, y, width, height, [color; 3], alpha);
+    }
+
+    /// Fills a rectangle with an RGB color, blended with the given opacity.
+    pub fn fill_rgb_rect(
+        &mut self,
+        x: isize,
+        y: isize,
+        width: usize,
+        height: usize,
+        color: [u8; 3],
+        alpha: u8,
+    ) {
+        // SDL rounds empty rectangles up to a pixel.
+        if width == 0 || height == 0 {
+            return;
         }
+        let [r, g, b] = color;
+        self.renderer.set_draw_color(Color::RGBA(r, g, b, alpha));
+        let rect = self.rect(x, y, width, height);
+        let _ = self.renderer.fill_rect(rect);
     }
-}
 
-#[derive(PartialEq, Eq)]
-enum StatusLineAnimation {
-    Idle,
-    Pausing(usize),
-    SlidingOut(usize),
+    fn rect(&self, x: isize, y: isize, width: usize, height: usize) -> Rect {
+        let scale = self.scale as isize;
+        Rect::new(
+            (x * scale) as i32,
+            (y * scale) as i32,
+            (width * self.scale) as u32,
+            (height * self.scale) as u32,
+        )
+    }
 }
 
-use self::StatusLineAnimation::*;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::render::TextureCreator;
-use sdl2::video::Window;
-use sdl2::video::WindowContext;
+//
+// On-screen display
+//
+
+/// The corner of the screen an OSD element is drawn in. Elements sharing a corner are stacked
+/// in the order they were first shown.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OsdAnchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// At a point of the screen, such as over a sprite, without stacking.
+    At(isize, isize),
+}
 
-struct StatusLineText {
-    string: String,
-    animation: StatusLineAnimation,
+/// What an OSD element displays.
+#[derive(Clone, PartialEq, Debug)]
+pub enum OsdContent {
+    Text(String),
+    /// Text in an RGB color.
+    ColoredText(String, [u8; 3]),
+    /// A label followed by a bar filled to a percentage.
+    Bar(String, u8),
+    /// A bar graph of `(emulation, render)` frame times in seconds, oldest first, one pixel per
+    /// frame. A line marks the time budget of a 60 Hz frame.
+    FrameTimes(Vec<(f64, f64)>),
+    /// RGB color swatches in rows of 16, with the one at the index outlined.
+    Swatches(Vec<[u8; 3]>, usize),
+    /// A white rectangle outline of a width and height.
+    Outline(usize, usize),
+    /// A crosshair with arms of a length in an RGB color, around a pixel left clear at its center.
+    Crosshair(usize, [u8; 3]),
 }
 
-impl StatusLineText {
-    fn new() -> StatusLineText {
-        StatusLineText {
-            string: "".to_string(),
-            animation: Idle,
+impl OsdContent {
+    fn width(&self) -> usize {
+        match *self {
+            OsdContent::Text(ref text) | OsdContent::ColoredText(ref text, _) => text_width(text),
+            OsdContent::Bar(ref label, _) => text_width(label) + OSD_BAR_SPACING + OSD_BAR_WIDTH,
+            OsdContent::FrameTimes(ref times) => times.len(),
+            OsdContent::Swatches(ref colors, _) => {
+                colors.len().min(OSD_SWATCHES_PER_ROW) * OSD_SWATCH_SIZE
+            }
+            OsdContent::Outline(width, _) => width,
+            OsdContent::Crosshair(arm, _) => arm * 2 + 1,
         }
     }
 
-    fn set(&mut self, string: String) {
-        self.string = string;
-        self.animation = Pausing(STATUS_LINE_PAUSE_DURATION);
+    fn height(&self) -> usize {
+        match *self {
+            OsdContent::Text(_) | OsdContent::ColoredText(..) | OsdContent::Bar(..) => FONT_HEIGHT,
+            OsdContent::FrameTimes(_) => OSD_GRAPH_HEIGHT,
+            OsdContent::Swatches(ref colors, _) => {
+                colors.len().div_ceil(OSD_SWATCHES_PER_ROW) * OSD_SWATCH_SIZE
+            }
+            OsdContent::Outline(_, height) => height,
+            OsdContent::Crosshair(arm, _) => arm * 2 + 1,
+        }
     }
 
-    fn tick(&mut self) {
-        self.animation = match self.animation {
-            Idle => Idle,
-            Pausing(0) => SlidingOut(STATUS_LINE_Y),
-            Pausing(time) => Pausing(time - 1),
-            SlidingOut(SCREEN_HEIGHT) => Idle,
-            SlidingOut(y) => SlidingOut(y + 1),
+    fn render(&self, canvas: &mut OsdCanvas, x: isize, y: isize, alpha: u8) {
+        match *self {
+            OsdContent::Text(ref text) => canvas.text(x, y, text, [0xff; 3], alpha),
+            OsdContent::ColoredText(ref text, color) => canvas.text(x, y, text, color, alpha),
+            OsdContent::Bar(ref label, percent) => {
+                canvas.text(x, y, label, [0xff; 3], alpha);
+
+                let bar_x = x + (text_width(label) + OSD_BAR_SPACING) as isize;
+                let bar_y = y + ((FONT_HEIGHT - OSD_BAR_HEIGHT) / 2) as isize;
+                let filled = OSD_BAR_WIDTH * percent.min(100) as usize / 100;
+                canvas.fill_rect(
+                    bar_x,
+                    bar_y + 1,
+                    OSD_BAR_WIDTH,
+                    OSD_BAR_HEIGHT,
+                    0x00,
+                    alpha / 2,
+                );
+                canvas.fill_rect(bar_x, bar_y, filled, OSD_BAR_HEIGHT, 0xff, alpha);
+            }
+            OsdContent::FrameTimes(ref times) => {
+                let bottom = y + OSD_GRAPH_HEIGHT as isize;
+                let scale = |time: f64| {
+                    ((time / OSD_GRAPH_MAX_TIME * OSD_GRAPH_HEIGHT as f64) as usize)
+                        .min(OSD_GRAPH_HEIGHT)
+                };
+                canvas.fill_rect(x, y, times.len(), OSD_GRAPH_HEIGHT, 0x00, alpha / 2);
+                for (i, &(emulation, render)) in times.iter().enumerate() {
+                    let x = x + i as isize;
+                    let emulation_height = scale(emulation);
+                    let total_height = scale(emulation + render);
+                    canvas.fill_rect(
+                        x,
+                        bottom - emulation_height as isize,
+                        1,
+                        emulation_height,
+                        0x80,
+                        alpha,
+                    );
+                    canvas.fill_rect(
+                        x,
+                        bottom - total_height as isize,
+                        1,
+                        total_height - emulation_height,
+                        0xff,
+                        alpha,
+                    );
+                }
+                let budget_y = bottom - scale(1.0 / 60.0) as isize;
+                canvas.fill_rect(x, budget_y, times.len(), 1, 0xff, alpha / 2);
+            }
+            OsdContent::Swatches(ref colors, selected) => {
+                let size = OSD_SWATCH_SIZE - 1;
+                let position = |i: usize| {
+                    (
+                        x + (i % OSD_SWATCHES_PER_ROW * OSD_SWATCH_SIZE) as isize,
+                        y + (i / OSD_SWATCHES_PER_ROW * OSD_SWATCH_SIZE) as isize,
+                    )
+                };
+                for (i, &color) in colors.iter().enumerate() {
+                    let (swatch_x, swatch_y) = position(i);
+                    canvas.fill_rgb_rect(swatch_x, swatch_y, size, size, color, alpha);
+                }
+                // The outline goes around the selected swatch, over the gaps next to it.
+                if let Some(&color) = colors.get(selected) {
+                    let (swatch_x, swatch_y) = position(selected);
+                    canvas.fill_rect(swatch_x - 1, swatch_y - 1, size + 2, size + 2, 0xff, alpha);
+                    canvas.fill_rgb_rect(swatch_x, swatch_y, size, size, color, alpha);
+                }
+            }
+            OsdContent::Outline(width, height) => {
+                let (right, bottom) = (x + width as isize - 1, y + height as isize - 1);
+                canvas.fill_rect(x, y, width, 1, 0xff, alpha);
+                canvas.fill_rect(x, bottom, width, 1, 0xff, alpha);
+                canvas.fill_rect(x, y, 1, height, 0xff, alpha);
+                canvas.fill_rect(right, y, 1, height, 0xff, alpha);
+            }
+            OsdContent::Crosshair(arm, color) => {
+                let (center_x, center_y) = (x + arm as isize, y + arm as isize);
+                canvas.fill_rgb_rect(x, center_y, arm, 1, color, alpha);
+                canvas.fill_rgb_rect(center_x + 1, center_y, arm, 1, color, alpha);
+                canvas.fill_rgb_rect(center_x, y, 1, arm, color, alpha);
+                canvas.fill_rgb_rect(center_x, center_y + 1, 1, arm, color, alpha);
+            }
         }
     }
+}
 
-    fn render(&self, pixels: &mut [u8]) {
-        if self.animation == Idle {
-            return;
+struct OsdElement {
+    id: &'static str,
+    anchor: OsdAnchor,
+    content: OsdContent,
+    /// Frames left until the element disappears, or `None` if it stays until removed.
+    remaining: Option<usize>,
+}
+
+impl OsdElement {
+    fn alpha(&self) -> u8 {
+        match self.remaining {
+            Some(remaining) if remaining < OSD_FADE_DURATION => {
+                (remaining * 255 / OSD_FADE_DURATION) as u8
+            }
+            _ => 255,
         }
-        let y = match self.animation {
-            Idle => panic!(),
-            SlidingOut(y) => y as isize,
-            Pausing(_) => STATUS_LINE_Y as isize,
-        };
-        draw_text(
-            pixels,
-            SCREEN_WIDTH,
-            STATUS_LINE_X as isize,
-            y,
-            &self.string,
-        );
     }
 }
 
-pub struct StatusLine {
-    text: StatusLineText,
+/// A retained set of text, bar and graph elements drawn over the emulated screen. Elements are keyed by
+/// an ID, so setting an element again updates it in place.
+pub struct Osd {
+    elements: Vec<OsdElement>,
+}
+
+impl Default for Osd {
+    fn default() -> Osd {
+        Osd::new()
+    }
 }
 
-impl StatusLine {
-    pub fn new() -> StatusLine {
-        StatusLine {
-            text: StatusLineText::new(),
+impl Osd {
+    pub fn new() -> Osd {
+        Osd {
+            elements: Vec::new(),
+        }
+    }
+
+    /// Shows a short-lived message in the bottom left corner, replacing the previous message.
+    pub fn message(&mut self, text: String) {
+        self.set(
+            "message",
+            OsdAnchor::BottomLeft,
+            OsdContent::Text(text),
+            Some(OSD_MESSAGE_DURATION),
+        );
+    }
+
+    /// Shows a short-lived percentage bar in the top right corner.
+    pub fn bar(&mut self, id: &'static str, label: String, percent: u8) {
+        self.set(
+            id,
+            OsdAnchor::TopRight,
+            OsdContent::Bar(label, percent),
+            Some(OSD_MESSAGE_DURATION),
+        );
+    }
+
+    /// Shows the element with the given ID for `duration` frames (forever if `None`), fading it
+    /// out at the end.
+    pub fn set(
+        &mut self,
+        id: &'static str,
+        anchor: OsdAnchor,
+        content: OsdContent,
+        duration: Option<usize>,
+    ) {
+        let element = OsdElement {
+            id,
+            anchor,
+            content,
+            remaining: duration,
+        };
+        match self.elements.iter_mut().find(|element| element.id == id) {
+            Some(existing) => *existing = element,
+            None => self.elements.push(element),
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.elements.retain(|element| element.id != id);
+    }
+
+    pub fn is_shown(&self, id: &str) -> bool {
+        self.elements.iter().any(|element| element.id == id)
+    }
+
+    /// Advances the fade animations by a frame.
+    pub fn tick(&mut self) {
+        for element in &mut self.elements {
+            if let Some(ref mut remaining) = element.remaining {
+                *remaining = remaining.saturating_sub(1);
+            }
+        }
+        self.elements.retain(|element| element.remaining != Some(0));
+    }
+
+    /// Draws the elements over a screen of `width` by `height` emulated pixels.
+    pub fn render(&self, canvas: &mut OsdCanvas, (width, height): (usize, usize)) {
+        for &anchor in &[
+            OsdAnchor::TopLeft,
+            OsdAnchor::TopRight,
+            OsdAnchor::BottomLeft,
+            OsdAnchor::BottomRight,
+        ] {
+            let elements = self
+                .elements
+                .iter()
+                .filter(|element| element.anchor == anchor);
+            let mut offset = 0;
+            for element in elements {
+                let element_width = element.content.width();
+                let element_height = element.content.height();
+                let x = match anchor {
+                    OsdAnchor::TopLeft | OsdAnchor::BottomLeft => OSD_PADDING,
+                    OsdAnchor::TopRight | OsdAnchor::BottomRight => {
+                        width.saturating_sub(OSD_PADDING + element_width)
+                    }
+                    OsdAnchor::At(..) => continue,
+                };
+                let y = match anchor {
+                    OsdAnchor::TopLeft | OsdAnchor::TopRight => (OSD_PADDING + offset) as isize,
+                    OsdAnchor::BottomLeft | OsdAnchor::BottomRight => {
+                        height as isize - (OSD_PADDING + offset + element_height) as isize
+                    }
+                    OsdAnchor::At(..) => continue,
+                };
+                offset += element_height + OSD_LINE_SPACING;
+                element
+                    .content
+                    .render(canvas, x as isize, y, element.alpha());
+            }
         }
+        for element in &self.elements {
+            if let OsdAnchor::At(x, y) = element.anchor {
+                element.content.render(canvas, x, y, element.alpha());
+            }
+        }
+    }
+}
+//
+// Frame blending
+//
+
+/// Averages each frame with the one before it, which turns sprites that games flicker on and off
+/// every other frame, for transparency or to show more than eight on a line, into steady
+/// half-transparent ones.
+pub struct FrameBlend {
+    pub enabled: bool,
+    /// The previous frame as drawn, before blending, if blending was on for it.
+    previous: Option<Box<[u8; SCREEN_SIZE]>>,
+}
+
+impl Default for FrameBlend {
+    fn default() -> FrameBlend {
+        FrameBlend::new()
     }
-    pub fn set(&mut self, new_text: String) {
-        self.text.set(new_text);
+}
+
+impl FrameBlend {
+    pub fn new() -> FrameBlend {
+        FrameBlend {
+            enabled: false,
+            previous: None,
+        }
     }
-    pub fn render(&self, pixels: &mut [u8]) {
-        self.text.render(pixels);
+
+    pub fn process(&mut self, screen: &mut [u8; SCREEN_SIZE]) {
+        if !self.enabled {
+            self.previous = None;
+            return;
+        }
+        let previous = match self.previous {
+            Some(ref mut previous) => previous,
+            None => {
+                self.previous = Some(Box::new(*screen));
+                return;
+            }
+        };
+        for (pixel, previous) in screen.iter_mut().zip(previous.iter_mut()) {
+            let current = *pixel;
+            *pixel = (current as u16 + *previous as u16).div_ceil(2) as u8;
+            *previous = current;
+        }
     }
 }
 
@@ -362,7 +701,7 @@ impl StatusLine {
 // Screen scaling
 //
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Scale {
     Scale1x,
     Scale2x,
@@ -381,23 +720,101 @@ impl Scale {
     }
 }
 
+/// Whether to run slightly off speed so that every emulated frame is shown for exactly one refresh
+/// of the display, rather than the odd frame being dropped or shown twice.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SmoothRefresh {
+    Off,
+    /// Match the refresh rate the display reports.
+    Auto,
+    /// Match a refresh rate in Hz.
+    Rate(f64),
+}
+
+impl SmoothRefresh {
+    pub fn from_name(name: &str) -> Option<SmoothRefresh> {
+        match name {
+            "off" => Some(SmoothRefresh::Off),
+            "auto" => Some(SmoothRefresh::Auto),
+            _ => match name.parse() {
+                Ok(rate) if rate > 0.0 => Some(SmoothRefresh::Rate(rate)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// How far the picture is turned clockwise, for homebrew made for a monitor on its side (TATE).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    Rotate0,
+    Rotate90,
+    Rotate270,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: &str) -> Option<Rotation> {
+        match degrees {
+            "0" => Some(Rotation::Rotate0),
+            "90" => Some(Rotation::Rotate90),
+            "270" => Some(Rotation::Rotate270),
+            _ => None,
+        }
+    }
+
+    pub fn degrees(self) -> u32 {
+        match self {
+            Rotation::Rotate0 => 0,
+            Rotation::Rotate90 => 90,
+            Rotation::Rotate270 => 270,
+        }
+    }
+
+    /// The rotation after turning another 90 degrees, skipping upside down.
+    pub fn next(self) -> Rotation {
+        match self {
+            Rotation::Rotate0 => Rotation::Rotate90,
+            Rotation::Rotate90 => Rotation::Rotate270,
+            Rotation::Rotate270 => Rotation::Rotate0,
+        }
+    }
+
+    fn is_sideways(self) -> bool {
+        self != Rotation::Rotate0
+    }
+}
+
 pub struct Gfx {
     pub renderer: Box<Canvas<Window>>,
     pub texture: Texture<'static>,
+    /// The OSD font, see `atlas_pixels`.
+    atlas: Texture<'static>,
     pub scale: Scale,
-    pub status_line: StatusLine,
+    pub rotation: Rotation,
+    pub osd: Osd,
+    /// The post-processing run on each frame, in order.
+    pub filters: Vec<Box<dyn FrameFilter>>,
+    /// Keeps the renderer alive until the textures are destroyed, being dropped after them.
     _texture_creator: TextureCreator<WindowContext>,
 }
 
 impl Gfx {
-    pub fn new(scale: Scale, sdl: Option<Sdl>) -> (Gfx, Sdl) {
-        // FIXME: Handle SDL better
-
+    /// Opens a window titled with the name of the game. Without `vsync`, presenting doesn't wait
+    /// for the display's refresh.
+    pub fn new(
+        game: &str,
+        scale: Scale,
+        vsync: bool,
+        sdl: Option<Sdl>,
+    ) -> Result<(Gfx, Sdl), Error> {
         let is_original = sdl.is_none();
-        let title = if is_original { "nes - cpu0" } else { "nes - cpu1" };
+        let title = format!("{} - {}", game, if is_original { "cpu0" } else { "cpu1" });
 
-        let sdl = sdl.unwrap_or_else(|| sdl2::init().unwrap());
-        let video_subsystem = sdl.video().unwrap();
+        let sdl = match sdl {
+            Some(sdl) => sdl,
+            None => sdl2::init().map_err(Error::Sdl)?,
+        };
+        let video_subsystem = sdl.video().map_err(Error::Sdl)?;
 
         let mut window_builder = video_subsystem.window(
             &title,
@@ -409,14 +826,16 @@ impl Gfx {
 
         let root_x = 3320;
         let position_x = if is_original { root_x } else { root_x + 256 + 10 };
-        let window = window_builder.position(position_x, 800).build().unwrap();
-
-        let renderer = window
-            .into_canvas()
-            .accelerated()
-            .present_vsync()
+        let window = window_builder
+            .position(position_x, 800)
             .build()
-            .unwrap();
+            .map_err(|err| Error::Sdl(err.to_string()))?;
+
+        let mut canvas = window.into_canvas().accelerated();
+        if vsync {
+            canvas = canvas.present_vsync();
+        }
+        let mut renderer = canvas.build().map_err(|err| Error::Sdl(err.to_string()))?;
         let texture_creator = renderer.texture_creator();
         let texture_creator_pointer = &texture_creator as *const TextureCreator<WindowContext>;
         let texture = unsafe { &*texture_creator_pointer }
@@ -426,37 +845,204 @@ impl Gfx {
                 SCREEN_WIDTH as u32,
                 SCREEN_HEIGHT as u32,
             )
-            .unwrap();
+            .map_err(|err| Error::Sdl(err.to_string()))?;
+        let mut atlas = unsafe { &*texture_creator_pointer }
+            .create_texture_static(
+                PixelFormatEnum::ABGR8888,
+                ATLAS_WIDTH as u32,
+                FONT_HEIGHT as u32,
+            )
+            .map_err(|err| Error::Sdl(err.to_string()))?;
+        atlas
+            .update(None, &atlas_pixels(), ATLAS_WIDTH * 4)
+            .map_err(|err| Error::Sdl(err.to_string()))?;
+        atlas.set_blend_mode(BlendMode::Blend);
+        renderer.set_blend_mode(BlendMode::Blend);
 
-        (
+        Ok((
             Gfx {
                 renderer: Box::new(renderer),
                 texture,
+                atlas,
                 scale,
-                status_line: StatusLine::new(),
+                rotation: Rotation::Rotate0,
+                osd: Osd::new(),
+                filters: Vec::new(),
                 _texture_creator: texture_creator,
             },
             sdl,
-        )
+        ))
     }
 
     pub fn tick(&mut self) {
-        self.status_line.text.tick();
+        self.osd.tick();
+    }
+
+    /// Resizes the window for a new scale factor.
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+        self.resize();
+    }
+
+    /// Turns the picture, resizing the window to fit it.
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+        self.resize();
+    }
+
+    fn resize(&mut self) {
+        let (width, height) = self.picture_size();
+        let (width, height) = if self.rotation.is_sideways() {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        let _ = self.renderer.window_mut().set_size(width, height);
+    }
+
+    /// The size of the scaled picture before rotation.
+    fn picture_size(&self) -> (u32, u32) {
+        (
+            (SCREEN_WIDTH * self.scale.factor()) as u32,
+            (SCREEN_HEIGHT * self.scale.factor()) as u32,
+        )
+    }
+
+    /// The refresh rate of the window's display in Hz, or `None` if it's unknown. SDL rounds rates
+    /// down to whole numbers, so 59 Hz is taken to be the common 59.94 Hz.
+    pub fn refresh_rate(&self) -> Option<f64> {
+        match self.renderer.window().display_mode() {
+            Ok(mode) if mode.refresh_rate == 59 => Some(59.94),
+            Ok(mode) if mode.refresh_rate > 0 => Some(mode.refresh_rate as f64),
+            _ => None,
+        }
+    }
+
+    /// The speed, as a multiple of normal, at which a console of `region` puts out a frame per
+    /// refresh of the display. 1 when `smooth` is off, or when matching the display would take
+    /// more than a small adjustment.
+    pub fn smooth_speed(&self, smooth: SmoothRefresh, region: Region) -> f64 {
+        let rate = match smooth {
+            SmoothRefresh::Off => None,
+            SmoothRefresh::Auto => self.refresh_rate(),
+            SmoothRefresh::Rate(rate) => Some(rate),
+        };
+        match rate.map(|rate| rate / region.frame_rate()) {
+            Some(speed) if (speed - 1.0).abs() <= MAX_SPEED_ADJUSTMENT => speed,
+            _ => 1.0,
+        }
+    }
+
+    /// The screen pixel under a point of the window, or `None` if the point is off the picture.
+    pub fn screen_point(&self, (x, y): (i32, i32)) -> Option<(usize, usize)> {
+        let factor = self.scale.factor() as i32;
+        let (x, y) = (x / factor, y / factor);
+        let (width, height) = (SCREEN_WIDTH as i32, SCREEN_HEIGHT as i32);
+        // Undo the clockwise turn of the picture.
+        let (x, y) = match self.rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (y, height - 1 - x),
+            Rotation::Rotate270 => (width - 1 - y, x),
+        };
+        if x < 0 || y < 0 || x >= width || y >= height {
+            return None;
+        }
+        Some((x as usize, y as usize))
+    }
+
+    /// Where a screen pixel shows in the overlay, which is laid out on the window as it's turned;
+    /// the reverse of `screen_point`, in emulated pixels.
+    pub fn osd_point(&self, (x, y): (usize, usize)) -> (isize, isize) {
+        let (x, y) = (x as isize, y as isize);
+        let (width, height) = (SCREEN_WIDTH as isize, SCREEN_HEIGHT as isize);
+        match self.rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (height - 1 - y, x),
+            Rotation::Rotate270 => (y, width - 1 - x),
+        }
+    }
+
+    /// Draws a crosshair with arms of `arm` pixels centered on the screen pixel a pointer device
+    /// aims at, or removes it if the device points away from the screen or `arm` is 0.
+    pub fn show_crosshair(&mut self, aim: Option<(usize, usize)>, arm: usize, color: [u8; 3]) {
+        match aim {
+            Some(aim) if arm > 0 => {
+                let (x, y) = self.osd_point(aim);
+                let anchor = OsdAnchor::At(x - arm as isize, y - arm as isize);
+                let content = OsdContent::Crosshair(arm, color);
+                self.osd.set("crosshair", anchor, content, None);
+            }
+            _ => self.osd.remove("crosshair"),
+        }
+    }
+
+    /// Rebuilds the filter chain in the order of the `filters` setting and configures each filter.
+    /// Filters already in the chain keep their state, such as the previous frame for blending.
+    pub fn set_filters(&mut self, config: &Config) {
+        let mut filters = Vec::new();
+        for &name in &config.filters {
+            let existing = self.filters.iter().position(|filter| filter.name() == name);
+            let filter = match existing {
+                Some(index) => Some(self.filters.remove(index)),
+                None => filter::create(name),
+            };
+            filters.extend(filter);
+        }
+        for filter in &mut filters {
+            filter.configure(config);
+        }
+        self.filters = filters;
     }
 
-    /// Copies the overlay onto the given screen and displays it to the SDL window.
-    pub fn composite(&mut self, ppu_screen: &mut [u8; SCREEN_SIZE]) {
-        self.status_line.render(ppu_screen);
+    /// Post-processes the given screen, drawn for `eye` of the 3D glasses, displays it to the SDL
+    /// window and draws the overlay over it.
+    pub fn composite(&mut self, ppu_screen: &mut [u8; SCREEN_SIZE], eye: Eye) {
+        for filter in &mut self.filters {
+            filter.process(ppu_screen, eye);
+        }
         self.blit(ppu_screen);
         self.renderer.clear();
-        let _ = self.renderer.copy(&self.texture, None, None);
+        if self.rotation.is_sideways() {
+            // Rotation is about the center of the destination, so center the unrotated picture on
+            // the window; turned, it fills the window exactly.
+            let (width, height) = self.picture_size();
+            let offset = (width as i32 - height as i32) / 2;
+            let destination = Rect::new(-offset, offset, width, height);
+            let angle = self.rotation.degrees() as f64;
+            let (renderer, texture) = (&mut self.renderer, &self.texture);
+            let _ = renderer.copy_ex(texture, None, destination, angle, None, false, false);
+        } else {
+            let _ = self.renderer.copy(&self.texture, None, None);
+        }
+
+        // The overlay stays upright and is laid out on the window as it's turned.
+        let size = if self.rotation.is_sideways() {
+            (SCREEN_HEIGHT, SCREEN_WIDTH)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        };
+        let mut canvas = OsdCanvas {
+            renderer: &mut self.renderer,
+            atlas: &mut self.atlas,
+            scale: self.scale.factor(),
+        };
+        self.osd.render(&mut canvas, size);
         self.renderer.present();
     }
 
     /// Updates the window texture with new screen data.
     fn blit(&mut self, ppu_screen: &[u8; SCREEN_SIZE]) {
-        self.texture
-            .update(None, ppu_screen, SCREEN_WIDTH * 3)
-            .unwrap()
+        let _ = self.texture.update(None, ppu_screen, SCREEN_WIDTH * 3);
+    }
+}
+
+/// As a sink, the window shows each frame under the on-screen display. The 3D glasses filter takes
+/// every frame for the left eye's.
+impl VideoSink for Gfx {
+    fn frame(&mut self, info: &FrameInfo) {
+        let mut screen = Box::new([0; SCREEN_SIZE]);
+        screen.copy_from_slice(info.screen);
+        self.tick();
+        self.composite(&mut screen, Eye::Left);
     }
 }
@@ -0,0 +1,126 @@
+//! The Famicom 3D System: LCD shutter glasses plugged into the expansion port, which black out one
+//! eye at a time in step with OUT1 ($4016 bit 1). Supporting games, such as Famicom Grand Prix II
+//! and Highway Star, draw alternate frames for each eye.
+//!
+//! Without the glasses the picture flickers between the two views, so the frames of each eye are
+//! kept and combined into one picture instead: a red-cyan anaglyph, or the two views squeezed
+//! side by side for cross-eyed or 3D TV viewing.
+
+use gfx::SCREEN_SIZE;
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// How many frames in a row the game may show the same eye before the picture is left alone, so
+/// that games that don't drive the glasses, or title screens of games that do, look normal.
+const IDLE_FRAMES: u32 = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+impl Eye {
+    /// The eye whose shutter is open for a level of OUT1.
+    pub fn from_shutter(out1: bool) -> Eye {
+        if out1 {
+            Eye::Right
+        } else {
+            Eye::Left
+        }
+    }
+}
+
+/// How the two views are combined.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StereoMode {
+    /// Show frames as they come, flicker and all.
+    Off,
+    /// Red for the left eye and cyan for the right.
+    Anaglyph,
+    /// The left eye's view on the left half of the screen and the right eye's on the right.
+    SideBySide,
+}
+
+impl StereoMode {
+    pub fn from_name(name: &str) -> Option<StereoMode> {
+        match name {
+            "off" => Some(StereoMode::Off),
+            "anaglyph" => Some(StereoMode::Anaglyph),
+            "side_by_side" => Some(StereoMode::SideBySide),
+            _ => None,
+        }
+    }
+}
+
+pub struct Glasses {
+    pub mode: StereoMode,
+    left: Box<[u8; SCREEN_SIZE]>,
+    right: Box<[u8; SCREEN_SIZE]>,
+    last_eye: Eye,
+    /// How many frames in a row have been for `last_eye`.
+    same_eye_frames: u32,
+}
+
+impl Glasses {
+    pub fn new(mode: StereoMode) -> Glasses {
+        Glasses {
+            mode,
+            left: Box::new([0; SCREEN_SIZE]),
+            right: Box::new([0; SCREEN_SIZE]),
+            last_eye: Eye::Left,
+            same_eye_frames: IDLE_FRAMES,
+        }
+    }
+
+    /// Takes a finished frame drawn for `eye` and replaces it with the combined picture of the
+    /// latest frame for each eye. The screen is BGR.
+    pub fn process(&mut self, screen: &mut [u8; SCREEN_SIZE], eye: Eye) {
+        if eye == self.last_eye {
+            self.same_eye_frames = self.same_eye_frames.saturating_add(1);
+        } else {
+            self.last_eye = eye;
+            self.same_eye_frames = 0;
+        }
+        if self.mode == StereoMode::Off {
+            return;
+        }
+        {
+            let view = match eye {
+                Eye::Left => &mut self.left,
+                Eye::Right => &mut self.right,
+            };
+            view.copy_from_slice(&screen[..]);
+        }
+        if self.same_eye_frames >= IDLE_FRAMES {
+            return;
+        }
+
+        match self.mode {
+            StereoMode::Off => {}
+            StereoMode::Anaglyph => {
+                for (i, pixel) in screen.chunks_mut(3).enumerate() {
+                    let offset = i * 3;
+                    pixel[0] = self.right[offset];
+                    pixel[1] = self.right[offset + 1];
+                    pixel[2] = self.left[offset + 2];
+                }
+            }
+            StereoMode::SideBySide => {
+                let half = SCREEN_WIDTH / 2;
+                for y in 0..SCREEN_HEIGHT {
+                    let row = y * SCREEN_WIDTH * 3;
+                    for x in 0..SCREEN_WIDTH {
+                        let (view, source_x) = if x < half {
+                            (&self.left, x * 2)
+                        } else {
+                            (&self.right, (x - half) * 2)
+                        };
+                        let source = row + source_x * 3;
+                        let dest = row + x * 3;
+                        screen[dest..dest + 3].copy_from_slice(&view[source..source + 3]);
+                    }
+                }
+            }
+        }
+    }
+}
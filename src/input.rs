@@ -2,13 +2,26 @@
 // Author: Patrick Walton
 //
 
+use gfx::Rotation;
 use mem::Mem;
+use movie::LatchMovie;
+use util::Save;
+use zapper::Zapper;
 
 use sdl2::event::Event;
+use sdl2::joystick::{HatState, Joystick};
 use sdl2::keyboard::Keycode;
-use sdl2::Sdl;
+use sdl2::mouse::MouseButton;
+use sdl2::{JoystickSubsystem, Sdl};
 
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::ops::Deref;
+use std::rc::Rc;
+
+// SDL's audio device events, which this version of the bindings doesn't know.
+const SDL_AUDIODEVICEADDED: u32 = 0x1100;
+const SDL_AUDIODEVICEREMOVED: u32 = 0x1101;
 
 //
 // The "strobe state": the order in which the NES reads the buttons.
@@ -83,21 +96,330 @@ pub struct GamePadState {
     strobe_state: StrobeState,
 }
 
+impl GamePadState {
+    /// Returns the buttons held, one bit per button as in `set_bits`.
+    pub fn bits(&self) -> u8 {
+        [
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+            self.up,
+            self.down,
+            self.left,
+            self.right,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (button, &held)| bits | (held as u8) << button)
+    }
+
+    /// Sets every button from a byte with one bit per button, in the order the NES reads them:
+    /// A in bit 0 through Right in bit 7.
+    pub fn set_bits(&mut self, bits: u8) {
+        let held = |button: u8| bits & (1 << button) != 0;
+        self.a = held(STROBE_STATE_A);
+        self.b = held(STROBE_STATE_B);
+        self.select = held(STROBE_STATE_SELECT);
+        self.start = held(STROBE_STATE_START);
+        self.up = held(STROBE_STATE_UP);
+        self.down = held(STROBE_STATE_DOWN);
+        self.left = held(STROBE_STATE_LEFT);
+        self.right = held(STROBE_STATE_RIGHT);
+    }
+}
+
+/// The names of the buttons, by bit in `GamePadState::set_bits`.
+const BUTTON_NAMES: [&str; 8] = ["a", "b", "select", "start", "up", "down", "left", "right"];
+
+/// Returns the bit of the button with the given name in `GamePadState::set_bits`.
+pub fn button_bit(name: &str) -> Option<u8> {
+    match name {
+        "a" => Some(STROBE_STATE_A),
+        "b" => Some(STROBE_STATE_B),
+        "select" => Some(STROBE_STATE_SELECT),
+        "start" => Some(STROBE_STATE_START),
+        "up" => Some(STROBE_STATE_UP),
+        "down" => Some(STROBE_STATE_DOWN),
+        "left" => Some(STROBE_STATE_LEFT),
+        "right" => Some(STROBE_STATE_RIGHT),
+        _ => None,
+    }
+}
+
+//
+// Auto-fire
+//
+
+/// An auto-fire pattern: while the button is held, it is pressed for `on` frames, then released
+/// for `off` frames, repeating. `phase` shifts the pattern by that many frames, so that buttons
+/// can fire in turn.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FirePattern {
+    pub on: u32,
+    pub off: u32,
+    pub phase: u32,
+}
+
+impl FirePattern {
+    /// Parses `<on>/<off>`, optionally followed by `+<phase>`, e.g. `2/1` or `1/1+1`.
+    pub fn parse(text: &str) -> Result<FirePattern, String> {
+        let error = || {
+            format!(
+                "expected `<on>/<off>` or `<on>/<off>+<phase>`, found `{}`",
+                text
+            )
+        };
+        let (timing, phase) = match text.find('+') {
+            Some(index) => (
+                &text[..index],
+                text[index + 1..].trim().parse().map_err(|_| error())?,
+            ),
+            None => (text, 0),
+        };
+        let mut frames = timing.split('/').map(|frames| frames.trim().parse::<u32>());
+        match (frames.next(), frames.next(), frames.next()) {
+            (Some(Ok(on)), Some(Ok(off)), None) if on > 0 && off > 0 => {
+                Ok(FirePattern { on, off, phase })
+            }
+            _ => Err(error()),
+        }
+    }
+
+    /// Whether a held button reads as pressed on `frame`.
+    pub fn pressed(&self, frame: u32) -> bool {
+        frame.wrapping_add(self.phase) % (self.on + self.off) < self.on
+    }
+}
+
+//
+// Keyboard bindings for the game pad
+//
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Bindings {
+    pub left: Keycode,
+    pub down: Keycode,
+    pub up: Keycode,
+    pub right: Keycode,
+    pub a: Keycode,
+    pub b: Keycode,
+    pub select: Keycode,
+    pub start: Keycode,
+    /// The Famicom's second controller has a microphone instead of Select and Start.
+    pub microphone: Keycode,
+}
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        Bindings::new()
+    }
+}
+
+impl Bindings {
+    pub fn new() -> Bindings {
+        Bindings {
+            left: Keycode::Left,
+            down: Keycode::Down,
+            up: Keycode::Up,
+            right: Keycode::Right,
+            a: Keycode::Z,
+            b: Keycode::X,
+            select: Keycode::RShift,
+            start: Keycode::Return,
+            microphone: Keycode::M,
+        }
+    }
+
+    /// Returns the binding for the button with the given name, as used in the configuration file.
+    pub fn button_mut(&mut self, name: &str) -> Option<&mut Keycode> {
+        match name {
+            "left" => Some(&mut self.left),
+            "down" => Some(&mut self.down),
+            "up" => Some(&mut self.up),
+            "right" => Some(&mut self.right),
+            "a" => Some(&mut self.a),
+            "b" => Some(&mut self.b),
+            "select" => Some(&mut self.select),
+            "start" => Some(&mut self.start),
+            "microphone" => Some(&mut self.microphone),
+            _ => None,
+        }
+    }
+}
+
+//
+// Joystick bindings for the game pad
+//
+
+/// How far an axis must be pushed, out of 32767, to press its direction.
+const AXIS_THRESHOLD: i16 = 16384;
+
+/// The joystick buttons, by SDL's numbering, that press the game pad's buttons. Whatever is bound,
+/// the first hat and the first two axes press the directions.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PadBindings {
+    /// The joystick button for each game pad button, indexed by button bit.
+    pub buttons: [Option<u8>; 8],
+}
+
+impl Default for PadBindings {
+    fn default() -> PadBindings {
+        PadBindings::new()
+    }
+}
+
+impl PadBindings {
+    /// The layout for pads without bindings of their own, as XInput pads number their buttons: A
+    /// on the right face button and B on the bottom one, as on the NES pad, and Select and Start
+    /// on Back and Start.
+    pub fn new() -> PadBindings {
+        let mut buttons = [None; 8];
+        buttons[STROBE_STATE_A as usize] = Some(1);
+        buttons[STROBE_STATE_B as usize] = Some(0);
+        buttons[STROBE_STATE_SELECT as usize] = Some(6);
+        buttons[STROBE_STATE_START as usize] = Some(7);
+        PadBindings { buttons }
+    }
+
+    /// Binds the game pad button `name` to joystick button `value`, or unbinds it if `value` is
+    /// `none`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let bit = match button_bit(name) {
+            Some(bit) => bit,
+            None => return Err(format!("unknown button `{}`", name)),
+        };
+        self.buttons[bit as usize] = match value {
+            "none" => None,
+            _ => match value.parse() {
+                Ok(button) => Some(button),
+                Err(_) => {
+                    return Err(format!(
+                        "expected a joystick button number, not `{}`",
+                        value
+                    ))
+                }
+            },
+        };
+        Ok(())
+    }
+
+    /// The bindings as `button_<name>` settings.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        BUTTON_NAMES
+            .iter()
+            .zip(self.buttons.iter())
+            .filter_map(|(name, button)| {
+                button.map(|button| (format!("button_{}", name), button.to_string()))
+            })
+            .collect()
+    }
+
+    /// The game pad button bits that joystick button `button` presses.
+    fn bits(&self, button: u8) -> u8 {
+        self.buttons
+            .iter()
+            .enumerate()
+            .filter(|&(_, &bound)| bound == Some(button))
+            .fold(0, |bits, (bit, _)| bits | 1 << bit)
+    }
+}
+
+/// An open joystick driving the game pad.
+#[derive(Clone)]
+struct Pad {
+    joystick: Rc<Joystick>,
+    guid: String,
+    bindings: PadBindings,
+    /// The game pad buttons held through the joystick's buttons, its hat and its two axes, as
+    /// bits.
+    buttons: u8,
+    hat: u8,
+    axes: [u8; 2],
+}
+
+impl Pad {
+    fn held(&self) -> u8 {
+        self.buttons | self.hat | self.axes[0] | self.axes[1]
+    }
+}
+
 #[derive(Clone)]
 pub struct Input {
     pub gamepad_0: GamePadState,
-    sdl: Sdl, // FIXME: Use a `&'a mut EventPump` instead
+    pub bindings: Bindings,
+    /// Auto-fire patterns, indexed by button bit.
+    pub fire_patterns: [Option<FirePattern>; 8],
+    /// The buttons whose keys are held, one bit per button as in `GamePadState::set_bits`.
+    keys: u8,
+    /// Counts calls to `check_input`, to time auto-fire.
+    frame: u32,
+    /// A movie recording or replaying the buttons at each latch.
+    pub latch_movie: Option<LatchMovie>,
+    /// Button changes waiting for their CPU cycle, in order, as `(cycle, buttons)`.
+    scheduled: VecDeque<(u64, u8)>,
+    /// The CPU cycle the instruction being executed started at, set before each CPU step while
+    /// button changes are scheduled.
+    pub cycle: u64,
+    /// Whether the microphone key is held.
+    pub microphone: bool,
+    /// How far the picture is turned, for turning the arrow keys with it.
+    pub rotation: Rotation,
+    /// The level of OUT1 ($4016 bit 1), which drives the shutters of the Famicom 3D System.
+    pub shutter: bool,
+    /// Whether the rewind key is held.
+    pub rewinding: bool,
+    /// Whether the fast-forward key is held.
+    pub fast_forwarding: bool,
+    /// The Zapper in the second controller port, if one is plugged in.
+    pub zapper: Option<Zapper>,
+    /// Whether the keyboard drives the second controller port and the Zapper the first, rather
+    /// than the other way around.
+    pub ports_swapped: bool,
+    /// Whether the palette or sprite editor is open, which takes every key but Escape, F11 and
+    /// F12.
+    pub editing: bool,
+    /// Joystick bindings by joystick GUID, for the pads plugged in now and later.
+    pad_bindings: Vec<(String, PadBindings)>,
+    pads: Vec<Pad>,
+    joysticks: Option<JoystickSubsystem>,
+    sdl: Option<Sdl>, // FIXME: Use a `&'a mut EventPump` instead
 }
 
 pub enum InputResult {
-    Continue,  // Keep playing.
-    Quit,      // Quit the emulator.
-    SaveState, // Save a state.
-    LoadState, // Load a state.
+    Continue,            // Keep playing.
+    Quit,                // Quit the emulator.
+    SaveState,           // Save a state.
+    LoadState,           // Load a state.
+    ReloadConfig,        // Re-read the configuration file.
+    VolumeDown,          // Turn the volume down.
+    VolumeUp,            // Turn the volume up.
+    ToggleFrameTimes,    // Show or hide the frame time graph.
+    ToggleFrameBlend,    // Turn frame blending on or off.
+    Rotate,              // Turn the picture another 90 degrees.
+    ToggleBackground,    // Show or hide the background layer.
+    ToggleSprites,       // Show or hide the sprite layer.
+    ExportLayers,        // Write the layers of the next frame to images.
+    ExportSession,       // Write a session bundle.
+    Reset,               // Press the reset button.
+    SwipeBarcode,        // Swipe the next barcode through the Datach reader.
+    SwapPorts,           // Swap the devices in the two controller ports.
+    AudioDevices,        // An audio device was plugged in or unplugged.
+    Pause,               // Pause or resume the machine.
+    TogglePaletteEditor, // Open or close the palette editor.
+    ToggleSpriteEditor,  // Open or close the sprite editor.
+    EditorKey(Keycode),  // A key was pressed in the open editor.
+    ToggleRecording,     // Start recording, with the instant replay, or stop.
+    // A joystick was plugged in. `known` is false if it has no bindings of its own yet.
+    GamepadAdded { guid: String, known: bool },
 }
 
 impl Input {
     pub fn new(sdl: Sdl) -> Input {
+        Input::with_sdl(Some(sdl))
+    }
+
+    fn with_sdl(sdl: Option<Sdl>) -> Input {
         Input {
             gamepad_0: GamePadState {
                 left: false,
@@ -113,31 +435,271 @@ impl Input {
                     val: STROBE_STATE_A,
                 },
             },
+            bindings: Bindings::new(),
+            fire_patterns: [None; 8],
+            keys: 0,
+            frame: 0,
+            latch_movie: None,
+            scheduled: VecDeque::new(),
+            cycle: 0,
+            microphone: false,
+            rotation: Rotation::Rotate0,
+            shutter: false,
+            rewinding: false,
+            fast_forwarding: false,
+            zapper: None,
+            ports_swapped: false,
+            editing: false,
+            pad_bindings: Vec::new(),
+            pads: Vec::new(),
+            joysticks: sdl.as_ref().and_then(|sdl| sdl.joystick().ok()),
             sdl: sdl,
         }
     }
 
+    /// Creates an input that never sees any events, for running without a window.
+    pub fn headless() -> Input {
+        Input::with_sdl(None)
+    }
+
+    /// The button a direction key presses when the picture is turned by `self.rotation`, so that
+    /// each arrow points the same way on the screen as before.
+    fn rotate_direction(&self, button: u8) -> u8 {
+        match (self.rotation, button) {
+            (Rotation::Rotate90, STROBE_STATE_UP) => STROBE_STATE_LEFT,
+            (Rotation::Rotate90, STROBE_STATE_RIGHT) => STROBE_STATE_UP,
+            (Rotation::Rotate90, STROBE_STATE_DOWN) => STROBE_STATE_RIGHT,
+            (Rotation::Rotate90, STROBE_STATE_LEFT) => STROBE_STATE_DOWN,
+            (Rotation::Rotate270, STROBE_STATE_UP) => STROBE_STATE_RIGHT,
+            (Rotation::Rotate270, STROBE_STATE_RIGHT) => STROBE_STATE_DOWN,
+            (Rotation::Rotate270, STROBE_STATE_DOWN) => STROBE_STATE_LEFT,
+            (Rotation::Rotate270, STROBE_STATE_LEFT) => STROBE_STATE_UP,
+            _ => button,
+        }
+    }
+
     pub fn handle_gamepad_event(&mut self, key: Keycode, down: bool) {
-        match key {
-            Keycode::Left => self.gamepad_0.left = down,
-            Keycode::Down => self.gamepad_0.down = down,
-            Keycode::Up => self.gamepad_0.up = down,
-            Keycode::Right => self.gamepad_0.right = down,
-            Keycode::Z => self.gamepad_0.a = down,
-            Keycode::X => self.gamepad_0.b = down,
-            Keycode::RShift => self.gamepad_0.select = down,
-            Keycode::Return => self.gamepad_0.start = down,
-            _ => {}
+        let bindings = &self.bindings;
+        if key == bindings.microphone {
+            self.microphone = down;
+            return;
+        }
+        let button = if key == bindings.left {
+            STROBE_STATE_LEFT
+        } else if key == bindings.down {
+            STROBE_STATE_DOWN
+        } else if key == bindings.up {
+            STROBE_STATE_UP
+        } else if key == bindings.right {
+            STROBE_STATE_RIGHT
+        } else if key == bindings.a {
+            STROBE_STATE_A
+        } else if key == bindings.b {
+            STROBE_STATE_B
+        } else if key == bindings.select {
+            STROBE_STATE_SELECT
+        } else if key == bindings.start {
+            STROBE_STATE_START
+        } else {
+            return;
+        };
+        let button = self.rotate_direction(button);
+        if down {
+            self.keys |= 1 << button
+        } else {
+            self.keys &= !(1 << button)
+        }
+    }
+
+    /// Sets the joystick bindings by GUID, and rebinds the pads plugged in.
+    pub fn set_pad_bindings(&mut self, bindings: Vec<(String, PadBindings)>) {
+        self.pad_bindings = bindings;
+        for index in 0..self.pads.len() {
+            let bindings = self.find_pad_bindings(&self.pads[index].guid);
+            self.pads[index].bindings = bindings.unwrap_or_else(PadBindings::new);
+        }
+    }
+
+    fn find_pad_bindings(&self, guid: &str) -> Option<PadBindings> {
+        self.pad_bindings
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(guid))
+            .map(|(_, bindings)| bindings.clone())
+    }
+
+    /// Opens joystick `index`, just plugged in, with the bindings for its GUID.
+    fn add_pad(&mut self, index: u32) -> Option<InputResult> {
+        let joystick = match self.joysticks.as_ref()?.open(index) {
+            Ok(joystick) => joystick,
+            Err(err) => {
+                println!("Error opening joystick: {}", err);
+                return None;
+            }
+        };
+        let guid = joystick.guid().string();
+        let bindings = self.find_pad_bindings(&guid);
+        println!("Gamepad connected: {} ({})", joystick.name(), guid);
+        self.pads.push(Pad {
+            joystick: Rc::new(joystick),
+            guid: guid.clone(),
+            bindings: bindings.clone().unwrap_or_else(PadBindings::new),
+            buttons: 0,
+            hat: 0,
+            axes: [0; 2],
+        });
+        Some(InputResult::GamepadAdded {
+            guid,
+            known: bindings.is_some(),
+        })
+    }
+
+    fn pad_mut(&mut self, instance_id: i32) -> Option<&mut Pad> {
+        self.pads
+            .iter_mut()
+            .find(|pad| pad.joystick.instance_id() == instance_id)
+    }
+
+    /// Turns the direction bits of `bits` with the picture, as `rotate_direction` does.
+    fn rotate_bits(&self, bits: u8) -> u8 {
+        (0..8)
+            .filter(|&button| bits & 1 << button != 0)
+            .fold(0, |rotated, button| {
+                rotated | 1 << self.rotate_direction(button)
+            })
+    }
+
+    fn handle_pad_button(&mut self, instance_id: i32, button: u8, down: bool) {
+        if let Some(pad) = self.pad_mut(instance_id) {
+            let bits = pad.bindings.bits(button);
+            if down {
+                pad.buttons |= bits;
+            } else {
+                pad.buttons &= !bits;
+            }
+        }
+    }
+
+    fn handle_pad_hat(&mut self, instance_id: i32, state: HatState) {
+        let state = state as u8;
+        let bits = [
+            (0x01, STROBE_STATE_UP),
+            (0x02, STROBE_STATE_RIGHT),
+            (0x04, STROBE_STATE_DOWN),
+            (0x08, STROBE_STATE_LEFT),
+        ]
+        .iter()
+        .filter(|&&(mask, _)| state & mask != 0)
+        .fold(0, |bits, &(_, button)| bits | 1 << button);
+        let bits = self.rotate_bits(bits);
+        if let Some(pad) = self.pad_mut(instance_id) {
+            pad.hat = bits;
+        }
+    }
+
+    fn handle_pad_axis(&mut self, instance_id: i32, axis: usize, value: i16) {
+        let (negative, positive) = if axis == 0 {
+            (STROBE_STATE_LEFT, STROBE_STATE_RIGHT)
+        } else {
+            (STROBE_STATE_UP, STROBE_STATE_DOWN)
+        };
+        let bits = if value <= -AXIS_THRESHOLD {
+            1 << negative
+        } else if value >= AXIS_THRESHOLD {
+            1 << positive
+        } else {
+            0
+        };
+        let bits = self.rotate_bits(bits);
+        if let Some(pad) = self.pad_mut(instance_id) {
+            pad.axes[axis] = bits;
+        }
+    }
+
+    /// The port the keyboard's game pad is read from.
+    pub fn gamepad_port(&self) -> u16 {
+        if self.ports_swapped {
+            0x4017
+        } else {
+            0x4016
+        }
+    }
+
+    /// The port the Zapper is read from.
+    pub fn zapper_port(&self) -> u16 {
+        if self.ports_swapped {
+            0x4016
+        } else {
+            0x4017
         }
     }
 
+    /// Sets the first game pad to `buttons` at the first latch on or after CPU cycle `cycle`.
+    pub fn schedule(&mut self, cycle: u64, buttons: u8) {
+        let index = self
+            .scheduled
+            .iter()
+            .position(|&(scheduled, _)| scheduled > cycle)
+            .unwrap_or(self.scheduled.len());
+        self.scheduled.insert(index, (cycle, buttons));
+    }
+
+    pub fn has_scheduled(&self) -> bool {
+        !self.scheduled.is_empty()
+    }
+
+    /// Sets the game pad from the held keys and joystick buttons, applying the auto-fire patterns.
+    fn update_gamepad(&mut self) {
+        let frame = self.frame;
+        let released = self
+            .fire_patterns
+            .iter()
+            .enumerate()
+            .filter(|&(_, pattern)| pattern.is_some_and(|pattern| !pattern.pressed(frame)))
+            .fold(0, |bits, (button, _)| bits | 1 << button);
+        let held = self
+            .pads
+            .iter()
+            .fold(self.keys, |held, pad| held | pad.held());
+        self.gamepad_0.set_bits(held & !released);
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Handles pending events, then updates the game pad. Call once per frame.
     pub fn check_input(&mut self) -> InputResult {
-        while let Some(ev) = self.sdl.event_pump().unwrap().poll_event() {
+        let sdl = match self.sdl {
+            Some(ref sdl) => sdl.clone(),
+            None => return InputResult::Continue,
+        };
+        let result = self.handle_events(&sdl);
+        self.update_gamepad();
+        result
+    }
+
+    fn handle_events(&mut self, sdl: &Sdl) -> InputResult {
+        let mut events = match sdl.event_pump() {
+            Ok(events) => events,
+            Err(err) => {
+                println!("Error reading input: {}", err);
+                return InputResult::Continue;
+            }
+        };
+        while let Some(ev) = events.poll_event() {
             match ev {
                 Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => return InputResult::Quit,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => return InputResult::TogglePaletteEditor,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => return InputResult::ToggleSpriteEditor,
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } if self.editing => return InputResult::EditorKey(key),
                 Event::KeyDown {
                     keycode: Some(Keycode::S),
                     ..
@@ -146,12 +708,140 @@ impl Input {
                     keycode: Some(Keycode::L),
                     ..
                 } => return InputResult::LoadState,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => return InputResult::ReloadConfig,
+                Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    ..
+                } => return InputResult::Reset,
+                Event::KeyDown {
+                    keycode: Some(Keycode::B),
+                    ..
+                } => return InputResult::SwipeBarcode,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Minus),
+                    ..
+                } => return InputResult::VolumeDown,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Equals),
+                    ..
+                } => return InputResult::VolumeUp,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    ..
+                } => return InputResult::ToggleRecording,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    ..
+                } => return InputResult::SwapPorts,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Pause),
+                    ..
+                } => return InputResult::Pause,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => return InputResult::ToggleFrameTimes,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => return InputResult::ToggleFrameBlend,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => return InputResult::Rotate,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => return InputResult::ToggleBackground,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => return InputResult::ToggleSprites,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
+                } => return InputResult::ExportLayers,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => return InputResult::ExportSession,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewinding = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => self.fast_forwarding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => self.fast_forwarding = false,
                 Event::KeyDown {
                     keycode: Some(key), ..
                 } => self.handle_gamepad_event(key, true),
                 Event::KeyUp {
                     keycode: Some(key), ..
                 } => self.handle_gamepad_event(key, false),
+                Event::MouseMotion { x, y, .. } => {
+                    if let Some(ref mut zapper) = self.zapper {
+                        zapper.pointer = (x, y);
+                    }
+                }
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    ..
+                } => {
+                    if let Some(ref mut zapper) = self.zapper {
+                        zapper.trigger = true;
+                    }
+                }
+                Event::MouseButtonUp {
+                    mouse_btn: MouseButton::Left,
+                    ..
+                } => {
+                    if let Some(ref mut zapper) = self.zapper {
+                        zapper.trigger = false;
+                    }
+                }
+                Event::JoyDeviceAdded { which, .. } => {
+                    if let Some(result) = self.add_pad(which) {
+                        return result;
+                    }
+                }
+                Event::JoyDeviceRemoved { which, .. } => {
+                    self.pads.retain(|pad| pad.joystick.instance_id() != which)
+                }
+                Event::JoyButtonDown {
+                    which, button_idx, ..
+                } => self.handle_pad_button(which, button_idx, true),
+                Event::JoyButtonUp {
+                    which, button_idx, ..
+                } => self.handle_pad_button(which, button_idx, false),
+                Event::JoyHatMotion {
+                    which,
+                    hat_idx: 0,
+                    state,
+                    ..
+                } => self.handle_pad_hat(which, state),
+                Event::JoyAxisMotion {
+                    which,
+                    axis_idx,
+                    value,
+                    ..
+                } if axis_idx < 2 => self.handle_pad_axis(which, axis_idx as usize, value),
+                Event::Unknown { type_, .. }
+                    if type_ == SDL_AUDIODEVICEADDED || type_ == SDL_AUDIODEVICEREMOVED =>
+                {
+                    return InputResult::AudioDevices
+                }
                 Event::Quit { .. } => return InputResult::Quit,
                 _ => {}
             }
@@ -163,20 +853,67 @@ impl Input {
 
 impl Mem for Input {
     fn loadb(&mut self, addr: u16) -> u8 {
-        if addr == 0x4016 {
-            let result = self.gamepad_0.strobe_state.get(&self.gamepad_0) as u8;
+        let mut result = 0;
+        if addr == self.gamepad_port() {
+            result = self.gamepad_0.strobe_state.get(&self.gamepad_0) as u8;
             self.gamepad_0.strobe_state.next();
-            result
-        } else {
-            0
         }
+        if addr == self.zapper_port() {
+            result |= self.zapper.as_ref().map_or(0, Zapper::bits);
+        }
+        if addr == 0x4016 {
+            // Bit 2 is the microphone on the second Famicom controller: set while it hears sound.
+            result |= (self.microphone as u8) << 2;
+        }
+        result
     }
 
-    fn storeb(&mut self, addr: u16, _: u8) {
+    fn storeb(&mut self, addr: u16, val: u8) {
         if addr == 0x4016 {
+            self.shutter = val & 2 != 0;
+
             // FIXME: This is not really accurate; you're supposed to not reset until you see
             // 1 strobed than 0. But I doubt this will break anything.
             self.gamepad_0.strobe_state.reset();
+
+            if val & 1 != 0 {
+                while let Some(&(cycle, buttons)) = self.scheduled.front() {
+                    if cycle > self.cycle {
+                        break;
+                    }
+                    self.gamepad_0.set_bits(buttons);
+                    self.scheduled.pop_front();
+                }
+                if let Some(ref mut movie) = self.latch_movie {
+                    let buttons = movie.latch(self.gamepad_0.bits());
+                    self.gamepad_0.set_bits(buttons);
+                }
+            }
         }
     }
 }
+
+/// A savestate holds where the game pad's shift register is, the buttons it latched and OUT1, so
+/// that a state saved while a game reads the pad loads mid-read.
+impl Save for Input {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        let mut strobe_state = *self.gamepad_0.strobe_state;
+        let mut buttons = self.gamepad_0.bits();
+        let mut shutter = self.shutter as u8;
+        strobe_state.save(fd)?;
+        buttons.save(fd)?;
+        shutter.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        let (mut strobe_state, mut buttons, mut shutter) = (0u8, 0u8, 0u8);
+        strobe_state.load(fd)?;
+        buttons.load(fd)?;
+        shutter.load(fd)?;
+        self.gamepad_0.strobe_state = StrobeState {
+            val: strobe_state & 7,
+        };
+        self.gamepad_0.set_bits(buttons);
+        self.shutter = shutter != 0;
+        Ok(())
+    }
+}
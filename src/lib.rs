@@ -8,42 +8,174 @@ extern crate libc;
 extern crate sdl2;
 extern crate time;
 
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+pub extern crate serde_json;
+
 // NB: This must be first to pick up the macro definitions. What a botch.
 #[macro_use]
 pub mod util;
 
 pub mod apu;
 pub mod audio;
+pub mod barcode;
+pub mod batch;
+pub mod battery;
+pub mod bench;
+pub mod boards;
+pub mod capture;
+pub mod cheat;
+pub mod clock;
+pub mod compat;
+pub mod config;
+pub mod coverage;
 #[macro_use]
 pub mod cpu;
 pub mod disasm;
+pub mod discord;
+pub mod dump;
+pub mod emulator;
+pub mod epsm;
+pub mod error;
+pub mod filter;
 pub mod gfx;
+pub mod glasses;
 pub mod input;
 pub mod mapper;
 pub mod mem;
+pub mod metrics;
+pub mod movie;
+pub mod palette;
+pub mod palette_editor;
+pub mod pipe;
+pub mod pirate;
+pub mod png;
+pub mod power;
 pub mod ppu;
+pub mod provenance;
+pub mod region;
+pub mod replay;
+pub mod rewind;
 pub mod rom;
+pub mod savestate;
+pub mod scan;
+pub mod session;
+pub mod sprite_editor;
+pub mod trace;
+pub mod vgm;
+pub mod watchdog;
+pub mod zapper;
 
 // C library support
 #[cfg(feature = "audio")]
 pub mod speex;
 
 use apu::Apu;
+use audio::AudioOutput;
+use barcode::Barcode;
+use battery::SaveFiles;
+use clock::MachineClock;
+use config::Config;
 use cpu::Cpu;
-use gfx::{Gfx, Scale};
-use input::{Input, InputResult};
+use error::Error;
+use gfx::{Gfx, OsdAnchor, OsdContent, Rotation, SCREEN_SIZE};
+use glasses::Eye;
+use input::{Input, InputResult, PadBindings};
 use mapper::Mapper;
 use mem::MemMap;
-use ppu::{Oam, Ppu, Vram};
+use metrics::METRICS;
+use movie::LatchMovie;
+use palette::Palette;
+use palette_editor::PaletteEditor;
+use power::{FramePacer, PowerSaving, INSTRUMENTATION_INTERVAL};
+use ppu::{LayerCapture, Oam, Ppu, Vram, SCREEN_HEIGHT, SCREEN_WIDTH};
+use replay::Recorder;
+use rewind::Rewind;
 use rom::Rom;
-use util::Save;
+use savestate::Compression;
+use session::Session;
+use sprite_editor::SpriteEditor;
+use trace::AccessTrace;
+use vgm::VgmLog;
+use watchdog::Watchdog;
+use zapper::Zapper;
 
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+#[cfg(feature = "serde")]
+use std::io::Write;
 use std::mem as smem;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How much the volume keys change the volume by, in percent.
+const VOLUME_STEP: u8 = 10;
+
+const STATE_PATH: &str = "state.sav";
+
+const SESSION_PATH: &str = "session.snsb";
+
+/// Where the savestate and screenshot are written when the capture condition is met.
+const CAPTURE_STATE_PATH: &str = "capture.sav";
+const CAPTURE_SCREENSHOT_PATH: &str = "capture.ppm";
+
+/// How many times faster than real time to run until the `run_until` condition is met.
+const RUN_UNTIL_SPEED: u32 = 1000;
+
+/// How long to wait between polls for input while paused, in milliseconds.
+const PAUSE_POLL_MS: u64 = 16;
+
+/// How often changed cartridge saves are written to disk, in frames: five seconds at 60 Hz.
+const SAVE_FLUSH_FRAMES: u32 = 300;
+
+/// Number of frames shown in the frame time graph.
+const FRAME_TIMES_LENGTH: usize = 120;
+
+/// The RGB color of OSD warnings, such as the CPU jamming.
+const OSD_WARNING_COLOR: [u8; 3] = [0xff, 0x60, 0x60];
+
+/// Why `start_emulator` returned.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExitReason {
+    /// The user pressed Escape or closed the window.
+    Quit,
+    /// `StopHandle::stop` was called.
+    Stopped,
+}
+
+/// Stops a running `start_emulator` from another thread, or from a callback, at the end of the
+/// frame. Clones stop the same machine.
+#[derive(Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl Default for StopHandle {
+    fn default() -> StopHandle {
+        StopHandle::new()
+    }
+}
+
+impl StopHandle {
+    pub fn new() -> StopHandle {
+        StopHandle(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
 
 #[derive(Default)]
 struct Stats {
@@ -77,7 +209,7 @@ impl Stats {
 }
 
 fn record_fps(stats: &mut Stats, now: f64, prefix: &str, print: bool) {
-    if !true {
+    if !cfg!(feature = "stats") {
         return;
     }
 
@@ -226,45 +358,375 @@ fn record_fps(stats: &mut Stats, now: f64, prefix: &str, print: bool) {
     stats.memory_writes_old = smem::replace(&mut stats.memory_writes, Default::default());
 }
 
-/// Starts the emulator main loop with a ROM and window scaling. Returns when the user presses ESC.
-pub fn start_emulator(rom: Rom, scale: Scale) {
+/// Drops the statistics a machine collected over a frame without looking at them.
+fn clear_stats(cpu: &mut Cpu<MemMap>) {
+    cpu.branches_taken.clear();
+    cpu.branches_not_taken.clear();
+    cpu.loads.clear();
+    cpu.stores.clear();
+}
+
+/// Writes the full machine state as pretty-printed JSON, for debugging and diffing states. The
+/// mapper is written as the board state savestates hold, without the battery-backed memory they
+/// also carry. Deserializing the JSON gives a machine without a cartridge, whose mapper is a
+/// `DetachedBoard` holding that state.
+///
+/// ```
+/// use nes::config::Config;
+/// use nes::cpu::Cpu;
+/// use nes::emulator::Emulator;
+/// use nes::mem::MemMap;
+/// use nes::serde_json;
+///
+/// let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+/// let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+/// emulator.step_frame();
+/// let mut json = Vec::new();
+/// nes::dump_state_json(&emulator.cpu, &mut json).unwrap();
+/// let cpu: Cpu<MemMap> = serde_json::from_slice(&json).unwrap();
+/// assert_eq!(cpu.cy, emulator.cpu.cy);
+/// let mut again = Vec::new();
+/// nes::dump_state_json(&cpu, &mut again).unwrap();
+/// assert_eq!(json, again);
+/// ```
+#[cfg(feature = "serde")]
+pub fn dump_state_json<W: Write>(cpu: &Cpu<MemMap>, writer: W) -> serde_json::Result<()> {
+    serde_json::to_writer_pretty(writer, cpu)
+}
+
+/// Pushes the runtime settings of `config` into a machine and its window.
+fn apply_config(config: &Config, cpu: &mut Cpu<MemMap>, gfx: &mut Gfx) {
+    cpu.interrupt_latency = config.interrupt_latency;
+    cpu.mem.track_writes(config.write_provenance);
+    cpu.mem.input.bindings = config.bindings.clone();
+    cpu.mem.input.fire_patterns = config.fire_patterns;
+    cpu.mem.input.set_pad_bindings(config.gamepads.clone());
+    cpu.mem.apu.volume = config.volume;
+    cpu.mem.apu.fast_forward.audio = config.fast_forward_audio;
+    let speed = gfx.smooth_speed(config.smooth_refresh, cpu.mem.apu.region());
+    cpu.mem.apu.set_speed(speed);
+
+    let palette = config.palette.as_ref().and_then(|path| match Palette::load(path) {
+        Ok(palette) => Some(palette),
+        Err(err) => {
+            println!("Error loading palette {}: {}", path.display(), err);
+            None
+        }
+    });
+    cpu.mem.ppu.set_palette(palette.as_ref());
+
+    cpu.mem.cheats.clear();
+    for &(addr, val) in &config.freeze {
+        cpu.mem.freeze(addr, val);
+    }
+
+    gfx.set_filters(config);
+
+    if gfx.scale != config.scale {
+        gfx.set_scale(config.scale);
+    }
+    if gfx.rotation != config.rotation {
+        gfx.set_rotation(config.rotation);
+    }
+    cpu.mem.input.rotation = if config.rotate_input {
+        config.rotation
+    } else {
+        Rotation::Rotate0
+    };
+
+    if config.zapper {
+        let zapper = &mut cpu.mem.input.zapper;
+        zapper
+            .get_or_insert_with(|| Zapper::new(config.zapper_calibration))
+            .calibration = config.zapper_calibration;
+    } else {
+        cpu.mem.input.zapper = None;
+    }
+}
+
+/// Writes the background, sprites and both together of a frame captured a layer at a time to
+/// `frame-<n>-background.png`, `frame-<n>-sprites.png` and `frame-<n>-composite.png`.
+fn export_layers(layers: &LayerCapture, frame: u64) -> io::Result<()> {
+    let images = [
+        ("background", &layers.background),
+        ("sprites", &layers.sprites),
+        ("composite", &layers.composite),
+    ];
+    for &(name, pixels) in &images {
+        let path = format!("frame-{}-{}.png", frame, name);
+        png::write_rgba(Path::new(&path), SCREEN_WIDTH, SCREEN_HEIGHT, pixels)?;
+    }
+    Ok(())
+}
+
+/// Shows the last frame until the pause key is pressed again. Returns false if the user quit
+/// instead.
+fn pause(cpu: &mut Cpu<MemMap>, gfx: &mut Gfx) -> bool {
+    gfx.osd.set(
+        "paused",
+        OsdAnchor::TopLeft,
+        OsdContent::Text("Paused; press Pause to resume".to_string()),
+        None,
+    );
+    let mut screen = Box::new([0; SCREEN_SIZE]);
+    let resumed = loop {
+        screen.copy_from_slice(&cpu.mem.ppu.screen[..]);
+        gfx.composite(&mut screen, Eye::Left);
+        match cpu.mem.input.check_input() {
+            InputResult::Pause => break true,
+            InputResult::Quit => break false,
+            _ => {}
+        }
+        std::thread::sleep(std::time::Duration::from_millis(PAUSE_POLL_MS));
+    };
+    gfx.osd.remove("paused");
+    resumed
+}
+
+/// The barcodes to swipe through the Datach reader: those in the settings, then those in the
+/// barcode file.
+fn load_barcodes(config: &Config) -> Vec<Barcode> {
+    let mut barcodes = config.barcodes.clone();
+    if let Some(ref path) = config.barcode_file {
+        match Barcode::load_file(path) {
+            Ok(more) => barcodes.extend(more),
+            Err(err) => println!("Error loading barcodes {}: {}", path.display(), err),
+        }
+    }
+    barcodes
+}
+
+/// Starts the emulator main loop with a ROM loaded from `rom_path` and configuration. Returns when
+/// the user presses ESC or `stop` is stopped, after saving, or early if the window can't be opened
+/// or the mapper isn't supported. The window and audio device close on return.
+pub fn start_emulator(
+    rom: Rom,
+    rom_path: &Path,
+    mut config: Config,
+    session: Option<Session>,
+    stop: StopHandle,
+) -> Result<ExitReason, Error> {
     let rom = Box::new(rom);
     println!("Loaded ROM: {}", rom.header);
+    for warning in &rom.warnings {
+        println!("Warning: {}", warning);
+    }
+    let bad_dump = !rom.warnings.is_empty();
+    let game = match rom.display_name() {
+        Some(name) => {
+            println!("Title: {}", name);
+            name
+        }
+        None => "nes".to_string(),
+    };
+
+    let region = config.region.unwrap_or(rom.header.region());
+    println!("Region: {}", region);
+    let mapper_number = rom.header.mapper();
+    if mapper::ignores_submapper(&rom.header) {
+        println!(
+            "Note: submapper {} isn't told apart; it runs as the base board",
+            rom.header.submapper().unwrap_or(0)
+        );
+    }
+    match mapper::unsupported(&rom.header) {
+        Some(unsupported) if config.nrom_fallback => {
+            println!("Warning: {}", unsupported);
+            println!("Running it as NROM, which may not get far");
+        }
+        _ => {}
+    }
 
-    let (mut gfx, sdl) = Gfx::new(scale, None);
-    let (mut gfx1, sdl) = Gfx::new(scale, Some(sdl));
-    let audio_buffer = audio::open(&sdl);
-    let mapper: Box<dyn Mapper + Send> = mapper::create_mapper(rom);
+    let (mapper, fallback) = mapper::create_mapper_or_fallback(rom, config.nrom_fallback)?;
+    let mapper: Box<dyn Mapper + Send> = mapper;
+    let power_saving = config.power_saving;
+    let vsync = power_saving == PowerSaving::Off;
+    let (mut gfx, sdl) = Gfx::new(&game, config.scale, vsync, None)?;
+    let (mut gfx1, sdl) = Gfx::new(&game, config.scale, vsync, Some(sdl))?;
+    if bad_dump {
+        gfx.osd.message("Bad dump; see the console".to_string());
+    }
+    if fallback {
+        gfx.osd.message("Running as NROM".to_string());
+    }
+    // Declared before the machines, so that it is dropped after them.
+    let audio_output = audio::open(&sdl);
+    let audio_buffer = audio_output.as_ref().map(AudioOutput::buffer);
+    let capabilities = mapper.capabilities();
     let mapper = Rc::new(RefCell::new(mapper));
-    let input = Input::new(sdl);
+    let mut watchdog = Watchdog::new(mapper_number, capabilities);
+    if capabilities.audio {
+        println!("Note: the cartridge's expansion audio isn't emulated");
+    }
+
+    let mut saves = if capabilities.non_volatile {
+        Some(SaveFiles::new(rom_path, config.sram_backups))
+    } else {
+        None
+    };
+    if let Some(ref mut saves) = saves {
+        if let Err(err) = saves.load(&mut mapper.borrow_mut().save_regions()) {
+            println!("Error loading save: {}", err);
+        }
+    }
+    let mut frames_since_flush = 0;
+    let input = Input::new(sdl.clone());
 
     // NES 0
-    let ppu = Ppu::new(Vram::new(mapper.clone()), Oam::new());
-    let apu = Apu::new(audio_buffer.clone());
+    let ppu = Ppu::new(Vram::new(Box::new(mapper.clone())), Oam::new(), region);
+    let apu = Apu::new(audio_buffer, region);
     let memmap = MemMap::new(ppu, input.clone(), mapper.clone(), apu);
     let mut cpu = Cpu::new(memmap);
 
     // NES 1
-    let ppu1 = Ppu::new(Vram::new(mapper.clone()), Oam::new());
-    let apu1 = Apu::new(audio_buffer.clone());
+    let ppu1 = Ppu::new(Vram::new(Box::new(mapper.clone())), Oam::new(), region);
+    let apu1 = Apu::new(audio_buffer, region);
     let memmap1 = MemMap::new(ppu1, input, mapper, apu1);
     let mut cpu1 = Cpu::new(memmap1);
 
+    apply_config(&config, &mut cpu, &mut gfx);
+    apply_config(&config, &mut cpu1, &mut gfx1);
+    let alignment = config.alignment.unwrap_or_else(ppu::random_alignment);
+    cpu.mem.ppu.alignment = alignment;
+    cpu1.mem.ppu.alignment = alignment;
+
+    if config.epsm {
+        epsm::attach(&mut cpu.mem);
+        epsm::attach(&mut cpu1.mem);
+    }
+    if let Some(ref path) = config.trace {
+        match AccessTrace::create(path, config.trace_filter) {
+            Ok(trace) => cpu.mem.access_trace = Some(trace),
+            Err(err) => println!("Error creating trace {}: {}", path.display(), err),
+        }
+    }
+    if let Some(ref path) = config.vgm {
+        cpu.mem.apu_log = Some(VgmLog::new(path, region));
+    }
+    if let Some(ref address) = config.metrics {
+        if let Err(err) = metrics::serve(address) {
+            println!("Error serving metrics on {}: {}", address, err);
+        }
+    }
+    if let Some(ref client_id) = config.discord {
+        let game = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+        if let Err(err) = discord::publish(client_id, &game) {
+            println!("Error publishing to Discord: {}", err);
+        }
+    }
+    if let Some(ref path) = config.play_latches {
+        match LatchMovie::load(path) {
+            Ok(movie) => cpu.mem.input.latch_movie = Some(movie),
+            Err(err) => println!("Error loading {}: {}", path.display(), err),
+        }
+    } else if let Some(ref path) = config.record_latches {
+        cpu.mem.input.latch_movie = Some(LatchMovie::record(path));
+    }
+
     // TODO: Add a flag to not reset for nestest.log
     cpu.reset();
     cpu1.reset();
 
+    // Keep the input since power on for session bundles, unless a movie is already being played
+    // or recorded.
+    let mut latches = Vec::new();
+    if let Some(ref session) = session {
+        match LatchMovie::decode(&session.movie) {
+            Ok(movie) => latches = movie,
+            Err(err) => println!("Error loading the session's movie: {}", err),
+        }
+        if !session.replay {
+            if let Err(err) = savestate::decode(&mut cpu, &session.state) {
+                println!("Error loading the session's state: {}", err);
+            }
+        }
+    }
+    if cpu.mem.input.latch_movie.is_none() {
+        cpu.mem.input.latch_movie = Some(match session {
+            Some(ref session) if session.replay => LatchMovie::play_latches(latches),
+            _ => LatchMovie::record_in_memory(latches),
+        });
+    }
+    let rom_crc32 = session::rom_crc32(rom_path).unwrap_or(0);
+
     let mut stats = Stats::new();
     let mut stats1 = Stats::new();
 
     let mut started = false;
 
-    loop {
+    // Emulation and render times of recent frames, for the frame time graph.
+    let mut frame_times = VecDeque::with_capacity(FRAME_TIMES_LENGTH);
+    let mut show_frame_times = false;
+    let mut palette_editor: Option<PaletteEditor> = None;
+    let mut sprite_editor: Option<SpriteEditor> = None;
+
+    let mut capture_on = config.capture_on.clone();
+    let mut run_until = config.run_until.clone();
+    capture::arm(capture_on.iter().chain(&run_until), &mut cpu);
+    let mut barcodes = load_barcodes(&config);
+    let mut next_barcode = 0;
+    let mut rewind = Rewind::new(config.rewind_memory << 20);
+    let mut recorder = Recorder::new(config.replay_seconds, region);
+    // OUT1 as each machine started its frame, which is the eye the frame was drawn for.
+    let mut frame_eyes = [Eye::Left; 2];
+    let mut frame_start = time::precise_time_s();
+    let mut pacer = FramePacer::new();
+
+    let reason = loop {
+        let pc = cpu.pc();
+        if let Some(ref mut trace) = cpu.mem.access_trace {
+            trace.pc = pc;
+            trace.cycle = cpu.cy;
+        }
+        if let Some(ref mut log) = cpu.mem.apu_log {
+            log.cycle = cpu.cy;
+        }
+        if let Some(ref mut provenance) = cpu.mem.provenance {
+            provenance.pc = pc;
+        }
+        cpu.mem.apu.cycle = cpu.cy;
+        cpu1.mem.apu.cycle = cpu1.cy;
+        cpu.mem.ppu.cycle = cpu.cy;
+        cpu1.mem.ppu.cycle = cpu1.cy;
+        watchdog.step(&cpu);
+
         cpu.step();
         cpu1.step();
 
+        let captured = capture_on.as_ref().is_some_and(|on| on.holds(&mut cpu));
+        let stopped = run_until.as_ref().is_some_and(|on| on.holds(&mut cpu));
+        if capture_on.is_some() || run_until.is_some() {
+            cpu.mem.ppu.clear_writes();
+        }
+        if captured {
+            capture_on = None;
+            capture::arm(run_until.iter(), &mut cpu);
+            if let Err(err) = savestate::save_file(&mut cpu, Path::new(CAPTURE_STATE_PATH)) {
+                println!("Error saving state: {}", err);
+            }
+            let screenshot_path = Path::new(CAPTURE_SCREENSHOT_PATH);
+            if let Err(err) = cpu.mem.ppu.save_screenshot(screenshot_path) {
+                println!("Error saving screenshot: {}", err);
+            }
+            println!(
+                "Capture condition met at ${:04X}, after the instruction at ${:04X}",
+                cpu.pc(),
+                pc
+            );
+            gfx.osd.message("Captured state".to_string());
+        }
+        if stopped {
+            run_until = None;
+            capture::arm(capture_on.iter(), &mut cpu);
+            println!("{}", capture::describe_stop(&mut cpu));
+            if !pause(&mut cpu, &mut gfx) {
+                break ExitReason::Quit;
+            }
+        }
+
         stats.steps += 1;
         stats.steps_s += 1;
+        METRICS.instructions.add(1);
 
         stats1.steps += 1;
         stats1.steps_s += 1;
@@ -284,6 +746,7 @@ pub fn start_emulator(rom: Rom, scale: Scale) {
             cpu.nmi();
         } else if ppu_result.scanline_irq {
             cpu.irq();
+            watchdog.irq();
         }
 
         let ppu_result1 = cpu1.mem.ppu.step(cpu1.cy);
@@ -293,11 +756,9 @@ pub fn start_emulator(rom: Rom, scale: Scale) {
             cpu1.irq();
         }
 
+        cpu.mem.apu.step(cpu.cy);
         #[cfg(feature = "audio")]
-        {
-            cpu.mem.apu.step(cpu.cy);
-            cpu1.mem.apu.step(cpu1.cy);
-        }
+        cpu1.mem.apu.step(cpu1.cy);
 
         let now = time::precise_time_s();
 
@@ -307,33 +768,334 @@ pub fn start_emulator(rom: Rom, scale: Scale) {
         }
 
         if ppu_result.new_frame {
-            std::mem::swap(&mut stats.branches_taken, &mut cpu.branches_taken);
-            std::mem::swap(&mut stats.branches_not_taken, &mut cpu.branches_not_taken);
+            METRICS.frames.add(1);
+            frames_since_flush += 1;
+            if frames_since_flush == SAVE_FLUSH_FRAMES {
+                frames_since_flush = 0;
+                if let Some(ref mut saves) = saves {
+                    if let Err(err) = saves.flush(&cpu.mem.mapper.borrow_mut().save_regions()) {
+                        println!("Error saving: {}", err);
+                    }
+                }
+            }
+            let frame = MachineClock::read(&cpu).since_power_on.frames;
+            if let Some(layers) = cpu.mem.ppu.take_layers() {
+                let message = match export_layers(&layers, frame) {
+                    Ok(()) => format!("Exported the layers of frame {}", frame),
+                    Err(err) => format!("Error exporting layers: {}", err),
+                };
+                gfx.osd.message(message);
+            }
+            if config.export_layers == Some(frame + 1) {
+                cpu.mem.ppu.capture_layers();
+            }
+            if let Some(stall) = watchdog.end_frame(&mut cpu) {
+                println!("{}", stall);
+                gfx.osd.message("Stalled; see the console".to_string());
+            }
+            if cpu.jammed() && !gfx.osd.is_shown("jammed") {
+                let pc = cpu.pc();
+                println!("CPU jammed by the KIL opcode at ${:04X}", pc);
+                gfx.osd.set(
+                    "jammed",
+                    OsdAnchor::TopLeft,
+                    OsdContent::ColoredText(
+                        format!("CPU jammed at ${:04X}; press R to reset", pc),
+                        OSD_WARNING_COLOR,
+                    ),
+                    None,
+                );
+            }
+            // While saving power, the statistics only cover one frame in every interval.
+            let instrumented =
+                power_saving == PowerSaving::Off || frame.is_multiple_of(INSTRUMENTATION_INTERVAL);
+            if instrumented {
+                std::mem::swap(&mut stats.branches_taken, &mut cpu.branches_taken);
+                std::mem::swap(&mut stats.branches_not_taken, &mut cpu.branches_not_taken);
 
-            std::mem::swap(&mut stats.stores, &mut cpu.stores);
-            std::mem::swap(&mut stats.loads, &mut cpu.loads);
+                std::mem::swap(&mut stats.stores, &mut cpu.stores);
+                std::mem::swap(&mut stats.loads, &mut cpu.loads);
+            } else {
+                clear_stats(&mut cpu);
+            }
 
             gfx.tick();
-            gfx.composite(&mut cpu.mem.ppu.screen);
+            if show_frame_times {
+                let times = frame_times.iter().cloned().collect();
+                gfx.osd.set(
+                    "frame_times",
+                    OsdAnchor::TopLeft,
+                    OsdContent::FrameTimes(times),
+                    None,
+                );
+            }
+            if let Some(ref editor) = palette_editor {
+                editor.show(&cpu.mem.ppu, &mut gfx.osd);
+            }
+            if let Some(ref editor) = sprite_editor {
+                editor.show(&cpu.mem.ppu, &mut gfx.osd);
+            }
+            // Recorded before the filters change the screen.
+            let index = frame as usize - 1;
+            let screen = &cpu.mem.ppu.screen[..];
+            if let Some(message) = recorder.frame(index, screen, cpu.cy, &mut cpu.mem.apu) {
+                gfx.osd.message(message);
+            }
+            // While fast-forwarding, show one frame in every `speed` so vsync doesn't hold the
+            // emulator to normal speed.
+            let speed = cpu.mem.apu.fast_forward.speed as u64;
+            let shown = power_saving.shows(frame) && frame.is_multiple_of(speed);
+            if shown {
+                gfx.composite(&mut cpu.mem.ppu.screen, frame_eyes[0]);
+            }
+            frame_eyes[0] = Eye::from_shutter(cpu.mem.input.shutter);
 
             gfx1.tick();
-            gfx1.composite(&mut cpu1.mem.ppu.screen);
+            if shown {
+                gfx1.composite(&mut cpu1.mem.ppu.screen, frame_eyes[1]);
+            }
+            frame_eyes[1] = Eye::from_shutter(cpu1.mem.input.shutter);
+
+            let render_end = time::precise_time_s();
+            if frame_times.len() == FRAME_TIMES_LENGTH {
+                frame_times.pop_front();
+            }
+            frame_times.push_back((now - frame_start, render_end - now));
 
-            record_fps(&mut stats, now, "cpu0", true);
+            if instrumented {
+                record_fps(&mut stats, now, "cpu0", true);
+            }
+
+            // With a device playing, the audio paces the emulator already.
+            let audio_paced = cfg!(feature = "audio") && audio::is_playing();
+            if power_saving != PowerSaving::Off && !audio_paced {
+                pacer.wait(cpu.mem.apu.frame_rate() * speed as f64);
+            }
+            frame_start = time::precise_time_s();
 
-            #[cfg(feature = "audio")]
             cpu.mem.apu.play_channels();
 
-            match cpu.mem.input.check_input() {
+            if config.reload_if_changed() {
+                apply_config(&config, &mut cpu, &mut gfx);
+                apply_config(&config, &mut cpu1, &mut gfx1);
+                capture_on = config.capture_on.clone();
+                capture::arm(capture_on.iter().chain(&run_until), &mut cpu);
+                barcodes = load_barcodes(&config);
+                gfx.osd.message("Reloaded config".to_string());
+            }
+
+            if stop.is_stopped() {
+                break ExitReason::Stopped;
+            }
+            let result = cpu.mem.input.check_input();
+            if let Some(ref mut zapper) = cpu.mem.input.zapper {
+                zapper.aim = gfx.screen_point(zapper.pointer);
+            }
+            let aim = cpu.mem.input.zapper.as_ref().and_then(|zapper| zapper.aim);
+            gfx.show_crosshair(aim, config.crosshair_size, config.crosshair_color);
+            match result {
                 InputResult::Continue => {}
-                InputResult::Quit => break,
+                InputResult::Quit => break ExitReason::Quit,
+                InputResult::Pause => {
+                    if !pause(&mut cpu, &mut gfx) {
+                        break ExitReason::Quit;
+                    }
+                }
                 InputResult::SaveState => {
-                    cpu.save(&mut File::create(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Saved state".to_string());
+                    match savestate::save_file(&mut cpu, Path::new(STATE_PATH)) {
+                        Ok(()) => gfx.osd.message("Saved state".to_string()),
+                        Err(err) => gfx.osd.message(format!("Error saving state: {}", err)),
+                    }
                 }
                 InputResult::LoadState => {
-                    cpu.load(&mut File::open(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Loaded state".to_string());
+                    match savestate::load_file(&mut cpu, Path::new(STATE_PATH)) {
+                        Ok(()) => gfx.osd.message("Loaded state".to_string()),
+                        Err(err) => gfx.osd.message(format!("Error loading state: {}", err)),
+                    }
+                }
+                InputResult::ReloadConfig => {
+                    config.reload();
+                    apply_config(&config, &mut cpu, &mut gfx);
+                    apply_config(&config, &mut cpu1, &mut gfx1);
+                    capture_on = config.capture_on.clone();
+                    capture::arm(capture_on.iter().chain(&run_until), &mut cpu);
+                    barcodes = load_barcodes(&config);
+                    gfx.osd.message("Reloaded config".to_string());
+                }
+                InputResult::VolumeDown | InputResult::VolumeUp => {
+                    let volume = match result {
+                        InputResult::VolumeDown => config.volume.saturating_sub(VOLUME_STEP),
+                        _ => (config.volume + VOLUME_STEP).min(100),
+                    };
+                    config.set_override("volume", &volume.to_string()).unwrap();
+                    cpu.mem.apu.volume = volume;
+                    cpu1.mem.apu.volume = volume;
+                    gfx.osd.bar("volume", "Volume".to_string(), volume);
+                }
+                InputResult::ToggleFrameTimes => {
+                    show_frame_times = !show_frame_times;
+                    if !show_frame_times {
+                        gfx.osd.remove("frame_times");
+                    }
+                }
+                InputResult::ToggleFrameBlend => {
+                    let enabled = !config.frame_blend;
+                    let value = if enabled { "on" } else { "off" };
+                    config.set_override("frame_blend", value).unwrap();
+                    gfx.set_filters(&config);
+                    gfx1.set_filters(&config);
+                    gfx.osd.message(format!("Frame blending {}", value));
+                }
+                InputResult::Rotate => {
+                    let degrees = config.rotation.next().degrees().to_string();
+                    match config.remember("rotation", &degrees) {
+                        Ok(()) => gfx.osd.message(format!("Rotated to {} degrees", degrees)),
+                        Err(err) => gfx.osd.message(format!("Error saving rotation: {}", err)),
+                    }
+                    apply_config(&config, &mut cpu, &mut gfx);
+                    apply_config(&config, &mut cpu1, &mut gfx1);
+                }
+                InputResult::ToggleBackground | InputResult::ToggleSprites => {
+                    let mut layers = cpu.mem.ppu.layers;
+                    let (name, shown) = match result {
+                        InputResult::ToggleBackground => {
+                            layers.background = !layers.background;
+                            ("Background", layers.background)
+                        }
+                        _ => {
+                            layers.sprites = !layers.sprites;
+                            ("Sprites", layers.sprites)
+                        }
+                    };
+                    cpu.mem.ppu.layers = layers;
+                    cpu1.mem.ppu.layers = layers;
+                    let state = if shown { "shown" } else { "hidden" };
+                    gfx.osd.message(format!("{} {}", name, state));
+                }
+                InputResult::ExportLayers => cpu.mem.ppu.capture_layers(),
+                InputResult::ExportSession => {
+                    let movie = match cpu.mem.input.latch_movie {
+                        Some(ref movie) => movie.encode_so_far(),
+                        None => LatchMovie::record_in_memory(Vec::new()).encode_so_far(),
+                    };
+                    let state = savestate::encode(&mut cpu, Compression::preferred());
+                    let exported = state.and_then(|state| {
+                        let session = Session::new(rom_crc32, config.settings(), state, movie);
+                        Ok(session.save(Path::new(SESSION_PATH))?)
+                    });
+                    match exported {
+                        Ok(()) => gfx.osd.message(format!("Exported {}", SESSION_PATH)),
+                        Err(err) => gfx.osd.message(format!("Error exporting session: {}", err)),
+                    }
+                }
+                InputResult::ToggleRecording => {
+                    let frame = MachineClock::read(&cpu).since_power_on.frames;
+                    gfx.osd.message(recorder.toggle(frame));
+                }
+                InputResult::Reset => {
+                    cpu.reset();
+                    cpu1.reset();
+                    gfx.osd.remove("jammed");
+                    gfx.osd.message("Reset".to_string());
+                }
+                InputResult::SwapPorts => {
+                    let swapped = !cpu.mem.input.ports_swapped;
+                    cpu.mem.input.ports_swapped = swapped;
+                    cpu1.mem.input.ports_swapped = swapped;
+                    let port = if swapped { 2 } else { 1 };
+                    let message = format!("Keyboard on controller port {}", port);
+                    gfx.osd.message(message);
+                }
+                InputResult::AudioDevices => {
+                    // Also taken when a device appears while none was playing.
+                    match audio_output {
+                        Some(ref output) if !audio::is_playing() => {
+                            let message = match output.reopen(&sdl) {
+                                Ok(()) => "Audio device changed".to_string(),
+                                Err(err) => format!("No audio device: {}", err),
+                            };
+                            gfx.osd.message(message);
+                        }
+                        _ => {}
+                    }
+                }
+                InputResult::TogglePaletteEditor | InputResult::ToggleSpriteEditor => {
+                    // One editor is open at a time. Closing the sprite editor lets the game write
+                    // OAM again.
+                    let palette = palette_editor.is_none();
+                    let sprites = sprite_editor.is_none();
+                    PaletteEditor::hide(&mut gfx.osd);
+                    SpriteEditor::hide(&mut gfx.osd);
+                    cpu.mem.ppu.oam_frozen = false;
+                    palette_editor = None;
+                    sprite_editor = None;
+                    match result {
+                        InputResult::TogglePaletteEditor if palette => {
+                            palette_editor = Some(PaletteEditor::new())
+                        }
+                        InputResult::ToggleSpriteEditor if sprites => {
+                            sprite_editor = Some(SpriteEditor::new())
+                        }
+                        _ => {}
+                    }
+                    cpu.mem.input.editing = palette_editor.is_some() || sprite_editor.is_some();
+                }
+                InputResult::EditorKey(key) => {
+                    let ppu = &mut cpu.mem.ppu;
+                    let message = match (&mut palette_editor, &mut sprite_editor) {
+                        (&mut Some(ref mut editor), _) => editor.key(key, ppu),
+                        (_, &mut Some(ref mut editor)) => editor.key(key, ppu),
+                        _ => None,
+                    };
+                    if let Some(message) = message {
+                        gfx.osd.message(message);
+                    }
+                }
+                InputResult::GamepadAdded { guid, known } => {
+                    // Write out the default bindings of a new model, for the player to edit.
+                    let message = if known {
+                        "Gamepad connected".to_string()
+                    } else {
+                        match config.remember_gamepad(&guid, &PadBindings::new()) {
+                            Ok(()) => "New gamepad; its buttons are in the config".to_string(),
+                            Err(err) => format!("Error saving gamepad bindings: {}", err),
+                        }
+                    };
+                    gfx.osd.message(message);
+                }
+                InputResult::SwipeBarcode => {
+                    let mut mapper = cpu.mem.mapper.borrow_mut();
+                    let message = match mapper.barcode_reader() {
+                        None => "This cartridge has no barcode reader".to_string(),
+                        Some(_) if barcodes.is_empty() => "No barcodes are set".to_string(),
+                        Some(reader) => {
+                            let barcode = &barcodes[next_barcode % barcodes.len()];
+                            next_barcode += 1;
+                            reader.swipe(barcode);
+                            format!("Swiped barcode {}", barcode)
+                        }
+                    };
+                    gfx.osd.message(message);
+                }
+            }
+
+            cpu.mem.apu.fast_forward.speed = if run_until.is_some() {
+                RUN_UNTIL_SPEED
+            } else if cpu.mem.input.fast_forwarding {
+                config.fast_forward_speed
+            } else {
+                1
+            };
+
+            if !cpu.mem.input.rewinding {
+                if let Err(err) = rewind.push(&mut cpu) {
+                    gfx.osd.message(format!("Error recording rewind: {}", err));
+                }
+            } else {
+                match rewind.pop(&mut cpu) {
+                    Ok(true) => METRICS.rewinds.add(1),
+                    Ok(false) => {}
+                    Err(err) => gfx.osd.message(format!("Error rewinding: {}", err)),
                 }
             }
 
@@ -353,15 +1115,40 @@ pub fn start_emulator(rom: Rom, scale: Scale) {
         }
 
         if ppu_result1.new_frame {
-            std::mem::swap(&mut stats1.branches_taken, &mut cpu1.branches_taken);
-            std::mem::swap(&mut stats1.branches_not_taken, &mut cpu1.branches_not_taken);
+            let frame = MachineClock::read(&cpu1).since_power_on.frames;
+            if power_saving == PowerSaving::Off || frame.is_multiple_of(INSTRUMENTATION_INTERVAL) {
+                std::mem::swap(&mut stats1.branches_taken, &mut cpu1.branches_taken);
+                std::mem::swap(&mut stats1.branches_not_taken, &mut cpu1.branches_not_taken);
 
-            std::mem::swap(&mut stats1.stores, &mut cpu1.stores);
-            std::mem::swap(&mut stats1.loads, &mut cpu1.loads);
+                std::mem::swap(&mut stats1.stores, &mut cpu1.stores);
+                std::mem::swap(&mut stats1.loads, &mut cpu1.loads);
+
+                record_fps(&mut stats1, now, "cpu1", true);
+            } else {
+                clear_stats(&mut cpu1);
+            }
+        }
+    };
+
+    if let Some(log) = cpu.mem.apu_log.take() {
+        if let Err(err) = log.finish(cpu.cy) {
+            println!("Error writing VGM log: {}", err);
+        }
+    }
+    if let Some(movie) = cpu.mem.input.latch_movie.take() {
+        if let Err(err) = movie.finish() {
+            println!("Error writing latch movie: {}", err);
+        }
+    }
+    if let Some(message) = recorder.stop() {
+        println!("{}", message);
+    }
 
-            record_fps(&mut stats1, now, "cpu1", true);            
+    if let Some(ref mut saves) = saves {
+        if let Err(err) = saves.flush(&cpu.mem.mapper.borrow_mut().save_regions()) {
+            println!("Error saving: {}", err);
         }
     }
 
-    audio::close();
+    Ok(reason)
 }
@@ -5,9 +5,114 @@
 // Author: Patrick Walton
 //
 
-use rom::Rom;
+use barcode::BarcodeReader;
+use boards;
+use error::Error;
+use pirate;
+use ppu::ChrBus;
+use rom::{INesHeader, Rom};
+use util::Save;
 
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::ops::Deref;
+use std::rc::Rc;
+
+/// A block of cartridge memory that persists across power cycles.
+pub struct SaveRegion<'a> {
+    /// Picks the region's save file: `""` is `<rom>.sav` and any other name `<rom>.<name>.sav`.
+    pub name: &'static str,
+    pub data: &'a mut [u8],
+}
+
+/// What a board has beyond banked PRG and CHR, so callers needn't guess from the mapper number.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    /// Raises IRQs of its own.
+    pub irq: bool,
+    /// Carries expansion audio.
+    pub audio: bool,
+    /// Has PRG-RAM at $6000-$7FFF.
+    pub prg_ram: bool,
+    /// Has memory that persists across power cycles, returned by `save_regions`.
+    pub non_volatile: bool,
+    /// Its register writes conflict with the ROM driving the data bus.
+    pub bus_conflicts: bool,
+}
+
+impl Capabilities {
+    /// The names of the capabilities that are present, e.g. `["irq", "prg_ram"]`.
+    pub fn names(&self) -> Vec<&'static str> {
+        let flags = [
+            (self.irq, "irq"),
+            (self.audio, "audio"),
+            (self.prg_ram, "prg_ram"),
+            (self.non_volatile, "non_volatile"),
+            (self.bus_conflicts, "bus_conflicts"),
+        ];
+        flags
+            .iter()
+            .filter(|flag| flag.0)
+            .map(|flag| flag.1)
+            .collect()
+    }
+}
+
+/// Cartridge memory seen as a row of equally sized banks, which turns a bank number and an address
+/// into an offset. Bank numbers past the end wrap around, as they do on boards whose chips are
+/// smaller than the mapper can address, so every offset is in bounds.
+///
+/// ```
+/// use nes::mapper::BankedRegion;
+///
+/// // 128K of PRG-ROM in 8K banks.
+/// let prg = BankedRegion::new(128 * 1024, 8192);
+/// assert_eq!(prg.offset(3, 0xa123), 3 * 8192 + 0x0123);
+/// // Bank 17 of 16 is bank 1.
+/// assert_eq!(prg.offset(17, 0x8000), 8192);
+/// assert_eq!(prg.offset(prg.from_end(1), 0xffff), 128 * 1024 - 1);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BankedRegion {
+    len: usize,
+    /// Selects the offset of an address within its bank.
+    mask: usize,
+    bank_count: usize,
+}
+
+impl BankedRegion {
+    /// Divides `len` bytes into banks of `bank_size`, which is a power of two.
+    pub fn new(len: usize, bank_size: usize) -> BankedRegion {
+        debug_assert!(bank_size.is_power_of_two());
+        BankedRegion {
+            len,
+            mask: bank_size - 1,
+            bank_count: (len / bank_size).max(1),
+        }
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.bank_count
+    }
+
+    /// The `n`th bank from the end: 1 is the last bank, 2 the one before it.
+    pub fn from_end(&self, n: usize) -> usize {
+        (self.bank_count - n % self.bank_count) % self.bank_count
+    }
+
+    /// The offset of `addr` within bank `bank`.
+    #[inline]
+    pub fn offset(&self, bank: usize, addr: u16) -> usize {
+        let offset = ((bank % self.bank_count) * (self.mask + 1)) | (addr as usize & self.mask);
+        // Only a region smaller than a bank can still overrun.
+        if offset < self.len {
+            offset
+        } else {
+            offset % self.len.max(1)
+        }
+    }
+}
 
 #[derive(PartialEq, Eq)]
 pub enum MapperResult {
@@ -15,21 +120,322 @@ pub enum MapperResult {
     Irq,
 }
 
-pub trait Mapper {
+/// A cartridge board. Its `Save` impl holds what savestates restore of it: the registers and the
+/// memory on the board, except the memory `save_data` returns, which is saved on its own if at
+/// all.
+pub trait Mapper: Save {
     fn prg_loadb(&mut self, addr: u16) -> u8;
     fn prg_storeb(&mut self, addr: u16, val: u8);
     fn chr_loadb(&mut self, addr: u16) -> u8;
     fn chr_storeb(&mut self, addr: u16, val: u8);
     fn next_scanline(&mut self) -> MapperResult;
+
+    /// Notifies the mapper that the PPU is starting a frame, for mappers that track the scanline
+    /// through `next_scanline`.
+    fn start_frame(&mut self) {}
+
+    /// Notifies the mapper that the CPU ran for the given number of cycles, for mappers that count
+    /// them.
+    fn cpu_cycles(&mut self, _cycles: u64) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    /// Returns the memory that persists across power cycles, if any: battery-backed PRG-RAM or
+    /// self-flashable PRG.
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Returns every block of memory that persists across power cycles, for boards with more than
+    /// one kind, such as an EEPROM next to PRG-RAM. Defaults to `save_data`, named `""`.
+    fn save_regions(&mut self) -> Vec<SaveRegion<'_>> {
+        self.save_data()
+            .map(|data| SaveRegion { name: "", data })
+            .into_iter()
+            .collect()
+    }
+
+    /// Returns what the board has, for the code that would otherwise assume it from the mapper
+    /// number. Defaults to none of it.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Names what a CPU address from $4020 up maps to, such as `PRG-ROM bank 5` or `SRAM`, to
+    /// annotate memory dumps. Defaults to `default_region_label`, which has no bank numbers.
+    fn region_label(&self, addr: u16) -> Option<String> {
+        default_region_label(self.capabilities(), addr)
+    }
+
+    /// Returns the barcode reader built into the board, if it has one.
+    fn barcode_reader(&mut self) -> Option<&mut BarcodeReader> {
+        None
+    }
+
+    /// Returns how the two internal nametables are mapped into the PPU address space.
+    fn mirroring(&mut self) -> Mirroring {
+        Mirroring::Vertical
+    }
+
+    /// Returns where in the cartridge's CHR memory a PPU address currently maps to, which
+    /// identifies a tile independently of banking so the PPU can cache decoded tiles. `None`
+    /// disables caching.
+    fn chr_location(&mut self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    /// Notifies the mapper of a pattern fetch by the rendering PPU. See `ChrBus::pattern_fetch`.
+    fn pattern_fetch(&mut self, _addr: u16) {}
+}
+
+/// Labels PRG-ROM, and PRG-RAM or SRAM at $6000-$7FFF if the board has it, from its
+/// capabilities alone.
+pub fn default_region_label(capabilities: Capabilities, addr: u16) -> Option<String> {
+    if addr >= 0x8000 {
+        Some("PRG-ROM".to_string())
+    } else if addr >= 0x6000 && capabilities.prg_ram {
+        let name = if capabilities.non_volatile {
+            "SRAM"
+        } else {
+            "PRG-RAM"
+        };
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Labels `bank` of `region`, as a mapper numbers its banks.
+fn bank_label(memory: &str, region: &BankedRegion, bank: usize) -> Option<String> {
+    Some(format!("{} bank {}", memory, bank % region.bank_count()))
+}
+
+/// A mapper shared between the CPU's memory map and the PPU.
+pub type SharedMapper = Rc<RefCell<Box<dyn Mapper + Send>>>;
+
+impl ChrBus for SharedMapper {
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.borrow_mut().chr_loadb(addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.borrow_mut().chr_storeb(addr, val)
+    }
+    fn mirroring(&mut self) -> Mirroring {
+        self.borrow_mut().mirroring()
+    }
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        self.borrow_mut().chr_location(addr)
+    }
+    fn pattern_fetch(&mut self, addr: u16) {
+        self.borrow_mut().pattern_fetch(addr)
+    }
+    fn cpu_cycles(&mut self, cycles: u64) -> MapperResult {
+        self.borrow_mut().cpu_cycles(cycles)
+    }
+    fn next_scanline(&mut self) -> MapperResult {
+        self.borrow_mut().next_scanline()
+    }
+    fn start_frame(&mut self) {
+        self.borrow_mut().start_frame()
+    }
+}
+
+/// The state of a board without its cartridge, as a savestate holds it, for machines deserialized
+/// from a state dump. It reads as 0 and saves the state it holds as it is.
+pub struct DetachedBoard {
+    pub state: Vec<u8>,
+}
+
+impl DetachedBoard {
+    pub fn shared(state: Vec<u8>) -> SharedMapper {
+        Rc::new(RefCell::new(Box::new(DetachedBoard { state })))
+    }
+}
+
+impl Save for DetachedBoard {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(&self.state)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.state.clear();
+        fd.read_to_end(&mut self.state).map(|_| ())
+    }
+}
+
+impl Mapper for DetachedBoard {
+    fn prg_loadb(&mut self, _: u16) -> u8 {
+        0
+    }
+    fn prg_storeb(&mut self, _: u16, _: u8) {}
+    fn chr_loadb(&mut self, _: u16) -> u8 {
+        0
+    }
+    fn chr_storeb(&mut self, _: u16, _: u8) {}
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+}
+
+/// Serializes a `SharedMapper` as the board state savestates hold, and deserializes it as a
+/// `DetachedBoard`.
+#[cfg(feature = "serde")]
+pub mod serde_board {
+    use super::{DetachedBoard, SharedMapper};
+    use serde::ser::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        mapper: &SharedMapper,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state = Vec::new();
+        mapper
+            .borrow_mut()
+            .save(&mut state)
+            .map_err(S::Error::custom)?;
+        serializer.serialize_bytes(&state)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SharedMapper, D::Error> {
+        let state = Deserialize::deserialize(deserializer)?;
+        Ok(DetachedBoard::shared(state))
+    }
+}
+
+pub type MapperConstructor = fn(Box<Rom>) -> Box<dyn Mapper + Send>;
+
+/// Returns the constructor for the mapper with the given iNES number, if it's supported.
+fn mapper_constructor(mapper: u8) -> Option<MapperConstructor> {
+    let constructor: MapperConstructor = match mapper {
+        0 => |rom| Box::new(Nrom::new(rom)),
+        1 => |rom| Box::new(SxRom::new(rom)),
+        4 => |rom| Box::new(TxRom::new(rom)),
+        16 => |rom| Box::new(BandaiFcg::new(rom, BandaiBoard::Fcg)),
+        18 => |rom| Box::new(Ss88006::new(rom)),
+        30 => |rom| Box::new(Unrom512::new(rom)),
+        32 => |rom| Box::new(G101::new(rom)),
+        64 => |rom| Box::new(Rambo1::new(rom)),
+        65 => |rom| Box::new(H3001::new(rom)),
+        76 => |rom| Box::new(Namco108::new(rom, Namco108Board::Namcot3446)),
+        77 => |rom| Box::new(Irem77::new(rom)),
+        88 => |rom| Box::new(Namco108::new(rom, Namco108Board::Namcot3433)),
+        95 => |rom| Box::new(Namco108::new(rom, Namco108Board::Namcot3425)),
+        97 => |rom| Box::new(TamS1::new(rom)),
+        111 => |rom| Box::new(Gtrom::new(rom)),
+        153 => |rom| Box::new(BandaiFcg::new(rom, BandaiBoard::Sram)),
+        154 => |rom| Box::new(Namco108::new(rom, Namco108Board::Namcot3453)),
+        157 => |rom| Box::new(BandaiFcg::new(rom, BandaiBoard::Datach)),
+        159 => |rom| Box::new(BandaiFcg::new(rom, BandaiBoard::X24C01)),
+        206 => |rom| Box::new(Namco108::new(rom, Namco108Board::Namco108)),
+        _ if is_experimental(mapper) => return pirate::mapper_constructor(mapper),
+        _ => return None,
+    };
+    Some(constructor)
+}
+
+/// Returns true if the mapper with the given iNES number is emulated.
+pub fn is_supported(mapper: u8) -> bool {
+    mapper_constructor(mapper).is_some()
+}
+
+/// Returns true if the mapper with the given iNES number is one of the experimental pirate
+/// mappers, which are only emulated when built with the `pirate` feature.
+pub fn is_experimental(mapper: u8) -> bool {
+    cfg!(feature = "pirate") && pirate::mapper_constructor(mapper).is_some()
+}
+
+/// A mapper that isn't emulated, with what is known about it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct UnsupportedMapper {
+    /// The full mapper number, up to 12 bits with NES 2.0 headers.
+    pub number: u16,
+    /// The submapper, if the header is NES 2.0.
+    pub submapper: Option<u8>,
+}
+
+impl UnsupportedMapper {
+    /// The name of the board, if it is known.
+    pub fn name(&self) -> Option<&'static str> {
+        boards::name(self.number)
+    }
+
+    /// The emulated mapper whose board is nearest, which the game may partly run on.
+    pub fn nearest(&self) -> Option<u16> {
+        boards::nearest(self.number)
+    }
+
+    /// Whether the mapper is one of the pirate mappers, which this build leaves out.
+    pub fn needs_pirate_feature(&self) -> bool {
+        self.number < 256 && pirate::mapper_constructor(self.number as u8).is_some()
+    }
+}
+
+impl fmt::Display for UnsupportedMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported mapper {}", self.number)?;
+        if let Some(name) = self.name() {
+            write!(f, " ({})", name)?;
+        }
+        match self.submapper {
+            Some(0) | None => {}
+            Some(submapper) => write!(f, ", submapper {}", submapper)?,
+        }
+        if self.needs_pirate_feature() {
+            return write!(f, "; it is emulated when built with the `pirate` feature");
+        }
+        match self.nearest() {
+            Some(nearest) => write!(
+                f,
+                "; the nearest supported board is mapper {} ({})",
+                nearest,
+                boards::name(nearest).unwrap_or("unknown")
+            ),
+            None => write!(f, "; no similar board is emulated"),
+        }
+    }
+}
+
+/// Returns what is known about the mapper a ROM needs if it isn't emulated.
+pub fn unsupported(header: &INesHeader) -> Option<UnsupportedMapper> {
+    let number = header.mapper_number();
+    if number < 256 && is_supported(number as u8) {
+        return None;
+    }
+    Some(UnsupportedMapper {
+        number,
+        submapper: header.submapper(),
+    })
+}
+
+/// Returns true if the ROM declares a submapper the emulated board doesn't tell apart from the
+/// others, so the game runs on the base board.
+pub fn ignores_submapper(header: &INesHeader) -> bool {
+    match header.submapper() {
+        Some(submapper) => submapper != 0 && unsupported(header).is_none(),
+        None => false,
+    }
 }
 
-pub fn create_mapper(rom: Box<Rom>) -> Box<dyn Mapper + Send> {
-    match rom.header.ines_mapper() {
-        0 => Box::new(Nrom { rom: rom }) as Box<dyn Mapper + Send>,
-        1 => Box::new(SxRom::new(rom)) as Box<dyn Mapper + Send>,
-        4 => Box::new(TxRom::new(rom)) as Box<dyn Mapper + Send>,
-        _ => panic!("unsupported mapper"),
+pub fn create_mapper(rom: Box<Rom>) -> Result<Box<dyn Mapper + Send>, Error> {
+    if let Some(mapper) = unsupported(&rom.header) {
+        return Err(Error::UnsupportedMapper(mapper));
     }
+    let constructor = mapper_constructor(rom.header.mapper()).unwrap();
+    Ok(constructor(rom))
+}
+
+/// Like `create_mapper`, but if `nrom_fallback` is set runs a ROM whose mapper isn't emulated on
+/// `Nrom::fallback` instead of failing. Returns the mapper and whether it is the fallback.
+pub fn create_mapper_or_fallback(
+    rom: Box<Rom>,
+    nrom_fallback: bool,
+) -> Result<(Box<dyn Mapper + Send>, bool), Error> {
+    if nrom_fallback && unsupported(&rom.header).is_some() {
+        return Ok((Box::new(Nrom::fallback(rom)), true));
+    }
+    create_mapper(rom).map(|mapper| (mapper, false))
 }
 
 //
@@ -41,26 +447,80 @@ pub fn create_mapper(rom: Box<Rom>) -> Box<dyn Mapper + Send> {
 // TODO: RAM.
 pub struct Nrom {
     pub rom: Box<Rom>,
+    /// 16K of PRG-ROM is mirrored into both halves of the 32K window.
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    /// The 32K bank mapped, which is only nonzero for the fallback.
+    prg_bank: usize,
+    /// 8K of CHR-RAM stands in for CHR-ROM if the cartridge has none.
+    chr_ram: bool,
+}
+
+impl Nrom {
+    pub fn new(rom: Box<Rom>) -> Nrom {
+        Nrom {
+            prg_region: BankedRegion::new(rom.prg.len(), 32768),
+            chr_region: BankedRegion::new(rom.chr.len(), 8192),
+            rom,
+            prg_bank: 0,
+            chr_ram: false,
+        }
+    }
+
+    /// A best effort at running a ROM whose mapper isn't emulated: maps its last 32K of PRG-ROM,
+    /// where boards that switch banks keep the reset vector, and its first 8K of CHR-ROM, or
+    /// CHR-RAM if it has none. Games that never switch banks may run; the rest won't get far.
+    pub fn fallback(mut rom: Box<Rom>) -> Nrom {
+        let chr_ram = rom.chr.is_empty();
+        if chr_ram {
+            rom.chr = vec![0; 8192];
+        }
+        let mut nrom = Nrom::new(rom);
+        nrom.prg_bank = nrom.prg_region.from_end(1);
+        nrom.chr_ram = chr_ram;
+        nrom
+    }
+}
+
+impl Save for Nrom {
+    // The fallback's bank is fixed, so only CHR-RAM changes.
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).load(fd)?;
+        }
+        Ok(())
+    }
 }
 
 impl Mapper for Nrom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
         if addr < 0x8000 {
             0u8
-        } else if self.rom.prg.len() > 16384 {
-            self.rom.prg[addr as usize & 0x7fff]
         } else {
-            self.rom.prg[addr as usize & 0x3fff]
+            self.rom.prg[self.prg_region.offset(self.prg_bank, addr)]
         }
     }
     fn prg_storeb(&mut self, _: u16, _: u8) {} // Can't store to PRG-ROM.
     fn chr_loadb(&mut self, addr: u16) -> u8 {
-        self.rom.chr[addr as usize]
+        self.rom.chr[self.chr_region.offset(0, addr)]
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            self.rom.chr[self.chr_region.offset(0, addr)] = val
+        }
     }
-    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
     fn next_scanline(&mut self) -> MapperResult {
         MapperResult::Continue
     }
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(addr as usize)
+    }
 }
 
 //
@@ -74,11 +534,33 @@ struct SxCtrl {
     val: u8,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Mirroring {
     OneScreenLower,
     OneScreenUpper,
     Vertical,
     Horizontal,
+    /// Each of the four nametables uses the given 1K page of nametable RAM.
+    Custom([u8; 4]),
+    /// The cartridge has its own nametable RAM, accessed through `chr_loadb` and `chr_storeb`.
+    Cartridge,
+}
+
+impl Mirroring {
+    /// Maps a nametable address ($2000-$3EFF) to an offset into the 2K of nametable RAM. Cartridge
+    /// nametables aren't in that RAM; they map as with vertical mirroring.
+    pub fn nametable_offset(self, addr: u16) -> usize {
+        let addr = addr as usize;
+        match self {
+            Mirroring::OneScreenLower => addr & 0x3ff,
+            Mirroring::OneScreenUpper => 0x400 | (addr & 0x3ff),
+            Mirroring::Vertical | Mirroring::Cartridge => addr & 0x7ff,
+            Mirroring::Horizontal => ((addr >> 1) & 0x400) | (addr & 0x3ff),
+            Mirroring::Custom(pages) => {
+                ((pages[(addr >> 10) & 3] as usize & 1) << 10) | (addr & 0x3ff)
+            }
+        }
+    }
 }
 
 enum SxPrgBankMode {
@@ -113,20 +595,30 @@ struct SxRegs {
     prg_bank: u8,
 }
 
+save_struct!(SxCtrl { val });
+save_struct!(SxRegs {
+    ctrl,
+    chr_bank_0,
+    chr_bank_1,
+    prg_bank
+});
+
 pub struct SxRom {
     rom: Box<Rom>,
+    prg_region: BankedRegion,
     regs: SxRegs,
     /// The internal accumulator.
     accum: u8,
     /// The write count. At the 5th write, we update the register.
     write_count: u8,
-    //prg_ram: Box<[u8; 8192]>,
+    prg_ram: Box<[u8; 8192]>,
     chr_ram: Box<[u8; 8192]>,
 }
 
 impl SxRom {
     fn new(rom: Box<Rom>) -> SxRom {
         SxRom {
+            prg_region: BankedRegion::new(rom.prg.len(), 16384),
             rom: rom,
             regs: SxRegs {
                 ctrl: SxCtrl { val: 3 << 2 },
@@ -136,35 +628,69 @@ impl SxRom {
             },
             accum: 0,
             write_count: 0,
-            //prg_ram: box() ([ 0, ..8192 ]),
+            prg_ram: Box::new([0; 8192]),
             chr_ram: Box::new([0; 8192]),
         }
     }
+
+    /// The 16K bank of PRG-ROM mapped at `addr`, from $8000 up.
+    fn prg_bank(&self, addr: u16) -> usize {
+        if addr < 0xc000 {
+            match self.regs.ctrl.prg_rom_mode() {
+                SxPrgBankMode::Switch32K => self.regs.prg_bank as usize & 0xfe,
+                SxPrgBankMode::FixFirstBank => 0,
+                SxPrgBankMode::FixLastBank => self.regs.prg_bank as usize,
+            }
+        } else {
+            match self.regs.ctrl.prg_rom_mode() {
+                SxPrgBankMode::Switch32K => (self.regs.prg_bank as usize & 0xfe) | 1,
+                SxPrgBankMode::FixFirstBank => self.regs.prg_bank as usize,
+                SxPrgBankMode::FixLastBank => self.prg_region.from_end(1),
+            }
+        }
+    }
+}
+
+impl Save for SxRom {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.regs.save(fd)?;
+        self.accum.save(fd)?;
+        self.write_count.save(fd)?;
+        self.chr_ram.save(fd)?;
+        if !self.rom.header.battery() {
+            self.prg_ram.save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.regs.load(fd)?;
+        self.accum.load(fd)?;
+        self.write_count.load(fd)?;
+        self.chr_ram.load(fd)?;
+        if !self.rom.header.battery() {
+            self.prg_ram.load(fd)?;
+        }
+        Ok(())
+    }
 }
 
 impl Mapper for SxRom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
-        if addr < 0x8000 {
+        if addr < 0x6000 {
             0u8
-        } else if addr < 0xc000 {
-            let bank = match self.regs.ctrl.prg_rom_mode() {
-                SxPrgBankMode::Switch32K => self.regs.prg_bank & 0xfe,
-                SxPrgBankMode::FixFirstBank => 0,
-                SxPrgBankMode::FixLastBank => self.regs.prg_bank,
-            };
-            self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as usize & 0x1fff]
         } else {
-            let bank = match self.regs.ctrl.prg_rom_mode() {
-                SxPrgBankMode::Switch32K => (self.regs.prg_bank & 0xfe) | 1,
-                SxPrgBankMode::FixFirstBank => self.regs.prg_bank,
-                SxPrgBankMode::FixLastBank => (*self.rom).header.prg_rom_size - 1,
-            };
-            self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
+            self.rom.prg[self.prg_region.offset(self.prg_bank(addr), addr)]
         }
     }
 
     fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x6000 {
+            return;
+        }
         if addr < 0x8000 {
+            self.prg_ram[addr as usize & 0x1fff] = val;
             return;
         }
 
@@ -212,6 +738,34 @@ impl Mapper for SxRom {
     fn next_scanline(&mut self) -> MapperResult {
         MapperResult::Continue
     }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        if self.rom.header.battery() {
+            Some(&mut *self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(addr as usize)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            prg_ram: true,
+            non_volatile: self.rom.header.battery(),
+            ..Capabilities::default()
+        }
+    }
+
+    fn region_label(&self, addr: u16) -> Option<String> {
+        if addr < 0x8000 {
+            default_region_label(self.capabilities(), addr)
+        } else {
+            bank_label("PRG-ROM", &self.prg_region, self.prg_bank(addr))
+        }
+    }
 }
 
 //
@@ -261,8 +815,13 @@ struct TxRegs {
     bank_select: TxBankSelect, // Bank select (0x8000-0x9ffe even)
 }
 
+save_struct!(TxBankSelect { val });
+save_struct!(TxRegs { bank_select });
+
 struct TxRom {
     rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
     regs: TxRegs,
     prg_ram: Box<[u8; 8192]>,
 
@@ -276,8 +835,29 @@ struct TxRom {
 }
 
 impl TxRom {
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        let (bank, two_kb) = match (addr, self.regs.bank_select.chr_a12_inversion()) {
+            (0x0000..=0x07ff, false) | (0x1000..=0x17ff, true) => (self.chr_banks_2k[0], true),
+            (0x0800..=0x0fff, false) | (0x1800..=0x1fff, true) => (self.chr_banks_2k[1], true),
+            (0x1000..=0x13ff, false) | (0x0000..=0x03ff, true) => (self.chr_banks_1k[0], false),
+            (0x1400..=0x17ff, false) | (0x0400..=0x07ff, true) => (self.chr_banks_1k[1], false),
+            (0x1800..=0x1bff, false) | (0x0800..=0x0bff, true) => (self.chr_banks_1k[2], false),
+            (0x1c00..=0x1fff, false) | (0x0c00..=0x0fff, true) => (self.chr_banks_1k[3], false),
+            _ => return None,
+        };
+        // 2K banks are numbered in 1K units and span the bank and the one after it.
+        let bank = if two_kb {
+            bank as usize + ((addr as usize >> 10) & 1)
+        } else {
+            bank as usize
+        };
+        Some(self.chr_region.offset(bank, addr))
+    }
+
     fn new(rom: Box<Rom>) -> TxRom {
         TxRom {
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
             rom: rom,
             regs: TxRegs {
                 bank_select: TxBankSelect { val: 0 },
@@ -294,8 +874,46 @@ impl TxRom {
         }
     }
 
-    fn prg_bank_count(&self) -> u8 {
-        self.rom.header.prg_rom_size * 2
+    /// The 8K bank of PRG-ROM mapped at `addr`, from $8000 up.
+    fn prg_bank(&self, addr: u16) -> usize {
+        match (addr, self.regs.bank_select.prg_bank_mode()) {
+            // $8000-$9FFF and $C000-$DFFF swap between switchable and fixed to the second to last
+            // bank.
+            (0x8000..=0x9fff, TxPrgBankMode::Swappable8000)
+            | (0xc000..=0xdfff, TxPrgBankMode::SwappableC000) => self.prg_banks[0] as usize,
+            (0x8000..=0x9fff, _) | (0xc000..=0xdfff, _) => self.prg_region.from_end(2),
+            // $A000-$BFFF is switchable.
+            (0xa000..=0xbfff, _) => self.prg_banks[1] as usize,
+            // $E000-$FFFF is fixed to the last bank.
+            _ => self.prg_region.from_end(1),
+        }
+    }
+}
+
+impl Save for TxRom {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.regs.save(fd)?;
+        if !self.rom.header.battery() {
+            self.prg_ram.save(fd)?;
+        }
+        self.chr_banks_2k.save(fd)?;
+        self.chr_banks_1k.save(fd)?;
+        self.prg_banks.save(fd)?;
+        self.scanline_counter.save(fd)?;
+        self.irq_reload.save(fd)?;
+        self.irq_enabled.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.regs.load(fd)?;
+        if !self.rom.header.battery() {
+            self.prg_ram.load(fd)?;
+        }
+        self.chr_banks_2k.load(fd)?;
+        self.chr_banks_1k.load(fd)?;
+        self.prg_banks.load(fd)?;
+        self.scanline_counter.load(fd)?;
+        self.irq_reload.load(fd)?;
+        self.irq_enabled.load(fd)
     }
 }
 
@@ -305,27 +923,8 @@ impl Mapper for TxRom {
             0u8
         } else if addr < 0x8000 {
             self.prg_ram[addr as usize & 0x1fff]
-        } else if addr < 0xa000 {
-            // $8000-$9FFF might be switchable or fixed to the second to last bank.
-            let bank = match self.regs.bank_select.prg_bank_mode() {
-                TxPrgBankMode::Swappable8000 => self.prg_banks[0],
-                TxPrgBankMode::SwappableC000 => self.prg_bank_count() - 2,
-            };
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
-        } else if addr < 0xc000 {
-            // $A000-$BFFF is switchable.
-            self.rom.prg[(self.prg_banks[1] as usize * 8192) | (addr as usize & 0x1fff)]
-        } else if addr < 0xe000 {
-            // $C000-$DFFF might be switchable or fixed to the second to last bank.
-            let bank = match self.regs.bank_select.prg_bank_mode() {
-                TxPrgBankMode::Swappable8000 => self.prg_bank_count() - 2,
-                TxPrgBankMode::SwappableC000 => self.prg_banks[0],
-            };
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
         } else {
-            // $E000-$FFFF is fixed to the last bank.
-            let bank = self.prg_bank_count() - 1;
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
+            self.rom.prg[self.prg_region.offset(self.prg_bank(addr), addr)]
         }
     }
 
@@ -367,19 +966,9 @@ impl Mapper for TxRom {
     }
 
     fn chr_loadb(&mut self, addr: u16) -> u8 {
-        let (bank, two_kb) = match (addr, self.regs.bank_select.chr_a12_inversion()) {
-            (0x0000..=0x07ff, false) | (0x1000..=0x17ff, true) => (self.chr_banks_2k[0], true),
-            (0x0800..=0x0fff, false) | (0x1800..=0x1fff, true) => (self.chr_banks_2k[1], true),
-            (0x1000..=0x13ff, false) | (0x0000..=0x03ff, true) => (self.chr_banks_1k[0], false),
-            (0x1400..=0x17ff, false) | (0x0400..=0x07ff, true) => (self.chr_banks_1k[1], false),
-            (0x1800..=0x1bff, false) | (0x0800..=0x0bff, true) => (self.chr_banks_1k[2], false),
-            (0x1c00..=0x1fff, false) | (0x0c00..=0x0fff, true) => (self.chr_banks_1k[3], false),
-            _ => return 0,
-        };
-        if two_kb {
-            self.rom.chr[(bank as usize * 1024) + (addr as usize & 0x7ff)]
-        } else {
-            self.rom.chr[(bank as usize * 1024) | (addr as usize & 0x3ff)]
+        match self.chr_offset(addr) {
+            Some(offset) => self.rom.chr[offset],
+            None => 0,
         }
     }
 
@@ -387,6 +976,10 @@ impl Mapper for TxRom {
         // TODO: CHR-RAM
     }
 
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        self.chr_offset(addr)
+    }
+
     fn next_scanline(&mut self) -> MapperResult {
         if self.scanline_counter != 0 {
             self.scanline_counter -= 1;
@@ -401,4 +994,1999 @@ impl Mapper for TxRom {
         }
         MapperResult::Continue
     }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        if self.rom.header.battery() {
+            Some(&mut *self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            irq: true,
+            prg_ram: true,
+            non_volatile: self.rom.header.battery(),
+            ..Capabilities::default()
+        }
+    }
+
+    fn region_label(&self, addr: u16) -> Option<String> {
+        if addr < 0x8000 {
+            default_region_label(self.capabilities(), addr)
+        } else {
+            bank_label("PRG-ROM", &self.prg_region, self.prg_bank(addr))
+        }
+    }
+}
+
+//
+// Self-flashable PRG, as used by modern homebrew boards
+//
+// See http://wiki.nesdev.com/w/index.php/Programming_flash_memory
+//
+
+/// Progress through the command sequence of the SST39SF040 flash chip.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FlashState {
+    Idle,
+    /// Seen $AA to $5555.
+    Unlock1,
+    /// Seen $55 to $2AAA; the next write to $5555 is a command.
+    Unlock2,
+    /// The next write programs a byte.
+    Program,
+    /// Seen the erase command; it must be unlocked again before the erase target.
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+}
+
+struct Flash {
+    state: FlashState,
+}
+
+impl Save for FlashState {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        let mut val: u8 = match *self {
+            FlashState::Idle => 0,
+            FlashState::Unlock1 => 1,
+            FlashState::Unlock2 => 2,
+            FlashState::Program => 3,
+            FlashState::Erase => 4,
+            FlashState::EraseUnlock1 => 5,
+            FlashState::EraseUnlock2 => 6,
+        };
+        val.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        let mut val: u8 = 0;
+        val.load(fd)?;
+        *self = match val {
+            1 => FlashState::Unlock1,
+            2 => FlashState::Unlock2,
+            3 => FlashState::Program,
+            4 => FlashState::Erase,
+            5 => FlashState::EraseUnlock1,
+            6 => FlashState::EraseUnlock2,
+            _ => FlashState::Idle,
+        };
+        Ok(())
+    }
+}
+
+save_struct!(Flash { state });
+
+impl Flash {
+    fn new() -> Flash {
+        Flash {
+            state: FlashState::Idle,
+        }
+    }
+
+    /// Handles a write of `val` to byte `offset` of the chip, which holds `prg`. The command
+    /// sequences use the chip addresses $5555 and $2AAA, which boards decode from the low CPU
+    /// address lines.
+    fn storeb(&mut self, prg: &mut [u8], offset: usize, val: u8) {
+        let chip_addr = offset & 0x7fff;
+        self.state = match (self.state, chip_addr, val) {
+            (FlashState::Idle, 0x5555, 0xaa) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2aaa, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, 0x5555, 0xa0) => FlashState::Program,
+            (FlashState::Unlock2, 0x5555, 0x80) => FlashState::Erase,
+            (FlashState::Program, _, _) => {
+                // Programming can only clear bits.
+                prg[offset] &= val;
+                FlashState::Idle
+            }
+            (FlashState::Erase, 0x5555, 0xaa) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2aaa, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, 0x5555, 0x10) => {
+                for byte in prg.iter_mut() {
+                    *byte = 0xff;
+                }
+                FlashState::Idle
+            }
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                let sector = offset & !0xfff;
+                for byte in &mut prg[sector..sector + 0x1000] {
+                    *byte = 0xff;
+                }
+                FlashState::Idle
+            }
+            // Anything else, including the software ID and reset commands, aborts the sequence.
+            _ => FlashState::Idle,
+        };
+    }
+}
+
+//
+// Mapper 30 (UNROM 512)
+//
+// See http://wiki.nesdev.com/w/index.php/UNROM_512
+//
+
+pub struct Unrom512 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    /// The last value written to the bank register: MCCPPPPP, with M selecting the one-screen
+    /// nametable, C the CHR-RAM bank and P the PRG bank at $8000.
+    bank: u8,
+    chr_ram: Box<[u8; 32768]>,
+    /// Whether the board has a flash chip instead of plain PRG-ROM. Writes to $8000-$BFFF then go
+    /// to the chip, and PRG is saved like battery-backed RAM.
+    flashable: bool,
+    flash: Flash,
+}
+
+impl Unrom512 {
+    fn new(rom: Box<Rom>) -> Unrom512 {
+        Unrom512 {
+            flashable: rom.header.battery(),
+            prg_region: BankedRegion::new(rom.prg.len(), 16384),
+            chr_region: BankedRegion::new(32768, 8192),
+            rom,
+            bank: 0,
+            chr_ram: Box::new([0; 32768]),
+            flash: Flash::new(),
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.chr_region.offset((self.bank >> 5) as usize & 3, addr)
+    }
+
+    /// The 16K bank of PRG mapped at `addr`, from $8000 up.
+    fn prg_bank(&self, addr: u16) -> usize {
+        if addr < 0xc000 {
+            self.bank as usize & 0x1f
+        } else {
+            self.prg_region.from_end(1)
+        }
+    }
+}
+
+// Flash PRG is left out of savestates, as it's the size of the game.
+save_struct!(Unrom512 {
+    bank,
+    chr_ram,
+    flash
+});
+
+impl Mapper for Unrom512 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            0u8
+        } else {
+            self.rom.prg[self.prg_region.offset(self.prg_bank(addr), addr)]
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        if self.flashable && addr < 0xc000 {
+            // The chip sees the bank number in its upper address lines, so the command addresses
+            // are $9555 with bank 1 and $AAAA with bank 0.
+            let offset = self.prg_region.offset(self.prg_bank(addr), addr);
+            self.flash.storeb(&mut self.rom.prg, offset, val);
+        } else {
+            self.bank = val;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr_ram[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr_ram[self.chr_offset(addr)] = val
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        if self.flashable {
+            Some(&mut self.rom.prg)
+        } else {
+            None
+        }
+    }
+
+    // FIXME: The four-screen variant (both header bits set) needs 4K of nametable RAM; it gets
+    // vertical mirroring for now.
+    fn mirroring(&mut self) -> Mirroring {
+        if self.rom.header.four_screen() && !self.rom.header.vertical_mirroring() {
+            if (self.bank & 0x80) == 0 {
+                Mirroring::OneScreenLower
+            } else {
+                Mirroring::OneScreenUpper
+            }
+        } else if self.rom.header.vertical_mirroring() {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            non_volatile: self.flashable,
+            // Only the flash variant drives the data bus on its own.
+            bus_conflicts: !self.flashable,
+            ..Capabilities::default()
+        }
+    }
+
+    fn region_label(&self, addr: u16) -> Option<String> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let memory = if self.flashable {
+            "PRG flash"
+        } else {
+            "PRG-ROM"
+        };
+        bank_label(memory, &self.prg_region, self.prg_bank(addr))
+    }
+}
+
+//
+// Mapper 111 (GTROM/Cheapocabra)
+//
+// See http://wiki.nesdev.com/w/index.php/GTROM
+//
+
+pub struct Gtrom {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    /// The last value written to the bank register: RGNCPPPP, with R and G the (active low) red
+    /// and green LEDs, N the nametable page, C the CHR-RAM page and P the 32K PRG bank.
+    bank: u8,
+    /// 16K of pattern tables followed by 16K of nametables, each split into two 8K pages.
+    ram: Box<[u8; 32768]>,
+    flash: Flash,
+}
+
+impl Gtrom {
+    fn new(rom: Box<Rom>) -> Gtrom {
+        Gtrom {
+            prg_region: BankedRegion::new(rom.prg.len(), 32768),
+            rom,
+            bank: 0,
+            ram: Box::new([0; 32768]),
+            flash: Flash::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        self.prg_region.offset(self.bank as usize & 0xf, addr)
+    }
+
+    /// Maps a PPU address to an offset into the board's RAM.
+    fn ram_offset(&self, addr: u16) -> usize {
+        if addr < 0x2000 {
+            (((self.bank >> 4) & 1) as usize * 0x2000) | (addr as usize & 0x1fff)
+        } else {
+            0x4000 | (((self.bank >> 5) & 1) as usize * 0x2000) | (addr as usize & 0x1fff)
+        }
+    }
+
+    /// Whether the red and green LEDs are lit. They're just indicators; nothing displays them yet.
+    pub fn leds(&self) -> (bool, bool) {
+        ((self.bank & 0x80) == 0, (self.bank & 0x40) == 0)
+    }
+}
+
+save_struct!(Gtrom { bank, ram, flash });
+
+impl Mapper for Gtrom {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            0u8
+        } else {
+            self.rom.prg[self.prg_offset(addr)]
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if (0x5000..0x6000).contains(&addr) || (0x7000..0x8000).contains(&addr) {
+            self.bank = val;
+        } else if addr >= 0x8000 {
+            let offset = self.prg_offset(addr);
+            self.flash.storeb(&mut self.rom.prg, offset, val);
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.ram[self.ram_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.ram[self.ram_offset(addr)] = val
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.ram_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.rom.prg)
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        Mirroring::Cartridge
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            non_volatile: true,
+            ..Capabilities::default()
+        }
+    }
+
+    fn region_label(&self, addr: u16) -> Option<String> {
+        if addr < 0x8000 {
+            return None;
+        }
+        bank_label("PRG flash", &self.prg_region, self.bank as usize & 0xf)
+    }
+}
+
+//
+// Mappers 206, 76, 88, 95 and 154 (DxROM/Namco 108 family)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_206
+//
+
+/// The boards built around the Namco 108, which differ in how they wire up CHR and mirroring.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Namco108Board {
+    /// Mapper 206: the plain chip, as in DxROM.
+    Namco108,
+    /// Mapper 76: four 2K CHR banks instead of two 2K and four 1K ones.
+    Namcot3446,
+    /// Mapper 88: the upper 64K of CHR is only visible in the second pattern table.
+    Namcot3433,
+    /// Mapper 95: the CHR bank registers for the first pattern table also select nametables.
+    Namcot3425,
+    /// Mapper 154: like mapper 88, with one-screen mirroring selected by any write.
+    Namcot3453,
+}
+
+pub struct Namco108 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    board: Namco108Board,
+    /// Which bank register the next odd write goes to.
+    bank_select: u8,
+    /// R0-R1: 2K CHR banks, R2-R5: 1K CHR banks, R6-R7: 8K PRG banks at $8000 and $A000.
+    regs: [u8; 8],
+    /// The nametable selected by mapper 154.
+    one_screen_upper: bool,
+    /// 8K of CHR-RAM stands in for CHR-ROM if the cartridge has none.
+    chr_ram: bool,
+}
+
+impl Namco108 {
+    fn new(mut rom: Box<Rom>, board: Namco108Board) -> Namco108 {
+        let chr_ram = rom.chr.is_empty();
+        if chr_ram {
+            rom.chr = vec![0; 8192];
+        }
+        Namco108 {
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            board,
+            bank_select: 0,
+            regs: [0, 2, 4, 5, 6, 7, 0, 1],
+            one_screen_upper: false,
+            chr_ram,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // Banks are counted in 1K; a 2K bank covers the 1K bank after it too.
+        let upper_half = (addr as usize >> 10) & 1;
+        let bank = match self.board {
+            Namco108Board::Namcot3446 => {
+                let bank = self.regs[2 + (addr as usize >> 11)] as usize;
+                (bank * 2) | upper_half
+            }
+            _ if addr < 0x1000 => {
+                let mut bank = self.regs[addr as usize >> 11] & 0x3e;
+                if self.board == Namco108Board::Namcot3425 {
+                    bank &= 0x1f;
+                }
+                bank as usize | upper_half
+            }
+            _ => {
+                let mut bank = self.regs[2 + ((addr as usize >> 10) & 3)] & 0x3f;
+                if self.board == Namco108Board::Namcot3433
+                    || self.board == Namco108Board::Namcot3453
+                {
+                    bank |= 0x40;
+                }
+                bank as usize
+            }
+        };
+        self.chr_region.offset(bank, addr)
+    }
+}
+
+impl Save for Namco108 {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.bank_select.save(fd)?;
+        self.regs.save(fd)?;
+        self.one_screen_upper.save(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.bank_select.load(fd)?;
+        self.regs.load(fd)?;
+        self.one_screen_upper.load(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).load(fd)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapper for Namco108 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0u8;
+        }
+        let bank = match addr {
+            0x8000..=0x9fff => self.regs[6] as usize & 0xf,
+            0xa000..=0xbfff => self.regs[7] as usize & 0xf,
+            0xc000..=0xdfff => self.prg_region.from_end(2),
+            _ => self.prg_region.from_end(1),
+        };
+        self.rom.prg[self.prg_region.offset(bank, addr)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        if self.board == Namco108Board::Namcot3453 {
+            self.one_screen_upper = (val & 0x40) != 0;
+        }
+        if addr < 0xa000 {
+            if (addr & 1) == 0 {
+                self.bank_select = val & 7;
+            } else {
+                self.regs[self.bank_select as usize] = val & 0x3f;
+            }
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        match self.board {
+            Namco108Board::Namcot3425 => {
+                let (top, bottom) = ((self.regs[0] >> 5) & 1, (self.regs[1] >> 5) & 1);
+                Mirroring::Custom([top, top, bottom, bottom])
+            }
+            Namco108Board::Namcot3453 if self.one_screen_upper => Mirroring::OneScreenUpper,
+            Namco108Board::Namcot3453 => Mirroring::OneScreenLower,
+            _ if self.rom.header.vertical_mirroring() => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+}
+
+//
+// Mapper 64 (Tengen RAMBO-1)
+//
+// See http://wiki.nesdev.com/w/index.php/RAMBO-1
+//
+
+pub struct Rambo1 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    /// Bank select: IPK-RRRR, with I inverting CHR A12, P the PRG mode, K enabling 1K banks for
+    /// the first pattern table and R the register the next bank data write goes to.
+    bank_select: u8,
+    /// R0-R9 and RF; RA-RE don't exist.
+    regs: [u8; 16],
+    horizontal_mirroring: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    /// Whether the IRQ counter is clocked every 4 CPU cycles instead of every scanline.
+    irq_cycle_mode: bool,
+    /// CPU cycles since the IRQ counter was last clocked, in cycle mode.
+    irq_prescaler: u64,
+    /// 8K of CHR-RAM stands in for CHR-ROM if the cartridge has none.
+    chr_ram: bool,
+}
+
+impl Rambo1 {
+    fn new(mut rom: Box<Rom>) -> Rambo1 {
+        let chr_ram = rom.chr.is_empty();
+        if chr_ram {
+            rom.chr = vec![0; 8192];
+        }
+        Rambo1 {
+            horizontal_mirroring: !rom.header.vertical_mirroring(),
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            bank_select: 0,
+            regs: [0; 16],
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_cycle_mode: false,
+            irq_prescaler: 0,
+            chr_ram,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // With CHR A12 inversion, the two pattern tables swap banking schemes.
+        let addr = if (self.bank_select & 0x80) != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        } as usize;
+        let one_kb_mode = (self.bank_select & 0x20) != 0;
+        let bank = match (addr >> 10, one_kb_mode) {
+            (0, true) => self.regs[0],
+            (1, true) => self.regs[8],
+            (2, true) => self.regs[1],
+            (3, true) => self.regs[9],
+            (0, false) => self.regs[0] & !1,
+            (1, false) => self.regs[0] | 1,
+            (2, false) => self.regs[1] & !1,
+            (3, false) => self.regs[1] | 1,
+            (n, _) => self.regs[n - 2],
+        };
+        self.chr_region.offset(bank as usize, addr as u16)
+    }
+
+    /// Clocks the IRQ counter, returning whether it fired.
+    fn clock_irq_counter(&mut self) -> bool {
+        if self.irq_reload {
+            // Reloading adds an extra count when the latch isn't 0 or 1, which Hard Drivin'
+            // depends on.
+            self.irq_counter = if self.irq_latch <= 1 {
+                self.irq_latch + 1
+            } else {
+                self.irq_latch.wrapping_add(2)
+            };
+            self.irq_reload = false;
+        } else if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch.wrapping_add(1);
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        self.irq_counter == 0 && self.irq_enabled
+    }
+}
+
+impl Save for Rambo1 {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.bank_select.save(fd)?;
+        self.regs.save(fd)?;
+        self.horizontal_mirroring.save(fd)?;
+        self.irq_latch.save(fd)?;
+        self.irq_counter.save(fd)?;
+        self.irq_reload.save(fd)?;
+        self.irq_enabled.save(fd)?;
+        self.irq_cycle_mode.save(fd)?;
+        self.irq_prescaler.save(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.bank_select.load(fd)?;
+        self.regs.load(fd)?;
+        self.horizontal_mirroring.load(fd)?;
+        self.irq_latch.load(fd)?;
+        self.irq_counter.load(fd)?;
+        self.irq_reload.load(fd)?;
+        self.irq_enabled.load(fd)?;
+        self.irq_cycle_mode.load(fd)?;
+        self.irq_prescaler.load(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).load(fd)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapper for Rambo1 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0u8;
+        }
+        let swapped = (self.bank_select & 0x40) != 0;
+        let bank = match (addr >> 13) & 3 {
+            0 if swapped => self.regs[15] as usize,
+            0 => self.regs[6] as usize,
+            1 if swapped => self.regs[6] as usize,
+            1 => self.regs[7] as usize,
+            2 if swapped => self.regs[7] as usize,
+            2 => self.regs[15] as usize,
+            _ => self.prg_region.from_end(1),
+        };
+        self.rom.prg[self.prg_region.offset(bank, addr)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        match addr & 0xe001 {
+            0x8000 => self.bank_select = val,
+            0x8001 => self.regs[(self.bank_select & 0xf) as usize] = val,
+            0xa000 => self.horizontal_mirroring = (val & 1) != 0,
+            0xc000 => self.irq_latch = val,
+            0xc001 => {
+                self.irq_cycle_mode = (val & 1) != 0;
+                self.irq_prescaler = 0;
+                self.irq_reload = true;
+            }
+            0xe000 => self.irq_enabled = false,
+            0xe001 => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.rom.chr[offset] = val
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        if !self.irq_cycle_mode && self.clock_irq_counter() {
+            MapperResult::Irq
+        } else {
+            MapperResult::Continue
+        }
+    }
+
+    fn cpu_cycles(&mut self, cycles: u64) -> MapperResult {
+        let mut result = MapperResult::Continue;
+        if self.irq_cycle_mode {
+            self.irq_prescaler += cycles;
+            while self.irq_prescaler >= 4 {
+                self.irq_prescaler -= 4;
+                if self.clock_irq_counter() {
+                    result = MapperResult::Irq;
+                }
+            }
+        }
+        result
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            irq: true,
+            ..Capabilities::default()
+        }
+    }
+}
+
+//
+// Serial EEPROM, as used by Bandai boards to save
+//
+// See http://wiki.nesdev.com/w/index.php/Bandai_FCG_board#PRG_RAM
+//
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum EepromChip {
+    /// 128 bytes. Each transfer starts with a 7-bit word address and a read bit, sent least
+    /// significant bit first, as is all data.
+    X24C01,
+    /// 256 bytes, with the standard I2C device select byte before the word address.
+    C24C02,
+}
+
+/// Progress through an I2C transfer.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum EepromState {
+    Idle,
+    /// Receiving the device select byte, or the address and read bit on the 24C01.
+    Command,
+    WordAddress,
+    Write,
+    Read,
+    /// Pulling the data line low to acknowledge a byte, then moving on to the given state.
+    Ack(EepromNext),
+    /// Waiting for the host to acknowledge a byte read, or to end the read by not doing so.
+    HostAck,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum EepromNext {
+    WordAddress,
+    Write,
+    Read,
+}
+
+pub struct Eeprom {
+    chip: EepromChip,
+    data: Vec<u8>,
+    state: EepromState,
+    scl: bool,
+    sda: bool,
+    /// The level the chip drives the data line to; high means released.
+    out: bool,
+    shift: u8,
+    /// Bits transferred of the current byte.
+    bits: u8,
+    address: u8,
+}
+
+impl Save for EepromState {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        let mut val: u8 = match *self {
+            EepromState::Idle => 0,
+            EepromState::Command => 1,
+            EepromState::WordAddress => 2,
+            EepromState::Write => 3,
+            EepromState::Read => 4,
+            EepromState::Ack(EepromNext::WordAddress) => 5,
+            EepromState::Ack(EepromNext::Write) => 6,
+            EepromState::Ack(EepromNext::Read) => 7,
+            EepromState::HostAck => 8,
+        };
+        val.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        let mut val: u8 = 0;
+        val.load(fd)?;
+        *self = match val {
+            1 => EepromState::Command,
+            2 => EepromState::WordAddress,
+            3 => EepromState::Write,
+            4 => EepromState::Read,
+            5 => EepromState::Ack(EepromNext::WordAddress),
+            6 => EepromState::Ack(EepromNext::Write),
+            7 => EepromState::Ack(EepromNext::Read),
+            8 => EepromState::HostAck,
+            _ => EepromState::Idle,
+        };
+        Ok(())
+    }
+}
+
+impl Save for Eeprom {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        (&mut self.data[..]).save(fd)?;
+        self.state.save(fd)?;
+        self.scl.save(fd)?;
+        self.sda.save(fd)?;
+        self.out.save(fd)?;
+        self.shift.save(fd)?;
+        self.bits.save(fd)?;
+        self.address.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        (&mut self.data[..]).load(fd)?;
+        self.state.load(fd)?;
+        self.scl.load(fd)?;
+        self.sda.load(fd)?;
+        self.out.load(fd)?;
+        self.shift.load(fd)?;
+        self.bits.load(fd)?;
+        self.address.load(fd)
+    }
+}
+
+impl Eeprom {
+    pub fn new(chip: EepromChip) -> Eeprom {
+        let size = match chip {
+            EepromChip::X24C01 => 128,
+            EepromChip::C24C02 => 256,
+        };
+        Eeprom {
+            chip,
+            data: vec![0; size],
+            state: EepromState::Idle,
+            scl: false,
+            sda: false,
+            out: true,
+            shift: 0,
+            bits: 0,
+            address: 0,
+        }
+    }
+
+    /// The level of the data line as the chip drives it.
+    pub fn read(&self) -> bool {
+        self.out
+    }
+
+    /// Sets the levels the host drives the clock and data lines to.
+    pub fn write(&mut self, scl: bool, sda: bool) {
+        let (old_scl, old_sda) = (self.scl, self.sda);
+        self.scl = scl;
+        self.sda = sda;
+        if old_scl && scl && old_sda != sda {
+            // Changing the data line while the clock is high is a start (falling) or stop
+            // (rising) condition.
+            self.state = if sda {
+                EepromState::Idle
+            } else {
+                EepromState::Command
+            };
+            self.bits = 0;
+            self.out = true;
+        } else if !old_scl && scl {
+            self.clock_rise(sda);
+        } else if old_scl && !scl {
+            self.clock_fall();
+        }
+    }
+
+    fn lsb_first(&self) -> bool {
+        self.chip == EepromChip::X24C01
+    }
+
+    /// Samples the data line.
+    fn clock_rise(&mut self, sda: bool) {
+        match self.state {
+            EepromState::Command | EepromState::WordAddress | EepromState::Write => {
+                if self.lsb_first() {
+                    self.shift = (self.shift >> 1) | if sda { 0x80 } else { 0 };
+                } else {
+                    self.shift = (self.shift << 1) | sda as u8;
+                }
+                self.bits += 1;
+            }
+            EepromState::Read => self.bits += 1,
+            EepromState::HostAck => {
+                if sda {
+                    self.state = EepromState::Idle;
+                }
+            }
+            EepromState::Idle | EepromState::Ack(_) => {}
+        }
+    }
+
+    /// Drives the data line for the next clock.
+    fn clock_fall(&mut self) {
+        match self.state {
+            EepromState::Command | EepromState::WordAddress | EepromState::Write
+                if self.bits == 8 =>
+            {
+                self.received(self.shift)
+            }
+            EepromState::Ack(next) => {
+                self.out = true;
+                self.bits = 0;
+                self.state = match next {
+                    EepromNext::WordAddress => EepromState::WordAddress,
+                    EepromNext::Write => EepromState::Write,
+                    EepromNext::Read => {
+                        self.shift = self.data[self.address as usize % self.data.len()];
+                        self.out = self.next_out_bit();
+                        EepromState::Read
+                    }
+                };
+            }
+            EepromState::Read => {
+                if self.bits == 8 {
+                    self.out = true;
+                    self.state = EepromState::HostAck;
+                } else {
+                    self.out = self.next_out_bit();
+                }
+            }
+            EepromState::HostAck => {
+                // The host acknowledged, so send the next byte.
+                self.address = self.address.wrapping_add(1);
+                self.state = EepromState::Ack(EepromNext::Read);
+                self.clock_fall();
+            }
+            _ => {}
+        }
+    }
+
+    fn next_out_bit(&self) -> bool {
+        if self.lsb_first() {
+            (self.shift >> self.bits) & 1 != 0
+        } else {
+            (self.shift << self.bits) & 0x80 != 0
+        }
+    }
+
+    fn received(&mut self, byte: u8) {
+        let next = match (self.state, self.chip) {
+            (EepromState::Command, EepromChip::X24C01) => {
+                self.address = byte & 0x7f;
+                if (byte & 0x80) != 0 {
+                    EepromNext::Read
+                } else {
+                    EepromNext::Write
+                }
+            }
+            (EepromState::Command, EepromChip::C24C02) => {
+                if (byte & 0xf0) != 0xa0 {
+                    // Addressed to some other device.
+                    self.state = EepromState::Idle;
+                    return;
+                }
+                if (byte & 1) != 0 {
+                    EepromNext::Read
+                } else {
+                    EepromNext::WordAddress
+                }
+            }
+            (EepromState::WordAddress, _) => {
+                self.address = byte;
+                EepromNext::Write
+            }
+            _ => {
+                let len = self.data.len();
+                self.data[self.address as usize % len] = byte;
+                // Writes wrap around within a page: 4 bytes on the 24C01 and 8 on the 24C02.
+                let page_mask = if self.lsb_first() { 3 } else { 7 };
+                self.address =
+                    (self.address & !page_mask) | (self.address.wrapping_add(1) & page_mask);
+                EepromNext::Write
+            }
+        };
+        self.out = false;
+        self.state = EepromState::Ack(next);
+    }
+}
+
+//
+// Mappers 16, 153, 157 and 159 (Bandai FCG, LZ93D50)
+//
+// See http://wiki.nesdev.com/w/index.php/Bandai_FCG_board
+//
+
+/// The boards built around the Bandai FCG and LZ93D50 chips.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum BandaiBoard {
+    /// Mapper 16: the FCG-1 and FCG-2, registers at $6000, and the LZ93D50 with a 24C02,
+    /// registers at $8000.
+    Fcg,
+    /// Mapper 153: an LZ93D50 with 8K of battery-backed PRG-RAM and 512K of PRG.
+    Sram,
+    /// Mapper 157: the Datach Joint ROM System, with a barcode reader and a 24C02 in the base
+    /// unit and sometimes a 24C01 in the cartridge.
+    Datach,
+    /// Mapper 159: an LZ93D50 with a 24C01.
+    X24C01,
+}
+
+pub struct BandaiFcg {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    board: BandaiBoard,
+    /// 8K of CHR-RAM stands in for CHR-ROM if the cartridge has none.
+    chr_ram: bool,
+    chr_banks: [u8; 8],
+    prg_bank: u8,
+    /// Register 9: 0 vertical, 1 horizontal, 2 and 3 one-screen.
+    mirroring: u8,
+
+    irq_enabled: bool,
+    irq_counter: u16,
+    /// The LZ93D50 writes the counter through this, copying it in when the IRQ is enabled.
+    irq_latch: u16,
+
+    /// Register D: the EEPROM clock (bit 5), data (bit 6) and read enable (bit 7), or the PRG-RAM
+    /// enable (bit 5) on mapper 153.
+    control: u8,
+    prg_ram: Vec<u8>,
+    eeprom: Option<Eeprom>,
+    /// The Datach cartridge's own 24C01, clocked by bit 3 of the CHR bank registers.
+    cartridge_eeprom: Option<Eeprom>,
+    barcode_reader: Option<BarcodeReader>,
+}
+
+impl BandaiFcg {
+    fn new(mut rom: Box<Rom>, board: BandaiBoard) -> BandaiFcg {
+        let chr_ram = rom.chr.is_empty();
+        if chr_ram {
+            rom.chr = vec![0; 8192];
+        }
+        let eeprom = match board {
+            BandaiBoard::Fcg | BandaiBoard::Datach => Some(Eeprom::new(EepromChip::C24C02)),
+            BandaiBoard::X24C01 => Some(Eeprom::new(EepromChip::X24C01)),
+            BandaiBoard::Sram => None,
+        };
+        let datach = board == BandaiBoard::Datach;
+        BandaiFcg {
+            prg_region: BankedRegion::new(rom.prg.len(), 16384),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            board,
+            chr_ram,
+            chr_banks: [0; 8],
+            prg_bank: 0,
+            mirroring: 0,
+
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+
+            control: 0,
+            prg_ram: if board == BandaiBoard::Sram {
+                vec![0; 8192]
+            } else {
+                Vec::new()
+            },
+            eeprom,
+            cartridge_eeprom: if datach {
+                Some(Eeprom::new(EepromChip::X24C01))
+            } else {
+                None
+            },
+            barcode_reader: if datach {
+                Some(BarcodeReader::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank = if self.board == BandaiBoard::Sram {
+            // Bit 0 of the CHR bank registers selects the 256K outer bank.
+            let outer = self.chr_banks.iter().any(|bank| (bank & 1) != 0) as usize * 16;
+            let inner = if addr < 0xc000 {
+                (self.prg_bank & 0xf) as usize
+            } else {
+                0xf
+            };
+            outer | inner
+        } else if addr < 0xc000 {
+            self.prg_bank as usize
+        } else {
+            self.prg_region.from_end(1)
+        };
+        self.prg_region.offset(bank, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        if self.chr_ram {
+            return addr as usize & 0x1fff;
+        }
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        self.chr_region.offset(bank, addr)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        // The FCG-1 and FCG-2 write the IRQ counter directly; the LZ93D50 latches it.
+        let direct = addr < 0x8000;
+        match addr & 0xf {
+            reg @ 0..=7 => {
+                self.chr_banks[reg as usize] = val;
+                if let Some(ref mut eeprom) = self.cartridge_eeprom {
+                    let sda = eeprom.sda;
+                    eeprom.write((val & 0x08) != 0, sda);
+                }
+            }
+            8 => self.prg_bank = val,
+            9 => self.mirroring = val & 3,
+            0xa => {
+                self.irq_enabled = (val & 1) != 0;
+                if !direct {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            0xb => {
+                if direct {
+                    self.irq_counter = (self.irq_counter & 0xff00) | val as u16;
+                } else {
+                    self.irq_latch = (self.irq_latch & 0xff00) | val as u16;
+                }
+            }
+            0xc => {
+                if direct {
+                    self.irq_counter = (self.irq_counter & 0x00ff) | (val as u16) << 8;
+                } else {
+                    self.irq_latch = (self.irq_latch & 0x00ff) | (val as u16) << 8;
+                }
+            }
+            0xd => {
+                self.control = val;
+                let sda = (val & 0x40) != 0;
+                if let Some(ref mut eeprom) = self.eeprom {
+                    eeprom.write((val & 0x20) != 0, sda);
+                }
+                if let Some(ref mut eeprom) = self.cartridge_eeprom {
+                    let scl = eeprom.scl;
+                    eeprom.write(scl, sda);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads $6000-$7FFF on the EEPROM boards: the EEPROM data line on bit 4, and the barcode
+    /// reader on bit 3.
+    fn read_status(&self) -> u8 {
+        let mut val = 0;
+        if (self.control & 0x80) != 0 {
+            let sda = self.eeprom.as_ref().is_none_or(|eeprom| eeprom.read())
+                && self
+                    .cartridge_eeprom
+                    .as_ref()
+                    .is_none_or(|eeprom| eeprom.read());
+            val |= (sda as u8) << 4;
+        }
+        if let Some(ref reader) = self.barcode_reader {
+            val |= reader.output();
+        }
+        val
+    }
+}
+
+impl Save for BandaiFcg {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.chr_banks.save(fd)?;
+        self.prg_bank.save(fd)?;
+        self.mirroring.save(fd)?;
+        self.irq_enabled.save(fd)?;
+        self.irq_counter.save(fd)?;
+        self.irq_latch.save(fd)?;
+        self.control.save(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).save(fd)?;
+        }
+        if self.save_data().is_none() {
+            (&mut self.prg_ram[..]).save(fd)?;
+        }
+        // The EEPROMs' contents are included: loading a state undoes what the game saved since.
+        if let Some(ref mut eeprom) = self.eeprom {
+            eeprom.save(fd)?;
+        }
+        if let Some(ref mut eeprom) = self.cartridge_eeprom {
+            eeprom.save(fd)?;
+        }
+        if let Some(ref mut barcode_reader) = self.barcode_reader {
+            barcode_reader.save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.chr_banks.load(fd)?;
+        self.prg_bank.load(fd)?;
+        self.mirroring.load(fd)?;
+        self.irq_enabled.load(fd)?;
+        self.irq_counter.load(fd)?;
+        self.irq_latch.load(fd)?;
+        self.control.load(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).load(fd)?;
+        }
+        if self.save_data().is_none() {
+            (&mut self.prg_ram[..]).load(fd)?;
+        }
+        if let Some(ref mut eeprom) = self.eeprom {
+            eeprom.load(fd)?;
+        }
+        if let Some(ref mut eeprom) = self.cartridge_eeprom {
+            eeprom.load(fd)?;
+        }
+        if let Some(ref mut barcode_reader) = self.barcode_reader {
+            barcode_reader.load(fd)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapper for BandaiFcg {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr >= 0x8000 {
+            self.rom.prg[self.prg_offset(addr)]
+        } else if addr < 0x6000 {
+            0
+        } else if self.board == BandaiBoard::Sram {
+            if (self.control & 0x20) != 0 {
+                self.prg_ram[addr as usize & 0x1fff]
+            } else {
+                0
+            }
+        } else {
+            self.read_status()
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            self.write_register(addr, val);
+        } else if addr >= 0x6000 {
+            match self.board {
+                BandaiBoard::Fcg => self.write_register(addr, val),
+                BandaiBoard::Sram if (self.control & 0x20) != 0 => {
+                    self.prg_ram[addr as usize & 0x1fff] = val
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            self.rom.chr[addr as usize & 0x1fff] = val
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn cpu_cycles(&mut self, cycles: u64) -> MapperResult {
+        if let Some(ref mut reader) = self.barcode_reader {
+            reader.cpu_cycles(cycles);
+        }
+        let mut result = MapperResult::Continue;
+        if self.irq_enabled {
+            for _ in 0..cycles {
+                // Checking for 0 before decrementing, rather than after, is what both FCG and
+                // LZ93D50 games expect.
+                if self.irq_counter == 0 {
+                    result = MapperResult::Irq;
+                }
+                self.irq_counter = self.irq_counter.wrapping_sub(1);
+            }
+        }
+        result
+    }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        if self.board == BandaiBoard::Sram && self.rom.header.battery() {
+            Some(&mut self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn save_regions(&mut self) -> Vec<SaveRegion<'_>> {
+        let mut regions = Vec::new();
+        if self.board == BandaiBoard::Sram && self.rom.header.battery() {
+            regions.push(SaveRegion {
+                name: "",
+                data: &mut self.prg_ram,
+            });
+        }
+        if let Some(ref mut eeprom) = self.eeprom {
+            regions.push(SaveRegion {
+                name: "eeprom",
+                data: &mut eeprom.data,
+            });
+        }
+        if let Some(ref mut eeprom) = self.cartridge_eeprom {
+            regions.push(SaveRegion {
+                name: "cartridge-eeprom",
+                data: &mut eeprom.data,
+            });
+        }
+        regions
+    }
+
+    fn barcode_reader(&mut self) -> Option<&mut BarcodeReader> {
+        self.barcode_reader.as_mut()
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::OneScreenLower,
+            _ => Mirroring::OneScreenUpper,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            irq: true,
+            prg_ram: self.board == BandaiBoard::Sram,
+            non_volatile: self.eeprom.is_some()
+                || (self.board == BandaiBoard::Sram && self.rom.header.battery()),
+            ..Capabilities::default()
+        }
+    }
+}
+
+//
+// Mapper 18 (Jaleco SS88006)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_018
+//
+
+pub struct Ss88006 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    /// The three switchable 8K PRG banks and eight 1K CHR banks, each written a nibble at a time.
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    /// Register $F002: 0 horizontal, 1 vertical, 2 and 3 one-screen.
+    mirroring: u8,
+    /// Register $9002: PRG-RAM enabled (bit 0) and writable (bit 1).
+    prg_ram_control: u8,
+    prg_ram: Box<[u8; 8192]>,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    /// The bits of the counter that count: the low 16, 12, 8 or 4.
+    irq_mask: u16,
+}
+
+impl Ss88006 {
+    fn new(rom: Box<Rom>) -> Ss88006 {
+        Ss88006 {
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            mirroring: 0,
+            prg_ram_control: 0,
+            prg_ram: Box::new([0; 8192]),
+
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_mask: 0xffff,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        self.chr_region.offset(bank, addr)
+    }
+}
+
+/// Replaces the low (`high` false) or high nibble of a bank register.
+fn set_nibble(reg: &mut u8, high: bool, val: u8) {
+    *reg = if high {
+        (*reg & 0x0f) | (val << 4)
+    } else {
+        (*reg & 0xf0) | (val & 0x0f)
+    };
+}
+
+impl Save for Ss88006 {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.prg_banks.save(fd)?;
+        self.chr_banks.save(fd)?;
+        self.mirroring.save(fd)?;
+        self.prg_ram_control.save(fd)?;
+        if !self.rom.header.battery() {
+            self.prg_ram.save(fd)?;
+        }
+        self.irq_reload.save(fd)?;
+        self.irq_counter.save(fd)?;
+        self.irq_enabled.save(fd)?;
+        self.irq_mask.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.prg_banks.load(fd)?;
+        self.chr_banks.load(fd)?;
+        self.mirroring.load(fd)?;
+        self.prg_ram_control.load(fd)?;
+        if !self.rom.header.battery() {
+            self.prg_ram.load(fd)?;
+        }
+        self.irq_reload.load(fd)?;
+        self.irq_counter.load(fd)?;
+        self.irq_enabled.load(fd)?;
+        self.irq_mask.load(fd)
+    }
+}
+
+impl Mapper for Ss88006 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x6000 {
+            0
+        } else if addr < 0x8000 {
+            if (self.prg_ram_control & 1) != 0 {
+                self.prg_ram[addr as usize & 0x1fff]
+            } else {
+                0
+            }
+        } else {
+            let bank = match (addr >> 13) & 3 {
+                3 => self.prg_region.from_end(1),
+                slot => self.prg_banks[slot as usize] as usize,
+            };
+            self.rom.prg[self.prg_region.offset(bank, addr)]
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            if self.prg_ram_control == 3 {
+                self.prg_ram[addr as usize & 0x1fff] = val;
+            }
+            return;
+        }
+        let high = (addr & 1) != 0;
+        match addr & 0xf003 {
+            0x8000 | 0x8001 => set_nibble(&mut self.prg_banks[0], high, val),
+            0x8002 | 0x8003 => set_nibble(&mut self.prg_banks[1], high, val),
+            0x9000 | 0x9001 => set_nibble(&mut self.prg_banks[2], high, val),
+            0x9002 => self.prg_ram_control = val & 3,
+            0xa000..=0xd003 => {
+                // Two banks per register page: $A000-$A001, $A002-$A003, $B000-$B001 and so on.
+                let bank = ((addr >> 12) - 0xa) as usize * 2 + ((addr >> 1) & 1) as usize;
+                set_nibble(&mut self.chr_banks[bank], high, val);
+            }
+            0xe000..=0xe003 => {
+                let shift = (addr & 3) * 4;
+                self.irq_reload =
+                    (self.irq_reload & !(0xf << shift)) | ((val as u16 & 0xf) << shift);
+            }
+            0xf000 => self.irq_counter = self.irq_reload,
+            0xf001 => {
+                self.irq_enabled = (val & 1) != 0;
+                self.irq_mask = if (val & 8) != 0 {
+                    0x000f
+                } else if (val & 4) != 0 {
+                    0x00ff
+                } else if (val & 2) != 0 {
+                    0x0fff
+                } else {
+                    0xffff
+                };
+            }
+            0xf002 => self.mirroring = val & 3,
+            // $F003 drives the uPD7756 ADPCM chip, which isn't emulated.
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn cpu_cycles(&mut self, cycles: u64) -> MapperResult {
+        let mut result = MapperResult::Continue;
+        if self.irq_enabled {
+            for _ in 0..cycles {
+                // Only the selected low bits count down; the IRQ fires when they wrap.
+                let count = self.irq_counter & self.irq_mask;
+                if count == 0 {
+                    result = MapperResult::Irq;
+                }
+                self.irq_counter =
+                    (self.irq_counter & !self.irq_mask) | (count.wrapping_sub(1) & self.irq_mask);
+            }
+        }
+        result
+    }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        if self.rom.header.battery() {
+            Some(&mut *self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::OneScreenLower,
+            _ => Mirroring::OneScreenUpper,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            irq: true,
+            // The uPD7756 that some boards carry for speech.
+            audio: true,
+            prg_ram: true,
+            non_volatile: self.rom.header.battery(),
+            ..Capabilities::default()
+        }
+    }
+}
+
+//
+// Mapper 32 (Irem G-101)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_032
+//
+
+pub struct G101 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    prg_banks: [u8; 2],
+    chr_banks: [u8; 8],
+    /// Register $9000: horizontal mirroring (bit 0) and the PRG mode (bit 1), which swaps the
+    /// first switchable bank with the fixed bank at $C000.
+    control: u8,
+}
+
+impl G101 {
+    fn new(rom: Box<Rom>) -> G101 {
+        G101 {
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            prg_banks: [0; 2],
+            chr_banks: [0; 8],
+            control: 0,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        self.chr_region.offset(bank, addr)
+    }
+}
+
+save_struct!(G101 {
+    prg_banks,
+    chr_banks,
+    control
+});
+
+impl Mapper for G101 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        let swapped = (self.control & 2) != 0;
+        let bank = match (addr >> 13) & 3 {
+            0 if swapped => self.prg_region.from_end(2),
+            0 => self.prg_banks[0] as usize,
+            1 => self.prg_banks[1] as usize,
+            2 if swapped => self.prg_banks[0] as usize,
+            2 => self.prg_region.from_end(2),
+            _ => self.prg_region.from_end(1),
+        };
+        self.rom.prg[self.prg_region.offset(bank, addr)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr & 0xf000 {
+            0x8000 => self.prg_banks[0] = val & 0x1f,
+            0x9000 => self.control = val,
+            0xa000 => self.prg_banks[1] = val & 0x1f,
+            0xb000 => self.chr_banks[addr as usize & 7] = val,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    // FIXME: Major League wires the board for one-screen mirroring, which needs the NES 2.0
+    // submapper to tell apart.
+    fn mirroring(&mut self) -> Mirroring {
+        if (self.control & 1) != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+}
+
+//
+// Mapper 65 (Irem H3001)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_065
+//
+
+pub struct H3001 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    horizontal_mirroring: bool,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+}
+
+impl H3001 {
+    fn new(rom: Box<Rom>) -> H3001 {
+        H3001 {
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            // The banks power on mapped like a 32K PRG-ROM.
+            prg_banks: [0, 1, 0xfe],
+            chr_banks: [0; 8],
+            horizontal_mirroring: false,
+
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        self.chr_region.offset(bank, addr)
+    }
+}
+
+save_struct!(H3001 {
+    prg_banks,
+    chr_banks,
+    horizontal_mirroring,
+    irq_reload,
+    irq_counter,
+    irq_enabled
+});
+
+impl Mapper for H3001 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        let bank = match (addr >> 13) & 3 {
+            3 => self.prg_region.from_end(1),
+            slot => self.prg_banks[slot as usize] as usize,
+        };
+        self.rom.prg[self.prg_region.offset(bank, addr)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr & 0xf007 {
+            0x8000..=0x8007 => self.prg_banks[0] = val,
+            0x9001 => self.horizontal_mirroring = (val & 0x80) != 0,
+            0x9003 => self.irq_enabled = (val & 0x80) != 0,
+            0x9004 => self.irq_counter = self.irq_reload,
+            0x9005 => self.irq_reload = (self.irq_reload & 0x00ff) | (val as u16) << 8,
+            0x9006 => self.irq_reload = (self.irq_reload & 0xff00) | val as u16,
+            0xa000..=0xa007 => self.prg_banks[1] = val,
+            0xb000..=0xb007 => self.chr_banks[addr as usize & 7] = val,
+            0xc000..=0xc007 => self.prg_banks[2] = val,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn cpu_cycles(&mut self, cycles: u64) -> MapperResult {
+        if !self.irq_enabled || self.irq_counter == 0 {
+            return MapperResult::Continue;
+        }
+        // The counter stops when it reaches 0, rather than wrapping.
+        self.irq_counter = self.irq_counter.saturating_sub(cycles.min(0xffff) as u16);
+        if self.irq_counter == 0 {
+            MapperResult::Irq
+        } else {
+            MapperResult::Continue
+        }
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            irq: true,
+            ..Capabilities::default()
+        }
+    }
+}
+
+//
+// Mapper 77 (Irem, Napoleon Senki)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_077
+//
+
+pub struct Irem77 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    /// CCCCPPPP: the 2K CHR-ROM bank at $0000 and the 32K PRG bank.
+    bank: u8,
+    /// 8K of RAM: the first 2K holds the nametables at $2000-$27FF and the rest the pattern
+    /// tables above $0800.
+    ram: Box<[u8; 8192]>,
+    /// The nametables at $2800-$2FFF, which are the console's own RAM on the real board.
+    console_nametables: Box<[u8; 2048]>,
+}
+
+impl Irem77 {
+    fn new(rom: Box<Rom>) -> Irem77 {
+        Irem77 {
+            prg_region: BankedRegion::new(rom.prg.len(), 32768),
+            chr_region: BankedRegion::new(rom.chr.len(), 2048),
+            rom,
+            bank: 0,
+            ram: Box::new([0; 8192]),
+            console_nametables: Box::new([0; 2048]),
+        }
+    }
+}
+
+save_struct!(Irem77 {
+    bank,
+    ram,
+    console_nametables
+});
+
+impl Mapper for Irem77 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        self.rom.prg[self.prg_region.offset(self.bank as usize & 0xf, addr)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            self.bank = val;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x07ff => {
+                self.rom.chr[self.chr_region.offset((self.bank >> 4) as usize, addr)]
+            }
+            0x0800..=0x1fff => self.ram[addr as usize],
+            _ if (addr & 0xfff) < 0x800 => self.ram[addr as usize & 0x7ff],
+            _ => self.console_nametables[addr as usize & 0x7ff],
+        }
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x07ff => {} // Can't store to CHR-ROM.
+            0x0800..=0x1fff => self.ram[addr as usize] = val,
+            _ if (addr & 0xfff) < 0x800 => self.ram[addr as usize & 0x7ff] = val,
+            _ => self.console_nametables[addr as usize & 0x7ff] = val,
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        if addr < 0x800 {
+            Some(self.chr_region.offset((self.bank >> 4) as usize, addr))
+        } else {
+            // Past the end of the ROM, so RAM tiles don't share cache entries with ROM tiles.
+            Some(self.rom.chr.len() + addr as usize)
+        }
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        Mirroring::Cartridge
+    }
+}
+
+//
+// Mapper 97 (Irem TAM-S1)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_097
+//
+
+pub struct TamS1 {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    /// MM-PPPPP: the mirroring and the 16K PRG bank at $C000. Unlike most boards, the bank at
+    /// $8000 is the one fixed, to the last bank.
+    bank: u8,
+    /// 8K of CHR-RAM stands in for CHR-ROM if the cartridge has none.
+    chr_ram: bool,
+}
+
+impl TamS1 {
+    fn new(mut rom: Box<Rom>) -> TamS1 {
+        let chr_ram = rom.chr.is_empty();
+        if chr_ram {
+            rom.chr = vec![0; 8192];
+        }
+        TamS1 {
+            prg_region: BankedRegion::new(rom.prg.len(), 16384),
+            rom,
+            bank: 0,
+            chr_ram,
+        }
+    }
+}
+
+impl Save for TamS1 {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.bank.save(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).save(fd)?;
+        }
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.bank.load(fd)?;
+        if self.chr_ram {
+            (&mut self.rom.chr[..]).load(fd)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapper for TamS1 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        let bank = if addr < 0xc000 {
+            self.prg_region.from_end(1)
+        } else {
+            self.bank as usize & 0x1f
+        };
+        self.rom.prg[self.prg_region.offset(bank, addr)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if (0x8000..0xc000).contains(&addr) {
+            self.bank = val;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.rom.chr[addr as usize & 0x1fff]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            self.rom.chr[addr as usize & 0x1fff] = val
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(addr as usize & 0x1fff)
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        match self.bank >> 6 {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::OneScreenUpper,
+        }
+    }
 }
@@ -3,15 +3,21 @@
 //
 
 use apu::Apu;
+use cheat::{self, Cheats};
+use cpu::{Cycles, InterruptLines, InterruptSource};
+use epsm;
 use input::Input;
-use mapper::Mapper;
+#[cfg(feature = "serde")]
+use mapper::DetachedBoard;
+use mapper::SharedMapper;
 use ppu::Ppu;
+use provenance::{WriteProvenance, Writer};
+use trace::AccessTrace;
 use util::Save;
+use vgm::VgmLog;
 
-use std::cell::RefCell;
-use std::fs::File;
+use std::io::{self, Read, Write};
 use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
 
 //
 // The memory interface
@@ -35,13 +41,24 @@ pub trait Mem {
     fn loadw_zp(&mut self, addr: u8) -> u16 {
         self.loadb(addr as u16) as u16 | (self.loadb((addr + 1) as u16) as u16) << 8
     }
+
+    /// Performs the DMA transfers pending at CPU cycle `cycle` and returns how many cycles they
+    /// stall the CPU for. Called by the CPU after every instruction.
+    fn run_dma(&mut self, _cycle: u64) -> u64 {
+        0
+    }
+
+    /// Called by the CPU when it is reset, at CPU cycle `cycle`.
+    fn on_reset(&mut self, _cycle: u64) {}
 }
 
 //
 // The NES' paltry 2KB of RAM
 //
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Ram {
+    #[cfg_attr(feature = "serde", serde(with = "::util::serde_byte_array"))]
     pub val: [u8; 0x800],
 }
 
@@ -69,76 +86,651 @@ impl Mem for Ram {
 }
 
 impl Save for Ram {
-    fn save(&mut self, fd: &mut File) {
-        (&mut **self as &mut [u8]).save(fd);
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        (&mut **self as &mut [u8]).save(fd)?;
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        (&mut **self as &mut [u8]).load(fd)?;
+        Ok(())
+    }
+}
+
+//
+// Buses with pluggable devices
+//
+
+/// A memory interface that other devices can be attached to. A device answers every read and
+/// write in the address range it's attached at, ahead of what was there before, so the last
+/// device attached over an address wins.
+pub trait Bus: Mem {
+    fn attach(&mut self, start: u16, end: u16, device: Box<dyn Mem>);
+}
+
+/// A bus made only of attached devices. Reads of unmapped addresses return the last value read
+/// (open bus) and writes to them are ignored. Devices that raise interrupts are attached with
+/// `attach_interrupts`; see `Cpu::step_polled`.
+///
+/// This is enough to run the CPU on a machine of your own, e.g. to test an instruction:
+///
+/// ```
+/// use nes::cpu::Cpu;
+/// use nes::mem::{Bus, DeviceBus, Mem, Ram};
+///
+/// /// A ROM filling $8000-$FFFF that resets to $8000.
+/// struct Rom(Vec<u8>);
+///
+/// impl Mem for Rom {
+///     fn loadb(&mut self, addr: u16) -> u8 {
+///         match addr {
+///             0xfffc => 0x00,
+///             0xfffd => 0x80,
+///             _ => self.0.get(addr as usize - 0x8000).cloned().unwrap_or(0xea),
+///         }
+///     }
+///     fn storeb(&mut self, _: u16, _: u8) {}
+/// }
+///
+/// let mut bus = DeviceBus::new();
+/// bus.attach(0x0000, 0x1fff, Box::new(Ram { val: [0; 0x800] }));
+/// // LDA #$42; STA $0010
+/// bus.attach(0x8000, 0xffff, Box::new(Rom(vec![0xa9, 0x42, 0x85, 0x10])));
+///
+/// let mut cpu = Cpu::new(bus);
+/// cpu.reset();
+/// cpu.step();
+/// cpu.step();
+/// assert_eq!(cpu.mem.loadb(0x0010), 0x42);
+/// // RAM is mirrored every 2K.
+/// assert_eq!(cpu.mem.loadb(0x0810), 0x42);
+/// ```
+pub struct DeviceBus {
+    devices: Vec<(u16, u16, Box<dyn Mem>)>,
+    /// Whether any device is attached in each 256-byte page, so that unmapped addresses are
+    /// found without searching the devices.
+    mapped_pages: [bool; 256],
+    interrupts: Vec<Box<dyn InterruptSource>>,
+    open_bus: u8,
+}
+
+impl Default for DeviceBus {
+    fn default() -> DeviceBus {
+        DeviceBus::new()
+    }
+}
+
+impl DeviceBus {
+    pub fn new() -> DeviceBus {
+        DeviceBus {
+            devices: Vec::new(),
+            mapped_pages: [false; 256],
+            interrupts: Vec::new(),
+            open_bus: 0,
+        }
+    }
+
+    /// Adds a source of interrupts, which the bus passes on to the CPU when polled.
+    pub fn attach_interrupts(&mut self, source: Box<dyn InterruptSource>) {
+        self.interrupts.push(source);
+    }
+
+    /// Whether a device is attached at `addr`.
+    #[inline]
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        self.device(addr).is_some()
+    }
+
+    #[inline]
+    fn device(&self, addr: u16) -> Option<usize> {
+        if !self.mapped_pages[addr as usize >> 8] {
+            return None;
+        }
+        self.devices
+            .iter()
+            .rposition(|&(start, end, _)| start <= addr && addr <= end)
+    }
+}
+
+impl Bus for DeviceBus {
+    fn attach(&mut self, start: u16, end: u16, device: Box<dyn Mem>) {
+        for page in (start >> 8)..=(end >> 8) {
+            self.mapped_pages[page as usize] = true;
+        }
+        self.devices.push((start, end, device));
+    }
+}
+
+impl Mem for DeviceBus {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        if let Some(index) = self.device(addr) {
+            self.open_bus = self.devices[index].2.loadb(addr);
+        }
+        self.open_bus
     }
-    fn load(&mut self, fd: &mut File) {
-        (&mut **self as &mut [u8]).load(fd);
+    fn storeb(&mut self, addr: u16, val: u8) {
+        if let Some(index) = self.device(addr) {
+            self.devices[index].2.storeb(addr, val);
+        }
+    }
+}
+
+impl InterruptSource for DeviceBus {
+    fn poll(&mut self, cycle: Cycles) -> InterruptLines {
+        self.interrupts
+            .iter_mut()
+            .fold(InterruptLines::default(), |lines, source| {
+                lines.merge(source.poll(cycle))
+            })
+    }
+}
+
+//
+// DMA
+//
+
+/// OAM DMA takes a cycle to halt the CPU, plus one more to line up with a read cycle when it
+/// starts on an odd cycle, and then a read and a write for each of the 256 bytes: 513 or 514
+/// cycles in all.
+const OAM_DMA_HALT_CYCLES: u64 = 1;
+/// CPU cycles taken by a DMC sample fetch: halt, dummy, alignment and the read itself.
+const DMC_DMA_CYCLES: u64 = 4;
+/// A DMC fetch during OAM DMA shares its halt and alignment cycles, so it only takes two.
+const DMC_DMA_DURING_OAM_CYCLES: u64 = 2;
+
+/// Arbitrates the units that take the bus from the CPU: OAM DMA, started by writing a page
+/// number to $4014, and the DMC's sample fetches, which the APU asks for with `Apu::dmc_fetch`.
+pub struct Dma {
+    /// The page a write to $4014 asked to copy to OAM. The transfer starts after the instruction.
+    oam_page: Option<u8>,
+}
+
+impl Default for Dma {
+    fn default() -> Dma {
+        Dma::new()
+    }
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma { oam_page: None }
+    }
+
+    pub fn start_oam(&mut self, page: u8) {
+        self.oam_page = Some(page);
     }
 }
 
+//
+// Access metadata
+//
+
+/// What answers an address on the CPU bus.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Device {
+    /// A device attached with `Bus::attach`.
+    Attached,
+    Ram,
+    /// The PPU registers, $2000-$3FFF.
+    Ppu,
+    /// OAM DMA, started by writing $4014.
+    OamDma,
+    /// The controller port, $4016.
+    Controller,
+    /// The APU and the rest of the I/O registers, $4000-$4018.
+    Apu,
+    /// Everything from $4019 up: cartridge RAM, ROM and mapper registers.
+    Cartridge,
+}
+
+impl Device {
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Attached => "attached",
+            Device::Ram => "ram",
+            Device::Ppu => "ppu",
+            Device::OamDma => "oam_dma",
+            Device::Controller => "controller",
+            Device::Apu => "apu",
+            Device::Cartridge => "cartridge",
+        }
+    }
+}
+
+/// What a memory access touched, for debuggers, tracers and tests.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Access {
+    pub device: Device,
+    /// A read of a write-only or unused register, which real hardware answers with whatever was
+    /// last on the data bus.
+    pub open_bus: bool,
+    /// The access changed more than the byte at its address, e.g. a read of PPUSTATUS clearing
+    /// the vblank flag, a controller read shifting out a button, or a write switching banks.
+    pub side_effects: bool,
+}
+
 //
 // The main CPU memory map
 //
 
+/// Deserializing gives a machine without a cartridge: its mapper is a `DetachedBoard`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default = "MemMap::detached"))]
 pub struct MemMap {
     pub ram: Ram,
     pub ppu: Ppu,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub input: Input,
-    pub mapper: Rc<RefCell<Box<dyn Mapper + Send>>>,
+    #[cfg_attr(feature = "serde", serde(with = "::mapper::serde_board"))]
+    pub mapper: SharedMapper,
     pub apu: Apu,
+    /// Where accesses are logged, if tracing is enabled.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub access_trace: Option<AccessTrace>,
+    /// Where APU writes are logged, if VGM logging is enabled.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub apu_log: Option<VgmLog>,
+    /// The last writer of each byte of RAM, if write provenance is tracked.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub provenance: Option<WriteProvenance>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cheats: Cheats,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub dma: Dma,
+    /// Devices attached with `Bus::attach`, which take precedence over the built-in ones. They
+    /// aren't part of savestates. The built-in devices aren't attached but decoded by `device`,
+    /// which only searches these in pages something is attached to.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub devices: DeviceBus,
+    /// The CPU cycle and frame of the last reset, for `MachineClock`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reset_cycle: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reset_frame: u64,
 }
 
 impl MemMap {
-    pub fn new(
-        ppu: Ppu,
-        input: Input,
-        mapper: Rc<RefCell<Box<dyn Mapper + Send>>>,
-        apu: Apu,
-    ) -> MemMap {
+    pub fn new(ppu: Ppu, input: Input, mapper: SharedMapper, apu: Apu) -> MemMap {
         MemMap {
             ram: Ram { val: [0; 0x800] },
             ppu: ppu,
             input: input,
             mapper: mapper,
             apu: apu,
+            access_trace: None,
+            apu_log: None,
+            provenance: None,
+            cheats: Cheats::new(),
+            dma: Dma::new(),
+            devices: DeviceBus::new(),
+            reset_cycle: 0,
+            reset_frame: 0,
         }
     }
-}
 
-impl Mem for MemMap {
-    fn loadb(&mut self, addr: u16) -> u8 {
+    /// A machine with nothing plugged in, for deserializing.
+    #[cfg(feature = "serde")]
+    fn detached() -> MemMap {
+        MemMap::new(
+            Ppu::detached(),
+            Input::headless(),
+            DetachedBoard::shared(Vec::new()),
+            Apu::detached(),
+        )
+    }
+
+    /// Reads a byte of RAM or cartridge space without side effects. Registers read as 0.
+    pub fn peek(&mut self, addr: u16) -> u8 {
         if addr < 0x2000 {
             self.ram.loadb(addr)
-        } else if addr < 0x4000 {
-            self.ppu.loadb(addr)
-        } else if addr == 0x4016 {
-            self.input.loadb(addr)
-        } else if addr <= 0x4018 {
-            self.apu.loadb(addr)
         } else if addr < 0x6000 {
-            0 // FIXME: I think some mappers use regs in this area?
+            0
         } else {
-            let mut mapper = self.mapper.borrow_mut();
-            mapper.prg_loadb(addr)
+            self.mapper.borrow_mut().prg_loadb(addr)
         }
     }
-    fn storeb(&mut self, addr: u16, val: u8) {
+
+    /// Writes a byte of RAM or cartridge RAM once, as a cheat would. Other addresses are ignored.
+    pub fn poke(&mut self, addr: u16, val: u8) {
         if addr < 0x2000 {
             self.ram.storeb(addr, val)
+        } else if cheat::is_memory(addr) {
+            self.mapper.borrow_mut().prg_storeb(addr, val)
+        }
+    }
+
+    /// Freezes a byte of RAM or cartridge RAM at `val`, starting now.
+    pub fn freeze(&mut self, addr: u16, val: u8) {
+        self.cheats.freeze(addr, val);
+        self.poke(addr, val);
+    }
+
+    /// Starts or stops tracking which instruction writes each byte of RAM and VRAM. Tracking that
+    /// is already on keeps what it has recorded.
+    pub fn track_writes(&mut self, on: bool) {
+        if !on {
+            self.provenance = None;
+        } else if self.provenance.is_none() {
+            self.provenance = Some(WriteProvenance::new());
+        }
+        self.ppu.track_vram_writes(on);
+    }
+
+    /// The instruction that last wrote `addr` in RAM or cartridge RAM, if writes are tracked.
+    pub fn last_writer<'a>(&'a self, addr: u16) -> Option<Writer<'a>> {
+        self.provenance.as_ref()?.last_writer(addr)
+    }
+
+    /// Names what `addr` is, with the bank mapped there for cartridge space, to annotate memory
+    /// dumps.
+    pub fn region_label(&self, addr: u16) -> String {
+        match addr {
+            0x0000..=0x1fff => "RAM".to_string(),
+            0x2000..=0x3fff => "PPU registers".to_string(),
+            0x4000..=0x401f => "APU and I/O registers".to_string(),
+            _ => self
+                .mapper
+                .borrow()
+                .region_label(addr)
+                .unwrap_or_else(|| "cartridge".to_string()),
+        }
+    }
+
+    /// Writes the bytes from `start` to `end`, inclusive, as `peek` reads them: 16 to a line, with
+    /// a `; label` line wherever the region changes.
+    pub fn hex_dump<W: Write>(&mut self, writer: &mut W, start: u16, end: u16) -> io::Result<()> {
+        let mut label = String::new();
+        // Lines start on multiples of 16, so no region boundary falls inside one.
+        for line in (start as u32 >> 4)..(end as u32 >> 4) + 1 {
+            let line = (line << 4) as u16;
+            let line_label = self.region_label(line);
+            if line_label != label {
+                writeln!(writer, "; {}", line_label)?;
+                label = line_label;
+            }
+            let mut text = format!("{:04X}:", line);
+            for addr in line..=line + 15 {
+                if addr < start || addr > end {
+                    text.push_str("   ");
+                } else {
+                    text.push_str(&format!(" {:02X}", self.peek(addr)));
+                }
+            }
+            writeln!(writer, "{}", text.trim_end())?;
+        }
+        Ok(())
+    }
+
+    /// The device that answers a read (`write` false) or write of `addr`.
+    #[inline]
+    fn device(&self, addr: u16, write: bool) -> Device {
+        if self.devices.is_mapped(addr) {
+            Device::Attached
+        } else if addr < 0x2000 {
+            Device::Ram
         } else if addr < 0x4000 {
-            self.ppu.storeb(addr, val)
-        } else if addr == 0x4016 {
-            self.input.storeb(addr, val)
+            Device::Ppu
+        } else if addr == 0x4014 && write {
+            Device::OamDma
+        } else if addr == 0x4016 || (addr == 0x4017 && !write) {
+            // Writes to $4017 set up the APU frame counter.
+            Device::Controller
         } else if addr <= 0x4018 {
-            self.apu.storeb(addr, val)
-        } else if addr < 0x6000 {
-            // Nothing. FIXME: I think some mappers use regs in this area?
+            Device::Apu
         } else {
-            let mut mapper = self.mapper.borrow_mut();
-            mapper.prg_storeb(addr, val)
+            Device::Cartridge
+        }
+    }
+
+    /// Describes a read (`write` false) or write of `addr` without performing it.
+    pub fn access(&self, addr: u16, write: bool) -> Access {
+        let device = self.device(addr, write);
+        let (open_bus, side_effects) = match device {
+            Device::Attached | Device::Ram => (false, false),
+            // PPUCTRL, PPUMASK, OAMADDR, PPUSCROLL and PPUADDR are write-only. Reading PPUSTATUS
+            // clears vblank and the address latch; PPUDATA moves the VRAM address.
+            Device::Ppu if !write => match addr & 7 {
+                0 | 1 | 3 | 5 | 6 => (true, false),
+                2 | 7 => (false, true),
+                _ => (false, false),
+            },
+            Device::Ppu => (false, addr & 7 != 2),
+            Device::OamDma => (false, true),
+            Device::Controller => (false, true),
+            Device::Apu if !write => (addr != 0x4015, false),
+            Device::Apu => (false, addr <= 0x4013 || addr == 0x4015 || addr == 0x4017),
+            // Mapper registers live outside cartridge RAM at $6000-$7FFF.
+            Device::Cartridge => (false, write && !(0x6000..0x8000).contains(&addr)),
+        };
+        Access {
+            device,
+            open_bus,
+            side_effects,
+        }
+    }
+
+    /// Reads a byte like `loadb` and describes what the read touched.
+    #[cfg(feature = "debug")]
+    pub fn loadb_with_access(&mut self, addr: u16) -> (u8, Access) {
+        let access = self.access(addr, false);
+        (self.loadb(addr), access)
+    }
+
+    /// Writes a byte like `storeb` and describes what the write touched.
+    #[cfg(feature = "debug")]
+    pub fn storeb_with_access(&mut self, addr: u16, val: u8) -> Access {
+        let access = self.access(addr, true);
+        self.storeb(addr, val);
+        access
+    }
+
+    fn trace_access(&mut self, write: bool, addr: u16, val: u8) {
+        let access = if cfg!(feature = "debug") {
+            Some(self.access(addr, write))
+        } else {
+            None
+        };
+        let failed = match self.access_trace {
+            Some(ref mut trace) if trace.wants(addr) => {
+                let position = self.ppu.position(trace.cycle);
+                trace.record(position, write, addr, val, access).err()
+            }
+            _ => None,
+        };
+        if let Some(err) = failed {
+            println!("Error writing access trace, stopping it: {}", err);
+            self.access_trace = None;
+        }
+    }
+
+    fn record_writer(&mut self, addr: u16) {
+        // Writes to PPUDATA are recorded by the PPU, which knows the VRAM address written.
+        let ppudata = (0x2000..0x4000).contains(&addr) && addr & 7 == 7;
+        let pc = match self.provenance {
+            Some(ref provenance) if provenance.wants(addr) || ppudata => provenance.pc,
+            _ => return,
+        };
+        let region = self.region_label(pc);
+        if ppudata {
+            if let Some(provenance) = self.ppu.vram_provenance_mut() {
+                provenance.set_writer(pc, &region);
+            }
+        } else if let Some(ref mut provenance) = self.provenance {
+            provenance.record(addr, &region);
+        }
+    }
+}
+
+impl Mem for MemMap {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        let val = match self.device(addr, false) {
+            Device::Attached => self.devices.loadb(addr),
+            Device::Ram => self.ram.loadb(addr),
+            Device::Ppu => self.ppu.loadb(addr),
+            Device::Controller => {
+                if addr == self.input.zapper_port() {
+                    if let Some(ref mut zapper) = self.input.zapper {
+                        zapper.sense(&self.ppu);
+                    }
+                }
+                self.input.loadb(addr)
+            }
+            Device::OamDma | Device::Apu => self.apu.loadb(addr),
+            Device::Cartridge => self.mapper.borrow_mut().prg_loadb(addr),
+        };
+        self.trace_access(false, addr, val);
+        val
+    }
+    fn storeb(&mut self, addr: u16, val: u8) {
+        self.trace_access(true, addr, val);
+        self.record_writer(addr);
+        let val = self.cheats.apply(addr, val);
+        match self.device(addr, true) {
+            Device::Attached => {
+                if let Some(ref mut log) = self.apu_log {
+                    if (epsm::START..=epsm::END).contains(&addr) {
+                        log.record(addr, val);
+                    }
+                }
+                self.devices.storeb(addr, val)
+            }
+            Device::Ram => self.ram.storeb(addr, val),
+            Device::Ppu => self.ppu.storeb(addr, val),
+            Device::OamDma => self.dma.start_oam(val),
+            Device::Controller => self.input.storeb(addr, val),
+            Device::Apu => {
+                if let Some(ref mut log) = self.apu_log {
+                    if VgmLog::wants(addr) {
+                        log.record(addr, val);
+                    }
+                }
+                self.apu.storeb(addr, val)
+            }
+            Device::Cartridge => self.mapper.borrow_mut().prg_storeb(addr, val),
+        }
+    }
+
+    fn run_dma(&mut self, cycle: u64) -> u64 {
+        let mut now = cycle;
+        if let Some(page) = self.dma.oam_page.take() {
+            now += OAM_DMA_HALT_CYCLES + (cycle & 1);
+            let start = (page as u16) << 8;
+            for offset in 0..256 {
+                // DMC fetches win the bus, delaying the transfer.
+                while let Some(addr) = self.apu.dmc_fetch(now) {
+                    self.loadb(addr);
+                    now += DMC_DMA_DURING_OAM_CYCLES;
+                }
+                let val = self.loadb(start + offset);
+                self.storeb(0x2004, val);
+                now += 2;
+            }
+        }
+        // The output unit isn't emulated, so the sample byte only matters for its timing and for
+        // the side effects of reading it.
+        while let Some(addr) = self.apu.dmc_fetch(now) {
+            self.loadb(addr);
+            now += DMC_DMA_CYCLES;
+        }
+        now - cycle
+    }
+
+    fn on_reset(&mut self, cycle: u64) {
+        self.reset_cycle = cycle;
+        self.reset_frame = self.ppu.frame();
+    }
+}
+
+impl Bus for MemMap {
+    fn attach(&mut self, start: u16, end: u16, device: Box<dyn Mem>) {
+        self.devices.attach(start, end, device)
+    }
+}
+
+impl Save for MemMap {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.ram.save(fd)?;
+        self.ppu.save(fd)?;
+        self.apu.save(fd)?;
+        // Battery-backed PRG-RAM is saved too: loading a state should undo what the game saved
+        // since. Self-flashable PRG is left out, as it's the size of the game.
+        let mut mapper = self.mapper.borrow_mut();
+        let capabilities = mapper.capabilities();
+        if capabilities.prg_ram && capabilities.non_volatile {
+            if let Some(mut data) = mapper.save_data() {
+                data.save(fd)?;
+            }
+        }
+        self.input.save(fd)?;
+        // Whether an expansion sound chip is plugged in, then its state.
+        let mut expansion = self.apu.expansion.is_some();
+        expansion.save(fd)?;
+        if let Some(ref expansion) = self.apu.expansion {
+            expansion.borrow_mut().save(fd)?;
+        }
+        // The board's registers and memory, after their length.
+        let mut board = Vec::new();
+        mapper.save(&mut board)?;
+        let mut len = board.len() as u32;
+        len.save(fd)?;
+        (&mut board[..]).save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.ram.load(fd)?;
+        self.ppu.load(fd)?;
+        self.apu.load(fd)?;
+        let mut mapper = self.mapper.borrow_mut();
+        let capabilities = mapper.capabilities();
+        if capabilities.prg_ram && capabilities.non_volatile {
+            if let Some(mut data) = mapper.save_data() {
+                data.load(fd)?;
+            }
+        }
+        self.input.load(fd)?;
+        // A state saved without the chip leaves the one plugged in, if any, as it is.
+        let mut expansion = false;
+        expansion.load(fd)?;
+        match (expansion, self.apu.expansion.as_ref()) {
+            (false, _) => {}
+            (true, Some(chip)) => chip.borrow_mut().load(fd)?,
+            (true, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "savestate has expansion audio, but none is plugged in",
+                ))
+            }
+        }
+        // States upgraded from before the board was saved have none, which leaves it as it is.
+        let mut len = 0u32;
+        len.load(fd)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let mut board = Vec::new();
+        fd.take(len as u64).read_to_end(&mut board)?;
+        if board.len() != len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "savestate is truncated",
+            ));
+        }
+        let mut board = &board[..];
+        match mapper.load(&mut board) {
+            Ok(()) if board.is_empty() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "savestate is for another board",
+            )),
         }
     }
 }
 
-save_struct!(MemMap { ram, ppu, apu });
+/// A view of the memory map that reads with `MemMap::peek` and ignores writes, for inspecting
+/// memory without disturbing the machine.
+pub struct Peek<'a>(pub &'a mut MemMap);
+
+impl<'a> Mem for Peek<'a> {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        self.0.peek(addr)
+    }
+    fn storeb(&mut self, _: u16, _: u8) {}
+}
@@ -0,0 +1,140 @@
+//! Counters for monitoring long-running emulators, served over HTTP at `/metrics` in the
+//! Prometheus text format.
+//!
+//! The counters only count, and `serve` only serves, when built with the `metrics` feature.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How long a client may take to send its request or read the response, so that one that stalls
+/// doesn't hold up the others.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    #[inline]
+    pub fn add(&self, count: u64) {
+        if cfg!(feature = "metrics") {
+            self.0.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Metrics {
+    pub frames: Counter,
+    pub instructions: Counter,
+    /// Times the audio device asked for more samples than were ready.
+    pub audio_underruns: Counter,
+    pub savestates_saved: Counter,
+    pub savestates_loaded: Counter,
+    /// Frames rewound.
+    pub rewinds: Counter,
+}
+
+pub static METRICS: Metrics = Metrics {
+    frames: Counter::new(),
+    instructions: Counter::new(),
+    audio_underruns: Counter::new(),
+    savestates_saved: Counter::new(),
+    savestates_loaded: Counter::new(),
+    rewinds: Counter::new(),
+};
+
+impl Metrics {
+    /// The counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let counters = [
+            ("frames", "Frames emulated.", &self.frames),
+            (
+                "instructions",
+                "CPU instructions executed.",
+                &self.instructions,
+            ),
+            (
+                "audio_underruns",
+                "Times the audio device ran out of samples.",
+                &self.audio_underruns,
+            ),
+            (
+                "savestates_saved",
+                "Savestates written to disk.",
+                &self.savestates_saved,
+            ),
+            (
+                "savestates_loaded",
+                "Savestates read from disk.",
+                &self.savestates_loaded,
+            ),
+            ("rewinds", "Frames rewound.", &self.rewinds),
+        ];
+        let mut text = String::new();
+        for &(name, help, counter) in &counters {
+            text.push_str(&format!(
+                "# HELP sprocketnes_{0}_total {1}\n# TYPE sprocketnes_{0}_total counter\n\
+                 sprocketnes_{0}_total {2}\n",
+                name,
+                help,
+                counter.get()
+            ));
+        }
+        text
+    }
+}
+
+/// Serves `METRICS` at `http://<address>/metrics` from a background thread.
+pub fn serve(address: &str) -> io::Result<()> {
+    if !cfg!(feature = "metrics") {
+        return Err(io::Error::other(
+            "serving metrics requires the `metrics` feature",
+        ));
+    }
+    let listener = TcpListener::bind(address)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A client that hangs up early is no concern of ours.
+            let _ = respond(stream);
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(&stream);
+        reader.read_line(&mut request_line)?;
+        // Skip the headers.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
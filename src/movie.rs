@@ -0,0 +1,172 @@
+//! Input movies in FCEUX's `.fm2` text format, and latch movies.
+//!
+//! Only the first controller of `.fm2` movies is read. Frame lines look like `|0|RLDUTSBA|||`,
+//! where any character other than `.` or a space means the button is held.
+//!
+//! `.fm2` movies hold one input per frame, which can't replay games that read the controller
+//! several times a frame with different buttons. Latch movies (`.lmv`) instead hold the buttons
+//! at every controller latch, that is every write of 1 to $4016: the magic `SNLM` followed by a
+//! byte per latch.
+
+use emulator::Emulator;
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+const LATCH_MAGIC: &[u8; 4] = b"SNLM";
+
+pub struct Movie {
+    /// The buttons held on each frame, in the layout of `GamePadState::set_bits`.
+    pub frames: Vec<u8>,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> io::Result<Movie> {
+        let mut frames = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !line.starts_with('|') {
+                continue; // Header line
+            }
+            let port_0 = match line.split('|').nth(2) {
+                Some(port_0) => port_0,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed frame: {}", line),
+                    ))
+                }
+            };
+            frames.push(parse_buttons(port_0));
+        }
+        Ok(Movie { frames })
+    }
+
+    /// Runs `emulator` for `frames` frames with this movie's input. No buttons are held after
+    /// the movie ends.
+    pub fn play(&self, emulator: &mut Emulator, frames: usize) {
+        for frame in 0..frames {
+            let buttons = self.frames.get(frame).cloned().unwrap_or(0);
+            emulator.cpu.mem.input.gamepad_0.set_bits(buttons);
+            emulator.step_frame();
+        }
+    }
+}
+
+/// A movie that is recorded or played back a controller latch at a time.
+#[derive(Clone)]
+pub struct LatchMovie {
+    /// The buttons at each latch, in the layout of `GamePadState::set_bits`.
+    pub latches: Vec<u8>,
+    /// Whether the movie is recording rather than playing back.
+    recording: bool,
+    /// Where `finish` writes a recording; `None` when playing back or recording in memory.
+    record_path: Option<PathBuf>,
+    position: usize,
+}
+
+impl LatchMovie {
+    /// Starts recording a movie that `finish` writes to `path`.
+    pub fn record(path: &Path) -> LatchMovie {
+        LatchMovie {
+            latches: Vec::new(),
+            recording: true,
+            record_path: Some(path.to_path_buf()),
+            position: 0,
+        }
+    }
+
+    /// Starts recording a movie that is only kept in memory, continuing from `latches`.
+    pub fn record_in_memory(latches: Vec<u8>) -> LatchMovie {
+        LatchMovie {
+            latches,
+            recording: true,
+            record_path: None,
+            position: 0,
+        }
+    }
+
+    /// Plays back the given latches.
+    pub fn play_latches(latches: Vec<u8>) -> LatchMovie {
+        LatchMovie {
+            latches,
+            recording: false,
+            record_path: None,
+            position: 0,
+        }
+    }
+
+    /// Loads a movie for playback.
+    pub fn load(path: &Path) -> io::Result<LatchMovie> {
+        let data = fs::read(path)?;
+        Ok(LatchMovie::play_latches(LatchMovie::decode(&data)?))
+    }
+
+    /// Called when the game latches the controller while `buttons` are held. Returns the buttons
+    /// the game should read: `buttons` when recording, or the movie's when playing back. No
+    /// buttons are held after the movie ends.
+    pub fn latch(&mut self, buttons: u8) -> u8 {
+        if self.recording {
+            self.latches.push(buttons);
+            return buttons;
+        }
+        let buttons = self.latches.get(self.position).cloned().unwrap_or(0);
+        self.position += 1;
+        buttons
+    }
+
+    /// The latches recorded, or played back, so far.
+    pub fn so_far(&self) -> &[u8] {
+        if self.recording {
+            &self.latches
+        } else {
+            &self.latches[..self.position.min(self.latches.len())]
+        }
+    }
+
+    /// Encodes the latches so far as a latch movie file.
+    pub fn encode_so_far(&self) -> Vec<u8> {
+        let mut data = LATCH_MAGIC.to_vec();
+        data.extend_from_slice(self.so_far());
+        data
+    }
+
+    /// Decodes a latch movie file.
+    pub fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < LATCH_MAGIC.len() || &data[0..4] != LATCH_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a latch movie",
+            ));
+        }
+        Ok(data[4..].to_vec())
+    }
+
+    /// Writes the movie if recording.
+    pub fn finish(self) -> io::Result<()> {
+        match self.record_path {
+            Some(ref path) => fs::write(path, self.encode_so_far()),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `emulator` for `frames` frames with this movie's input.
+    pub fn play(self, emulator: &mut Emulator, frames: usize) {
+        emulator.cpu.mem.input.latch_movie = Some(self);
+        for _ in 0..frames {
+            emulator.step_frame();
+        }
+    }
+}
+
+/// Converts the `RLDUTSBA` field of a frame line to the bit layout of `GamePadState::set_bits`.
+pub fn parse_buttons(field: &str) -> u8 {
+    // Bit numbers of R, L, D, U, T(start), S(elect), B and A.
+    const BITS: [u8; 8] = [7, 6, 5, 4, 3, 2, 1, 0];
+    field
+        .chars()
+        .zip(BITS.iter())
+        .filter(|&(ch, _)| ch != '.' && ch != ' ')
+        .fold(0, |buttons, (_, &bit)| buttons | (1 << bit))
+}
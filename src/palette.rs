@@ -0,0 +1,154 @@
+//! The master palette: the RGB color of each of the PPU's 64 colors, under each combination of
+//! the PPUMASK color emphasis and grayscale bits.
+//!
+//! All 16 variants are built when a palette is loaded, so that drawing a pixel is a table lookup.
+//! `.pal` files of 512 colors carry their own emphasis variants, in the order of the PPUMASK
+//! emphasis bits; for 64-color files, each emphasis bit darkens the other two channels.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The colors the PPU can output.
+pub const COLORS: usize = 64;
+/// The size of a palette without emphasis variants in bytes: 64 RGB colors.
+pub const PALETTE_SIZE: usize = COLORS * 3;
+/// Emphasis off and the 7 combinations of the red, green and blue emphasis bits.
+const EMPHASIS_VARIANTS: usize = 8;
+/// What emphasizing another channel scales a channel by, out of 256 (about 0.816).
+const ATTENUATION: u32 = 209;
+
+static BUILTIN: [u8; PALETTE_SIZE] = [
+    124, 124, 124, 0, 0, 252, 0, 0, 188, 68, 40, 188, 148, 0, 132, 168, 0, 32, 168, 16, 0, 136, 20,
+    0, 80, 48, 0, 0, 120, 0, 0, 104, 0, 0, 88, 0, 0, 64, 88, 0, 0, 0, 0, 0, 0, 0, 0, 0, 188, 188,
+    188, 0, 120, 248, 0, 88, 248, 104, 68, 252, 216, 0, 204, 228, 0, 88, 248, 56, 0, 228, 92, 16,
+    172, 124, 0, 0, 184, 0, 0, 168, 0, 0, 168, 68, 0, 136, 136, 0, 0, 0, 0, 0, 0, 0, 0, 0, 248,
+    248, 248, 60, 188, 252, 104, 136, 252, 152, 120, 248, 248, 120, 248, 248, 88, 152, 248, 120,
+    88, 252, 160, 68, 248, 184, 0, 184, 248, 24, 88, 216, 84, 88, 248, 152, 0, 232, 216, 120, 120,
+    120, 0, 0, 0, 0, 0, 0, 252, 252, 252, 164, 228, 252, 184, 184, 248, 216, 184, 248, 248, 184,
+    248, 248, 164, 192, 240, 208, 176, 252, 224, 168, 248, 216, 120, 216, 248, 120, 184, 248, 184,
+    184, 248, 216, 0, 252, 252, 248, 216, 248, 0, 0, 0, 0, 0, 0,
+];
+
+#[derive(Clone)]
+pub struct Palette {
+    /// `COLORS` RGB triples for each variant, indexed by `variant`.
+    colors: Box<[[u8; 3]]>,
+}
+
+impl Palette {
+    /// The built-in palette.
+    pub fn builtin() -> Palette {
+        Palette::from_bytes(&BUILTIN).unwrap()
+    }
+
+    /// Builds a palette from RGB triples: 64 colors, or 512 with the emphasis variants. Returns
+    /// `None` if there are fewer than 64.
+    pub fn from_bytes(data: &[u8]) -> Option<Palette> {
+        let with_emphasis = data.len() >= PALETTE_SIZE * EMPHASIS_VARIANTS;
+        if data.len() < PALETTE_SIZE {
+            return None;
+        }
+
+        let mut colors = vec![[0; 3]; COLORS * EMPHASIS_VARIANTS * 2].into_boxed_slice();
+        for emphasis in 0..EMPHASIS_VARIANTS {
+            for grayscale in 0..2 {
+                let variant = Palette::variant(emphasis as u8, grayscale == 1);
+                for index in 0..COLORS {
+                    // Grayscale keeps only the brightness bits of the color.
+                    let source = if grayscale == 1 { index & 0x30 } else { index };
+                    let color = if with_emphasis {
+                        let offset = (emphasis * COLORS + source) * 3;
+                        [data[offset], data[offset + 1], data[offset + 2]]
+                    } else {
+                        let offset = source * 3;
+                        emphasize([data[offset], data[offset + 1], data[offset + 2]], emphasis)
+                    };
+                    colors[variant * COLORS + index] = color;
+                }
+            }
+        }
+        Some(Palette { colors })
+    }
+
+    /// Loads a palette from a `.pal` file.
+    pub fn load(path: &Path) -> io::Result<Palette> {
+        Palette::from_bytes(&fs::read(path)?).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "a palette has at least 64 RGB colors",
+            )
+        })
+    }
+
+    /// Writes the palette as a `.pal` file of 512 colors, with the emphasis variants.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// The palette as RGB triples: the 64 colors with emphasis off, then under each combination of
+    /// the emphasis bits in turn, as `from_bytes` reads them.
+    ///
+    /// ```
+    /// use nes::palette::Palette;
+    ///
+    /// let mut palette = Palette::builtin();
+    /// palette.set_color(0x21, [10, 20, 30]);
+    /// let copy = Palette::from_bytes(&palette.to_bytes()).unwrap();
+    /// assert_eq!(copy.color(0, 0x21), [10, 20, 30]);
+    /// assert_eq!(copy.color(Palette::variant(1, false), 0x21), [10, 16, 24]);
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PALETTE_SIZE * EMPHASIS_VARIANTS);
+        for emphasis in 0..EMPHASIS_VARIANTS {
+            let variant = Palette::variant(emphasis as u8, false);
+            for index in 0..COLORS {
+                bytes.extend_from_slice(&self.color(variant, index as u8));
+            }
+        }
+        bytes
+    }
+
+    /// Sets the color of `index`, $00-$3F, with emphasis off. Its emphasis and grayscale variants
+    /// are made from it as for 64-color files, replacing any that a 512-color file had.
+    pub fn set_color(&mut self, index: u8, color: [u8; 3]) {
+        let index = index as usize & 0x3f;
+        for emphasis in 0..EMPHASIS_VARIANTS {
+            for grayscale in 0..2 {
+                let variant = Palette::variant(emphasis as u8, grayscale == 1);
+                for shown in 0..COLORS {
+                    let source = if grayscale == 1 { shown & 0x30 } else { shown };
+                    if source == index {
+                        self.colors[variant * COLORS + shown] = emphasize(color, emphasis);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The variant for the emphasis bits, red in bit 0, green in bit 1 and blue in bit 2, and the
+    /// grayscale bit.
+    #[inline(always)]
+    pub fn variant(emphasis: u8, grayscale: bool) -> usize {
+        (emphasis as usize & 7) << 1 | grayscale as usize
+    }
+
+    /// The RGB color of `index`, $00-$3F, in `variant`.
+    #[inline(always)]
+    pub fn color(&self, variant: usize, index: u8) -> [u8; 3] {
+        self.colors[variant * COLORS + (index as usize & 0x3f)]
+    }
+}
+
+/// Darkens the channels of `color` that the emphasis bits don't select, once per bit set.
+fn emphasize(color: [u8; 3], emphasis: usize) -> [u8; 3] {
+    let mut color = color;
+    for (channel, level) in color.iter_mut().enumerate() {
+        for bit in 0..3 {
+            if bit != channel && emphasis & (1 << bit) != 0 {
+                *level = (*level as u32 * ATTENUATION / 256) as u8;
+            }
+        }
+    }
+    color
+}
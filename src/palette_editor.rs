@@ -0,0 +1,128 @@
+//! A debug tool for editing colors while the game runs, for ROM hackers trying out palette changes
+//! and for checking color emphasis. It edits the 32 entries of palette RAM, and the colors of the
+//! master palette they show, which can be exported as a `.pal` file.
+//!
+//! A palette RAM entry keeps its edited value until the game writes it again. Edits to the master
+//! palette last until the configuration is reloaded.
+
+use gfx::{Osd, OsdAnchor, OsdContent};
+use ppu::Ppu;
+
+use sdl2::keyboard::Keycode;
+use std::path::Path;
+
+/// Where the master palette is exported.
+pub const EXPORT_PATH: &str = "palette.pal";
+/// How much a key press raises or lowers a color channel.
+const CHANNEL_STEP: i32 = 4;
+/// The palette RAM entries.
+const ENTRIES: u8 = 0x20;
+
+pub struct PaletteEditor {
+    /// The palette RAM entry selected, $00-$1F.
+    entry: u8,
+}
+
+impl Default for PaletteEditor {
+    fn default() -> PaletteEditor {
+        PaletteEditor::new()
+    }
+}
+
+impl PaletteEditor {
+    pub fn new() -> PaletteEditor {
+        PaletteEditor { entry: 0 }
+    }
+
+    /// Handles a key pressed while the editor is open:
+    ///
+    /// * Left and Right select a palette RAM entry.
+    /// * Up and Down change the entry's color by 1, Page Up and Page Down by $10 (a brightness
+    ///   row).
+    /// * Q and A raise and lower the red of the master palette color the entry shows, W and S the
+    ///   green, E and D the blue.
+    /// * P exports the master palette to `EXPORT_PATH`.
+    ///
+    /// Returns a message to show, if any.
+    pub fn key(&mut self, key: Keycode, ppu: &mut Ppu) -> Option<String> {
+        let value = ppu.palette_entry(self.entry) & 0x3f;
+        let step = match key {
+            Keycode::Left => {
+                self.entry = (self.entry + ENTRIES - 1) % ENTRIES;
+                return None;
+            }
+            Keycode::Right => {
+                self.entry = (self.entry + 1) % ENTRIES;
+                return None;
+            }
+            Keycode::P => {
+                return Some(match ppu.master_palette().save(Path::new(EXPORT_PATH)) {
+                    Ok(()) => format!("Exported {}", EXPORT_PATH),
+                    Err(err) => format!("Error exporting palette: {}", err),
+                });
+            }
+            Keycode::Up => 1,
+            Keycode::Down => 0x3f,
+            Keycode::PageUp => 0x10,
+            Keycode::PageDown => 0x30,
+            _ => 0,
+        };
+        if step != 0 {
+            ppu.set_palette_entry(self.entry, value.wrapping_add(step) & 0x3f);
+            return None;
+        }
+
+        let (channel, change) = match key {
+            Keycode::Q => (0, CHANNEL_STEP),
+            Keycode::A => (0, -CHANNEL_STEP),
+            Keycode::W => (1, CHANNEL_STEP),
+            Keycode::S => (1, -CHANNEL_STEP),
+            Keycode::E => (2, CHANNEL_STEP),
+            Keycode::D => (2, -CHANNEL_STEP),
+            _ => return None,
+        };
+        let mut color = ppu.master_palette().color(0, value);
+        color[channel] = (color[channel] as i32 + change).clamp(0, 255) as u8;
+        ppu.set_master_color(value, color);
+        None
+    }
+
+    /// Shows the editor in the top left corner: palette RAM as it looks under the current
+    /// emphasis, then the selected entry and the color it shows without emphasis. Call every
+    /// frame, as the game changes the palette.
+    pub fn show(&self, ppu: &Ppu, osd: &mut Osd) {
+        let colors = (0..ENTRIES)
+            .map(|entry| ppu.rgb(ppu.palette_entry(entry)))
+            .collect();
+        osd.set(
+            "palette_swatches",
+            OsdAnchor::TopLeft,
+            OsdContent::Swatches(colors, self.entry as usize),
+            None,
+        );
+
+        let value = ppu.palette_entry(self.entry) & 0x3f;
+        let color = ppu.master_palette().color(0, value);
+        let bits = ppu.emphasis();
+        let emphasis: String = ['R', 'G', 'B']
+            .iter()
+            .enumerate()
+            .map(|(bit, &name)| if bits & 1 << bit != 0 { name } else { '-' })
+            .collect();
+        let text = format!(
+            "$3F{:02X} = ${:02X} #{:02X}{:02X}{:02X} emphasis {}",
+            self.entry, value, color[0], color[1], color[2], emphasis
+        );
+        osd.set(
+            "palette_entry",
+            OsdAnchor::TopLeft,
+            OsdContent::Text(text),
+            None,
+        );
+    }
+
+    pub fn hide(osd: &mut Osd) {
+        osd.remove("palette_swatches");
+        osd.remove("palette_entry");
+    }
+}
@@ -0,0 +1,97 @@
+//! A line protocol for driving the emulator from another program through a pipe, for shell
+//! scripts and tools not written in Rust that need deterministic runs.
+//!
+//! Each input line is `<frame>:<buttons>`, where the buttons are an `RLDUTSBA` field as in `.fm2`
+//! movies (any character other than `.` or a space means the button is held, and an empty field
+//! holds none). The emulator runs up to and including the given frame, counted from 1, holding the
+//! buttons on every frame since the previous line, and answers with `<frame>:<crc32>`, the screen
+//! hash after that frame in the form batch manifests take. Blank lines and lines starting with
+//! `#` are skipped. For example:
+//!
+//! ```text
+//! 60:........
+//! 61:...T....
+//! 120:R......A
+//! ```
+
+use emulator::Emulator;
+use movie;
+
+use std::io::{self, BufRead, Write};
+
+/// Runs `emulator` as the lines of `input` ask, writing a hash to `output` for each and flushing
+/// it, so a program waiting on the answer to one line before sending the next doesn't stall.
+/// Returns the number of frames run. Stops at the first malformed line or a frame that was
+/// already run.
+///
+/// ```
+/// use nes::config::Config;
+/// use nes::emulator::Emulator;
+/// use nes::pipe;
+///
+/// let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+/// let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+/// let mut output = Vec::new();
+/// let frames = pipe::run(&mut emulator, &b"# Hold A\n2:.......A\n\n5:\n"[..], &mut output);
+/// assert_eq!(frames.unwrap(), 5);
+/// let hash = format!("{:08x}", emulator.screen_hash());
+/// let output = String::from_utf8(output).unwrap();
+/// assert_eq!(output.lines().nth(1), Some(&*format!("5:{}", hash)));
+/// ```
+pub fn run<R: BufRead, W: Write>(
+    emulator: &mut Emulator,
+    input: R,
+    output: &mut W,
+) -> io::Result<u64> {
+    let mut frame = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (target, buttons) = match parse_line(line) {
+            Some((target, _)) if target <= frame => {
+                return Err(malformed(
+                    index + 1,
+                    &format!("frame {} was already run", target),
+                ))
+            }
+            Some(parsed) => parsed,
+            None => {
+                return Err(malformed(
+                    index + 1,
+                    &format!("expected <frame>:<buttons>, not {}", line),
+                ))
+            }
+        };
+
+        emulator.set_buttons(buttons);
+        while frame < target {
+            emulator.step_frame();
+            frame += 1;
+        }
+        writeln!(output, "{}:{:08x}", frame, emulator.screen_hash())?;
+        output.flush()?;
+    }
+    Ok(frame)
+}
+
+/// Parses `<frame>:<buttons>` into the frame and the buttons in the layout of
+/// `GamePadState::set_bits`.
+fn parse_line(line: &str) -> Option<(u64, u8)> {
+    let mut parts = line.splitn(2, ':');
+    let frame = parts.next()?.trim().parse().ok()?;
+    let buttons = parts.next()?;
+    if buttons.chars().count() > 8 {
+        return None;
+    }
+    Some((frame, movie::parse_buttons(buttons)))
+}
+
+fn malformed(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
@@ -0,0 +1,592 @@
+//! Experimental support for common pirate and unlicensed mappers, built with the `pirate` feature.
+//!
+//! These boards are poorly documented and vary from cartridge to cartridge, so they are emulated
+//! from what the games in circulation seem to need and reported as experimental.
+
+use mapper::{BankedRegion, Capabilities, Mapper, MapperConstructor, MapperResult, Mirroring};
+use rom::Rom;
+use util::Save;
+
+use std::io::{self, Read, Write};
+
+/// Returns the constructor for the pirate mapper with the given iNES number, if there is one.
+pub fn mapper_constructor(mapper: u8) -> Option<MapperConstructor> {
+    let constructor: MapperConstructor = match mapper {
+        90 => |rom| Box::new(JyCompany::new(rom, JyBoard::Jy90)),
+        163 => |rom| Box::new(Nanjing::new(rom)),
+        209 => |rom| Box::new(JyCompany::new(rom, JyBoard::Jy209)),
+        211 => |rom| Box::new(JyCompany::new(rom, JyBoard::Jy211)),
+        _ => return None,
+    };
+    Some(constructor)
+}
+
+//
+// Mappers 90, 209 and 211 (J.Y. Company)
+//
+// See http://wiki.nesdev.com/w/index.php/J.Y._Company_ASIC
+//
+
+/// The boards built around the J.Y. Company ASIC, which differ in which of its features are
+/// wired up.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum JyBoard {
+    /// Mapper 90: no CHR-ROM nametables.
+    Jy90,
+    /// Mapper 209: CHR-ROM nametables when enabled, and an MMC4-like CHR latch.
+    Jy209,
+    /// Mapper 211: CHR-ROM nametables always enabled.
+    Jy211,
+}
+
+/// How many times the IRQ counter's PPU sources are clocked per scanline: the pattern table
+/// address line rises once per sprite fetch, and the PPU reads about 170 times.
+const JY_A12_RISES_PER_SCANLINE: u32 = 8;
+const JY_PPU_READS_PER_SCANLINE: u32 = 170;
+
+pub struct JyCompany {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    chr_region: BankedRegion,
+    board: JyBoard,
+    /// 8K PRG banks; the units depend on the PRG mode.
+    prg_regs: [u8; 4],
+    chr_regs: [u16; 8],
+    /// The CHR-ROM bank or console nametable page each nametable uses when CHR-ROM nametables are
+    /// on.
+    nametable_regs: [u16; 4],
+    /// Register $D000: the PRG mode (bits 0-2, bit 2 unfixing the last bank), CHR mode (bits 3-4),
+    /// CHR-ROM nametables (bit 5, mapper 209), all nametables from CHR-ROM (bit 6) and PRG-ROM at
+    /// $6000 (bit 7).
+    mode: u8,
+    /// Register $D001: 0 vertical, 1 horizontal, 2 and 3 one-screen.
+    mirroring: u8,
+    /// Register $D002: bit 7 picks which nametable registers select console RAM.
+    nametable_select: u8,
+    /// Register $D003: outer PRG (bits 1-2) and CHR (bits 0, 3 and 4) banks, with the CHR outer
+    /// bank ignored when bit 5 is set.
+    outer_bank: u8,
+    /// For the MMC4-like latch: whether tile $FE rather than $FD was last fetched from each pattern
+    /// table.
+    chr_latches: [bool; 2],
+    /// The console's nametables, which the board maps itself when CHR-ROM nametables are on.
+    console_nametables: Box<[u8; 2048]>,
+
+    irq_enabled: bool,
+    /// Register $C001: the count direction (bits 6-7, 1 up and 2 down), a 3-bit prescaler (bit 2)
+    /// and the clock source (bits 0-1): CPU cycles, PPU A12 rises, PPU reads or CPU writes. Only
+    /// writes to the cartridge count as CPU writes.
+    irq_mode: u8,
+    irq_prescaler: u8,
+    irq_counter: u8,
+    irq_xor: u8,
+    /// An IRQ raised outside of `cpu_cycles`, to be reported by its next call.
+    irq_pending: bool,
+
+    multiplicand: u8,
+    multiplier: u8,
+    /// The byte of RAM at $5803.
+    scratch: u8,
+}
+
+impl JyCompany {
+    fn new(mut rom: Box<Rom>, board: JyBoard) -> JyCompany {
+        if rom.chr.is_empty() {
+            rom.chr = vec![0; 8192];
+        }
+        JyCompany {
+            prg_region: BankedRegion::new(rom.prg.len(), 8192),
+            chr_region: BankedRegion::new(rom.chr.len(), 1024),
+            rom,
+            board,
+            prg_regs: [0; 4],
+            chr_regs: [0; 8],
+            nametable_regs: [0; 4],
+            mode: 0,
+            mirroring: 0,
+            nametable_select: 0,
+            outer_bank: 0,
+            chr_latches: [false, true],
+            console_nametables: Box::new([0; 2048]),
+
+            irq_enabled: false,
+            irq_mode: 0,
+            irq_prescaler: 0,
+            irq_counter: 0,
+            irq_xor: 0,
+            irq_pending: false,
+
+            multiplicand: 0,
+            multiplier: 0,
+            scratch: 0,
+        }
+    }
+
+    /// Maps a CPU address in $6000-$FFFF to an offset into PRG-ROM.
+    fn prg_offset(&self, addr: u16) -> usize {
+        // PRG mode 3 is mode 2 with the bank numbers written backwards.
+        let reversed = (self.mode & 3) == 3;
+        let reg = |index: usize| {
+            let val = self.prg_regs[index] as usize & 0x7f;
+            if reversed {
+                (0..7).fold(0, |bank, bit| bank | ((val >> bit) & 1) << (6 - bit))
+            } else {
+                val
+            }
+        };
+        // The last bank is fixed unless bit 2 of the mode says otherwise.
+        let last = if (self.mode & 4) != 0 { reg(3) } else { 0x7f };
+        let slot = if addr < 0x8000 {
+            3
+        } else {
+            ((addr >> 13) & 3) as usize
+        };
+        let bank = match (self.mode & 3, addr < 0x8000) {
+            (0, true) => (reg(3) << 2) | 3,
+            (0, false) => (last << 2) | slot,
+            (1, true) => (reg(3) << 1) | 1,
+            (1, false) if slot < 2 => (reg(1) << 1) | (slot & 1),
+            (1, false) => (last << 1) | (slot & 1),
+            (_, true) => reg(3),
+            (_, false) if slot < 3 => reg(slot),
+            (_, false) => last,
+        };
+        let outer = ((self.outer_bank >> 1) & 3) as usize;
+        let bank = (bank & 0x3f) | outer << 6;
+        self.prg_region.offset(bank, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let slot = (addr >> 10) as usize & 7;
+        let (reg, shift) = match (self.mode >> 3) & 3 {
+            0 => (self.chr_regs[0], 3),
+            1 if self.board == JyBoard::Jy209 => {
+                let half = slot / 4;
+                let index = half * 4 + if self.chr_latches[half] { 2 } else { 0 };
+                (self.chr_regs[index], 2)
+            }
+            1 => (self.chr_regs[slot & 4], 2),
+            2 => (self.chr_regs[slot & 6], 1),
+            _ => (self.chr_regs[slot], 0),
+        };
+        let reg = if (self.outer_bank & 0x20) == 0 {
+            // The outer bank picks a 256K block.
+            let block = (self.outer_bank & 1) | ((self.outer_bank >> 2) & 6);
+            (reg & (0xff >> shift)) | (block as u16) << (8 - shift)
+        } else {
+            reg
+        } as usize;
+        let bank = (reg << shift) | (slot & ((1 << shift) - 1));
+        self.chr_region.offset(bank, addr)
+    }
+
+    fn rom_nametables(&self) -> bool {
+        match self.board {
+            JyBoard::Jy90 => false,
+            JyBoard::Jy209 => (self.mode & 0x20) != 0,
+            JyBoard::Jy211 => true,
+        }
+    }
+
+    /// Where a nametable address maps to: CHR-ROM (true) or console RAM, and the offset in it.
+    fn nametable_offset(&self, addr: u16) -> (bool, usize) {
+        let reg = self.nametable_regs[(addr >> 10) as usize & 3];
+        let from_rom =
+            (self.mode & 0x40) != 0 || (reg & 0x80) as u8 != (self.nametable_select & 0x80);
+        if from_rom {
+            (true, self.chr_region.offset(reg as usize, addr))
+        } else {
+            (false, ((reg as usize & 1) * 1024) | (addr as usize & 0x3ff))
+        }
+    }
+
+    fn clock_irq(&mut self, clocks: u32) {
+        let direction = self.irq_mode >> 6;
+        if !self.irq_enabled || direction == 0 || direction == 3 {
+            return;
+        }
+        let mask = if (self.irq_mode & 4) != 0 { 0x07 } else { 0xff };
+        for _ in 0..clocks {
+            let prescaler = self.irq_prescaler & mask;
+            let (prescaler, wrapped) = if direction == 1 {
+                let prescaler = prescaler.wrapping_add(1) & mask;
+                (prescaler, prescaler == 0)
+            } else {
+                (prescaler.wrapping_sub(1) & mask, prescaler == 0)
+            };
+            self.irq_prescaler = (self.irq_prescaler & !mask) | prescaler;
+            if wrapped {
+                let (counter, wrapped) = if direction == 1 {
+                    (self.irq_counter.wrapping_add(1), self.irq_counter == 0xff)
+                } else {
+                    (self.irq_counter.wrapping_sub(1), self.irq_counter == 0)
+                };
+                self.irq_counter = counter;
+                if wrapped {
+                    self.irq_pending = true;
+                }
+            }
+        }
+    }
+
+    fn take_irq(&mut self) -> MapperResult {
+        if self.irq_pending {
+            self.irq_pending = false;
+            MapperResult::Irq
+        } else {
+            MapperResult::Continue
+        }
+    }
+}
+
+impl Save for JyCompany {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.prg_regs.save(fd)?;
+        for reg in self.chr_regs.iter_mut().chain(&mut self.nametable_regs) {
+            reg.save(fd)?;
+        }
+        self.mode.save(fd)?;
+        self.mirroring.save(fd)?;
+        self.nametable_select.save(fd)?;
+        self.outer_bank.save(fd)?;
+        for latch in &mut self.chr_latches {
+            latch.save(fd)?;
+        }
+        self.console_nametables.save(fd)?;
+        self.irq_enabled.save(fd)?;
+        self.irq_mode.save(fd)?;
+        self.irq_prescaler.save(fd)?;
+        self.irq_counter.save(fd)?;
+        self.irq_xor.save(fd)?;
+        self.irq_pending.save(fd)?;
+        self.multiplicand.save(fd)?;
+        self.multiplier.save(fd)?;
+        self.scratch.save(fd)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.prg_regs.load(fd)?;
+        for reg in self.chr_regs.iter_mut().chain(&mut self.nametable_regs) {
+            reg.load(fd)?;
+        }
+        self.mode.load(fd)?;
+        self.mirroring.load(fd)?;
+        self.nametable_select.load(fd)?;
+        self.outer_bank.load(fd)?;
+        for latch in &mut self.chr_latches {
+            latch.load(fd)?;
+        }
+        self.console_nametables.load(fd)?;
+        self.irq_enabled.load(fd)?;
+        self.irq_mode.load(fd)?;
+        self.irq_prescaler.load(fd)?;
+        self.irq_counter.load(fd)?;
+        self.irq_xor.load(fd)?;
+        self.irq_pending.load(fd)?;
+        self.multiplicand.load(fd)?;
+        self.multiplier.load(fd)?;
+        self.scratch.load(fd)
+    }
+}
+
+impl Mapper for JyCompany {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5000..=0x57ff => 0, // The DIP switches, all off.
+            0x5800 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5801 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5803 => self.scratch,
+            0x6000..=0x7fff if (self.mode & 0x80) != 0 => self.rom.prg[self.prg_offset(addr)],
+            0x8000..=0xffff => self.rom.prg[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if (self.irq_mode & 3) == 3 {
+            self.clock_irq(1);
+        }
+        match addr {
+            0x5800 => self.multiplicand = val,
+            0x5801 => self.multiplier = val,
+            0x5803 => self.scratch = val,
+            _ => {}
+        }
+        match addr & 0xf007 {
+            0x8000..=0x8007 => self.prg_regs[addr as usize & 3] = val,
+            0x9000..=0x9007 => {
+                let reg = &mut self.chr_regs[addr as usize & 7];
+                *reg = (*reg & 0xff00) | val as u16;
+            }
+            0xa000..=0xa007 => {
+                let reg = &mut self.chr_regs[addr as usize & 7];
+                *reg = (*reg & 0x00ff) | (val as u16) << 8;
+            }
+            0xb000..=0xb003 => {
+                let reg = &mut self.nametable_regs[addr as usize & 3];
+                *reg = (*reg & 0xff00) | val as u16;
+            }
+            0xb004..=0xb007 => {
+                let reg = &mut self.nametable_regs[addr as usize & 3];
+                *reg = (*reg & 0x00ff) | (val as u16) << 8;
+            }
+            0xc000 | 0xc002 | 0xc003 => {
+                self.irq_enabled = match addr & 7 {
+                    0 => (val & 1) != 0,
+                    2 => false,
+                    _ => true,
+                };
+                if !self.irq_enabled {
+                    self.irq_pending = false;
+                }
+            }
+            0xc001 => self.irq_mode = val,
+            0xc004 => self.irq_prescaler = val ^ self.irq_xor,
+            0xc005 => self.irq_counter = val ^ self.irq_xor,
+            0xc006 => self.irq_xor = val,
+            0xd000 | 0xd004 => self.mode = val,
+            0xd001 | 0xd005 => self.mirroring = val & 3,
+            0xd002 | 0xd006 => self.nametable_select = val,
+            0xd003 | 0xd007 => self.outer_bank = val,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        if addr >= 0x2000 {
+            return match self.nametable_offset(addr) {
+                (true, offset) => self.rom.chr[offset],
+                (false, offset) => self.console_nametables[offset],
+            };
+        }
+        let val = self.rom.chr[self.chr_offset(addr)];
+        if self.board == JyBoard::Jy209 {
+            // Fetching tile $FD or $FE from a pattern table switches its bank, as on the MMC4.
+            match addr & 0xff8 {
+                0xfd8 => self.chr_latches[addr as usize >> 12] = false,
+                0xfe8 => self.chr_latches[addr as usize >> 12] = true,
+                _ => {}
+            }
+        }
+        val
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x2000 {
+            if let (false, offset) = self.nametable_offset(addr) {
+                self.console_nametables[offset] = val;
+            }
+        }
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        if self.board == JyBoard::Jy209 && ((self.mode >> 3) & 3) == 1 {
+            // The latch has to see every fetch.
+            None
+        } else {
+            Some(self.chr_offset(addr))
+        }
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        match self.irq_mode & 3 {
+            1 => self.clock_irq(JY_A12_RISES_PER_SCANLINE),
+            2 => self.clock_irq(JY_PPU_READS_PER_SCANLINE),
+            _ => {}
+        }
+        self.take_irq()
+    }
+
+    fn cpu_cycles(&mut self, cycles: u64) -> MapperResult {
+        if (self.irq_mode & 3) == 0 {
+            self.clock_irq(cycles as u32);
+        }
+        self.take_irq()
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        if self.rom_nametables() {
+            return Mirroring::Cartridge;
+        }
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::OneScreenLower,
+            _ => Mirroring::OneScreenUpper,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            irq: true,
+            ..Capabilities::default()
+        }
+    }
+}
+
+//
+// Mapper 163 (Nanjing)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_163
+//
+
+pub struct Nanjing {
+    rom: Box<Rom>,
+    prg_region: BankedRegion,
+    /// The registers at $5000 (low PRG bits, and automatic CHR switching in bit 7), $5100, $5200
+    /// (high PRG bits) and $5300, which the copy protection reads back.
+    regs: [u8; 4],
+    /// Forces the first 32K bank, as written by $5100 = 6.
+    prg_forced: bool,
+    /// The protection's feedback bit, toggled by falling writes to $5101.
+    strobe: u8,
+    trigger: bool,
+    chr_ram: Box<[u8; 8192]>,
+    /// The 4K pages of CHR-RAM in each pattern table. With automatic switching, both tables show
+    /// the first page above the middle of the screen and the second below it.
+    chr_pages: [u8; 2],
+    chr_region: BankedRegion,
+    prg_ram: Box<[u8; 8192]>,
+    scanline: u16,
+}
+
+impl Nanjing {
+    fn new(rom: Box<Rom>) -> Nanjing {
+        Nanjing {
+            prg_region: BankedRegion::new(rom.prg.len(), 32768),
+            rom,
+            regs: [0; 4],
+            prg_forced: false,
+            strobe: 1,
+            trigger: false,
+            chr_ram: Box::new([0; 8192]),
+            chr_pages: [0, 1],
+            chr_region: BankedRegion::new(8192, 4096),
+            prg_ram: Box::new([0; 8192]),
+            scanline: 0,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let page = self.chr_pages[(addr >> 12) as usize & 1] as usize;
+        self.chr_region.offset(page, addr)
+    }
+}
+
+save_struct!(Nanjing {
+    regs,
+    prg_forced,
+    strobe,
+    trigger,
+    chr_ram,
+    chr_pages,
+    prg_ram,
+    scanline
+});
+
+impl Mapper for Nanjing {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5000..=0x5fff => match addr & 0x7700 {
+                0x5100 => self.regs[3] | self.regs[1] | self.regs[0] | (self.regs[2] ^ 0xff),
+                0x5500 if self.trigger => self.regs[3] | self.regs[0],
+                0x5500 => 0,
+                _ => 4,
+            },
+            0x6000..=0x7fff => self.prg_ram[addr as usize & 0x1fff],
+            0x8000..=0xffff => {
+                let bank = if self.prg_forced {
+                    3
+                } else {
+                    (self.regs[0] & 0xf) as usize | ((self.regs[2] & 0xf) as usize) << 4
+                };
+                self.rom.prg[self.prg_region.offset(bank, addr)]
+            }
+            _ => 0,
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if (0x6000..0x8000).contains(&addr) {
+            self.prg_ram[addr as usize & 0x1fff] = val;
+            return;
+        }
+        if addr == 0x5101 {
+            if self.strobe != 0 && val == 0 {
+                self.trigger = !self.trigger;
+            }
+            self.strobe = val;
+            return;
+        }
+        match addr & 0x7300 {
+            0x5000 => {
+                self.regs[0] = val;
+                self.prg_forced = false;
+                if (val & 0x80) == 0 && self.scanline < 128 {
+                    self.chr_pages = [0, 1];
+                }
+            }
+            0x5100 => {
+                self.regs[1] = val;
+                self.prg_forced = val == 6;
+            }
+            0x5200 => {
+                self.regs[2] = val;
+                self.prg_forced = false;
+            }
+            0x5300 => self.regs[3] = val,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr_ram[self.chr_offset(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr_ram[self.chr_offset(addr)] = val
+    }
+
+    fn chr_location(&mut self, addr: u16) -> Option<usize> {
+        Some(self.chr_offset(addr))
+    }
+
+    fn next_scanline(&mut self) -> MapperResult {
+        self.scanline += 1;
+        if (self.regs[0] & 0x80) != 0 {
+            match self.scanline {
+                128 => self.chr_pages = [1, 1],
+                240 => self.chr_pages = [0, 0],
+                _ => {}
+            }
+        }
+        MapperResult::Continue
+    }
+
+    fn start_frame(&mut self) {
+        self.scanline = 0;
+    }
+
+    fn save_data(&mut self) -> Option<&mut [u8]> {
+        if self.rom.header.battery() {
+            Some(&mut *self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn mirroring(&mut self) -> Mirroring {
+        if self.rom.header.vertical_mirroring() {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            prg_ram: true,
+            non_volatile: self.rom.header.battery(),
+            ..Capabilities::default()
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Power saving for handhelds and laptops: the emulator sleeps until each frame is due instead of
+//! leaving the display driver to spin waiting for vsync, can show only every other frame, and
+//! does its debug bookkeeping less often.
+//!
+//! While the audio device is playing, it already holds the emulator to real time, and the pacer
+//! stays out of its way.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long before a deadline to stop sleeping and yield instead, since sleeps overshoot by up to
+/// a millisecond or so on most systems.
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+/// How often debug bookkeeping, such as the statistics, runs while saving power, in frames.
+pub const INSTRUMENTATION_INTERVAL: u64 = 60;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PowerSaving {
+    Off,
+    /// Sleep between frames.
+    On,
+    /// Sleep between frames and show every other one, for 30 Hz on NTSC.
+    HalfRate,
+}
+
+impl PowerSaving {
+    pub fn from_name(name: &str) -> Option<PowerSaving> {
+        match name {
+            "off" => Some(PowerSaving::Off),
+            "on" => Some(PowerSaving::On),
+            "half" => Some(PowerSaving::HalfRate),
+            _ => None,
+        }
+    }
+
+    /// Whether frame `frame` is shown in this mode.
+    pub fn shows(self, frame: u64) -> bool {
+        self != PowerSaving::HalfRate || frame.is_multiple_of(2)
+    }
+}
+
+/// Holds the emulator to a frame rate by sleeping until each frame is due.
+pub struct FramePacer {
+    /// When the next frame is due.
+    deadline: Instant,
+}
+
+impl Default for FramePacer {
+    fn default() -> FramePacer {
+        FramePacer::new()
+    }
+}
+
+impl FramePacer {
+    pub fn new() -> FramePacer {
+        FramePacer {
+            deadline: Instant::now(),
+        }
+    }
+
+    /// Sleeps until the frame after the last one is due, at `frame_rate` frames per second. After
+    /// falling more than a frame behind, as when paused or fast-forwarding, starts over from now
+    /// rather than rushing to catch up.
+    pub fn wait(&mut self, frame_rate: f64) {
+        let period = Duration::from_nanos((1e9 / frame_rate) as u64);
+        self.deadline += period;
+        let now = Instant::now();
+        if self.deadline + period < now {
+            self.deadline = now;
+            return;
+        }
+        if self.deadline > now + SPIN_MARGIN {
+            thread::sleep(self.deadline - now - SPIN_MARGIN);
+        }
+        while Instant::now() < self.deadline {
+            thread::yield_now();
+        }
+    }
+
+    /// Forgets the deadline, for when frames stop being paced for a while.
+    pub fn reset(&mut self) {
+        self.deadline = Instant::now();
+    }
+}
@@ -2,38 +2,73 @@
 // Author: Patrick Walton
 //
 
-use mapper::{Mapper, MapperResult};
+#[cfg(feature = "serde")]
+use mapper::DetachedBoard;
+use mapper::{MapperResult, Mirroring};
 use mem::Mem;
+use palette::Palette;
+use provenance::VramProvenance;
+use region::Region;
+use trace::VideoPosition;
 use util::Save;
 
-use std::cell::RefCell;
 use std::fs::File;
+use std::io::{self, Read, Write};
 use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const SCREEN_WIDTH: usize = 256;
 pub const SCREEN_HEIGHT: usize = 240;
-pub const CYCLES_PER_SCANLINE: u64 = 114; // 29781 cycles per frame / 261 scanlines
-pub const VBLANK_SCANLINE: usize = 241;
-pub const LAST_SCANLINE: usize = 261;
-
-static PALETTE: [u8; 192] = [
-    124, 124, 124, 0, 0, 252, 0, 0, 188, 68, 40, 188, 148, 0, 132, 168, 0, 32, 168, 16, 0, 136, 20,
-    0, 80, 48, 0, 0, 120, 0, 0, 104, 0, 0, 88, 0, 0, 64, 88, 0, 0, 0, 0, 0, 0, 0, 0, 0, 188, 188,
-    188, 0, 120, 248, 0, 88, 248, 104, 68, 252, 216, 0, 204, 228, 0, 88, 248, 56, 0, 228, 92, 16,
-    172, 124, 0, 0, 184, 0, 0, 168, 0, 0, 168, 68, 0, 136, 136, 0, 0, 0, 0, 0, 0, 0, 0, 0, 248,
-    248, 248, 60, 188, 252, 104, 136, 252, 152, 120, 248, 248, 120, 248, 248, 88, 152, 248, 120,
-    88, 252, 160, 68, 248, 184, 0, 184, 248, 24, 88, 216, 84, 88, 248, 152, 0, 232, 216, 120, 120,
-    120, 0, 0, 0, 0, 0, 0, 252, 252, 252, 164, 228, 252, 184, 184, 248, 216, 184, 248, 248, 184,
-    248, 248, 164, 192, 240, 208, 176, 252, 224, 168, 248, 216, 120, 216, 248, 120, 184, 248, 184,
-    184, 248, 216, 0, 252, 252, 248, 216, 248, 0, 0, 0, 0, 0, 0,
-];
+
+/// The number of PPU dots in a scanline.
+const DOTS_PER_SCANLINE: u64 = 341;
+
+/// The number of phases the CPU clock can have against the PPU clock.
+pub const ALIGNMENTS: u8 = 4;
+
+/// Picks a CPU/PPU alignment at random, as powering on a console does.
+pub fn random_alignment() -> u8 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos())
+        .unwrap_or(0);
+    (nanos % ALIGNMENTS as u32) as u8
+}
+
+/// Spreads the bits of a pattern table byte into the low bit of each byte of a `u64`, with the
+/// leftmost pixel in the lowest byte, so a tile row decodes eight pixels at a time.
+const PLANE_LUT: [u64; 256] = build_plane_lut();
+
+const fn build_plane_lut() -> [u64; 256] {
+    let mut lut = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut x = 0;
+        while x < 8 {
+            if byte & (0x80 >> x) != 0 {
+                lut[byte] |= 1 << (x * 8);
+            }
+            x += 1;
+        }
+        byte += 1;
+    }
+    lut
+}
+
+/// Gives the opaque pixels of a decoded pattern row the palette of attribute bits `attr`, making
+/// each a 4-bit palette index.
+fn with_attribute(pattern: u64, attr: u8) -> u64 {
+    let opaque = (pattern | (pattern >> 1)) & 0x0101010101010101;
+    pattern | (opaque * ((attr as u64) << 2))
+}
 
 //
 // Registers
 //
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Regs {
     ctrl: PpuCtrl,     // PPUCTRL: 0x2000
     mask: PpuMask,     // PPUMASK: 0x2001
@@ -57,6 +92,7 @@ save_struct!(Regs {
 //
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct PpuCtrl {
     val: u8,
 }
@@ -133,6 +169,7 @@ impl PpuCtrl {
 //
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct PpuMask {
     val: u8,
 }
@@ -152,7 +189,9 @@ impl DerefMut for PpuMask {
 }
 
 impl PpuMask {
-    // 0x01: grayscale
+    fn grayscale(self) -> bool {
+        (*self & 0x01) != 0
+    }
     // 0x02: show background on left
     // 0x04: show sprites on left
     fn show_background(self) -> bool {
@@ -161,9 +200,18 @@ impl PpuMask {
     fn show_sprites(self) -> bool {
         (*self & 0x10) != 0
     }
-    // 0x20: intensify reds
-    // 0x40: intensify greens
-    // 0x80: intensify blues
+    fn rendering_enabled(self) -> bool {
+        self.show_background() || self.show_sprites()
+    }
+    /// The color emphasis bits, red in bit 0, green in bit 1 and blue in bit 2. PAL and Dendy
+    /// PPUs swap the red and green bits.
+    fn emphasis(self, region: Region) -> u8 {
+        let bits = *self >> 5;
+        match region {
+            Region::Ntsc => bits,
+            Region::Pal | Region::Dendy => bits & 4 | (bits & 1) << 1 | (bits & 2) >> 1,
+        }
+    }
 }
 
 //
@@ -171,6 +219,7 @@ impl PpuMask {
 //
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct PpuStatus {
     val: u8,
 }
@@ -225,6 +274,7 @@ impl PpuStatus {
 //
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct PpuScroll {
     x: u8,
     y: u8,
@@ -234,6 +284,7 @@ struct PpuScroll {
 save_struct!(PpuScroll { x, y, next });
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 enum PpuScrollDir {
     XDir,
     YDir,
@@ -246,6 +297,7 @@ save_enum!(PpuScrollDir { XDir, YDir });
 //
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct PpuAddr {
     val: u16,
     next: PpuAddrByte,
@@ -254,6 +306,7 @@ struct PpuAddr {
 save_struct!(PpuAddr { val, next });
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 enum PpuAddrByte {
     Hi,
     Lo,
@@ -261,20 +314,134 @@ enum PpuAddrByte {
 
 save_enum!(PpuAddrByte { Hi, Lo });
 
+/// A location in PPU memory that a watchpoint can name.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PpuLocation {
+    /// A pattern table or name table address, as written through $2006/$2007.
+    Vram(u16),
+    /// A palette entry, $00-$1F.
+    Palette(u8),
+    /// A byte of OAM.
+    Oam(u8),
+}
+
+/// The cartridge as the PPU sees it: pattern tables, nametable mirroring, and the signals mappers
+/// that watch the PPU count. On the NES both sides share the mapper, which implements this through
+/// `SharedMapper`, so each access still borrows its `RefCell`.
+pub trait ChrBus {
+    fn chr_loadb(&mut self, addr: u16) -> u8;
+    fn chr_storeb(&mut self, addr: u16, val: u8);
+
+    /// How the internal nametables are mapped. Defaults to vertical mirroring.
+    fn mirroring(&mut self) -> Mirroring {
+        Mirroring::Vertical
+    }
+
+    /// Where in CHR memory a PPU address maps to, for the tile cache. See `Mapper::chr_location`.
+    fn chr_location(&mut self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    /// Called with the address of each pattern row the PPU fetches while rendering, before it's
+    /// read, including rows served from the tile cache. For boards that switch banks on fetches,
+    /// such as the MMC2's latches.
+    fn pattern_fetch(&mut self, _addr: u16) {}
+
+    /// The CPU ran for the given number of cycles. See `Mapper::cpu_cycles`.
+    fn cpu_cycles(&mut self, _cycles: u64) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    /// The PPU finished a scanline. See `Mapper::next_scanline`.
+    fn next_scanline(&mut self) -> MapperResult {
+        MapperResult::Continue
+    }
+
+    /// The PPU is starting a frame.
+    fn start_frame(&mut self) {}
+}
+
 // PPU VRAM. This implements the same Mem trait that the CPU memory does.
 
+/// The PPU's memory: its nametable and palette RAM, and the cartridge through a `ChrBus`.
+/// Savestates hold the nametables and palette only; the cartridge's memory is its own.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default = "Vram::detached"))]
 pub struct Vram {
-    pub mapper: Rc<RefCell<Box<dyn Mapper + Send>>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub chr: Box<dyn ChrBus>,
+    #[cfg_attr(feature = "serde", serde(with = "::util::serde_byte_array"))]
     pub nametables: [u8; 0x800], // 2 nametables, 0x400 each. FIXME: Not correct for all mappers.
     pub palette: [u8; 0x20],
+    /// Decoded pattern tiles, indexed by their CHR location (see `Mapper::chr_location`) divided
+    /// by 16. Each holds the tile's eight rows in the layout of `pattern_row`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tile_cache: Vec<Option<[u64; 8]>>,
+    /// Locations written since this was last cleared, if watchpoints are on.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub writes: Option<Vec<PpuLocation>>,
+    /// Who writes each address and how often each tile is drawn, if write provenance is tracked.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub provenance: Option<VramProvenance>,
 }
 
 impl Vram {
-    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper + Send>>>) -> Vram {
+    /// VRAM without a cartridge, for deserializing.
+    #[cfg(feature = "serde")]
+    pub fn detached() -> Vram {
+        Vram::new(Box::new(DetachedBoard::shared(Vec::new())))
+    }
+
+    pub fn new(chr: Box<dyn ChrBus>) -> Vram {
         Vram {
-            mapper: mapper,
+            chr,
             nametables: [0; 0x800],
             palette: [0; 0x20],
+            tile_cache: Vec::new(),
+            writes: None,
+            provenance: None,
+        }
+    }
+
+    /// Returns a row of a pattern tile as one byte per pixel holding its 2-bit color, leftmost
+    /// pixel first, given the address of the row's low bit plane. Tiles are cached by where they
+    /// live in CHR memory rather than by PPU address, so bank switches select other entries
+    /// instead of invalidating them; CHR writes invalidate the tile written.
+    fn pattern_row(&mut self, addr: u16) -> u64 {
+        self.chr.pattern_fetch(addr);
+        let location = self.chr.chr_location(addr);
+        if let Some(ref mut provenance) = self.provenance {
+            provenance.count_tile_row(location.unwrap_or(addr as usize) / 16);
+        }
+        let index = match location {
+            Some(location) => location / 16,
+            None => return self.decode_pattern_row(addr),
+        };
+        if index >= self.tile_cache.len() {
+            self.tile_cache.resize(index + 1, None);
+        }
+        if let Some(ref rows) = self.tile_cache[index] {
+            return rows[addr as usize % 8];
+        }
+
+        let tile_addr = addr & !0xf;
+        let mut rows = [0; 8];
+        for (y, row) in rows.iter_mut().enumerate() {
+            *row = self.decode_pattern_row(tile_addr + y as u16);
+        }
+        self.tile_cache[index] = Some(rows);
+        rows[addr as usize % 8]
+    }
+
+    fn decode_pattern_row(&mut self, addr: u16) -> u64 {
+        let plane0 = self.loadb(addr);
+        let plane1 = self.loadb(addr + 8);
+        PLANE_LUT[plane0 as usize] | (PLANE_LUT[plane1 as usize] << 1)
+    }
+
+    fn invalidate_tile(&mut self, location: Option<usize>) {
+        if let Some(tile) = location.and_then(|location| self.tile_cache.get_mut(location / 16)) {
+            *tile = None;
         }
     }
 }
@@ -284,11 +451,13 @@ impl Mem for Vram {
     fn loadb(&mut self, addr: u16) -> u8 {
         if addr < 0x2000 {
             // Tilesets 0 or 1
-            let mut mapper = self.mapper.borrow_mut();
-            mapper.chr_loadb(addr)
+            self.chr.chr_loadb(addr)
         } else if addr < 0x3f00 {
             // Name table area
-            self.nametables[addr as usize & 0x07ff]
+            match self.chr.mirroring() {
+                Mirroring::Cartridge => self.chr.chr_loadb(addr),
+                mirroring => self.nametables[mirroring.nametable_offset(addr)],
+            }
         } else if addr < 0x4000 {
             // Palette area
             self.palette[addr as usize & 0x1f]
@@ -298,12 +467,15 @@ impl Mem for Vram {
     }
     fn storeb(&mut self, addr: u16, val: u8) {
         if addr < 0x2000 {
-            let mut mapper = self.mapper.borrow_mut();
-            mapper.chr_storeb(addr, val)
+            self.chr.chr_storeb(addr, val);
+            let location = self.chr.chr_location(addr);
+            self.invalidate_tile(location);
         } else if addr < 0x3f00 {
             // Name table area
-            let addr = addr & 0x07ff;
-            self.nametables[addr as usize] = val;
+            match self.chr.mirroring() {
+                Mirroring::Cartridge => self.chr.chr_storeb(addr, val),
+                mirroring => self.nametables[mirroring.nametable_offset(addr)] = val,
+            }
         } else if addr < 0x4000 {
             // Palette area
             let mut addr = addr & 0x1f;
@@ -312,21 +484,37 @@ impl Mem for Vram {
             }
             self.palette[addr as usize] = val;
         }
+
+        if let Some(ref mut provenance) = self.provenance {
+            provenance.record(addr);
+        }
+        if let Some(ref mut writes) = self.writes {
+            writes.push(if addr < 0x3f00 {
+                PpuLocation::Vram(addr)
+            } else {
+                PpuLocation::Palette(match addr & 0x1f {
+                    0x10 => 0x00,
+                    index => index as u8,
+                })
+            });
+        }
     }
 }
 
 impl Save for Vram {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
         let mut nametables: &mut [u8] = &mut self.nametables;
-        nametables.save(fd);
+        nametables.save(fd)?;
         let mut palette: &mut [u8] = &mut self.palette;
-        palette.save(fd);
+        palette.save(fd)?;
+        Ok(())
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
         let mut nametables: &mut [u8] = &mut self.nametables;
-        nametables.load(fd);
+        nametables.load(fd)?;
         let mut palette: &mut [u8] = &mut self.palette;
-        palette.load(fd);
+        palette.load(fd)?;
+        Ok(())
     }
 }
 
@@ -334,13 +522,21 @@ impl Save for Vram {
 // Object Attribute Memory (OAM)
 //
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Oam {
+    #[cfg_attr(feature = "serde", serde(with = "::util::serde_byte_array"))]
     pub oam: [u8; 0x100],
+    /// Locations written since this was last cleared, if watchpoints are on.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub writes: Option<Vec<PpuLocation>>,
 }
 
 impl Oam {
     pub fn new() -> Oam {
-        Oam { oam: [0; 0x100] }
+        Oam {
+            oam: [0; 0x100],
+            writes: None,
+        }
     }
 }
 
@@ -349,18 +545,23 @@ impl Mem for Oam {
         self.oam[addr as usize]
     }
     fn storeb(&mut self, addr: u16, val: u8) {
-        self.oam[addr as usize] = val
+        self.oam[addr as usize] = val;
+        if let Some(ref mut writes) = self.writes {
+            writes.push(PpuLocation::Oam(addr as u8));
+        }
     }
 }
 
 impl Save for Oam {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
         let mut oam: &mut [u8] = &mut self.oam;
-        oam.save(fd);
+        oam.save(fd)?;
+        Ok(())
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
         let mut oam: &mut [u8] = &mut self.oam;
-        oam.load(fd);
+        oam.load(fd)?;
+        Ok(())
     }
 }
 
@@ -432,11 +633,14 @@ impl SpriteStruct {
 
 // The main PPU structure. This structure is separate from the PPU memory just as the CPU is.
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default = "Ppu::detached"))]
 pub struct Ppu {
     regs: Regs,
     vram: Vram,
     oam: Oam,
 
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub screen: Box<[u8; 184320]>, // 256 * 240 * 3
     scanline: u16,
     ppudata_buffer: u8,
@@ -447,6 +651,40 @@ pub struct Ppu {
     scroll_y: u16,
 
     cy: u64,
+    /// The number of frames rendered since power on.
+    frame: u64,
+    /// The CPU cycle the mapper has been clocked up to.
+    mapper_cy: u64,
+    /// The CPU cycle the instruction being executed started at, set before each CPU step, which
+    /// times reads of PPUSTATUS.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cycle: u64,
+    /// The phase of the CPU clock against the PPU clock, from 0 to `ALIGNMENTS - 1`, fixed at
+    /// power on. It decides how a read of PPUSTATUS in the cycle vblank starts races the flag.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub alignment: u8,
+    /// Whether such a race has kept the coming vblank from setting the flag or signaling the NMI.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    vblank_flag_suppressed: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    vblank_nmi_suppressed: bool,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    region: Region,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    palette: Palette,
+    /// The layers drawn to the screen.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub layers: Layers,
+    /// While set, OAMDATA writes and OAM DMA leave OAM alone, so that edits made with `set_oam`
+    /// stay.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub oam_frozen: bool,
+    /// The frame being captured a layer at a time, and the last one captured.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    layer_capture: Option<Box<LayerCapture>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    captured_layers: Option<Box<LayerCapture>>,
 }
 
 impl Mem for Ppu {
@@ -458,7 +696,7 @@ impl Mem for Ppu {
             1 => *self.regs.mask,
             2 => self.read_ppustatus(),
             3 => 0, // OAMADDR is read-only
-            4 => panic!("OAM read unimplemented"),
+            4 => self.oam.loadb(self.regs.oam_addr as u16),
             5 => 0, // PPUSCROLL is read-only
             6 => 0, // PPUADDR is read-only
             7 => self.read_ppudata(),
@@ -487,7 +725,55 @@ impl Mem for Ppu {
 pub struct StepResult {
     pub new_frame: bool,    // We wrapped around to the next scanline.
     pub vblank_nmi: bool,   // We entered VBLANK and must generate an NMI.
-    pub scanline_irq: bool, // The mapper wants to execute a scanline IRQ.
+    pub scanline_irq: bool, // The mapper wants to execute a scanline or cycle counter IRQ.
+}
+
+/// Which layers are drawn, for looking at the background or the sprites alone. Hiding a layer
+/// only changes the picture, not what the game sees, such as sprite 0 hits.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Layers {
+    pub background: bool,
+    pub sprites: bool,
+}
+
+impl Layers {
+    pub const ALL: Layers = Layers {
+        background: true,
+        sprites: true,
+    };
+}
+
+/// A frame drawn with each layer on its own as well as together, as RGBA pixels.
+pub struct LayerCapture {
+    /// The background over the backdrop color.
+    pub background: Vec<u8>,
+    /// The sprites alone, transparent where there are none.
+    pub sprites: Vec<u8>,
+    /// Both layers, as the frame looks with every layer shown.
+    pub composite: Vec<u8>,
+    /// Whether the frame being captured has started; capturing begins at the top of a frame.
+    started: bool,
+}
+
+impl LayerCapture {
+    fn new() -> LayerCapture {
+        LayerCapture {
+            background: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            sprites: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            composite: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            started: false,
+        }
+    }
+}
+
+fn put_rgba(pixels: &mut [u8], x: usize, y: usize, color: Option<Rgb>) {
+    let offset = (y * SCREEN_WIDTH + x) * 4;
+    if let Some(color) = color {
+        // `Rgb` holds the screen's BGR order: its `b` is the red channel.
+        pixels[offset..offset + 4].copy_from_slice(&[color.b, color.g, color.r, 0xff]);
+    } else {
+        pixels[offset..offset + 4].copy_from_slice(&[0; 4]);
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -497,11 +783,6 @@ struct Rgb {
     b: u8,
 }
 
-enum PatternPixelKind {
-    Background,
-    Sprite,
-}
-
 struct NametableAddr {
     base: u16,
     x_index: u8,
@@ -518,34 +799,74 @@ enum SpritePriority {
     BelowBg,
 }
 
+/// The color of a pixel with the given background and sprite colors, by sprite priority.
+fn combine(backdrop: Rgb, background: Option<Rgb>, sprite: Option<&SpriteColor>) -> Rgb {
+    match (background, sprite) {
+        (None, None) => backdrop,
+        (Some(color), None) => color,
+        (
+            Some(color),
+            Some(&SpriteColor {
+                priority: BelowBg, ..
+            }),
+        ) => color,
+        (
+            None,
+            Some(&SpriteColor {
+                priority: BelowBg,
+                color,
+            }),
+        ) => color,
+        (
+            _,
+            Some(&SpriteColor {
+                priority: AboveBg,
+                color,
+            }),
+        ) => color,
+    }
+}
+
 use self::SpritePriority::*;
 use std::num::Wrapping;
 
 impl Save for Ppu {
-    fn save(&mut self, fd: &mut File) {
-        self.regs.save(fd);
-        self.vram.save(fd);
-        self.oam.save(fd);
-        self.scanline.save(fd);
-        self.ppudata_buffer.save(fd);
-        self.scroll_x.save(fd);
-        self.scroll_y.save(fd);
-        self.cy.save(fd);
-    }
-    fn load(&mut self, fd: &mut File) {
-        self.regs.load(fd);
-        self.vram.load(fd);
-        self.oam.load(fd);
-        self.scanline.load(fd);
-        self.ppudata_buffer.load(fd);
-        self.scroll_x.load(fd);
-        self.scroll_y.load(fd);
-        self.cy.load(fd);
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        self.regs.save(fd)?;
+        self.vram.save(fd)?;
+        self.oam.save(fd)?;
+        self.scanline.save(fd)?;
+        self.ppudata_buffer.save(fd)?;
+        self.scroll_x.save(fd)?;
+        self.scroll_y.save(fd)?;
+        self.cy.save(fd)?;
+        self.frame.save(fd)?;
+        self.mapper_cy.save(fd)?;
+        Ok(())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        self.regs.load(fd)?;
+        self.vram.load(fd)?;
+        self.oam.load(fd)?;
+        self.scanline.load(fd)?;
+        self.ppudata_buffer.load(fd)?;
+        self.scroll_x.load(fd)?;
+        self.scroll_y.load(fd)?;
+        self.cy.load(fd)?;
+        self.frame.load(fd)?;
+        self.mapper_cy.load(fd)?;
+        Ok(())
     }
 }
 
 impl Ppu {
-    pub fn new(vram: Vram, oam: Oam) -> Ppu {
+    /// An NTSC PPU without a cartridge, for deserializing.
+    #[cfg(feature = "serde")]
+    pub fn detached() -> Ppu {
+        Ppu::new(Vram::detached(), Oam::new(), Region::Ntsc)
+    }
+
+    pub fn new(vram: Vram, oam: Oam, region: Region) -> Ppu {
         Ppu {
             regs: Regs {
                 ctrl: PpuCtrl { val: 0 },
@@ -573,19 +894,194 @@ impl Ppu {
             scroll_y: 0,
 
             cy: 0,
+            frame: 0,
+            mapper_cy: 0,
+            cycle: 0,
+            alignment: 0,
+            vblank_flag_suppressed: false,
+            vblank_nmi_suppressed: false,
+
+            region,
+            palette: Palette::builtin(),
+            layers: Layers::ALL,
+            oam_frozen: false,
+            layer_capture: None,
+            captured_layers: None,
+        }
+    }
+
+    /// Captures a frame a layer at a time, for `take_layers` once it's drawn: the one starting now
+    /// if nothing of it has been drawn yet, or else the next.
+    pub fn capture_layers(&mut self) {
+        let mut capture = LayerCapture::new();
+        capture.started = self.scanline == 0;
+        self.layer_capture = Some(Box::new(capture));
+    }
+
+    /// Returns the frame captured by `capture_layers`, once it has been drawn.
+    pub fn take_layers(&mut self) -> Option<Box<LayerCapture>> {
+        self.captured_layers.take()
+    }
+
+    /// Returns the frame, scanline and dot being drawn when the CPU is at `cpu_cycle`. Since the
+    /// PPU runs a scanline at a time, the dot is extrapolated from the CPU cycle.
+    pub fn position(&self, cpu_cycle: u64) -> VideoPosition {
+        let dot = cpu_cycle.saturating_sub(self.cy) * 3;
+        VideoPosition {
+            frame: self.frame,
+            scanline: self.scanline,
+            dot: dot.min(DOTS_PER_SCANLINE - 1) as u16,
         }
     }
 
+    /// The number of frames rendered since power on.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// The scanline drawn next. Those above it are done for this frame.
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// Writes the screen as drawn so far to a binary PPM image.
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        write!(file, "P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT)?;
+        // The screen is stored as BGR for the SDL texture.
+        let rgb: Vec<u8> = self
+            .screen
+            .chunks(3)
+            .flat_map(|pixel| vec![pixel[2], pixel[1], pixel[0]])
+            .collect();
+        file.write_all(&rgb)
+    }
+
+    /// Whether PPUCTRL asks for an NMI at the start of vblank.
+    pub fn nmi_enabled(&self) -> bool {
+        self.regs.ctrl.vblank_nmi()
+    }
+
+    /// Starts or stops recording which locations of VRAM, palette RAM and OAM are written.
+    pub fn watch_writes(&mut self, watch: bool) {
+        let writes = if watch { Some(Vec::new()) } else { None };
+        self.vram.writes = writes.clone();
+        self.oam.writes = writes;
+    }
+
+    /// Starts or stops tracking who writes VRAM and how often each tile is drawn. Tracking that is
+    /// already on keeps what it has recorded.
+    pub fn track_vram_writes(&mut self, on: bool) {
+        if !on {
+            self.vram.provenance = None;
+        } else if self.vram.provenance.is_none() {
+            self.vram.provenance = Some(VramProvenance::new());
+        }
+    }
+
+    pub fn vram_provenance(&self) -> Option<&VramProvenance> {
+        self.vram.provenance.as_ref()
+    }
+
+    pub fn vram_provenance_mut(&mut self) -> Option<&mut VramProvenance> {
+        self.vram.provenance.as_mut()
+    }
+
+    /// Whether `location` was written since the last `clear_writes`. Always false unless
+    /// `watch_writes` is on.
+    pub fn was_written(&self, location: PpuLocation) -> bool {
+        let written = |writes: &Option<Vec<PpuLocation>>| match *writes {
+            Some(ref writes) => writes.contains(&location),
+            None => false,
+        };
+        written(&self.vram.writes) || written(&self.oam.writes)
+    }
+
+    pub fn clear_writes(&mut self) {
+        for writes in [&mut self.vram.writes, &mut self.oam.writes] {
+            if let Some(ref mut writes) = *writes {
+                writes.clear();
+            }
+        }
+    }
+
+    /// Replaces the master palette. `None` restores the built-in one.
+    pub fn set_palette(&mut self, palette: Option<&Palette>) {
+        self.palette = palette.cloned().unwrap_or_else(Palette::builtin);
+    }
+
+    pub fn master_palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// Changes a color of the master palette, as `Palette::set_color` does.
+    pub fn set_master_color(&mut self, index: u8, color: [u8; 3]) {
+        self.palette.set_color(index, color);
+    }
+
+    /// The palette RAM entry `entry`, $00-$1F, as PPUDATA writes see it: $10 is $00.
+    pub fn palette_entry(&self, entry: u8) -> u8 {
+        match entry & 0x1f {
+            0x10 => self.vram.palette[0],
+            entry => self.vram.palette[entry as usize],
+        }
+    }
+
+    /// Sets a palette RAM entry, as a PPUDATA write to it would.
+    pub fn set_palette_entry(&mut self, entry: u8, value: u8) {
+        match entry & 0x1f {
+            0x10 => self.vram.palette[0] = value,
+            entry => self.vram.palette[entry as usize] = value,
+        }
+    }
+
+    /// OAM: 64 sprites of 4 bytes, Y minus 1, tile, attributes and X.
+    pub fn oam(&self) -> &[u8; 0x100] {
+        &self.oam.oam
+    }
+
+    /// Writes a byte of OAM directly, for debugging, even while `oam_frozen` is set.
+    pub fn set_oam(&mut self, addr: u8, val: u8) {
+        self.oam.oam[addr as usize] = val;
+    }
+
+    /// The height of sprites, 8 or 16, as PPUCTRL sets it.
+    pub fn sprite_height(&self) -> u8 {
+        match self.regs.ctrl.sprite_size() {
+            SpriteSize::SpriteSize8x8 => 8,
+            SpriteSize::SpriteSize8x16 => 16,
+        }
+    }
+
+    /// The color emphasis bits of PPUMASK, red in bit 0, green in bit 1 and blue in bit 2.
+    pub fn emphasis(&self) -> u8 {
+        self.regs.mask.emphasis(self.region)
+    }
+
+    /// The RGB color of `index`, $00-$3F, under the current emphasis and grayscale bits.
+    pub fn rgb(&self, index: u8) -> [u8; 3] {
+        let mask = self.regs.mask;
+        let variant = Palette::variant(mask.emphasis(self.region), mask.grayscale());
+        self.palette.color(variant, index)
+    }
+
     //
     // Color utilities
     //
 
     #[inline(always)]
     fn get_color(&self, palette_index: u8) -> Rgb {
+        let mask = self.regs.mask;
+        let variant = Palette::variant(mask.emphasis(self.region), mask.grayscale());
+        let color = self.palette.color(variant, palette_index);
         Rgb {
-            r: PALETTE[palette_index as usize * 3 + 2],
-            g: PALETTE[palette_index as usize * 3 + 1],
-            b: PALETTE[palette_index as usize * 3 + 0],
+            r: color[2],
+            g: color[1],
+            b: color[0],
         }
     }
 
@@ -618,7 +1114,9 @@ impl Ppu {
     }
 
     fn write_oamdata(&mut self, val: u8) {
-        self.oam.storeb(self.regs.oam_addr as u16, val);
+        if !self.oam_frozen {
+            self.oam.storeb(self.regs.oam_addr as u16, val);
+        }
         self.regs.oam_addr = (Wrapping(self.regs.oam_addr) + Wrapping(1)).0;
     }
 
@@ -648,6 +1146,25 @@ impl Ppu {
         self.regs.scroll.next = PpuScrollDir::XDir;
         self.regs.addr.next = PpuAddrByte::Hi;
 
+        // Games poll with 4-cycle absolute loads, which read on their last cycle. A read in the
+        // cycle vblank starts lands a dot before the flag is set, on that dot, or after it,
+        // depending on the alignment.
+        let read_cycle = self.cycle + 3;
+        let vblank_cycle = self.cy + self.region.cycles_per_scanline();
+        if self.scanline + 1 == self.region.vblank_scanline() && read_cycle == vblank_cycle {
+            match self.alignment {
+                0 => {
+                    self.vblank_flag_suppressed = true;
+                    self.vblank_nmi_suppressed = true;
+                }
+                1 => {
+                    self.vblank_nmi_suppressed = true;
+                    return *self.regs.status | 0x80;
+                }
+                _ => return *self.regs.status | 0x80,
+            }
+        }
+
         *self.regs.status
     }
 
@@ -727,70 +1244,49 @@ impl Ppu {
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = color.b;
     }
 
-    // Returns the color (pre-palette lookup) of pixel (x,y) within the given tile.
+    // Returns the color (pre-palette lookup) of pixel (x,y) within the given sprite tile.
     #[inline(always)]
-    fn get_pattern_pixel(&mut self, kind: PatternPixelKind, tile: u16, x: u8, y: u8) -> u8 {
+    fn get_pattern_pixel(&mut self, tile: u16, x: u8, y: u8) -> u8 {
         // Compute the pattern offset.
-        let mut pattern_offset = (tile << 4) + (y as u16);
-        match kind {
-            PatternPixelKind::Background => {
-                pattern_offset += self.regs.ctrl.background_pattern_table_addr()
-            }
-            PatternPixelKind::Sprite => {
-                pattern_offset += self.regs.ctrl.sprite_pattern_table_addr()
-            }
-        }
+        let pattern_offset = (tile << 4) + (y as u16) + self.regs.ctrl.sprite_pattern_table_addr();
 
         // Determine the color of this pixel.
-        let plane0 = self.vram.loadb(pattern_offset);
-        let plane1 = self.vram.loadb(pattern_offset + 8);
-        let bit0 = (plane0 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-        let bit1 = (plane1 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-        (bit1 << 1) | bit0
+        (self.vram.pattern_row(pattern_offset) >> ((x % 8) * 8)) as u8
     }
 
-    // Returns true if the background was opaque here, false otherwise.
-    #[inline(always)]
-    fn get_background_pixel(&mut self, x: u8) -> Option<Rgb> {
-        // Adjust X and Y to account for scrolling.
-        let x = x as u16 + self.scroll_x;
+    // Decodes the background of the current scanline into 4-bit palette indices, where 0 is
+    // transparent, eight pixels at a time. The line starts at the left edge of the first visible
+    // tile, so it's offset from the screen by the fine X scroll.
+    fn background_line(&mut self) -> [u8; SCREEN_WIDTH + 8] {
+        let mut line = [0; SCREEN_WIDTH + 8];
         let y = self.scanline as u16 + self.scroll_y;
+        let first_column = self.scroll_x / 8;
+        for column in 0..(SCREEN_WIDTH / 8 + 1) {
+            let NametableAddr {
+                base,
+                x_index,
+                y_index,
+            } = self.nametable_addr(first_column + column as u16, y / 8);
+            let tile = self
+                .vram
+                .loadb(base + 32 * (y_index as u16) + (x_index as u16));
+
+            let pattern_offset =
+                ((tile as u16) << 4) + (y % 8) + self.regs.ctrl.background_pattern_table_addr();
+            let pattern = self.vram.pattern_row(pattern_offset);
+            if pattern == 0 {
+                continue; // Transparent.
+            }
 
-        // Compute the nametable address, tile index, and pixel offset within that tile.
-        let NametableAddr {
-            base,
-            x_index,
-            y_index,
-        } = self.nametable_addr(x / 8, y / 8);
-        let (xsub, ysub) = ((x % 8) as u8, (y % 8) as u8);
-
-        // Compute the nametable address and load the tile number from the nametable.
-        let tile = self
-            .vram
-            .loadb(base + 32 * (y_index as u16) + (x_index as u16));
-
-        // Fetch the pattern color.
-        let pattern_color =
-            self.get_pattern_pixel(PatternPixelKind::Background, tile as u16, xsub, ysub);
-        if pattern_color == 0 {
-            return None; // Transparent.
-        }
-
-        // Now load the attribute bits from the attribute table.
-        let group = y_index / 4 * 8 + x_index / 4;
-        let attr_byte = self.vram.loadb(base + 0x3c0 + (group as u16));
-        let (left, top) = (x_index % 4 < 2, y_index % 4 < 2);
-        let attr_table_color = match (left, top) {
-            (true, true) => attr_byte & 0x3,
-            (false, true) => (attr_byte >> 2) & 0x3,
-            (true, false) => (attr_byte >> 4) & 0x3,
-            (false, false) => (attr_byte >> 6) & 0x3,
-        };
+            // Each attribute byte covers 4x4 tiles, two bits per 2x2 quadrant.
+            let group = y_index / 4 * 8 + x_index / 4;
+            let attr_byte = self.vram.loadb(base + 0x3c0 + (group as u16));
+            let attr = (attr_byte >> ((x_index & 2) | ((y_index & 2) << 1))) & 0x3;
 
-        // Determine the final color and fetch the palette from VRAM.
-        let tile_color = (attr_table_color << 2) | pattern_color;
-        let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
-        return Some(self.get_color(palette_index));
+            let pixels = with_attribute(pattern, attr);
+            line[column * 8..column * 8 + 8].copy_from_slice(&pixels.to_le_bytes());
+        }
+        line
     }
 
     fn get_sprite_pixel(
@@ -827,8 +1323,7 @@ impl Ppu {
                             debug_assert!(x < 8, "sprite X miscalculation");
                             debug_assert!(y < 8, "sprite Y miscalculation");
 
-                            pattern_color =
-                                self.get_pattern_pixel(PatternPixelKind::Sprite, tile, x, y);
+                            pattern_color = self.get_pattern_pixel(tile, x, y);
                         }
                     }
 
@@ -878,18 +1373,53 @@ impl Ppu {
         result
     }
 
+    // Fills the current scanline with one color.
+    fn fill_scanline(&mut self, color: Rgb) {
+        let start = self.scanline as usize * SCREEN_WIDTH * 3;
+        for pixel in self.screen[start..start + SCREEN_WIDTH * 3].chunks_mut(3) {
+            pixel[0] = color.r;
+            pixel[1] = color.g;
+            pixel[2] = color.b;
+        }
+    }
+
     fn render_scanline(&mut self) {
+        let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
+        let backdrop_color = self.get_color(backdrop_color_index);
+
+        // Fast path for loading screens and the like: with rendering disabled, the PPU fetches
+        // nothing and evaluates no sprites, and the whole line is the backdrop color.
+        if !self.regs.mask.rendering_enabled() {
+            self.fill_scanline(backdrop_color);
+            if let Some(ref mut capture) = self.layer_capture {
+                if capture.started {
+                    for x in 0..SCREEN_WIDTH {
+                        let y = self.scanline as usize;
+                        put_rgba(&mut capture.background, x, y, Some(backdrop_color));
+                        put_rgba(&mut capture.sprites, x, y, None);
+                        put_rgba(&mut capture.composite, x, y, Some(backdrop_color));
+                    }
+                }
+            }
+            return;
+        }
+
         // TODO: Scrolling, mirroring
         let visible_sprites = self.compute_visible_sprites();
 
-        let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
-        let backdrop_color = self.get_color(backdrop_color_index);
+        let background_line = if self.regs.mask.show_background() {
+            self.background_line()
+        } else {
+            [0; SCREEN_WIDTH + 8]
+        };
+        let fine_x = (self.scroll_x % 8) as usize;
 
         for x in 0..SCREEN_WIDTH {
-            // FIXME: For performance, we shouldn't be recomputing the tile for every pixel.
+            let tile_color = background_line[x + fine_x];
             let mut background_color = None;
-            if self.regs.mask.show_background() {
-                background_color = self.get_background_pixel(x as u8);
+            if tile_color != 0 {
+                let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
+                background_color = Some(self.get_color(palette_index));
             }
 
             let mut sprite_color = None;
@@ -898,46 +1428,39 @@ impl Ppu {
                     self.get_sprite_pixel(&visible_sprites, x as u8, background_color.is_some());
             }
 
-            // Combine colors using priority.
-            let color = match (background_color, sprite_color) {
-                (None, None) => backdrop_color,
-                (Some(color), None) => color,
-                (
-                    Some(color),
-                    Some(SpriteColor {
-                        priority: BelowBg, ..
-                    }),
-                ) => color,
-                (
-                    None,
-                    Some(SpriteColor {
-                        priority: BelowBg,
-                        color,
-                    }),
-                ) => color,
-                (
-                    _,
-                    Some(SpriteColor {
-                        priority: AboveBg,
-                        color,
-                    }),
-                ) => color,
-            };
-
-            let scanline = self.scanline;
-            self.putpixel(x, scanline as usize, color);
+            let scanline = self.scanline as usize;
+            if let Some(ref mut capture) = self.layer_capture {
+                if capture.started {
+                    let background = background_color.unwrap_or(backdrop_color);
+                    let sprite = sprite_color.as_ref().map(|sprite| sprite.color);
+                    let color = combine(backdrop_color, background_color, sprite_color.as_ref());
+                    put_rgba(&mut capture.background, x, scanline, Some(background));
+                    put_rgba(&mut capture.sprites, x, scanline, sprite);
+                    put_rgba(&mut capture.composite, x, scanline, Some(color));
+                }
+            }
+
+            // Combine colors using priority, leaving out hidden layers.
+            let background_color = background_color.filter(|_| self.layers.background);
+            let sprite_color = sprite_color.filter(|_| self.layers.sprites);
+            let color = combine(backdrop_color, background_color, sprite_color.as_ref());
+            self.putpixel(x, scanline, color);
         }
     }
 
     fn start_vblank(&mut self, result: &mut StepResult) {
-        self.regs.status.set_in_vblank(true);
+        if !self.vblank_flag_suppressed {
+            self.regs.status.set_in_vblank(true);
+        }
 
         // FIXME: Is this correct? Or does it happen on the *next* frame?
         self.regs.status.set_sprite_zero_hit(false);
 
-        if self.regs.ctrl.vblank_nmi() {
+        if self.regs.ctrl.vblank_nmi() && !self.vblank_nmi_suppressed {
             result.vblank_nmi = true;
         }
+        self.vblank_flag_suppressed = false;
+        self.vblank_nmi_suppressed = false;
     }
 
     #[inline(never)]
@@ -947,8 +1470,18 @@ impl Ppu {
             vblank_nmi: false,
             scanline_irq: false,
         };
+
+        {
+            let elapsed = run_to_cycle.saturating_sub(self.mapper_cy);
+            self.mapper_cy = run_to_cycle;
+            if self.vram.chr.cpu_cycles(elapsed) == MapperResult::Irq {
+                result.scanline_irq = true
+            }
+        }
+
+        let cycles_per_scanline = self.region.cycles_per_scanline();
         loop {
-            let next_scanline_cycle: u64 = self.cy + CYCLES_PER_SCANLINE;
+            let next_scanline_cycle: u64 = self.cy + cycles_per_scanline;
             if next_scanline_cycle > run_to_cycle {
                 break;
             }
@@ -959,26 +1492,85 @@ impl Ppu {
 
             self.scanline += 1;
 
-            {
-                let mut mapper = self.vram.mapper.borrow_mut();
-                if mapper.next_scanline() == MapperResult::Irq {
-                    result.scanline_irq = true
-                }
+            if self.vram.chr.next_scanline() == MapperResult::Irq {
+                result.scanline_irq = true
             }
 
-            if self.scanline == (VBLANK_SCANLINE as u16) {
+            if self.scanline == self.region.vblank_scanline() {
                 self.start_vblank(&mut result);
-            } else if self.scanline == (LAST_SCANLINE as u16) {
+            } else if self.scanline == self.region.last_scanline() {
                 result.new_frame = true;
+                self.frame += 1;
+                if let Some(ref mut provenance) = self.vram.provenance {
+                    provenance.end_frame();
+                }
                 self.scanline = 0;
+                let captured = match self.layer_capture {
+                    Some(ref mut capture) => {
+                        let captured = capture.started;
+                        capture.started = true;
+                        captured
+                    }
+                    None => false,
+                };
+                if captured {
+                    self.captured_layers = self.layer_capture.take();
+                }
+                self.vram.chr.start_frame();
                 self.regs.status.set_in_vblank(false);
             }
 
-            self.cy += CYCLES_PER_SCANLINE;
+            self.cy += cycles_per_scanline;
 
-            debug_assert!(self.cy % CYCLES_PER_SCANLINE == 0, "at even scanline cycle");
+            debug_assert!(
+                self.cy.is_multiple_of(cycles_per_scanline),
+                "at even scanline cycle"
+            );
         }
 
         return result;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{with_attribute, PLANE_LUT};
+    use util::Xorshift;
+
+    /// The color of pixel `x` of a tile row, one bit at a time.
+    fn pixel(plane0: u8, plane1: u8, attr: u8, x: usize) -> u8 {
+        let color = (plane0 >> (7 - x)) & 1 | ((plane1 >> (7 - x)) & 1) << 1;
+        if color == 0 {
+            0
+        } else {
+            attr << 2 | color
+        }
+    }
+
+    #[test]
+    fn plane_lut_matches_bit_extraction() {
+        let mut rng = Xorshift::new();
+        for _ in 0..10000 {
+            let bytes = rng.next().to_le_bytes();
+            let (plane0, plane1) = (bytes[0], bytes[1]);
+            let (next_plane0, next_plane1) = (bytes[2], bytes[3]);
+            let (attr, next_attr) = (rng.next() as u8 & 3, (rng.next() >> 8) as u8 & 3);
+            let fine_x = rng.next() as usize % 8;
+
+            // Two tiles side by side, scrolled as `render_scanline` does.
+            let mut line = [0; 16];
+            let row = PLANE_LUT[plane0 as usize] | PLANE_LUT[plane1 as usize] << 1;
+            let next_row = PLANE_LUT[next_plane0 as usize] | PLANE_LUT[next_plane1 as usize] << 1;
+            line[..8].copy_from_slice(&with_attribute(row, attr).to_le_bytes());
+            line[8..].copy_from_slice(&with_attribute(next_row, next_attr).to_le_bytes());
+            for x in 0..8 {
+                let expected = if x + fine_x < 8 {
+                    pixel(plane0, plane1, attr, x + fine_x)
+                } else {
+                    pixel(next_plane0, next_plane1, next_attr, x + fine_x - 8)
+                };
+                assert_eq!(line[x + fine_x], expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Write provenance: which instruction last wrote each byte of RAM, cartridge RAM, nametables and
+//! CHR-RAM, to answer "who wrote $00C5?" when hunting down the code behind a value or a graphic.
+//! How often each pattern tile is drawn is counted alongside, to find the tiles a screen uses.
+//!
+//! The tables are compact: one packed word per byte tracked, holding the writer's address and the
+//! region its code ran from, such as `PRG-ROM bank 5`, numbered in order of first use.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// The 2KB of internal RAM, whose mirrors up to $1FFF share entries.
+const RAM_SIZE: usize = 0x800;
+/// Cartridge RAM at $6000-$7FFF.
+const CARTRIDGE_RAM_START: u16 = 0x6000;
+const CARTRIDGE_RAM_SIZE: usize = 0x2000;
+/// The pattern tables and nametables, $0000-$2FFF, whose mirrors up to $3EFF share entries.
+const VRAM_SIZE: usize = 0x3000;
+/// How many tiles the report lists.
+const REPORT_TILES: usize = 64;
+
+/// The instruction that last wrote a byte.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Writer<'a> {
+    pub pc: u16,
+    /// What was mapped at `pc` when it ran, as `MemMap::region_label` names it.
+    pub region: &'a str,
+}
+
+impl<'a> fmt::Display for Writer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${:04X} ({})", self.pc, self.region)
+    }
+}
+
+/// The last writer of each byte of a memory, packed as `region << 16 | pc`, where `region` is an
+/// index into `regions` plus 1. 0 means never written.
+struct Writers {
+    writers: Box<[u32]>,
+    regions: Vec<String>,
+}
+
+impl Writers {
+    fn new(size: usize) -> Writers {
+        Writers {
+            writers: vec![0; size].into_boxed_slice(),
+            regions: Vec::new(),
+        }
+    }
+
+    fn pack(&mut self, pc: u16, region: &str) -> u32 {
+        let number = match self.regions.iter().position(|known| known == region) {
+            Some(number) => number,
+            None => {
+                self.regions.push(region.to_string());
+                self.regions.len() - 1
+            }
+        };
+        (number as u32 + 1) << 16 | pc as u32
+    }
+
+    fn get<'a>(&'a self, index: usize) -> Option<Writer<'a>> {
+        let writer = self.writers[index];
+        if writer == 0 {
+            return None;
+        }
+        Some(Writer {
+            pc: writer as u16,
+            region: &self.regions[(writer >> 16) as usize - 1],
+        })
+    }
+}
+
+pub struct WriteProvenance {
+    /// The address of the instruction being run. Set before each instruction.
+    pub pc: u16,
+    writers: Writers,
+}
+
+impl Default for WriteProvenance {
+    fn default() -> WriteProvenance {
+        WriteProvenance::new()
+    }
+}
+
+impl WriteProvenance {
+    pub fn new() -> WriteProvenance {
+        WriteProvenance {
+            pc: 0,
+            writers: Writers::new(RAM_SIZE + CARTRIDGE_RAM_SIZE),
+        }
+    }
+
+    /// Returns true if writes to `addr` are tracked.
+    pub fn wants(&self, addr: u16) -> bool {
+        WriteProvenance::index(addr).is_some()
+    }
+
+    /// Records that the current instruction, running from `region`, wrote `addr`.
+    pub fn record(&mut self, addr: u16, region: &str) {
+        if let Some(index) = WriteProvenance::index(addr) {
+            self.writers.writers[index] = self.writers.pack(self.pc, region);
+        }
+    }
+
+    /// The instruction that last wrote `addr`, or `None` if nothing has since tracking started or
+    /// the address isn't tracked.
+    pub fn last_writer<'a>(&'a self, addr: u16) -> Option<Writer<'a>> {
+        self.writers.get(WriteProvenance::index(addr)?)
+    }
+
+    fn index(addr: u16) -> Option<usize> {
+        if addr < 0x2000 {
+            Some(addr as usize % RAM_SIZE)
+        } else if (CARTRIDGE_RAM_START..0x8000).contains(&addr) {
+            Some(RAM_SIZE + (addr - CARTRIDGE_RAM_START) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+/// Write provenance for the PPU's memory, which the CPU writes through PPUDATA, and how often each
+/// tile is drawn.
+pub struct VramProvenance {
+    /// The instruction writing PPUDATA, packed as in `Writers`.
+    writer: u32,
+    writers: Writers,
+    /// Pattern rows fetched for drawing, by tile: the tile's location in CHR memory divided by 16,
+    /// or its PPU address divided by 16 on boards that don't say where their CHR is.
+    tile_rows: Vec<u64>,
+    frames: u64,
+}
+
+impl Default for VramProvenance {
+    fn default() -> VramProvenance {
+        VramProvenance::new()
+    }
+}
+
+impl VramProvenance {
+    pub fn new() -> VramProvenance {
+        VramProvenance {
+            writer: 0,
+            writers: Writers::new(VRAM_SIZE),
+            tile_rows: Vec::new(),
+            frames: 0,
+        }
+    }
+
+    /// Sets the instruction, running from `region`, that the next PPUDATA write comes from.
+    pub fn set_writer(&mut self, pc: u16, region: &str) {
+        self.writer = self.writers.pack(pc, region);
+    }
+
+    /// Records a write of `addr` by the instruction given to `set_writer`.
+    pub fn record(&mut self, addr: u16) {
+        if let Some(index) = VramProvenance::index(addr) {
+            self.writers.writers[index] = self.writer;
+        }
+    }
+
+    /// Counts a fetch of a row of `tile` for drawing.
+    pub fn count_tile_row(&mut self, tile: usize) {
+        if tile >= self.tile_rows.len() {
+            self.tile_rows.resize(tile + 1, 0);
+        }
+        self.tile_rows[tile] += 1;
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    /// The instruction that last wrote the PPU address `addr`, or `None` if nothing has since
+    /// tracking started or the address isn't tracked. Palette writes aren't.
+    pub fn last_writer<'a>(&'a self, addr: u16) -> Option<Writer<'a>> {
+        self.writers.get(VramProvenance::index(addr)?)
+    }
+
+    /// Writes the report: the writers of the pattern tables and nametables, a line per run of
+    /// addresses with the same writer, then the tiles drawn most, in pattern rows per frame.
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "; VRAM writers")?;
+        let mut start = 0;
+        while start < VRAM_SIZE {
+            let packed = self.writers.writers[start];
+            let end = (start..VRAM_SIZE)
+                .find(|&index| self.writers.writers[index] != packed)
+                .unwrap_or(VRAM_SIZE);
+            if let Some(last_writer) = self.writers.get(start) {
+                writeln!(writer, "{:04X}-{:04X}: {}", start, end - 1, last_writer)?;
+            }
+            start = end;
+        }
+
+        writeln!(
+            writer,
+            "; Tiles drawn over {} frames, in pattern rows per frame",
+            self.frames
+        )?;
+        let mut tiles: Vec<(usize, u64)> = self
+            .tile_rows
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|&(_, rows)| rows > 0)
+            .collect();
+        tiles.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for &(tile, rows) in tiles.iter().take(REPORT_TILES) {
+            let per_frame = rows as f64 / self.frames.max(1) as f64;
+            writeln!(
+                writer,
+                "tile {:04X} (CHR {:05X}): {:.1}",
+                tile,
+                tile * 16,
+                per_frame
+            )?;
+        }
+        Ok(())
+    }
+
+    fn index(addr: u16) -> Option<usize> {
+        if addr < 0x3000 {
+            Some(addr as usize)
+        } else if addr < 0x3f00 {
+            Some(addr as usize - 0x1000)
+        } else {
+            None
+        }
+    }
+}
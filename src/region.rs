@@ -0,0 +1,127 @@
+//! Console regions and their timing parameters.
+//!
+//! The Dendy is a Russian famiclone that runs at 50 Hz like a PAL NES, but keeps the NTSC
+//! CPU/PPU clock ratio and places vblank 50 scanlines later so that NTSC games run with their
+//! expected amount of vblank time. It is treated as a region of its own rather than as PAL.
+
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /// CPU cycles per scanline, rounded to a whole number.
+    pub fn cycles_per_scanline(self) -> u64 {
+        match self {
+            Region::Ntsc | Region::Dendy => 114, // 341 dots / 3 dots per cycle
+            Region::Pal => 107,                  // 341 dots / 3.2 dots per cycle
+        }
+    }
+
+    /// The scanline on which vblank starts and the NMI fires.
+    pub fn vblank_scanline(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// The scanline after which the PPU wraps around to the next frame.
+    pub fn last_scanline(self) -> u16 {
+        match self {
+            Region::Ntsc => 261,
+            Region::Pal | Region::Dendy => 311,
+        }
+    }
+
+    /// The CPU clock rate in Hz.
+    pub fn cpu_clock(self) -> u32 {
+        match self {
+            Region::Ntsc => 1789773,
+            Region::Pal => 1662607,
+            Region::Dendy => 1773448,
+        }
+    }
+
+    /// The PPU dots in `cpu_cycles` CPU cycles: 3 per cycle, or 3.2 on PAL.
+    pub fn ppu_dots(self, cpu_cycles: u64) -> u64 {
+        match self {
+            Region::Ntsc | Region::Dendy => cpu_cycles * 3,
+            Region::Pal => cpu_cycles * 16 / 5,
+        }
+    }
+
+    /// Frames per second. The Dendy's frame is as long as a PAL one.
+    pub fn frame_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    /// The APU frame counter period, in CPU cycles, for even and odd quarter frames. The Dendy
+    /// uses the NTSC frame counter.
+    pub fn apu_tick_cycles(self) -> (u64, u64) {
+        match self {
+            Region::Ntsc | Region::Dendy => (7438, 7439),
+            Region::Pal => (8313, 8314),
+        }
+    }
+
+    /// The noise channel periods, in CPU cycles. The Dendy uses the NTSC table.
+    pub fn noise_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    /// The DMC rates, in CPU cycles per output bit. The Dendy uses the NTSC table.
+    pub fn dmc_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_PERIODS,
+            Region::Pal => &PAL_DMC_PERIODS,
+        }
+    }
+
+    /// Parses a region name as used in the configuration file.
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let name = match *self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const NTSC_DMC_PERIODS: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+const PAL_DMC_PERIODS: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
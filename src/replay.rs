@@ -0,0 +1,251 @@
+//! Instant replay: a rolling buffer of the last few seconds of frames and audio, so a recording
+//! started after something happened can still show it.
+//!
+//! Recordings are written the way `dump` writes them: a directory of raw 24-bit RGB frames named
+//! by frame index, and the audio as raw signed 16-bit little-endian mono PCM in `audio.raw`.
+//!
+//! The buffer holds the raw frames, about 11 MB a second.
+
+use apu::{Apu, SampleClock, OUTPUT_SAMPLE_RATE};
+use dump::{AudioDump, FrameDump, FrameFormat};
+use emulator::{AudioChunk, Frame};
+use region::Region;
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the audio file in a recording's directory.
+pub const AUDIO_FILE: &str = "audio.raw";
+
+struct ReplayFrame {
+    index: usize,
+    /// Laid out as `Emulator::screen`.
+    screen: Box<[u8]>,
+    samples: Vec<i16>,
+}
+
+/// The most recent frames, up to a capacity, each with the audio that belongs to it.
+///
+/// ```
+/// use nes::config::Config;
+/// use nes::emulator::Emulator;
+/// use nes::replay::ReplayBuffer;
+///
+/// let rom = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/backdrop.nes"));
+/// let mut emulator = Emulator::from_bytes(rom, &Config::new()).unwrap();
+/// let mut replay = ReplayBuffer::new(4);
+/// for index in 0..10 {
+///     emulator.step_frame();
+///     let audio = emulator.take_audio();
+///     replay.push(index, emulator.screen(), &audio);
+/// }
+/// assert_eq!(replay.len(), 4);
+/// assert_eq!(replay.first_index(), Some(6));
+/// ```
+pub struct ReplayBuffer {
+    capacity: usize,
+    frames: VecDeque<ReplayFrame>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer {
+            capacity,
+            frames: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// A buffer holding `seconds` of frames of `region`.
+    pub fn for_seconds(seconds: f64, region: Region) -> ReplayBuffer {
+        ReplayBuffer::new((seconds * region.frame_rate()).ceil() as usize)
+    }
+
+    /// Adds a frame, laid out as `Emulator::screen`, and its audio, dropping the oldest frame if
+    /// the buffer is full.
+    pub fn push(&mut self, index: usize, screen: &[u8], audio: &AudioChunk) {
+        if self.capacity == 0 {
+            return;
+        }
+        // The oldest frame's buffers are reused, as the buffer fills up once and stays full.
+        let mut frame = if self.frames.len() == self.capacity {
+            self.frames.pop_front().unwrap()
+        } else {
+            ReplayFrame {
+                index: 0,
+                screen: vec![0; screen.len()].into_boxed_slice(),
+                samples: Vec::new(),
+            }
+        };
+        frame.index = index;
+        frame.screen.copy_from_slice(screen);
+        frame.samples.clear();
+        frame.samples.extend_from_slice(&audio.samples);
+        self.frames.push_back(frame);
+    }
+
+    /// How many frames the buffer holds when full.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The index of the oldest frame held.
+    pub fn first_index(&self) -> Option<usize> {
+        self.frames.front().map(|frame| frame.index)
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Writes the frames held, oldest first, to `frames` and their audio to `audio`, and empties
+    /// the buffer.
+    pub fn drain_into(
+        &mut self,
+        frames: Option<&FrameDump>,
+        mut audio: Option<&mut AudioDump>,
+    ) -> io::Result<()> {
+        for frame in self.frames.drain(..) {
+            if let Some(frames) = frames {
+                frames.write(frame.index, &Frame::from_screen(&frame.screen))?;
+            }
+            if let Some(ref mut audio) = audio {
+                audio.write_samples(&frame.samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a recording in `dir` with the frames held, and empties the buffer.
+    pub fn start_recording(&mut self, dir: &Path) -> io::Result<Recording> {
+        let mut recording = Recording::create(dir)?;
+        recording.written = self.len();
+        self.drain_into(Some(&recording.frames), Some(&mut recording.audio))?;
+        Ok(recording)
+    }
+}
+
+/// A recording being written to a directory.
+pub struct Recording {
+    frames: FrameDump,
+    audio: AudioDump,
+    written: usize,
+}
+
+impl Recording {
+    pub fn create(dir: &Path) -> io::Result<Recording> {
+        let frames = FrameDump::new(dir, FrameFormat::Rgb)?;
+        let audio = AudioDump::create(&dir.join(AUDIO_FILE))?;
+        Ok(Recording {
+            frames,
+            audio,
+            written: 0,
+        })
+    }
+
+    /// Writes a frame, laid out as `Emulator::screen`, and its audio.
+    pub fn write(&mut self, index: usize, screen: &[u8], audio: &AudioChunk) -> io::Result<()> {
+        self.frames.write(index, &Frame::from_screen(screen))?;
+        self.audio.write(audio)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Flushes the audio and returns the number of frames written.
+    pub fn finish(self) -> io::Result<usize> {
+        self.audio.finish()?;
+        Ok(self.written)
+    }
+}
+
+/// Instant replay for the window: keeps the last frames, and starts a recording with them when
+/// asked, which then goes on until asked to stop.
+pub struct Recorder {
+    buffer: ReplayBuffer,
+    /// The recording in progress and its directory.
+    recording: Option<(PathBuf, Recording)>,
+    /// Counts the samples of each frame.
+    audio_clock: SampleClock,
+    /// Samples the APU has played past the last frame.
+    audio: Vec<i16>,
+    region: Region,
+}
+
+impl Recorder {
+    /// Keeps `seconds` of frames, or none if 0.
+    pub fn new(seconds: f64, region: Region) -> Recorder {
+        Recorder {
+            buffer: ReplayBuffer::for_seconds(seconds, region),
+            recording: None,
+            audio_clock: SampleClock::new(region),
+            audio: Vec::new(),
+            region,
+        }
+    }
+
+    /// Takes a finished frame, laid out as `Emulator::screen`, that ended on CPU cycle `cycle`,
+    /// and the sound `apu` played for it. Call it before `Apu::play_channels`. Returns a message
+    /// to show if writing it to the recording failed, which stops the recording.
+    pub fn frame(
+        &mut self,
+        index: usize,
+        screen: &[u8],
+        cycle: u64,
+        apu: &mut Apu,
+    ) -> Option<String> {
+        apu.take_samples(&mut self.audio);
+        let audio = AudioChunk {
+            sample_rate: OUTPUT_SAMPLE_RATE,
+            samples: self.audio_clock.take(cycle, &mut self.audio),
+        };
+        let written = match self.recording {
+            Some((_, ref mut recording)) => recording.write(index, screen, &audio),
+            None => {
+                self.buffer.push(index, screen, &audio);
+                return None;
+            }
+        };
+        match written {
+            Ok(()) => None,
+            Err(err) => {
+                self.recording = None;
+                Some(format!("Error recording: {}", err))
+            }
+        }
+    }
+
+    /// Starts recording into `recording-<frame>`, beginning with the frames kept, or stops the
+    /// recording in progress. Returns a message to show.
+    pub fn toggle(&mut self, frame: u64) -> String {
+        if let Some(message) = self.stop() {
+            return message;
+        }
+        let dir = PathBuf::from(format!("recording-{}", frame));
+        let seconds = self.buffer.len() as f64 / self.region.frame_rate();
+        match self.buffer.start_recording(&dir) {
+            Ok(recording) => {
+                let message = format!("Recording to {} from {:.1} s ago", dir.display(), seconds);
+                self.recording = Some((dir, recording));
+                message
+            }
+            Err(err) => format!("Error recording to {}: {}", dir.display(), err),
+        }
+    }
+
+    /// Finishes the recording in progress, if any, and returns a message saying how it went.
+    pub fn stop(&mut self) -> Option<String> {
+        let (dir, recording) = self.recording.take()?;
+        Some(match recording.finish() {
+            Ok(frames) => format!("Recorded {} frames to {}", frames, dir.display()),
+            Err(err) => format!("Error recording to {}: {}", dir.display(), err),
+        })
+    }
+}
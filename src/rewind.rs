@@ -0,0 +1,304 @@
+//! A memory-bounded history of savestates for rewinding.
+//!
+//! Every `KEYFRAME_INTERVAL`th snapshot is a full state. The others are stored as the XOR against
+//! the last keyframe with runs of zero bytes collapsed, which is small because little of the
+//! machine changes from one frame to the next. Both are then compressed with
+//! `Compression::preferred`. Old keyframes are dropped together with their deltas once the buffer
+//! outgrows its budget.
+
+use error::Error;
+use savestate::{self, Compression};
+use util::Save;
+
+use std::collections::VecDeque;
+
+/// How many snapshots share a keyframe, counting the keyframe.
+const KEYFRAME_INTERVAL: usize = 60;
+
+/// A compressed snapshot.
+enum Snapshot {
+    Keyframe(Vec<u8>),
+    /// A delta against the nearest earlier keyframe.
+    Delta(Vec<u8>),
+}
+
+impl Snapshot {
+    fn len(&self) -> usize {
+        match *self {
+            Snapshot::Keyframe(ref data) | Snapshot::Delta(ref data) => data.len(),
+        }
+    }
+}
+
+pub struct Rewind {
+    snapshots: VecDeque<Snapshot>,
+    /// Total size of `snapshots` in bytes.
+    size: usize,
+    capacity: usize,
+    /// Snapshots pushed since the last keyframe, including it.
+    since_keyframe: usize,
+    compression: Compression,
+    /// The last keyframe, uncompressed, which deltas are taken against.
+    keyframe: Option<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Creates a buffer that holds at most `capacity` bytes of snapshots.
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            snapshots: VecDeque::new(),
+            size: 0,
+            capacity,
+            since_keyframe: 0,
+            compression: Compression::preferred(),
+            keyframe: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Records the current state of `state`.
+    pub fn push<S: Save>(&mut self, state: &mut S) -> Result<(), Error> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let full = savestate::encode(state, Compression::None)?;
+        let snapshot = match self.keyframe {
+            Some(ref keyframe) if self.since_keyframe < KEYFRAME_INTERVAL => {
+                Snapshot::Delta(self.compression.compress(&encode_delta(keyframe, &full))?)
+            }
+            _ => {
+                let snapshot = Snapshot::Keyframe(self.compression.compress(&full)?);
+                self.keyframe = Some(full);
+                snapshot
+            }
+        };
+        self.since_keyframe = match snapshot {
+            Snapshot::Keyframe(_) => 1,
+            Snapshot::Delta(_) => self.since_keyframe + 1,
+        };
+        self.size += snapshot.len();
+        self.snapshots.push_back(snapshot);
+
+        while self.size > self.capacity {
+            self.drop_oldest_keyframe();
+        }
+        Ok(())
+    }
+
+    /// Restores `state` to the most recent snapshot and forgets it. Returns false if the buffer
+    /// is empty.
+    pub fn pop<S: Save>(&mut self, state: &mut S) -> Result<bool, Error> {
+        let snapshot = match self.snapshots.pop_back() {
+            Some(snapshot) => snapshot,
+            None => return Ok(false),
+        };
+        self.size -= snapshot.len();
+        self.since_keyframe = self.since_keyframe.saturating_sub(1);
+        match snapshot {
+            Snapshot::Keyframe(keyframe) => {
+                let full = self.compression.decompress(&keyframe)?;
+                // The deltas before it, and those pushed from now on, are against the keyframe
+                // before it.
+                self.keyframe = match self.last_keyframe() {
+                    Some(keyframe) => Some(self.compression.decompress(keyframe)?),
+                    None => None,
+                };
+                savestate::decode(state, &full)?
+            }
+            Snapshot::Delta(delta) => {
+                let full = match self.keyframe {
+                    Some(ref keyframe) => {
+                        decode_delta(keyframe, &self.compression.decompress(&delta)?)
+                    }
+                    None => return Ok(false),
+                };
+                savestate::decode(state, &full)?
+            }
+        }
+        if self.since_keyframe == 0 {
+            // Count the deltas remaining after the previous keyframe so new snapshots use it.
+            self.since_keyframe = self
+                .snapshots
+                .iter()
+                .rev()
+                .position(|snapshot| match *snapshot {
+                    Snapshot::Keyframe(_) => true,
+                    Snapshot::Delta(_) => false,
+                })
+                .map_or(0, |deltas| deltas + 1);
+        }
+        Ok(true)
+    }
+
+    fn last_keyframe(&self) -> Option<&[u8]> {
+        self.snapshots
+            .iter()
+            .rev()
+            .filter_map(|snapshot| match *snapshot {
+                Snapshot::Keyframe(ref full) => Some(&full[..]),
+                Snapshot::Delta(_) => None,
+            })
+            .next()
+    }
+
+    /// Removes the oldest keyframe and the deltas that depend on it.
+    fn drop_oldest_keyframe(&mut self) {
+        if let Some(snapshot) = self.snapshots.pop_front() {
+            self.size -= snapshot.len();
+        }
+        while let Some(&Snapshot::Delta(_)) = self.snapshots.front() {
+            let snapshot = self.snapshots.pop_front().unwrap();
+            self.size -= snapshot.len();
+        }
+        if self.snapshots.is_empty() {
+            self.since_keyframe = 0;
+            self.keyframe = None;
+        }
+    }
+}
+
+/// Encodes `full` as a sequence of `(zero run length, literal length, literals)` records, using
+/// LEB128 for the lengths, where the literals are the XOR of `full` and `keyframe`.
+fn encode_delta(keyframe: &[u8], full: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| full[i] ^ keyframe.get(i).cloned().unwrap_or(0);
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < full.len() {
+        let zeros_start = i;
+        while i < full.len() && xor(i) == 0 {
+            i += 1;
+        }
+        let literals_start = i;
+        while i < full.len() && xor(i) != 0 {
+            i += 1;
+        }
+        push_length(&mut delta, literals_start - zeros_start);
+        push_length(&mut delta, i - literals_start);
+        delta.extend((literals_start..i).map(&xor));
+    }
+    delta
+}
+
+fn decode_delta(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(keyframe.len());
+    let mut pos = 0;
+    while pos < delta.len() {
+        let zeros = read_length(delta, &mut pos);
+        let literals = read_length(delta, &mut pos);
+        for _ in 0..zeros {
+            let i = full.len();
+            full.push(keyframe.get(i).cloned().unwrap_or(0));
+        }
+        for &byte in &delta[pos..pos + literals] {
+            let i = full.len();
+            full.push(byte ^ keyframe.get(i).cloned().unwrap_or(0));
+        }
+        pos += literals;
+    }
+    full
+}
+
+fn push_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        out.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+}
+
+fn read_length(data: &[u8], pos: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_delta, encode_delta, Rewind, KEYFRAME_INTERVAL};
+    use util::{Save, Xorshift};
+
+    struct Machine {
+        ram: [u8; 64],
+    }
+
+    save_struct!(Machine { ram });
+
+    impl Machine {
+        /// A machine whose RAM says it's at `frame`.
+        fn at(frame: usize) -> Machine {
+            let mut ram = [0; 64];
+            ram[0] = frame as u8;
+            ram[1] = (frame * 3) as u8;
+            ram[40] = (frame / 7) as u8;
+            Machine { ram }
+        }
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let mut rng = Xorshift::new();
+        for _ in 0..200 {
+            let keyframe: Vec<u8> = (0..rng.next() % 600).map(|_| rng.next() as u8).collect();
+            // Mostly the keyframe, with runs of changes, and longer or shorter than it.
+            let len = rng.next() as usize % 600;
+            let full: Vec<u8> = (0..len)
+                .map(|i| match keyframe.get(i) {
+                    Some(&byte) if (i / 50 + rng.next() as usize % 2) % 3 != 0 => byte,
+                    _ => rng.next() as u8,
+                })
+                .collect();
+            assert_eq!(
+                decode_delta(&keyframe, &encode_delta(&keyframe, &full)),
+                full
+            );
+        }
+    }
+
+    #[test]
+    fn pop_across_dropped_keyframe() {
+        let mut rewind = Rewind::new(1500);
+        let frames = KEYFRAME_INTERVAL * 3 + 20;
+        for frame in 0..frames {
+            rewind.push(&mut Machine::at(frame)).unwrap();
+        }
+        let mut machine = Machine::at(0);
+        let mut frame = frames;
+        while rewind.pop(&mut machine).unwrap() {
+            frame -= 1;
+            assert_eq!(machine.ram[..], Machine::at(frame).ram[..]);
+        }
+        // The oldest keyframes went, and popping went back across the newest to an older one.
+        assert!(frame > 0 && frame + KEYFRAME_INTERVAL < frames);
+        assert_eq!(frame % KEYFRAME_INTERVAL, 0);
+        assert!(rewind.is_empty());
+
+        // Pushing after popping back past a keyframe takes deltas against the one before it.
+        for frame in 0..KEYFRAME_INTERVAL + 10 {
+            rewind.push(&mut Machine::at(frame)).unwrap();
+        }
+        for _ in 0..20 {
+            rewind.pop(&mut machine).unwrap();
+        }
+        for frame in 100..110 {
+            rewind.push(&mut Machine::at(frame)).unwrap();
+        }
+        rewind.pop(&mut machine).unwrap();
+        assert_eq!(machine.ram[..], Machine::at(109).ram[..]);
+        for _ in 0..9 {
+            rewind.pop(&mut machine).unwrap();
+        }
+        rewind.pop(&mut machine).unwrap();
+        assert_eq!(machine.ram[..], Machine::at(KEYFRAME_INTERVAL - 11).ram[..]);
+    }
+}
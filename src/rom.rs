@@ -1,28 +1,28 @@
 //! Contains iNES ROM loading code.
+//!
+//! Data after CHR-ROM is kept as the misc ROMs when an NES 2.0 header declares them. Either way, if
+//! it is text it is read as the title and author homebrew puts there, following the iNES
+//! convention of a title at the end of the file: lines such as `Title: Alter Ego` and
+//! `Author: Shiru`, or the title on the first line and the author on the second. Padding with
+//! zeros or $FF is allowed.
 
 //
 // Author: Patrick Walton
 //
 
+use error::Error;
+use region::Region;
 use util;
 
 use std::fmt;
-use std::io::{self, Read};
+use std::io::Read;
+use std::str;
 use std::vec::Vec;
 
-#[derive(Debug)]
-pub enum RomLoadError {
-    /// IO error while reading the ROM image
-    IoError(io::Error),
-    /// The ROM image has an invalid format
-    FormatError,
-}
-
-impl From<io::Error> for RomLoadError {
-    fn from(err: io::Error) -> Self {
-        RomLoadError::IoError(err)
-    }
-}
+/// The size of the trainer that may sit between the header and PRG-ROM.
+const TRAINER_SIZE: usize = 512;
+/// Trailing data longer than this isn't taken for a title and author.
+const MAX_METADATA_SIZE: usize = 1024;
 
 /// A ROM image
 pub struct Rom {
@@ -31,10 +31,52 @@ pub struct Rom {
     pub prg: Vec<u8>,
     /// CHR-ROM
     pub chr: Vec<u8>,
+    /// The NES 2.0 misc ROMs, all of them together.
+    pub misc: Vec<u8>,
+    /// The title and author in the data after CHR-ROM, if any.
+    pub metadata: RomMetadata,
+    /// How the image disagreed with its header, and what was done about it.
+    pub warnings: Vec<RomWarning>,
+}
+
+/// A sign of a bad dump: an image whose size doesn't match what its header gives.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RomWarning {
+    /// PRG-ROM (`chr` false) or CHR-ROM ends early. The rest is filled by repeating what there
+    /// is if that divides evenly, as a smaller chip would be mirrored, or else with $FF.
+    Underdump {
+        chr: bool,
+        expected: usize,
+        found: usize,
+        mirrored: bool,
+    },
+    /// Bytes past the end of CHR-ROM, which are ignored.
+    Overdump(usize),
+}
+
+impl fmt::Display for RomWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomWarning::Underdump {
+                chr,
+                expected,
+                found,
+                mirrored,
+            } => write!(
+                f,
+                "{} is {} bytes, short of the {} the header gives; {}",
+                if chr { "CHR-ROM" } else { "PRG-ROM" },
+                found,
+                expected,
+                if mirrored { "mirrored" } else { "padded" }
+            ),
+            RomWarning::Overdump(extra) => write!(f, "ignored {} bytes past the end", extra),
+        }
+    }
 }
 
 impl Rom {
-    pub fn load(r: &mut dyn Read) -> Result<Rom, RomLoadError> {
+    pub fn load(r: &mut dyn Read) -> Result<Rom, Error> {
         let mut header = [0u8; 16];
         util::read_to_buf(&mut header, r)?;
 
@@ -47,27 +89,156 @@ impl Rom {
             prg_ram_size: header[8],
             flags_9: header[9],
             flags_10: header[10],
-            zero: [0; 5],
+            nes2: [header[11], header[12], header[13], header[14], header[15]],
         };
 
         if header.magic != *b"NES\x1a" {
-            return Err(RomLoadError::FormatError);
+            return Err(Error::InvalidRom);
         }
 
-        let prg_bytes = header.prg_rom_size as usize * 16384;
-        let mut prg_rom = vec![0u8; prg_bytes];
-        util::read_to_buf(&mut prg_rom, r)?;
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        let mut rest = &data[..];
+        if header.trainer() {
+            rest = &rest[TRAINER_SIZE.min(rest.len())..];
+        }
+        if rest.is_empty() {
+            return Err(Error::InvalidRom);
+        }
 
+        let mut warnings = Vec::new();
+        let prg_bytes = header.prg_rom_size as usize * 16384;
+        let prg_rom = take_section(&mut rest, prg_bytes, false, &mut warnings);
         let chr_bytes = header.chr_rom_size as usize * 8192;
-        let mut chr_rom = vec![0u8; chr_bytes];
-        util::read_to_buf(&mut chr_rom, r)?;
+        let chr_rom = take_section(&mut rest, chr_bytes, true, &mut warnings);
+        let metadata = RomMetadata::parse(rest).unwrap_or_default();
+        let misc = if header.misc_roms() > 0 {
+            rest.to_vec()
+        } else {
+            if !rest.is_empty() && metadata.is_empty() {
+                warnings.push(RomWarning::Overdump(rest.len()));
+            }
+            Vec::new()
+        };
 
         Ok(Rom {
             header: header,
             prg: prg_rom,
             chr: chr_rom,
+            misc,
+            metadata,
+            warnings,
         })
     }
+
+    /// The name to show for the game: its title and author, or `None` if the ROM has no title.
+    pub fn display_name(&self) -> Option<String> {
+        let title = self.metadata.title.as_ref()?;
+        Some(match self.metadata.author {
+            Some(ref author) => format!("{} by {}", title, author),
+            None => title.clone(),
+        })
+    }
+}
+
+/// Takes `size` bytes off the front of `rest`, filling in what's missing if it's too short.
+fn take_section(
+    rest: &mut &[u8],
+    size: usize,
+    chr: bool,
+    warnings: &mut Vec<RomWarning>,
+) -> Vec<u8> {
+    let found = size.min(rest.len());
+    let mut section = rest[..found].to_vec();
+    *rest = &rest[found..];
+    if found < size {
+        let mirrored = found > 0 && size.is_multiple_of(found);
+        if mirrored {
+            section = section.iter().cycle().take(size).cloned().collect();
+        } else {
+            section.resize(size, 0xff);
+        }
+        warnings.push(RomWarning::Underdump {
+            chr,
+            expected: size,
+            found,
+            mirrored,
+        });
+    }
+    section
+}
+
+/// Homebrew metadata found after CHR-ROM.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RomMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+}
+
+impl RomMetadata {
+    /// Reads the title and author from `data`, or returns `None` if it isn't short, padded text.
+    ///
+    /// ```
+    /// use nes::rom::RomMetadata;
+    ///
+    /// let metadata = RomMetadata::parse(b"Alter Ego\nby Shiru\n\0\0\0\0").unwrap();
+    /// assert_eq!(metadata.title, Some("Alter Ego".to_string()));
+    /// assert_eq!(metadata.author, Some("Shiru".to_string()));
+    /// let metadata = RomMetadata::parse(b"Author: Shiru\nTitle: Alter Ego").unwrap();
+    /// assert_eq!(metadata.title, Some("Alter Ego".to_string()));
+    /// assert!(RomMetadata::parse(&[0x4c, 0x00, 0x80, 0x01]).is_none());
+    /// ```
+    pub fn parse(data: &[u8]) -> Option<RomMetadata> {
+        let end = data
+            .iter()
+            .rposition(|&byte| byte != 0 && byte != 0xff)
+            .map_or(0, |last| last + 1);
+        if end == 0 || end > MAX_METADATA_SIZE {
+            return None;
+        }
+        let text = str::from_utf8(&data[..end]).ok()?;
+        if text
+            .chars()
+            .any(|ch| ch.is_control() && ch != '\n' && ch != '\r' && ch != '\t')
+        {
+            return None;
+        }
+
+        let mut metadata = RomMetadata::default();
+        let mut unlabeled = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, [':', '=']);
+            let label = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            match (&*label, parts.next()) {
+                ("title", Some(value)) => metadata.title = non_empty(value),
+                ("author", Some(value)) => metadata.author = non_empty(value),
+                _ => unlabeled.push(line),
+            }
+        }
+        if metadata.title.is_none() {
+            metadata.title = unlabeled.first().and_then(|line| non_empty(line));
+        }
+        if metadata.author.is_none() {
+            metadata.author = unlabeled.get(1).and_then(|line| match line.get(..3) {
+                Some(by) if by.eq_ignore_ascii_case("by ") => non_empty(&line[3..]),
+                _ => non_empty(line),
+            });
+        }
+        Some(metadata)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.author.is_none()
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
 }
 
 pub struct INesHeader {
@@ -101,8 +272,9 @@ pub struct INesHeader {
     /// * T: 0 for NTSC, 1 for PAL
     pub flags_9: u8,
     pub flags_10: u8,
-    /// always zero
-    pub zero: [u8; 5],
+    /// Bytes 11-15, which only NES 2.0 headers use. Byte 14 holds the number of misc ROMs in its
+    /// low two bits.
+    pub nes2: [u8; 5],
 }
 
 impl INesHeader {
@@ -116,9 +288,68 @@ impl INesHeader {
         self.flags_6 >> 4
     }
 
+    /// Whether the cartridge has battery-backed PRG-RAM.
+    pub fn battery(&self) -> bool {
+        (self.flags_6 & 0x02) != 0
+    }
+
+    /// Whether the nametables are mirrored vertically (horizontal arrangement).
+    pub fn vertical_mirroring(&self) -> bool {
+        (self.flags_6 & 0x01) != 0
+    }
+
+    /// Whether the cartridge provides its own nametable RAM. Some boards reuse this bit for other
+    /// purposes.
+    pub fn four_screen(&self) -> bool {
+        (self.flags_6 & 0x08) != 0
+    }
+
     pub fn trainer(&self) -> bool {
         (self.flags_6 & 0x04) != 0
     }
+
+    /// Whether the header is in the NES 2.0 format.
+    pub fn is_nes2(&self) -> bool {
+        (self.flags_7 & 0x0c) == 0x08
+    }
+
+    /// The full mapper number, which NES 2.0 headers extend to 12 bits with the low nibble of byte
+    /// 8.
+    pub fn mapper_number(&self) -> u16 {
+        if self.is_nes2() {
+            (self.prg_ram_size as u16 & 0x0f) << 8 | self.mapper() as u16
+        } else {
+            self.mapper() as u16
+        }
+    }
+
+    /// The submapper, the variant of the board, which only NES 2.0 headers declare.
+    pub fn submapper(&self) -> Option<u8> {
+        if self.is_nes2() {
+            Some(self.prg_ram_size >> 4)
+        } else {
+            None
+        }
+    }
+
+    /// The number of misc ROMs after CHR-ROM, which only NES 2.0 headers declare.
+    pub fn misc_roms(&self) -> u8 {
+        if self.is_nes2() {
+            self.nes2[3] & 0x03
+        } else {
+            0
+        }
+    }
+
+    /// Returns the region the ROM declares. iNES headers can't express Dendy, so that has to be
+    /// selected in the configuration.
+    pub fn region(&self) -> Region {
+        if (self.flags_9 & 0x01) != 0 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
 }
 
 impl fmt::Display for INesHeader {
@@ -0,0 +1,353 @@
+//! The savestate format: a short header followed by the machine state, optionally compressed.
+//!
+//! The header is the magic `SNST`, a format version byte and a compression byte. States are
+//! encoded in memory, so the same encoding serves files and in-memory snapshots.
+//!
+//! Only states of the current version load. `upgrade` converts older ones forward a version at a
+//! time, so each change to the format that bumps `VERSION` adds a converter to `CONVERTERS`.
+
+use error::Error;
+use metrics::METRICS;
+use util::{self, Save};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"SNST";
+pub const VERSION: u8 = 9;
+/// The first version with the board's registers and memory. Older states can't put back a
+/// cartridge's bank switching.
+pub const BOARD_STATE_VERSION: u8 = 9;
+const HEADER_LENGTH: usize = 6;
+
+/// Converts the body of a state to the next version. The PRG-RAM is that of `upgrade`.
+type Converter = fn(&mut Vec<u8>, Option<&[u8]>) -> Result<(), Error>;
+
+/// The converter from each version to the next, starting with version 1.
+static CONVERTERS: [Converter; VERSION as usize - 1] = [
+    upgrade_v1, upgrade_v2, upgrade_v3, upgrade_v4, upgrade_v5, upgrade_v6, upgrade_v7, upgrade_v8,
+];
+
+// Up to version 3, the state ends with the APU: its registers, the last being the DMC's 4
+// registers and the status register, then `cy` and `ticks`, and from version 2 the sample clock.
+
+/// Version 2 added the APU's sample clock after `ticks`. Starting it at the APU's cycle outputs
+/// no catch-up samples.
+fn upgrade_v1(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    let cy = body.len().checked_sub(16).ok_or(TRUNCATED)?;
+    let cy = body[cy..cy + 8].to_vec();
+    body.extend_from_slice(&cy);
+    Ok(())
+}
+
+/// Version 3 added the DMC sample reader after the DMC registers: the address, the bytes left and
+/// the cycle of the next fetch. An idle reader is all zeros.
+fn upgrade_v2(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    let end_of_dmc = body.len().checked_sub(1 + 8 * 3).ok_or(TRUNCATED)?;
+    let reader = [0; 2 + 2 + 8];
+    body.splice(end_of_dmc..end_of_dmc, reader.iter().cloned());
+    Ok(())
+}
+
+/// Version 4 added the battery-backed PRG-RAM at the end, for cartridges that have it.
+fn upgrade_v3(body: &mut Vec<u8>, prg_ram: Option<&[u8]>) -> Result<(), Error> {
+    if let Some(prg_ram) = prg_ram {
+        body.extend_from_slice(prg_ram);
+    }
+    Ok(())
+}
+
+/// Version 5 added the controller state at the end: the game pad's shift register position, its
+/// latched buttons and OUT1. A pad waiting for a strobe with nothing held is all zeros.
+fn upgrade_v4(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    body.extend_from_slice(&[0; 3]);
+    Ok(())
+}
+
+/// Version 6 fixed booleans, which were saved as 0 for true and 1 for false but loaded as true for
+/// anything but 0. They are the CPU's pending NMI and IRQ, after its cycle count and registers,
+/// and the APU's length counter halts, envelope enables and triangle linear counter halt, after
+/// the CPU, the 2K of RAM and the PPU. Everything before the APU is the same size for every game.
+#[allow(clippy::ptr_arg)] // Converters all take a `Vec`.
+fn upgrade_v5(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    const CPU_BOOLS: [usize; 2] = [15, 16];
+    const APU_START: usize = 17 + 0x800 + 2377;
+    // The two pulses, then the triangle and the noise.
+    const APU_BOOLS: [usize; 7] = [0, 4, 21, 25, 52, 56, 60];
+    if body.len() < APU_START + APU_BOOLS[6] + 1 {
+        return Err(TRUNCATED);
+    }
+    let apu_bools = APU_BOOLS.iter().map(|offset| APU_START + offset);
+    for offset in CPU_BOOLS.iter().cloned().chain(apu_bools) {
+        body[offset] = (body[offset] == 0) as u8;
+    }
+    Ok(())
+}
+
+/// Version 7 added whether a KIL opcode jammed the CPU, after the pending interrupts. States
+/// saved while jammed loaded as running, so they still do.
+fn upgrade_v6(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    if body.len() < 17 {
+        return Err(TRUNCATED);
+    }
+    body.insert(17, 0);
+    Ok(())
+}
+
+/// Version 8 added the state of the expansion sound chip at the end, after whether one is plugged
+/// in. Older states have none, which leaves the chip as it is when loading.
+fn upgrade_v7(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    body.push(0);
+    Ok(())
+}
+
+/// Version 9 added the board's registers and memory at the end, after their length. Older states
+/// have none, which leaves the board as it is when loading.
+fn upgrade_v8(body: &mut Vec<u8>, _: Option<&[u8]>) -> Result<(), Error> {
+    body.extend_from_slice(&[0; 4]);
+    Ok(())
+}
+
+const TRUNCATED: Error = Error::InvalidSavestate("savestate is truncated");
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    /// LZ4 if built with the `compression` feature; otherwise no compression.
+    pub fn preferred() -> Compression {
+        if cfg!(feature = "compression") {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Compresses `data` this way. Fails for LZ4 without the `compression` feature.
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => compress(data),
+        }
+    }
+
+    /// Undoes `compress`.
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => decompress(data),
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(::lz4_flex::compress_prepend_size(data))
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::InvalidSavestate(
+        "savestate compression requires the `compression` feature",
+    ))
+}
+
+#[cfg(feature = "compression")]
+fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    ::lz4_flex::decompress_size_prepended(data)
+        .map_err(|_| Error::InvalidSavestate("corrupt savestate"))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::InvalidSavestate(
+        "savestate is compressed; build with the `compression` feature to load it",
+    ))
+}
+
+/// Serializes `state` with a header. Fails if asked for LZ4 without the `compression` feature.
+pub fn encode<S: Save>(state: &mut S, compression: Compression) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    state.save(&mut data)?;
+    with_header(&data, compression)
+}
+
+fn with_header(body: &[u8], compression: Compression) -> Result<Vec<u8>, Error> {
+    let mut encoded = MAGIC.to_vec();
+    encoded.push(VERSION);
+    encoded.push(compression.id());
+    match compression {
+        Compression::None => encoded.extend_from_slice(body),
+        Compression::Lz4 => encoded.extend_from_slice(&compress(body)?),
+    }
+    Ok(encoded)
+}
+
+/// Restores `state` from the output of `encode`. `state` is untouched if the state can't be
+/// loaded.
+pub fn decode<S: Save>(state: &mut S, encoded: &[u8]) -> Result<(), Error> {
+    let version = version(encoded)?;
+    if version != VERSION {
+        return Err(Error::InvalidSavestate(if version < VERSION {
+            "savestate is from an older version; convert it with `sprocketnes state-upgrade`"
+        } else {
+            "savestate is from an incompatible version"
+        }));
+    }
+    let body = &encoded[HEADER_LENGTH..];
+    let decompressed;
+    let body = match compression(encoded)? {
+        Compression::None => body,
+        Compression::Lz4 => {
+            decompressed = decompress(body)?;
+            &decompressed[..]
+        }
+    };
+    // A truncated body fails partway through loading, so keep what was there to put it back.
+    let mut backup = Vec::new();
+    state.save(&mut backup)?;
+    if let Err(err) = state.load(&mut &body[..]) {
+        state.load(&mut &backup[..])?;
+        // Bodies that are all there but don't fit the machine are invalid data.
+        return Err(if err.kind() == io::ErrorKind::InvalidData {
+            Error::InvalidSavestate("savestate is for a machine with other hardware plugged in")
+        } else {
+            TRUNCATED
+        });
+    }
+    Ok(())
+}
+
+/// Returns the format version of a state.
+pub fn version(encoded: &[u8]) -> Result<u8, Error> {
+    if encoded.len() < HEADER_LENGTH || &encoded[0..4] != MAGIC {
+        return Err(Error::InvalidSavestate("not a savestate"));
+    }
+    Ok(encoded[4])
+}
+
+fn compression(encoded: &[u8]) -> Result<Compression, Error> {
+    Compression::from_id(encoded[5]).ok_or(Error::InvalidSavestate("unknown savestate compression"))
+}
+
+/// Converts a state from an earlier version to the current one, keeping its compression. States
+/// from before version 4 lack the battery-backed PRG-RAM that later ones include, so for
+/// cartridges whose PRG-RAM `MemMap` saves, pass what it should hold in `prg_ram`; loading the
+/// old state would have left the PRG-RAM as it was, which is usually what the battery save file
+/// holds.
+pub fn upgrade(encoded: &[u8], prg_ram: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    let version = version(encoded)?;
+    if version == 0 || version > VERSION {
+        return Err(Error::InvalidSavestate(
+            "savestate is from an incompatible version",
+        ));
+    }
+    let compression = compression(encoded)?;
+    let mut body = match compression {
+        Compression::None => encoded[HEADER_LENGTH..].to_vec(),
+        Compression::Lz4 => decompress(&encoded[HEADER_LENGTH..])?,
+    };
+    for converter in &CONVERTERS[version as usize - 1..] {
+        converter(&mut body, prg_ram)?;
+    }
+    with_header(&body, compression)
+}
+
+pub fn save_file<S: Save>(state: &mut S, path: &Path) -> Result<(), Error> {
+    util::write_atomic(path, &encode(state, Compression::preferred())?)?;
+    METRICS.savestates_saved.add(1);
+    Ok(())
+}
+
+pub fn load_file<S: Save>(state: &mut S, path: &Path) -> Result<(), Error> {
+    decode(state, &fs::read(path)?)?;
+    METRICS.savestates_loaded.add(1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, upgrade, Compression};
+    use config::Config;
+    use emulator::Emulator;
+
+    const ROM: &[u8] = include_bytes!("../roms/backdrop.nes");
+
+    /// Upgrades `old`, a state of `roms/backdrop.nes` saved after 3 frames by the version that
+    /// wrote it, and checks that it loads and matches a fresh save of the same moment.
+    fn check_upgrade(old: &[u8]) {
+        let mut fresh = Emulator::from_bytes(ROM, &Config::new()).unwrap();
+        for _ in 0..3 {
+            fresh.step_frame();
+        }
+        let fresh = encode(&mut fresh.cpu, Compression::None).unwrap();
+
+        let upgraded = upgrade(old, None).unwrap();
+        let mut emulator = Emulator::from_bytes(ROM, &Config::new()).unwrap();
+        decode(&mut emulator.cpu, &upgraded).unwrap();
+        // These versions didn't run the APU without an audio device, and the ROM never writes
+        // it, so catching it up to the CPU gives the state it would have had.
+        let cy = emulator.cpu.cy;
+        emulator.cpu.mem.apu.step(cy);
+        assert_eq!(encode(&mut emulator.cpu, Compression::None).unwrap(), fresh);
+    }
+
+    #[test]
+    fn upgrades_v1() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v1.state"));
+    }
+
+    #[test]
+    fn upgrades_v2() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v2.state"));
+    }
+
+    #[test]
+    fn upgrades_v3() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v3.state"));
+    }
+
+    #[test]
+    fn upgrades_v4() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v4.state"));
+    }
+
+    #[test]
+    fn upgrades_v5() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v5.state"));
+    }
+
+    #[test]
+    fn upgrades_v6() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v6.state"));
+    }
+
+    #[test]
+    fn upgrades_v7() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v7.state"));
+    }
+
+    #[test]
+    fn upgrades_v8() {
+        check_upgrade(include_bytes!("../roms/states/backdrop-v8.state"));
+    }
+}
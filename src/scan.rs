@@ -0,0 +1,204 @@
+//! Surveys a directory of ROMs: what each one is and whether its mapper is emulated.
+//!
+//! ROMs are identified from their iNES header plus the CRC-32 of their PRG and CHR data, which is
+//! what ROM databases key on. Titles come from a database file of the user's, as none ships with
+//! the emulator.
+
+use error::Error;
+use mapper;
+use region::Region;
+use rom::Rom;
+use util;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Game titles by the CRC-32 of the ROM data without its header.
+pub struct Database {
+    titles: HashMap<u32, String>,
+}
+
+impl Default for Database {
+    fn default() -> Database {
+        Database::new()
+    }
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database {
+            titles: HashMap::new(),
+        }
+    }
+
+    /// Reads a database of `<crc32>,<title>` lines, with the CRC-32 in hex. Blank lines and lines
+    /// starting with `#` are skipped.
+    ///
+    /// ```
+    /// let text = "# Test ROMs\n0123ABCD,Some Game (USA)\n";
+    /// let database = nes::scan::Database::parse(text).unwrap();
+    /// assert_eq!(database.title(0x0123abcd), Some("Some Game (USA)"));
+    /// assert_eq!(database.title(0), None);
+    /// ```
+    pub fn parse(text: &str) -> Result<Database, String> {
+        let mut database = Database::new();
+        for line in text.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(2, ',');
+            let crc32 = fields.next().unwrap_or("");
+            let crc32 = match u32::from_str_radix(crc32.trim(), 16) {
+                Ok(crc32) => crc32,
+                Err(_) => return Err(format!("`{}` doesn't start with a CRC-32", line)),
+            };
+            match fields.next() {
+                Some(title) if !title.trim().is_empty() => {
+                    database.titles.insert(crc32, title.trim().to_string());
+                }
+                _ => return Err(format!("`{}` has no title", line)),
+            }
+        }
+        Ok(database)
+    }
+
+    pub fn load_file(path: &Path) -> io::Result<Database> {
+        let text = fs::read_to_string(path)?;
+        Database::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn title(&self, crc32: u32) -> Option<&str> {
+        self.titles.get(&crc32).map(|title| &title[..])
+    }
+}
+
+pub struct RomInfo {
+    pub path: PathBuf,
+    /// The title the database gives the ROM, if it's there.
+    pub title: Option<String>,
+    pub mapper: u16,
+    pub supported: bool,
+    pub prg_kb: u32,
+    pub chr_kb: u32,
+    pub battery: bool,
+    pub region: Region,
+    /// CRC-32 of the ROM data without its header.
+    pub crc32: u32,
+}
+
+impl RomInfo {
+    pub fn identify(path: &Path, database: &Database) -> Result<RomInfo, Error> {
+        let rom = Rom::load(&mut File::open(path)?)?;
+        let mut data = rom.prg.clone();
+        data.extend_from_slice(&rom.chr);
+        let crc32 = util::crc32(&data);
+        Ok(RomInfo {
+            path: path.to_path_buf(),
+            title: database.title(crc32).map(|title| title.to_string()),
+            mapper: rom.header.mapper_number(),
+            supported: mapper::unsupported(&rom.header).is_none(),
+            prg_kb: rom.header.prg_rom_size as u32 * 16,
+            chr_kb: rom.header.chr_rom_size as u32 * 8,
+            battery: rom.header.battery(),
+            region: rom.header.region(),
+            crc32,
+        })
+    }
+}
+
+/// Returns the paths of all `.nes` files under `dir`, sorted. Symlinks to directories aren't
+/// followed, so links can't loop, and subdirectories that can't be read are skipped.
+pub fn find_roms(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if let Ok(found) = find_roms(&path) {
+                roms.extend(found);
+            }
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("nes"))
+        {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+/// The outcome of scanning a directory. ROMs that failed to load are kept with their error.
+pub struct Scan {
+    pub roms: Vec<RomInfo>,
+    pub errors: Vec<(PathBuf, Error)>,
+}
+
+impl Scan {
+    pub fn run(dir: &Path, database: &Database) -> io::Result<Scan> {
+        let mut scan = Scan {
+            roms: Vec::new(),
+            errors: Vec::new(),
+        };
+        for path in find_roms(dir)? {
+            match RomInfo::identify(&path, database) {
+                Ok(info) => scan.roms.push(info),
+                Err(err) => scan.errors.push((path, err)),
+            }
+        }
+        Ok(scan)
+    }
+
+    pub fn supported_count(&self) -> usize {
+        self.roms.iter().filter(|info| info.supported).count()
+    }
+
+    pub fn identified_count(&self) -> usize {
+        self.roms.iter().filter(|info| info.title.is_some()).count()
+    }
+
+    /// Writes a CSV line per ROM, including the ones that failed to load.
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "path,title,crc32,mapper,supported,prg_kb,chr_kb,battery,region,error"
+        )?;
+        for info in &self.roms {
+            writeln!(
+                writer,
+                "\"{}\",\"{}\",{:08X},{},{},{},{},{},{},",
+                info.path.display().to_string().replace('"', "\"\""),
+                info.title
+                    .as_ref()
+                    .map_or("", |title| &title[..])
+                    .replace('"', "\"\""),
+                info.crc32,
+                info.mapper,
+                info.supported,
+                info.prg_kb,
+                info.chr_kb,
+                info.battery,
+                info.region,
+            )?;
+        }
+        for (path, err) in &self.errors {
+            writeln!(
+                writer,
+                "\"{}\",,,,false,,,,,\"{}\"",
+                path.display().to_string().replace('"', "\"\""),
+                err.to_string().replace('"', "\"\"")
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes an M3U playlist of the ROMs that should run.
+    pub fn write_playlist<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for info in self.roms.iter().filter(|info| info.supported) {
+            writeln!(writer, "{}", info.path.display())?;
+        }
+        Ok(())
+    }
+}
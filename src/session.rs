@@ -0,0 +1,150 @@
+//! Session bundles: one file holding what it takes to pick up a session on another machine, for
+//! bug reports and for sharing TAS work.
+//!
+//! A bundle is the magic `SNSB` and a version byte, followed by sections of a four-byte tag, a
+//! little-endian `u32` length and the data:
+//!
+//! * `ROM ` - the CRC-32 of the ROM file, as in `sprocketnes scan` reports.
+//! * `CONF` - the settings in effect, as `key = value` lines.
+//! * `STAT` - a savestate of the moment the session was exported.
+//! * `LTCH` - a latch movie of the input since power on.
+//!
+//! Replaying the movie from power on reproduces the session exactly, where the savestate only
+//! picks it up where it was exported.
+
+use util;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"SNSB";
+const VERSION: u8 = 1;
+
+/// Settings that name files or addresses on the exporting machine, which are left out.
+const LOCAL_SETTINGS: [&str; 9] = [
+    "trace",
+    "vgm",
+    "record_latches",
+    "play_latches",
+    "metrics",
+    "palette",
+    "barcode_file",
+    "export_layers",
+    "capture_on",
+];
+
+pub struct Session {
+    pub rom_crc32: u32,
+    /// Settings as `(key, value)` pairs, in the order to apply them.
+    pub settings: Vec<(String, String)>,
+    /// An encoded savestate.
+    pub state: Vec<u8>,
+    /// An encoded latch movie.
+    pub movie: Vec<u8>,
+    /// Whether to replay the movie from power on instead of loading the savestate. Not stored.
+    pub replay: bool,
+}
+
+impl Session {
+    /// Bundles a session, leaving out the settings that only make sense on this machine.
+    pub fn new(
+        rom_crc32: u32,
+        settings: &[(String, String)],
+        state: Vec<u8>,
+        movie: Vec<u8>,
+    ) -> Session {
+        Session {
+            rom_crc32,
+            settings: settings
+                .iter()
+                .filter(|(key, _)| !LOCAL_SETTINGS.contains(&&key[..]))
+                .cloned()
+                .collect(),
+            state,
+            movie,
+            replay: false,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let settings: String = self
+            .settings
+            .iter()
+            .map(|(key, value)| format!("{} = {}\n", key, value))
+            .collect();
+
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        push_section(&mut data, b"ROM ", &self.rom_crc32.to_le_bytes());
+        push_section(&mut data, b"CONF", settings.as_bytes());
+        push_section(&mut data, b"STAT", &self.state);
+        push_section(&mut data, b"LTCH", &self.movie);
+        util::write_atomic(path, &data)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Session> {
+        let data = fs::read(path)?;
+        if data.len() < 5 || &data[0..4] != MAGIC {
+            return Err(invalid("not a session bundle"));
+        }
+        if data[4] != VERSION {
+            return Err(invalid("unsupported session bundle version"));
+        }
+
+        let mut session = Session {
+            rom_crc32: 0,
+            settings: Vec::new(),
+            state: Vec::new(),
+            movie: Vec::new(),
+            replay: false,
+        };
+        let mut rest = &data[5..];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(invalid("truncated section header"));
+            }
+            let length = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            if rest.len() < 8 + length {
+                return Err(invalid("truncated section"));
+            }
+            let section = &rest[8..8 + length];
+            match &rest[0..4] {
+                b"ROM " if length == 4 => {
+                    session.rom_crc32 =
+                        u32::from_le_bytes([section[0], section[1], section[2], section[3]])
+                }
+                b"CONF" => {
+                    let text = String::from_utf8_lossy(section);
+                    for line in text.lines() {
+                        if let Some(pos) = line.find('=') {
+                            let (key, value) = (line[..pos].trim(), line[pos + 1..].trim());
+                            session.settings.push((key.to_string(), value.to_string()));
+                        }
+                    }
+                }
+                b"STAT" => session.state = section.to_vec(),
+                b"LTCH" => session.movie = section.to_vec(),
+                // Skip sections this version doesn't know.
+                _ => {}
+            }
+            rest = &rest[8 + length..];
+        }
+        Ok(session)
+    }
+}
+
+/// The CRC-32 of a ROM file, which identifies the ROM a session was exported from.
+pub fn rom_crc32(path: &Path) -> io::Result<u32> {
+    Ok(util::crc32(&fs::read(path)?))
+}
+
+fn push_section(data: &mut Vec<u8>, tag: &[u8; 4], section: &[u8]) {
+    data.extend_from_slice(tag);
+    data.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    data.extend_from_slice(section);
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -5,7 +5,6 @@
 //
 
 use libc::{c_int, c_void, int16_t, uint32_t};
-use std::mem::transmute;
 use std::ptr::null;
 
 type SpeexResamplerState = c_void;
@@ -20,12 +19,17 @@ extern "C" {
         err: *mut c_int,
     ) -> *const SpeexResamplerState;
     fn speex_resampler_destroy(st: *const SpeexResamplerState);
+    fn speex_resampler_set_rate(
+        st: *const SpeexResamplerState,
+        in_rate: uint32_t,
+        out_rate: uint32_t,
+    ) -> c_int;
     fn speex_resampler_process_int(
         st: *const SpeexResamplerState,
         channel_index: uint32_t,
         input: *const int16_t,
         in_len: *mut uint32_t,
-        out: *const int16_t,
+        out: *mut int16_t,
         out_len: *mut uint32_t,
     ) -> c_int;
 }
@@ -58,14 +62,22 @@ impl Resampler {
         }
     }
 
+    /// Changes the rates, keeping the stream going without a gap.
+    pub fn set_rate(&self, in_rate: u32, out_rate: u32) {
+        unsafe {
+            let err = speex_resampler_set_rate(self.speex_resampler, in_rate, out_rate);
+            assert!(err == 0);
+        }
+    }
+
     /// Resamples `input` on channel `channel_index` and writes the result to `out`.
     ///
     /// Returns a tuple of the number of input samples processed and output samples written.
-    pub fn process(&self, channel_index: u32, input: &[i16], out: &mut [u8]) -> (u32, u32) {
+    pub fn process(&self, channel_index: u32, input: &[i16], out: &mut [i16]) -> (u32, u32) {
         unsafe {
             assert!(input.len() <= 0xffffffff);
-            assert!(out.len() / 2 <= 0xffffffff);
-            let (in_len, out_len) = (input.len() as u32, out.len() as u32 / 2);
+            assert!(out.len() <= 0xffffffff);
+            let (in_len, out_len) = (input.len() as u32, out.len() as u32);
             let mut in_len = in_len;
             let mut out_len = out_len;
             let err = speex_resampler_process_int(
@@ -73,7 +85,7 @@ impl Resampler {
                 channel_index,
                 &input[0],
                 &mut in_len,
-                transmute(&out[0]),
+                &mut out[0],
                 &mut out_len,
             );
             assert!(err == 0);
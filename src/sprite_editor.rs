@@ -0,0 +1,163 @@
+//! A debug tool for editing sprites while the game runs, for reverse engineers working out how a
+//! game uses its sprites. It changes the position, tile and attributes of a sprite in OAM, and
+//! outlines the sprite on the screen.
+//!
+//! Games rewrite OAM every frame, usually with OAM DMA, so edits only last a frame unless OAM is
+//! frozen, which keeps the game from writing it until it is unfrozen or the editor closes.
+
+use gfx::{Osd, OsdAnchor, OsdContent};
+use ppu::Ppu;
+
+use sdl2::keyboard::Keycode;
+
+/// The sprites in OAM.
+const SPRITES: u8 = 64;
+/// The Y coordinates from here on put a sprite below the screen, hiding it.
+const HIDDEN_Y: u8 = 0xef;
+
+// Attribute bits.
+const ATTR_PALETTE: u8 = 0x03;
+const ATTR_BEHIND: u8 = 0x20;
+const ATTR_FLIP_H: u8 = 0x40;
+const ATTR_FLIP_V: u8 = 0x80;
+
+// Offsets of a sprite's bytes in OAM.
+const Y: u8 = 0;
+const TILE: u8 = 1;
+const ATTR: u8 = 2;
+const X: u8 = 3;
+
+pub struct SpriteEditor {
+    /// The sprite selected, 0-63.
+    sprite: u8,
+}
+
+impl Default for SpriteEditor {
+    fn default() -> SpriteEditor {
+        SpriteEditor::new()
+    }
+}
+
+impl SpriteEditor {
+    pub fn new() -> SpriteEditor {
+        SpriteEditor { sprite: 0 }
+    }
+
+    /// Handles a key pressed while the editor is open:
+    ///
+    /// * Left and Right select a sprite, Up and Down 8 sprites at a time.
+    /// * W and S move the sprite up and down, A and D left and right.
+    /// * T and G change its tile.
+    /// * P cycles its palette, B puts it in front of or behind the background, and H and V flip it.
+    /// * F freezes or unfreezes OAM.
+    ///
+    /// Returns a message to show, if any.
+    pub fn key(&mut self, key: Keycode, ppu: &mut Ppu) -> Option<String> {
+        let (offset, change) = match key {
+            Keycode::Left => return self.select(SPRITES - 1),
+            Keycode::Right => return self.select(1),
+            Keycode::Up => return self.select(SPRITES - 8),
+            Keycode::Down => return self.select(8),
+            Keycode::F => {
+                ppu.oam_frozen = !ppu.oam_frozen;
+                let state = if ppu.oam_frozen { "frozen" } else { "unfrozen" };
+                return Some(format!("OAM {}", state));
+            }
+            Keycode::W => (Y, 0xff),
+            Keycode::S => (Y, 1),
+            Keycode::A => (X, 0xff),
+            Keycode::D => (X, 1),
+            Keycode::T => (TILE, 1),
+            Keycode::G => (TILE, 0xff),
+            Keycode::P => {
+                let attr = self.byte(ppu, ATTR);
+                let palette = attr.wrapping_add(1) & ATTR_PALETTE;
+                self.set_byte(ppu, ATTR, attr & !ATTR_PALETTE | palette);
+                return None;
+            }
+            Keycode::B => (ATTR, ATTR_BEHIND),
+            Keycode::H => (ATTR, ATTR_FLIP_H),
+            Keycode::V => (ATTR, ATTR_FLIP_V),
+            _ => return None,
+        };
+        let val = self.byte(ppu, offset);
+        let val = if offset == ATTR {
+            val ^ change
+        } else {
+            val.wrapping_add(change)
+        };
+        self.set_byte(ppu, offset, val);
+        None
+    }
+
+    /// Shows the selected sprite's bytes in the top left corner and outlines it on the screen.
+    /// Call every frame, as the game changes OAM.
+    pub fn show(&self, ppu: &Ppu, osd: &mut Osd) {
+        let (y, tile, attr, x) = (
+            self.byte(ppu, Y),
+            self.byte(ppu, TILE),
+            self.byte(ppu, ATTR),
+            self.byte(ppu, X),
+        );
+        let text = format!(
+            "Sprite {}: X {} Y {} tile ${:02X} attr ${:02X}",
+            self.sprite, x, y, tile, attr
+        );
+        osd.set(
+            "sprite_bytes",
+            OsdAnchor::TopLeft,
+            OsdContent::Text(text),
+            None,
+        );
+
+        let mut flags = format!("palette {}", attr & ATTR_PALETTE);
+        let named = [
+            (attr & ATTR_BEHIND != 0, "behind"),
+            (attr & ATTR_FLIP_H != 0, "flipped H"),
+            (attr & ATTR_FLIP_V != 0, "flipped V"),
+            (ppu.oam_frozen, "OAM frozen"),
+        ];
+        for &(_, name) in named.iter().filter(|&&(set, _)| set) {
+            flags.push_str(", ");
+            flags.push_str(name);
+        }
+        osd.set(
+            "sprite_flags",
+            OsdAnchor::TopLeft,
+            OsdContent::Text(flags),
+            None,
+        );
+
+        if y >= HIDDEN_Y {
+            osd.remove("sprite_outline");
+            return;
+        }
+        // Sprites are drawn a line below their Y coordinate; the outline goes around them.
+        let height = ppu.sprite_height() as usize;
+        osd.set(
+            "sprite_outline",
+            OsdAnchor::At(x as isize - 1, y as isize),
+            OsdContent::Outline(10, height + 2),
+            None,
+        );
+    }
+
+    pub fn hide(osd: &mut Osd) {
+        osd.remove("sprite_bytes");
+        osd.remove("sprite_flags");
+        osd.remove("sprite_outline");
+    }
+
+    fn select(&mut self, change: u8) -> Option<String> {
+        self.sprite = (self.sprite + change) % SPRITES;
+        None
+    }
+
+    fn byte(&self, ppu: &Ppu, offset: u8) -> u8 {
+        ppu.oam()[(self.sprite * 4 + offset) as usize]
+    }
+
+    fn set_byte(&self, ppu: &mut Ppu, offset: u8, val: u8) {
+        ppu.set_oam(self.sprite * 4 + offset, val);
+    }
+}
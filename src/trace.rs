@@ -0,0 +1,118 @@
+use mem::Access;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Which memory accesses are written to an access trace.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TraceFilter {
+    All,
+    /// The PPU registers and their mirrors, $2000-$3FFF.
+    PpuRegisters,
+    /// The controller port, $4016.
+    Controller,
+    /// An inclusive address range.
+    Range(u16, u16),
+}
+
+impl TraceFilter {
+    /// Parses `all`, `ppu`, `4016`, or a range of hex addresses such as `6000-7fff`.
+    pub fn from_name(name: &str) -> Option<TraceFilter> {
+        match name {
+            "all" => Some(TraceFilter::All),
+            "ppu" => Some(TraceFilter::PpuRegisters),
+            "4016" => Some(TraceFilter::Controller),
+            _ => {
+                let mut bounds = name.splitn(2, '-');
+                let start = u16::from_str_radix(bounds.next()?, 16).ok()?;
+                let end = u16::from_str_radix(bounds.next()?, 16).ok()?;
+                if start > end {
+                    return None;
+                }
+                Some(TraceFilter::Range(start, end))
+            }
+        }
+    }
+
+    pub fn matches(self, addr: u16) -> bool {
+        match self {
+            TraceFilter::All => true,
+            TraceFilter::PpuRegisters => (0x2000..0x4000).contains(&addr),
+            TraceFilter::Controller => addr == 0x4016,
+            TraceFilter::Range(start, end) => addr >= start && addr <= end,
+        }
+    }
+}
+
+/// The position of the video hardware when an access happened.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VideoPosition {
+    pub frame: u64,
+    pub scanline: u16,
+    pub dot: u16,
+}
+
+/// Logs CPU memory accesses as CSV, one line per access, for analysis in external tools.
+pub struct AccessTrace {
+    filter: TraceFilter,
+    writer: BufWriter<File>,
+    /// The address of the instruction being executed, set before each CPU step.
+    pub pc: u16,
+    /// The CPU cycle the instruction being executed started at, set before each CPU step.
+    pub cycle: u64,
+}
+
+impl AccessTrace {
+    pub fn create(path: &Path, filter: TraceFilter) -> io::Result<AccessTrace> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "frame,scanline,dot,pc,access,address,value")?;
+        if cfg!(feature = "debug") {
+            write!(writer, ",device,open_bus,side_effects")?;
+        }
+        writeln!(writer)?;
+        Ok(AccessTrace {
+            filter,
+            writer,
+            pc: 0,
+            cycle: 0,
+        })
+    }
+
+    /// Whether an access to `addr` would be recorded.
+    pub fn wants(&self, addr: u16) -> bool {
+        self.filter.matches(addr)
+    }
+
+    /// Records a read (`write` false) or write of `val` at `addr`, with what it touched if known.
+    pub fn record(
+        &mut self,
+        position: VideoPosition,
+        write: bool,
+        addr: u16,
+        val: u8,
+        access: Option<Access>,
+    ) -> io::Result<()> {
+        write!(
+            self.writer,
+            "{},{},{},${:04X},{},${:04X},${:02X}",
+            position.frame,
+            position.scanline,
+            position.dot,
+            self.pc,
+            if write { 'W' } else { 'R' },
+            addr,
+            val
+        )?;
+        if let Some(access) = access {
+            write!(
+                self.writer,
+                ",{},{},{}",
+                access.device.name(),
+                access.open_bus,
+                access.side_effects
+            )?;
+        }
+        writeln!(self.writer)
+    }
+}
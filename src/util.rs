@@ -2,8 +2,10 @@
 // Author: Patrick Walton
 //
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
 /// Reads until the buffer is filled or the reader signals EOF
 pub fn read_to_buf(buf: &mut [u8], rd: &mut dyn Read) -> io::Result<()> {
@@ -23,6 +25,87 @@ pub fn read_to_buf(buf: &mut [u8], rd: &mut dyn Read) -> io::Result<()> {
     Ok(())
 }
 
+/// Replaces the file at `path` with `data` so that a crash, or a sync client reading the file
+/// mid-write, sees either the old contents or the new ones and never a truncated file: the data
+/// goes to a temporary file in the same directory, which is flushed to disk and renamed over the
+/// old file. If `path` is a symlink, the file it points to is replaced, not the link.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let path = resolve_symlinks(path)?;
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let written = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(data).and_then(|()| file.sync_all()))
+        .and_then(|()| fs::rename(&temp_path, &path));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+
+    // The rename is only durable once the directory is flushed too. Windows can't open
+    // directories, and its renames don't need it.
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Follows `path` while it is a symlink, so a save file linked into a synced folder stays a link.
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    // Give up on loops the way the OS does, after 40 links.
+    for _ in 0..40 {
+        match fs::symlink_metadata(&path) {
+            Ok(ref metadata) if metadata.file_type().is_symlink() => {}
+            Ok(_) => return Ok(path),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(path),
+            Err(err) => return Err(err),
+        }
+        let target = fs::read_link(&path)?;
+        path = match path.parent() {
+            Some(dir) => dir.join(target),
+            None => target,
+        };
+    }
+    Err(io::Error::other(format!(
+        "too many levels of symbolic links at {}",
+        path.display()
+    )))
+}
+
+/// Computes the CRC-32 (as used by zip and most ROM databases) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// Quotes `text` as a JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            ch if (ch as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 //
 // A tiny custom serialization infrastructure, used for savestates.
 //
@@ -33,67 +116,92 @@ pub fn read_to_buf(buf: &mut [u8], rd: &mut dyn Read) -> io::Result<()> {
 // TODO: use `serde` (if it's ready) or `rustc-serialize` and `bincode`
 
 pub trait Save {
-    fn save(&mut self, fd: &mut File);
-    fn load(&mut self, fd: &mut File);
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()>;
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()>;
 }
 
 impl Save for u8 {
-    fn save(&mut self, fd: &mut File) {
-        fd.write_all(&[*self]).unwrap();
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(&[*self])
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
         let mut buf = [0];
-        read_to_buf(&mut buf, fd).unwrap();
+        read_to_buf(&mut buf, fd)?;
         *self = buf[0];
+        Ok(())
     }
 }
 
 impl Save for u16 {
-    fn save(&mut self, fd: &mut File) {
-        fd.write(&[*self as u8, (*self >> 8) as u8]).unwrap();
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(&[*self as u8, (*self >> 8) as u8])
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
         let mut buf = [0, 0];
-        read_to_buf(&mut buf, fd).unwrap();
+        read_to_buf(&mut buf, fd)?;
         *self = (buf[0] as u16) | ((buf[1] as u16) << 8);
+        Ok(())
+    }
+}
+
+impl Save for u32 {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(&self.to_le_bytes())
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        let mut buf = [0; 4];
+        read_to_buf(&mut buf, fd)?;
+        *self = u32::from_le_bytes(buf);
+        Ok(())
     }
 }
 
 impl Save for u64 {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
         let mut buf = [0; 8];
         for i in 0..8 {
             buf[i] = ((*self) >> (i * 8)) as u8;
         }
-        fd.write_all(&buf).unwrap();
+        fd.write_all(&buf)
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
         let mut buf = [0; 8];
-        read_to_buf(&mut buf, fd).unwrap();
+        read_to_buf(&mut buf, fd)?;
         *self = 0;
         for i in 0..8 {
             *self = *self | (buf[i] as u64) << (i * 8);
         }
+        Ok(())
     }
 }
 
 impl<'a> Save for &'a mut [u8] {
-    fn save(&mut self, fd: &mut File) {
-        fd.write(*self).unwrap();
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(self)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        read_to_buf(self, fd)
     }
-    fn load(&mut self, fd: &mut File) {
-        read_to_buf(self, fd).unwrap();
+}
+
+impl<const N: usize> Save for [u8; N] {
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(self)
+    }
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
+        read_to_buf(self, fd)
     }
 }
 
 impl Save for bool {
-    fn save(&mut self, fd: &mut File) {
-        fd.write(&[if *self { 0 } else { 1 }]).unwrap();
+    fn save(&mut self, fd: &mut dyn Write) -> io::Result<()> {
+        fd.write_all(&[*self as u8])
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut dyn Read) -> io::Result<()> {
         let mut val: [u8; 1] = [0];
-        read_to_buf(&mut val, fd).unwrap();
-        *self = val[0] != 0
+        read_to_buf(&mut val, fd)?;
+        *self = val[0] != 0;
+        Ok(())
     }
 }
 
@@ -101,11 +209,13 @@ impl Save for bool {
 macro_rules! save_struct(
     ($name:ident { $($field:ident),* }) => (
         impl Save for $name {
-            fn save(&mut self, fd: &mut File) {
-                $(self.$field.save(fd);)*
+            fn save(&mut self, fd: &mut dyn std::io::Write) -> std::io::Result<()> {
+                $(self.$field.save(fd)?;)*
+                Ok(())
             }
-            fn load(&mut self, fd: &mut File) {
-                $(self.$field.load(fd);)*
+            fn load(&mut self, fd: &mut dyn std::io::Read) -> std::io::Result<()> {
+                $(self.$field.load(fd)?;)*
+                Ok(())
             }
         }
     )
@@ -114,19 +224,52 @@ macro_rules! save_struct(
 macro_rules! save_enum(
     ($name:ident { $val_0:ident, $val_1:ident }) => (
         impl Save for $name {
-            fn save(&mut self, fd: &mut File) {
+            fn save(&mut self, fd: &mut dyn std::io::Write) -> std::io::Result<()> {
                 let mut val: u8 = match *self { $name::$val_0 => 0, $name::$val_1 => 1 };
                 val.save(fd)
             }
-            fn load(&mut self, fd: &mut File) {
+            fn load(&mut self, fd: &mut dyn std::io::Read) -> std::io::Result<()> {
                 let mut val: u8 = 0;
-                val.load(fd);
+                val.load(fd)?;
                 *self = if val == 0 { $name::$val_0 } else { $name::$val_1 };
+                Ok(())
             }
         }
     )
 );
 
+//
+// Serde support for byte arrays larger than the 32 elements serde handles natively
+//
+
+#[cfg(feature = "serde")]
+pub mod serde_byte_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        if bytes.len() != N {
+            return Err(D::Error::invalid_length(
+                bytes.len(),
+                &"a fixed-size byte array",
+            ));
+        }
+        let mut array = [0; N];
+        array.copy_from_slice(&bytes);
+        Ok(array)
+    }
+}
+
 //
 // Random number generation
 //
@@ -134,6 +277,7 @@ macro_rules! save_enum(
 // TODO remove this and emulate the APU's noise generator properly
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Xorshift {
     pub x: u32,
     pub y: u32,
@@ -0,0 +1,136 @@
+//! Logs APU register writes as a VGM file, which chiptune players such as VGMPlay can play back.
+//! Writes to an EPSM are logged too, as YM2608 writes.
+//!
+//! Writes are timestamped with the CPU cycle of the instruction that made them and converted to
+//! 44.1 kHz VGM waits. DMC sample data isn't included, so DMC channels play silently.
+
+use apu::SampleClock;
+use epsm;
+use region::Region;
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The VGM version whose header includes the NES APU clock.
+const VERSION: u32 = 0x161;
+const HEADER_LENGTH: usize = 0xc0;
+
+const COMMAND_NES_APU_WRITE: u8 = 0xb4;
+/// Followed by 0x57 for the YM2608's second register bank.
+const COMMAND_YM2608_WRITE: u8 = 0x56;
+const COMMAND_WAIT: u8 = 0x61;
+const COMMAND_WAIT_NTSC_FRAME: u8 = 0x62;
+const COMMAND_WAIT_PAL_FRAME: u8 = 0x63;
+/// 0x70-0x7f wait 1-16 samples.
+const COMMAND_WAIT_SHORT: u8 = 0x70;
+const COMMAND_END: u8 = 0x66;
+
+pub struct VgmLog {
+    path: PathBuf,
+    cpu_clock: u32,
+    clock: SampleClock,
+    commands: Vec<u8>,
+    total_samples: u64,
+    /// The YM2608 register selected in each bank, and whether any were written.
+    ym2608_address: [u8; 2],
+    ym2608: bool,
+    /// The CPU cycle the instruction being executed started at, set before each CPU step.
+    pub cycle: u64,
+}
+
+impl VgmLog {
+    /// Starts a log that is written to `path` by `finish`.
+    pub fn new(path: &Path, region: Region) -> VgmLog {
+        VgmLog {
+            path: path.to_path_buf(),
+            cpu_clock: region.cpu_clock(),
+            clock: SampleClock::new(region),
+            commands: Vec::new(),
+            total_samples: 0,
+            ym2608_address: [0; 2],
+            ym2608: false,
+            cycle: 0,
+        }
+    }
+
+    /// Whether a write to `addr` goes to the APU and belongs in the log.
+    pub fn wants(addr: u16) -> bool {
+        (0x4000..=0x4013).contains(&addr) || addr == 0x4015 || addr == 0x4017
+    }
+
+    /// Records a write of `val` to the APU register at `addr`, or to the EPSM at $401C-$401F.
+    pub fn record(&mut self, addr: u16, val: u8) {
+        if (epsm::START..=epsm::END).contains(&addr) {
+            return self.record_ym2608(addr, val);
+        }
+        let cycle = self.cycle;
+        self.wait_until(cycle);
+        self.commands
+            .extend_from_slice(&[COMMAND_NES_APU_WRITE, (addr - 0x4000) as u8, val]);
+    }
+
+    /// The EPSM has an address and a data register per bank, where VGM writes both at once, so
+    /// only data writes are logged, to the register last selected.
+    fn record_ym2608(&mut self, addr: u16, val: u8) {
+        let bank = ((addr >> 1) & 1) as usize;
+        if addr & 1 == 0 {
+            self.ym2608_address[bank] = val;
+            return;
+        }
+        let cycle = self.cycle;
+        self.wait_until(cycle);
+        self.commands.extend_from_slice(&[
+            COMMAND_YM2608_WRITE + bank as u8,
+            self.ym2608_address[bank],
+            val,
+        ]);
+        self.ym2608 = true;
+    }
+
+    fn wait_until(&mut self, cycle: u64) {
+        let mut samples = self.clock.advance(cycle);
+        self.total_samples += samples as u64;
+        while samples > 0 {
+            let wait = samples.min(0xffff);
+            match wait {
+                735 => self.commands.push(COMMAND_WAIT_NTSC_FRAME),
+                882 => self.commands.push(COMMAND_WAIT_PAL_FRAME),
+                1..=16 => self.commands.push(COMMAND_WAIT_SHORT + (wait - 1) as u8),
+                _ => {
+                    self.commands
+                        .extend_from_slice(&[COMMAND_WAIT, wait as u8, (wait >> 8) as u8])
+                }
+            }
+            samples -= wait;
+        }
+    }
+
+    /// Writes the log, lasting until `cycle`.
+    pub fn finish(mut self, cycle: u64) -> io::Result<()> {
+        self.wait_until(cycle);
+        self.commands.push(COMMAND_END);
+
+        let mut header = [0; HEADER_LENGTH];
+        {
+            let mut put = |offset: usize, value: u32| {
+                for i in 0..4 {
+                    header[offset + i] = (value >> (i * 8)) as u8;
+                }
+            };
+            put(0x04, (HEADER_LENGTH + self.commands.len() - 4) as u32); // End of file offset
+            put(0x08, VERSION);
+            put(0x18, self.total_samples as u32);
+            put(0x34, (HEADER_LENGTH - 0x34) as u32); // Data offset
+            if self.ym2608 {
+                put(0x48, epsm::CLOCK as u32); // YM2608 clock
+            }
+            put(0x84, self.cpu_clock); // NES APU clock
+        }
+        header[0..4].copy_from_slice(b"Vgm ");
+
+        let mut file = File::create(&self.path)?;
+        file.write_all(&header)?;
+        file.write_all(&self.commands)
+    }
+}
@@ -0,0 +1,177 @@
+//! Detects emulation that has stalled in a tight loop, such as a game polling for an interrupt
+//! that a mapper doesn't emulate, so that it can be reported instead of silently freezing.
+//!
+//! Only the main program is watched: interrupt handlers run outside of it. A loop that waits on
+//! RAM while NMIs are on is how games wait for a button press, so it isn't reported.
+
+use cpu::Cpu;
+use disasm::{Disassembler, Instruction};
+use mapper::Capabilities;
+use mem::{MemMap, Peek};
+
+use std::fmt;
+
+/// How many frames in a row the CPU must stay in one loop before it counts as stalled: three
+/// seconds at 60 Hz.
+pub const STALL_FRAMES: u32 = 180;
+/// The largest loop, in bytes from its first instruction to its last, that counts as tight.
+const LOOP_SPAN: u16 = 32;
+
+const BRANCHES: [&str; 8] = ["BPL", "BMI", "BVC", "BVS", "BCC", "BCS", "BNE", "BEQ"];
+
+pub struct Watchdog {
+    mapper: u8,
+    capabilities: Capabilities,
+    /// The lowest and highest addresses of the main program executed this frame.
+    low: u16,
+    high: u16,
+    /// The loop of the previous frame, and how many frames in a row it has been all that the main
+    /// program ran.
+    stall: Option<(u16, u16)>,
+    frames: u32,
+    /// Whether the mapper has raised an IRQ since the loop started.
+    irq_seen: bool,
+    /// Whether the current loop has been diagnosed.
+    checked: bool,
+}
+
+impl Watchdog {
+    /// A watchdog for a game on the given mapper, which is named in diagnoses.
+    pub fn new(mapper: u8, capabilities: Capabilities) -> Watchdog {
+        Watchdog {
+            mapper,
+            capabilities,
+            low: 0xffff,
+            high: 0,
+            stall: None,
+            frames: 0,
+            irq_seen: false,
+            checked: false,
+        }
+    }
+
+    /// Notes that the instruction at `pc` is about to run. Call before every instruction.
+    #[inline]
+    pub fn step(&mut self, cpu: &Cpu<MemMap>) {
+        if !cpu.in_interrupt() && !cpu.jammed() {
+            let pc = cpu.pc();
+            self.low = self.low.min(pc);
+            self.high = self.high.max(pc);
+        }
+    }
+
+    /// Notes that the mapper raised an IRQ.
+    pub fn irq(&mut self) {
+        self.irq_seen = true;
+    }
+
+    /// Call at the end of each frame. Returns a diagnosis the first time the main program has run
+    /// nothing but the same tight loop for `STALL_FRAMES` frames, if the loop looks stuck.
+    pub fn end_frame(&mut self, cpu: &mut Cpu<MemMap>) -> Option<Stall> {
+        let range = (self.low, self.high);
+        self.low = 0xffff;
+        self.high = 0;
+
+        let tight = range.0 <= range.1 && range.1 - range.0 <= LOOP_SPAN;
+        if !tight || self.stall != Some(range) {
+            self.stall = if tight { Some(range) } else { None };
+            self.frames = 0;
+            self.irq_seen = false;
+            self.checked = false;
+            return None;
+        }
+
+        self.frames += 1;
+        if self.checked || self.frames < STALL_FRAMES {
+            return None;
+        }
+        self.checked = true;
+
+        let body = {
+            let mut peek = Peek(&mut cpu.mem);
+            let mut disassembler = Disassembler {
+                pc: range.0,
+                mem: &mut peek,
+            };
+            let mut body = Vec::new();
+            while disassembler.pc >= range.0 && disassembler.pc <= range.1 {
+                body.push(disassembler.instruction());
+            }
+            body
+        };
+        self.suspect(&body, cpu).map(|suspect| Stall {
+            low: range.0,
+            high: range.1,
+            body,
+            suspect,
+        })
+    }
+
+    /// Guesses what the loop is waiting for from the registers it polls and which interrupts
+    /// could end it, or returns `None` if the wait looks deliberate.
+    fn suspect(&self, body: &[Instruction], cpu: &Cpu<MemMap>) -> Option<String> {
+        let polls = |register: &str| {
+            body.iter()
+                .any(|instruction| instruction.operand == register)
+        };
+        let branches = body
+            .iter()
+            .any(|instruction| BRANCHES.contains(&&instruction.mnemonic[..]));
+        let nmis = cpu.mem.ppu.nmi_enabled();
+
+        let suspect = if polls("$2002") {
+            "polling PPUSTATUS for a sprite 0 hit or sprite overflow that never happens"
+        } else if polls("$4015") {
+            "polling APU status for a frame counter or DMC interrupt, which isn't fully emulated"
+        } else if !nmis
+            && !cpu.irqs_masked()
+            && branches
+            && !self.irq_seen
+            && !self.capabilities.irq
+        {
+            return Some(format!(
+                "waiting for an IRQ that never came; mapper {} has no IRQ, so the game may be \
+                 expecting a different board",
+                self.mapper
+            ));
+        } else if !nmis && !cpu.irqs_masked() && branches && !self.irq_seen {
+            return Some(format!(
+                "waiting for an IRQ that never came; mapper {}'s IRQ may not be emulated",
+                self.mapper
+            ));
+        } else if !nmis && cpu.irqs_masked() {
+            "nothing can end the loop, as NMIs are off and IRQs are masked; the game may have \
+             finished or crashed"
+        } else {
+            return None;
+        };
+        Some(suspect.to_string())
+    }
+}
+
+/// A diagnosis of a stalled game.
+pub struct Stall {
+    /// The addresses of the first and last instructions of the loop.
+    pub low: u16,
+    pub high: u16,
+    pub body: Vec<Instruction>,
+    pub suspect: String,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Emulation stalled: the CPU has run only the loop at ${:04X}-${:04X} for {} frames:",
+            self.low, self.high, STALL_FRAMES
+        )?;
+        for instruction in &self.body {
+            writeln!(
+                f,
+                "    ${:04X}  {} {}",
+                instruction.addr, instruction.mnemonic, instruction.operand
+            )?;
+        }
+        write!(f, "Suspected cause: {}", self.suspect)
+    }
+}
@@ -0,0 +1,114 @@
+//! The Zapper light gun, plugged into the second controller port and aimed with the mouse.
+//!
+//! A real Zapper's photodiode sees light only while the beam passes the spot it points at, and
+//! LCD lag throws that timing off. Calibration widens what counts as a hit: light can be held for
+//! several frames, the brightness threshold can be set, and an assist mode counts any bright
+//! pixel near the crosshair, whenever the beam is.
+
+use ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// How many scanlines the photodiode keeps seeing light after the beam has passed.
+const SENSE_SCANLINES: u16 = 20;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ZapperCalibration {
+    /// How bright, from 0 to 255, a pixel must be to be seen.
+    pub threshold: u8,
+    /// How many frames light stays seen, counting the frame it was seen in.
+    pub frames: u32,
+    /// With assist, the radius in pixels around the crosshair in which any bright pixel is a hit.
+    /// 0 turns assist off.
+    pub assist_radius: u32,
+}
+
+impl Default for ZapperCalibration {
+    fn default() -> ZapperCalibration {
+        ZapperCalibration::new()
+    }
+}
+
+impl ZapperCalibration {
+    pub fn new() -> ZapperCalibration {
+        ZapperCalibration {
+            threshold: 192,
+            frames: 1,
+            assist_radius: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Zapper {
+    pub calibration: ZapperCalibration,
+    /// Where the mouse is in the window, in window pixels.
+    pub pointer: (i32, i32),
+    /// The screen pixel the gun points at, or `None` if it points away from the screen.
+    pub aim: Option<(usize, usize)>,
+    pub trigger: bool,
+    /// Whether light was seen at the last `sense`.
+    light: bool,
+    /// The last frame light was seen on.
+    last_seen: Option<u64>,
+}
+
+impl Zapper {
+    pub fn new(calibration: ZapperCalibration) -> Zapper {
+        Zapper {
+            calibration,
+            pointer: (0, 0),
+            aim: None,
+            trigger: false,
+            light: false,
+            last_seen: None,
+        }
+    }
+
+    /// Looks at the screen as the PPU has drawn it so far. Call before each read of $4017.
+    pub fn sense(&mut self, ppu: &Ppu) {
+        let (frame, scanline) = (ppu.frame(), ppu.scanline());
+        let seen = match self.aim {
+            None => false,
+            Some((x, y)) if self.calibration.assist_radius > 0 => {
+                let radius = self.calibration.assist_radius as isize;
+                let mut seen = false;
+                for dy in -radius..radius + 1 {
+                    for dx in -radius..radius + 1 {
+                        if dx * dx + dy * dy <= radius * radius {
+                            let point = (x as isize + dx, y as isize + dy);
+                            seen = seen || self.is_bright(&ppu.screen[..], point);
+                        }
+                    }
+                }
+                seen
+            }
+            Some((x, y)) => {
+                let beam_passed = (y as u16) < scanline && scanline - (y as u16) <= SENSE_SCANLINES;
+                beam_passed && self.is_bright(&ppu.screen[..], (x as isize, y as isize))
+            }
+        };
+        if seen {
+            self.last_seen = Some(frame);
+        }
+        self.light = match self.last_seen {
+            Some(last_seen) => frame - last_seen < self.calibration.frames as u64,
+            None => false,
+        };
+    }
+
+    /// The Zapper's bits of $4017: bit 3 clear when light is seen, and bit 4 set while the
+    /// trigger is pulled.
+    pub fn bits(&self) -> u8 {
+        (!self.light as u8) << 3 | (self.trigger as u8) << 4
+    }
+
+    fn is_bright(&self, screen: &[u8], (x, y): (isize, isize)) -> bool {
+        if x < 0 || y < 0 || x >= SCREEN_WIDTH as isize || y >= SCREEN_HEIGHT as isize {
+            return false;
+        }
+        // The screen is BGR.
+        let offset = (y as usize * SCREEN_WIDTH + x as usize) * 3;
+        let (b, g, r) = (screen[offset], screen[offset + 1], screen[offset + 2]);
+        let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+        luma >= self.calibration.threshold as u32
+    }
+}